@@ -0,0 +1,31 @@
+use crate::bitstream::bext::{self, Loudness};
+use crate::plan::Plan;
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+/// Fail early if the output file is no WAV file
+pub fn check(output_file: &Path) -> Result<()> {
+    let extension = output_file
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+
+    if extension.as_deref() != Some("wav") {
+        bail!("--bext requires a WAV output file");
+    }
+
+    Ok(())
+}
+
+/// Write the loudness of the normalized output measured by the timeline pass to the bext chunk.
+pub fn write(output_file: &Path, input_file: &Path, loudness: &Loudness) -> Result<()> {
+    bext::write_file(output_file, Some(input_file), loudness)
+        .with_context(|| "Failed to write loudness to bext chunk")
+}
+
+/// Add a note of the bext chunk to the measure pass of the plan
+pub fn plan(plan: &mut Plan) {
+    plan.note(
+        "The measured loudness is written to the bext chunk of the output file, \
+         which has no equivalent command",
+    );
+}
@@ -0,0 +1,572 @@
+use crate::algorithm::range::MeasureRange;
+use crate::algorithm::timeline::{self, Timeline};
+use crate::plan::Plan;
+use crate::progress::{Measurement, Pass, Phase, Progress};
+use crate::xml::escape;
+use anyhow::{bail, Context, Result};
+use clap::crate_name;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PASS: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Measure,
+    description: "Processing audio file to check loudness",
+};
+
+/// Exit code if a file cannot be measured, like any other error
+const EXIT_ERROR: u8 = 1;
+/// Exit code bits of the failed checks of all files, e.g. 12 for loudness and true peak
+const EXIT_LOUDNESS: u8 = 4;
+const EXIT_TRUE_PEAK: u8 = 8;
+const EXIT_LOUDNESS_RANGE: u8 = 16;
+const EXIT_SHORT_TERM: u8 = 32;
+
+/// Loudness the files have to comply with
+#[derive(Serialize)]
+pub struct Spec {
+    /// Target integrated loudness in LUFS
+    pub target_level: f64,
+    /// Allowed deviation from the target level in LU
+    pub tolerance: f64,
+    /// Maximum true peak in dBTP
+    pub max_true_peak: f64,
+    /// Maximum loudness range in LU, not checked if `None`
+    pub max_loudness_range: Option<f64>,
+    /// Maximum short-term loudness in LUFS, not checked if `None`
+    pub max_short_term: Option<f64>,
+}
+
+pub struct CheckArgs<'a> {
+    pub verbose: bool,
+    pub files: &'a [PathBuf],
+    pub spec: &'a Spec,
+    /// Region of the files that is measured
+    pub measure_range: &'a MeasureRange,
+    /// JUnit XML or JSON file the result is written to
+    pub result_file: Option<&'a Path>,
+    pub progress: &'a dyn Progress,
+}
+
+/// Result file format, selected by the file extension
+#[derive(Clone, Copy)]
+enum Format {
+    JUnit,
+    Json,
+}
+
+/// Check of one spec value
+#[derive(Serialize)]
+struct Check {
+    name: &'static str,
+    /// Measured value, `None` if it is not measured
+    value: Option<f64>,
+    /// Description of the limit, e.g. "-23 ±1 LUFS"
+    limit: String,
+    passed: bool,
+    #[serde(skip)]
+    exit_code: u8,
+}
+
+#[derive(Serialize)]
+struct FileResult {
+    file: String,
+    passed: bool,
+    integrated: Option<f64>,
+    loudness_range: Option<f64>,
+    true_peak: Option<f64>,
+    max_momentary: Option<f64>,
+    max_short_term: Option<f64>,
+    checks: Vec<Check>,
+    /// Error if the file cannot be measured
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    spec: &'a Spec,
+    passed: usize,
+    failed: usize,
+    errors: usize,
+    exit_code: u8,
+    files: &'a [FileResult],
+}
+
+/// Fail early if the result file is neither JUnit XML nor JSON
+pub fn check(result_file: &Path) -> Result<()> {
+    format(result_file).map(|_| ())
+}
+
+fn format(result_file: &Path) -> Result<Format> {
+    let extension = result_file
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("xml") => Ok(Format::JUnit),
+        Some("json") => Ok(Format::Json),
+        _ => bail!("--result requires a .xml (JUnit) or .json file"),
+    }
+}
+
+/// Measure the files and check them against the spec, the exit code is 0 if all comply
+pub fn run(args: CheckArgs) -> Result<u8> {
+    let format = args.result_file.map(format).transpose()?;
+
+    let results: Vec<FileResult> = args
+        .files
+        .iter()
+        .map(|file| {
+            args.progress
+                .message(&format!("Checking \"{}\"", file.display()));
+            let result = match timeline::measure_file(
+                file,
+                args.measure_range,
+                &PASS,
+                args.verbose,
+                args.progress,
+            ) {
+                Ok(timeline) => evaluate(file, &timeline, args.spec, args.progress),
+                Err(err) => {
+                    let error = format!("{err:#}");
+                    args.progress.error(&format!(
+                        "Failed to measure loudness of \"{}\": {error}",
+                        file.display()
+                    ));
+                    FileResult {
+                        file: file.display().to_string(),
+                        passed: false,
+                        integrated: None,
+                        loudness_range: None,
+                        true_peak: None,
+                        max_momentary: None,
+                        max_short_term: None,
+                        checks: Vec::new(),
+                        error: Some(error),
+                    }
+                }
+            };
+            report(&result, args.progress);
+            result
+        })
+        .collect();
+
+    let errors = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+    let failed = results
+        .iter()
+        .filter(|result| result.error.is_none() && !result.passed)
+        .count();
+    let exit_code = exit_code(&results);
+
+    args.progress.message(&format!(
+        "{} of {} files comply with the spec, {failed} failed, {errors} could not be measured",
+        results.len() - failed - errors,
+        results.len()
+    ));
+
+    if let (Some(result_file), Some(format)) = (args.result_file, format) {
+        let summary = Summary {
+            spec: args.spec,
+            passed: results.len() - failed - errors,
+            failed,
+            errors,
+            exit_code,
+            files: &results,
+        };
+        let data = match format {
+            Format::JUnit => junit(&summary),
+            Format::Json => serde_json::to_string_pretty(&summary)?,
+        };
+        fs::write(result_file, data).with_context(|| {
+            format!("Failed to write check result \"{}\"", result_file.display())
+        })?;
+    }
+
+    Ok(exit_code)
+}
+
+/// Exit code of the results, the bits of all failed checks or 1 if a file cannot be measured
+fn exit_code(results: &[FileResult]) -> u8 {
+    if results.iter().any(|result| result.error.is_some()) {
+        return EXIT_ERROR;
+    }
+    results
+        .iter()
+        .flat_map(|result| &result.checks)
+        .filter(|check| !check.passed)
+        .fold(0, |exit_code, check| exit_code | check.exit_code)
+}
+
+pub fn plan(args: CheckArgs, plan: &mut Plan) {
+    args.files.iter().for_each(|file| {
+        timeline::plan_file(file, args.measure_range, &PASS, plan);
+    });
+    if let Some(result_file) = args.result_file {
+        plan.note(&format!(
+            "The result of the checks is written to \"{}\"",
+            result_file.display()
+        ));
+    }
+}
+
+fn evaluate(file: &Path, timeline: &Timeline, spec: &Spec, progress: &dyn Progress) -> FileResult {
+    let loudness = &timeline.loudness;
+
+    [
+        ("input_i", "Integrated loudness", loudness.value, "LUFS"),
+        ("input_lra", "Loudness range", loudness.range, "LU"),
+        ("input_tp", "True peak", loudness.max_true_peak, "dBTP"),
+        (
+            "input_max_s",
+            "Max short-term loudness",
+            loudness.max_short_term,
+            "LUFS",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, label, value, unit)| value.map(|value| (name, label, value, unit)))
+    .for_each(|(name, label, value, unit)| {
+        progress.measurement(&Measurement {
+            name,
+            label,
+            value,
+            unit,
+        })
+    });
+
+    let mut checks = vec![
+        Check {
+            name: "integrated_loudness",
+            value: loudness.value,
+            limit: format!("{} ±{} LUFS", spec.target_level, spec.tolerance),
+            passed: loudness
+                .value
+                .is_some_and(|value| (value - spec.target_level).abs() <= spec.tolerance),
+            exit_code: EXIT_LOUDNESS,
+        },
+        Check {
+            name: "true_peak",
+            value: loudness.max_true_peak,
+            limit: format!("≤ {} dBTP", spec.max_true_peak),
+            passed: loudness
+                .max_true_peak
+                .is_some_and(|value| value <= spec.max_true_peak),
+            exit_code: EXIT_TRUE_PEAK,
+        },
+    ];
+    if let Some(max) = spec.max_loudness_range {
+        checks.push(Check {
+            name: "loudness_range",
+            value: loudness.range,
+            limit: format!("≤ {max} LU"),
+            passed: loudness.range.is_some_and(|value| value <= max),
+            exit_code: EXIT_LOUDNESS_RANGE,
+        });
+    }
+    if let Some(max) = spec.max_short_term {
+        checks.push(Check {
+            name: "max_short_term",
+            value: loudness.max_short_term,
+            limit: format!("≤ {max} LUFS"),
+            passed: loudness.max_short_term.is_some_and(|value| value <= max),
+            exit_code: EXIT_SHORT_TERM,
+        });
+    }
+
+    FileResult {
+        file: file.display().to_string(),
+        passed: checks.iter().all(|check| check.passed),
+        integrated: loudness.value,
+        loudness_range: loudness.range,
+        true_peak: loudness.max_true_peak,
+        max_momentary: loudness.max_momentary,
+        max_short_term: loudness.max_short_term,
+        checks,
+        error: None,
+    }
+}
+
+/// Verdict of a file with the failed checks
+fn report(result: &FileResult, progress: &dyn Progress) {
+    let verdict = match &result.error {
+        Some(error) => format!("cannot be measured: {error}"),
+        None if result.passed => "passed".to_string(),
+        None => format!(
+            "failed {}",
+            result
+                .checks
+                .iter()
+                .filter(|check| !check.passed)
+                .map(describe)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    progress.message(&format!("\"{}\" {verdict}", result.file));
+}
+
+/// Measured value and limit of a check, e.g. "true_peak -0.5 dBTP (≤ -1 dBTP)"
+fn describe(check: &Check) -> String {
+    match check.value {
+        Some(value) => format!("{} {value} ({})", check.name, check.limit),
+        None => format!("{} not measured ({})", check.name, check.limit),
+    }
+}
+
+/// Result as JUnit XML, a test suite per file with a test case per check
+fn junit(summary: &Summary) -> String {
+    let checks = |result: &FileResult| result.checks.len().max(1);
+    let tests: usize = summary.files.iter().map(checks).sum();
+    let failures = summary
+        .files
+        .iter()
+        .flat_map(|result| &result.checks)
+        .filter(|check| !check.passed)
+        .count();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuites name=\"{}\" tests=\"{tests}\" failures=\"{failures}\" errors=\"{}\">",
+        crate_name!(),
+        summary.errors
+    );
+
+    summary.files.iter().for_each(|result| {
+        let file = escape(&result.file);
+        let failures = result.checks.iter().filter(|check| !check.passed).count();
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{file}\" tests=\"{}\" failures=\"{failures}\" errors=\"{}\">",
+            checks(result),
+            usize::from(result.error.is_some())
+        );
+
+        match &result.error {
+            Some(error) => {
+                let _ = writeln!(
+                    xml,
+                    "    <testcase classname=\"{file}\" name=\"measure\">\n      \
+                     <error message=\"{}\"/>\n    </testcase>",
+                    escape(error)
+                );
+            }
+            None => result.checks.iter().for_each(|check| {
+                if check.passed {
+                    let _ = writeln!(
+                        xml,
+                        "    <testcase classname=\"{file}\" name=\"{}\"/>",
+                        check.name
+                    );
+                } else {
+                    let _ = writeln!(
+                        xml,
+                        "    <testcase classname=\"{file}\" name=\"{}\">\n      \
+                         <failure message=\"{}\"/>\n    </testcase>",
+                        check.name,
+                        escape(&describe(check))
+                    );
+                }
+            }),
+        }
+
+        xml += "  </testsuite>\n";
+    });
+
+    xml += "</testsuites>\n";
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::bext::Loudness;
+    use crate::progress::{self, Recorder};
+
+    const SPEC: Spec = Spec {
+        target_level: -23.0,
+        tolerance: 1.0,
+        max_true_peak: -1.0,
+        max_loudness_range: Some(15.0),
+        max_short_term: Some(-18.0),
+    };
+
+    /// Result of a file of integrated loudness, loudness range, true peak and max short-term
+    fn result(file: &str, spec: &Spec, measured: [Option<f64>; 4]) -> FileResult {
+        let [value, range, max_true_peak, max_short_term] = measured;
+        let timeline = Timeline {
+            loudness: Loudness {
+                value,
+                range,
+                max_true_peak,
+                max_momentary: None,
+                max_short_term,
+            },
+            sample_peak: None,
+            frames: Vec::new(),
+        };
+        let progress = progress::new(progress::Format::Plain, None, false).unwrap();
+        evaluate(Path::new(file), &timeline, spec, progress.as_ref())
+    }
+
+    fn error(file: &str) -> FileResult {
+        FileResult {
+            file: file.to_string(),
+            passed: false,
+            integrated: None,
+            loudness_range: None,
+            true_peak: None,
+            max_momentary: None,
+            max_short_term: None,
+            checks: Vec::new(),
+            error: Some("No such file".to_string()),
+        }
+    }
+
+    fn passing() -> [Option<f64>; 4] {
+        [Some(-23.5), Some(8.0), Some(-1.5), Some(-19.0)]
+    }
+
+    #[test]
+    fn evaluate_reports_measurements() {
+        let progress = progress::new(progress::Format::Plain, None, false).unwrap();
+        let recorder = Recorder::new(progress.as_ref());
+        let timeline = Timeline {
+            loudness: Loudness {
+                value: Some(-23.5),
+                range: Some(8.0),
+                max_true_peak: Some(-1.5),
+                max_momentary: Some(-15.0),
+                max_short_term: None,
+            },
+            sample_peak: None,
+            frames: Vec::new(),
+        };
+
+        let result = evaluate(Path::new("a.wav"), &timeline, &SPEC, &recorder);
+        assert_eq!(recorder.get("input_i"), Some(-23.5));
+        assert_eq!(recorder.get("input_lra"), Some(8.0));
+        assert_eq!(recorder.get("input_tp"), Some(-1.5));
+        assert_eq!(recorder.get("input_max_s"), None);
+        // a value that is not measured fails its check
+        assert!(!result.passed);
+        assert_eq!(exit_code(&[result]), EXIT_SHORT_TERM);
+    }
+
+    #[test]
+    fn exit_code_of_failed_checks() {
+        let exit = |measured: [Option<f64>; 4]| exit_code(&[result("a.wav", &SPEC, measured)]);
+        let [i, lra, tp, s] = passing();
+
+        assert_eq!(exit(passing()), 0);
+        // the tolerance is inclusive
+        assert_eq!(exit([Some(-22.0), lra, tp, s]), 0);
+        assert_eq!(exit([Some(-21.9), lra, tp, s]), EXIT_LOUDNESS);
+        assert_eq!(exit([Some(-24.1), lra, tp, s]), EXIT_LOUDNESS);
+        assert_eq!(exit([i, lra, Some(-0.9), s]), EXIT_TRUE_PEAK);
+        assert_eq!(exit([Some(-20.0), lra, Some(0.0), s]), 12);
+        assert_eq!(exit([i, Some(15.1), tp, s]), EXIT_LOUDNESS_RANGE);
+        assert_eq!(exit([i, lra, tp, Some(-17.0)]), EXIT_SHORT_TERM);
+        assert_eq!(exit([None, None, None, None]), 60);
+    }
+
+    #[test]
+    fn exit_code_of_all_files() {
+        let loud = result(
+            "a.wav",
+            &SPEC,
+            [Some(-20.0), Some(8.0), Some(-1.5), Some(-19.0)],
+        );
+        let peak = result(
+            "b.wav",
+            &SPEC,
+            [Some(-23.0), Some(8.0), Some(-0.5), Some(-19.0)],
+        );
+        let passed = result("c.wav", &SPEC, passing());
+        assert_eq!(exit_code(&[loud, peak, passed]), 12);
+
+        // a file that cannot be measured is an error, whatever the checks of the others
+        let loud = result(
+            "a.wav",
+            &SPEC,
+            [Some(-20.0), Some(8.0), Some(-1.5), Some(-19.0)],
+        );
+        assert_eq!(exit_code(&[loud, error("d.wav")]), EXIT_ERROR);
+    }
+
+    #[test]
+    fn optional_limits_are_not_checked() {
+        let spec = Spec {
+            max_loudness_range: None,
+            max_short_term: None,
+            ..SPEC
+        };
+        let result = result(
+            "a.wav",
+            &spec,
+            [Some(-23.0), Some(30.0), Some(-2.0), Some(0.0)],
+        );
+        assert!(result.passed);
+        let names: Vec<_> = result.checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, ["integrated_loudness", "true_peak"]);
+    }
+
+    #[test]
+    fn result_format_by_extension() {
+        assert!(matches!(format(Path::new("qc.XML")), Ok(Format::JUnit)));
+        assert!(matches!(format(Path::new("qc.json")), Ok(Format::Json)));
+        assert!(check(Path::new("qc.txt")).is_err());
+        assert!(check(Path::new("qc")).is_err());
+    }
+
+    #[test]
+    fn junit_of_results() {
+        let results = [
+            result("a&b.wav", &SPEC, passing()),
+            result(
+                "c.wav",
+                &SPEC,
+                [Some(-23.0), Some(8.0), Some(-0.5), Some(-19.0)],
+            ),
+            error("d.wav"),
+        ];
+        let summary = Summary {
+            spec: &SPEC,
+            passed: 1,
+            failed: 1,
+            errors: 1,
+            exit_code: exit_code(&results),
+            files: &results,
+        };
+
+        let xml = junit(&summary);
+        assert!(xml.contains(&format!(
+            "<testsuites name=\"{}\" tests=\"9\" failures=\"1\" errors=\"1\">",
+            crate_name!()
+        )));
+        assert!(xml
+            .contains("<testsuite name=\"a&amp;b.wav\" tests=\"4\" failures=\"0\" errors=\"0\">"));
+        assert!(xml.contains("<testcase classname=\"a&amp;b.wav\" name=\"true_peak\"/>"));
+        assert!(xml.contains(
+            "<testcase classname=\"c.wav\" name=\"true_peak\">\n      \
+             <failure message=\"true_peak -0.5 (≤ -1 dBTP)\"/>"
+        ));
+        assert!(xml.contains(
+            "<testsuite name=\"d.wav\" tests=\"1\" failures=\"0\" errors=\"1\">\n    \
+             <testcase classname=\"d.wav\" name=\"measure\">\n      \
+             <error message=\"No such file\"/>"
+        ));
+
+        // the exit code of a check is not part of the JSON result
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["exit_code"], EXIT_ERROR);
+        assert_eq!(json["files"][1]["checks"][1]["passed"], false);
+        assert!(json["files"][1]["checks"][1].get("exit_code").is_none());
+    }
+}
@@ -1,46 +1,533 @@
-use crate::io::to_stdout;
+use crate::algorithm::range::{MeasureRange, WHOLE};
+use crate::algorithm::speech;
+use crate::algorithm::{probe_input, probe_reference, select_codec};
+use crate::bitstream::ac3;
+use crate::io::to_progress;
+use crate::plan::{Plan, Value};
+use crate::progress::{Measurement, Pass, Phase, Progress, Status};
+use crate::provenance::{self, Provenance};
+use crate::tool::codec::{CodecOptions, OutputCodec};
 use crate::tool::ffmpeg::FFmpeg;
-use crate::tool::ffprobe::FFprobe;
-use anyhow::{Context, Result};
+use crate::tool::ffprobe::Probe;
+use anyhow::{bail, Context, Result};
+use clap::{crate_name, ValueEnum};
+use std::ffi::OsStr;
 use std::path::Path;
 
+const PASS1: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Normalize,
+    description: "Dialogue Normalizing audio file",
+};
+const AUTO_PASS1: Pass = Pass {
+    number: 1,
+    count: 2,
+    phase: Phase::Measure,
+    description: "Processing audio file to measure dialogue loudness",
+};
+const AUTO_PASS2: Pass = Pass {
+    number: 2,
+    count: 2,
+    phase: Phase::Normalize,
+    description: "Dialogue Normalizing audio file",
+};
+const REWRITE_PASS1: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Normalize,
+    description: "Rewriting dialnorm of audio file without re-encoding",
+};
+const AUTO_REWRITE_PASS2: Pass = Pass {
+    number: 2,
+    count: 2,
+    phase: Phase::Normalize,
+    description: "Rewriting dialnorm of audio file without re-encoding",
+};
+const REFERENCE_PASS: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Measure,
+    description: "Processing reference file to measure dialogue loudness",
+};
+
+/// Raw bitstream formats and output file extensions the dialnorm can be rewritten in
+const REWRITE_FORMATS: &[(&str, &[&str])] = &[("ac3", &["ac3"]), ("eac3", &["eac3", "ec3"])];
+
+/// Valid dialnorm values of AC-3 and E-AC-3
+const DIALNORM_RANGE: (f64, f64) = (-31.0, -1.0);
+
+/// Codecs with a dialnorm field
+const DIALNORM_CODECS: &[&str] = &["ac3", "eac3"];
+
+/// What to do if the output codec does not support dialnorm
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fallback {
+    /// Fail with an error
+    Error,
+    /// Store the dialnorm value as "DIALNORM" tag of the audio stream
+    Tag,
+    /// Apply the level shift a decoder would apply as volume adjustment
+    Gain,
+}
+
 pub struct NormalizationArgs<'a> {
     pub verbose: bool,
     pub input_file: &'a Path,
     pub output_file: &'a Path,
-    pub overwrite: bool,
     pub target_level: i8,
+    /// Measure the dialogue loudness to set the dialnorm value instead of `target_level`
+    pub auto: bool,
+    /// Measure the dialogue loudness of this file to set the dialnorm value instead of `target_level`
+    pub reference: Option<&'a Path>,
+    pub fallback: Fallback,
+    /// Encode with ffmpeg even if the dialnorm can be rewritten in the bitstream
+    pub reencode: bool,
+    /// Region of the input that pass 1 measures
+    pub measure_range: &'a MeasureRange,
     pub ffmpeg_args: &'a [String],
+    pub codec_options: &'a CodecOptions,
+    pub progress: &'a dyn Progress,
+}
+
+struct NormalizationCommonArgs<'a> {
+    verbose: bool,
+    input_file: &'a Path,
+    input_file_info: Probe,
+    output_codec: OutputCodec,
+    /// `None` if the output codec supports dialnorm
+    fallback: Option<Fallback>,
+    /// Rewrite the dialnorm in the bitstream instead of encoding with ffmpeg
+    rewrite: bool,
+    /// Write provenance tags to the output file
+    tags: bool,
+    reference: Option<&'a Path>,
+    measure_range: &'a MeasureRange,
+    ffmpeg_args: &'a [String],
+    progress: &'a dyn Progress,
 }
 
 pub fn normalize(args: NormalizationArgs) -> Result<()> {
-    // get input file information
-    let input_file_info =
-        FFprobe::info(args.input_file).with_context(|| "Failed to get input file information")?;
+    let common_args = common_args(&args)?;
 
-    let mut ffmpeg = FFmpeg::new(args.input_file);
+    let (pass, dialnorm, loudness) = if args.auto {
+        let loudness = measure(&common_args, &AUTO_PASS1)
+            .with_context(|| "Failed to run pass 1 to measure dialogue loudness")?;
+        let Some(dialnorm) = dialnorm(loudness) else {
+            bail!(
+                "Dialogue loudness of {} cannot be measured, it is silent, set --target-level instead",
+                args.input_file.display()
+            );
+        };
 
-    ffmpeg
-        .cmd()
-        .arg("-dialnorm")
-        .arg(args.target_level.to_string());
+        args.progress.measurement(&Measurement {
+            name: "input_i",
+            label: "Dialogue loudness",
+            value: loudness,
+            unit: "LUFS",
+        });
+        args.progress.measurement(&Measurement {
+            name: "dialnorm",
+            label: "Dialogue normalization",
+            value: dialnorm,
+            unit: "dB",
+        });
+        args.progress.message(&format!(
+            "Measured dialogue loudness {loudness} LUFS, dialnorm is set to {dialnorm} dB"
+        ));
 
-    ffmpeg.add_common_args(&input_file_info, args.ffmpeg_args);
+        let pass = if common_args.rewrite {
+            &AUTO_REWRITE_PASS2
+        } else {
+            &AUTO_PASS2
+        };
+        (pass, dialnorm, Some(Value::Known(loudness)))
+    } else {
+        let pass = if common_args.rewrite {
+            &REWRITE_PASS1
+        } else {
+            &PASS1
+        };
+        let dialnorm = match args.reference {
+            Some(reference) => measure_reference(&args, reference)?,
+            None => f64::from(args.target_level),
+        };
+        (pass, dialnorm, None)
+    };
 
-    if args.overwrite {
-        ffmpeg.cmd().arg("-y");
+    if common_args.rewrite {
+        return rewrite(&common_args, pass, dialnorm as i8, args.output_file);
     }
-    ffmpeg.cmd().arg(args.output_file);
+
+    let mut ffmpeg = command(
+        &common_args,
+        Value::Known(dialnorm),
+        loudness,
+        args.output_file,
+    );
 
     let reader = ffmpeg
         .exec(
-            "[1/1] Dialogue Normalizing audio file:",
+            pass,
             args.verbose,
-            input_file_info.duration,
+            common_args.input_file_info.stream.duration,
+            args.progress,
         )
         .with_context(|| "Failed to normalizing audio file")?;
 
-    to_stdout(reader);
+    to_progress(reader, args.progress);
 
     Ok(())
 }
+
+pub fn plan(args: NormalizationArgs, plan: &mut Plan) -> Result<()> {
+    let common_args = common_args(&args)?;
+
+    let dialnorm = if args.auto || args.reference.is_some() {
+        Value::Placeholder("DIALNORM")
+    } else {
+        Value::Known(f64::from(args.target_level))
+    };
+
+    if args.auto {
+        plan.add(&AUTO_PASS1, &measure_command(&common_args));
+    } else if let Some(reference) = args.reference {
+        plan.add(
+            &REFERENCE_PASS,
+            &measure_command(&reference_args(&args, reference)?),
+        );
+    }
+
+    match (common_args.rewrite, args.auto) {
+        (true, auto) => {
+            // the rewrite is built in, this is the equivalent command
+            let dialnorm = dialnorm.to_string();
+            plan.add_command(
+                if auto {
+                    &AUTO_REWRITE_PASS2
+                } else {
+                    &REWRITE_PASS1
+                },
+                [
+                    OsStr::new(crate_name!()),
+                    OsStr::new("-i"),
+                    args.input_file.as_os_str(),
+                    OsStr::new("-o"),
+                    args.output_file.as_os_str(),
+                    OsStr::new("dialogue"),
+                    OsStr::new("--target-level"),
+                    OsStr::new(&dialnorm),
+                ]
+                .into_iter(),
+            );
+        }
+        (false, true) => plan.add(
+            &AUTO_PASS2,
+            &command(&common_args, dialnorm, None, args.output_file),
+        ),
+        (false, false) => plan.add(
+            &PASS1,
+            &command(&common_args, dialnorm, None, args.output_file),
+        ),
+    }
+
+    let measured = match args.reference {
+        Some(_) => Some("of the reference file"),
+        None => args.auto.then_some("measured by pass 1"),
+    };
+    if let Some(measured) = measured {
+        let description = format!(
+            "dialogue loudness {measured}, rounded and clamped to {} .. {}",
+            DIALNORM_RANGE.0, DIALNORM_RANGE.1
+        );
+        match common_args.fallback {
+            Some(Fallback::Gain) => plan.placeholder(
+                "VOLUME_ADJUSTMENT",
+                &format!("{} minus {description}", DIALNORM_RANGE.0),
+            ),
+            _ => plan.placeholder("DIALNORM", &description),
+        }
+    }
+
+    Ok(())
+}
+
+fn common_args<'a>(args: &NormalizationArgs<'a>) -> Result<NormalizationCommonArgs<'a>> {
+    if args.auto && args.reference.is_some() {
+        bail!("--match cannot be combined with --auto, the dialnorm value is measured from the reference file");
+    }
+
+    let input_file_info = probe_input(args.input_file, args.verbose, args.progress)?;
+
+    // a raw AC-3 or E-AC-3 bitstream that is not changed otherwise
+    let input_codec = input_file_info.stream.codec_name.as_str();
+    let extension = args
+        .output_file
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let rewrite = !args.reencode
+        && args.ffmpeg_args.is_empty()
+        && args.codec_options.bit_rate.is_none()
+        && args.codec_options.quality.is_none()
+        && !args
+            .codec_options
+            .codec_map
+            .iter()
+            .any(|(codec, _)| codec == input_codec)
+        && REWRITE_FORMATS.iter().any(|(format, extensions)| {
+            *format == input_codec
+                && input_file_info.format.format_name == *format
+                && extensions.contains(&extension.as_str())
+        });
+
+    let output_codec = if rewrite {
+        OutputCodec {
+            codec: Some(input_codec.to_string()),
+            ..Default::default()
+        }
+    } else {
+        select_codec(
+            &input_file_info,
+            args.output_file,
+            args.codec_options,
+            args.ffmpeg_args,
+            args.progress,
+        )?
+    };
+
+    let codec = output_codec.codec.as_deref().unwrap_or("unknown");
+    let fallback = if DIALNORM_CODECS.contains(&codec) {
+        None
+    } else {
+        match args.fallback {
+            Fallback::Error => bail!(
+                "Output codec \"{codec}\" does not support dialnorm, only {} do. Use an .ac3 or .eac3 output file, --codec-map or --fallback tag|gain",
+                DIALNORM_CODECS.join(" and ")
+            ),
+            Fallback::Tag => args.progress.message(&format!(
+                "Warning: output codec \"{codec}\" does not support dialnorm, it is stored as DIALNORM tag"
+            )),
+            Fallback::Gain => args.progress.message(&format!(
+                "Warning: output codec \"{codec}\" does not support dialnorm, it is applied as volume adjustment"
+            )),
+        }
+        Some(args.fallback)
+    };
+
+    Ok(NormalizationCommonArgs {
+        verbose: args.verbose,
+        input_file: args.input_file,
+        input_file_info,
+        output_codec,
+        fallback,
+        rewrite,
+        tags: provenance::is_enabled(args.output_file, args.codec_options.tags),
+        reference: args.reference,
+        measure_range: args.measure_range,
+        ffmpeg_args: args.ffmpeg_args,
+        progress: args.progress,
+    })
+}
+
+/// Dialnorm value matching the dialogue loudness, e.g. -24 for -23.6 LUFS,
+/// `None` if the loudness is not finite, e.g. -inf of silence
+fn dialnorm(loudness: f64) -> Option<f64> {
+    loudness
+        .is_finite()
+        .then(|| loudness.round().clamp(DIALNORM_RANGE.0, DIALNORM_RANGE.1))
+}
+
+/// Common args to measure the reference file of `--match`
+fn reference_args<'a>(
+    args: &NormalizationArgs<'a>,
+    reference: &'a Path,
+) -> Result<NormalizationCommonArgs<'a>> {
+    Ok(NormalizationCommonArgs {
+        verbose: args.verbose,
+        input_file: reference,
+        input_file_info: probe_reference(reference, args.verbose, args.progress)?,
+        // the reference is only decoded
+        output_codec: OutputCodec::default(),
+        fallback: None,
+        rewrite: false,
+        tags: false,
+        reference: None,
+        measure_range: &WHOLE,
+        ffmpeg_args: &[],
+        progress: args.progress,
+    })
+}
+
+/// Dialnorm value matching the dialogue loudness of the reference file of `--match`
+fn measure_reference(args: &NormalizationArgs, reference: &Path) -> Result<f64> {
+    let loudness = measure(&reference_args(args, reference)?, &REFERENCE_PASS)
+        .with_context(|| "Failed to measure reference file")?;
+    let Some(dialnorm) = dialnorm(loudness) else {
+        bail!(
+            "Dialogue loudness of reference file {} cannot be measured, it is silent",
+            reference.display()
+        );
+    };
+
+    args.progress.measurement(&Measurement {
+        name: "reference_i",
+        label: "Reference dialogue loudness",
+        value: loudness,
+        unit: "LUFS",
+    });
+    args.progress.measurement(&Measurement {
+        name: "dialnorm",
+        label: "Dialogue normalization",
+        value: dialnorm,
+        unit: "dB",
+    });
+    args.progress.message(&format!(
+        "Measured dialogue loudness {loudness} LUFS of the reference file, dialnorm is set to {dialnorm} dB"
+    ));
+
+    Ok(dialnorm)
+}
+
+fn rewrite(
+    args: &NormalizationCommonArgs,
+    pass: &Pass,
+    dialnorm: i8,
+    output_file: &Path,
+) -> Result<()> {
+    args.progress
+        .start(pass, args.input_file_info.stream.duration);
+
+    let summary = ac3::rewrite_file(args.input_file, output_file, dialnorm)
+        .with_context(|| "Failed to rewrite dialnorm")?;
+
+    args.progress.finish(&Status {
+        out_time: summary.duration,
+        total_size: output_file.metadata().ok().map(|metadata| metadata.len()),
+        end: true,
+        ..Default::default()
+    });
+
+    let previous: Vec<String> = summary
+        .previous
+        .iter()
+        .map(|previous| format!("{previous} dB"))
+        .collect();
+    args.progress.message(&format!(
+        "Dialnorm of {} frames is changed from {} to {dialnorm} dB",
+        summary.frames,
+        previous.join(", ")
+    ));
+
+    Ok(())
+}
+
+/// Dialogue loudness of the speech in the measured range like `ebu --gating dialogue`, the
+/// loudness of the whole range if there is no speech and -inf if it is silent
+fn measure(args: &NormalizationCommonArgs, pass: &Pass) -> Result<f64> {
+    let mut ffmpeg = measure_command(args);
+
+    // speech is detected in the whole input
+    let reader = ffmpeg
+        .exec(
+            pass,
+            args.verbose,
+            args.input_file_info.stream.duration,
+            args.progress,
+        )
+        .with_context(|| "Failed to processing audio file to measure dialogue loudness")?;
+
+    let speech =
+        speech::result(reader).with_context(|| "Failed to parse speech detection result")?;
+
+    let loudness = match speech.dialogue_loudness(args.measure_range) {
+        Some(loudness) => Some(loudness),
+        None => {
+            args.progress.message(&format!(
+                "Warning: no speech is detected in the measured range of {}, the loudness of the whole range is measured",
+                args.input_file.display()
+            ));
+            speech.loudness(args.measure_range)
+        }
+    };
+
+    Ok(loudness.unwrap_or(f64::NEG_INFINITY))
+}
+
+fn measure_command(args: &NormalizationCommonArgs) -> FFmpeg {
+    speech::command(args.input_file, args.input_file_info.stream.sample_rate)
+}
+
+/// Command to set the dialnorm, `loudness` is the measured dialogue loudness of `--auto`
+fn command(
+    args: &NormalizationCommonArgs,
+    dialnorm: Value,
+    loudness: Option<Value>,
+    output_file: &Path,
+) -> FFmpeg {
+    let mut ffmpeg = FFmpeg::new(args.input_file);
+
+    // a decoder attenuates by the difference to -31 dB
+    let volume_adjustment = match dialnorm {
+        Value::Known(dialnorm) => Value::Known(DIALNORM_RANGE.0 - dialnorm),
+        Value::Placeholder(_) => Value::Placeholder("VOLUME_ADJUSTMENT"),
+    };
+
+    match args.fallback {
+        None | Some(Fallback::Error) => {
+            ffmpeg.cmd().arg("-dialnorm").arg(dialnorm.to_string());
+        }
+        Some(Fallback::Tag) => {
+            ffmpeg
+                .cmd()
+                .arg("-metadata:s:a:0")
+                .arg(format!("DIALNORM={dialnorm}"));
+        }
+        Some(Fallback::Gain) => {
+            ffmpeg
+                .cmd()
+                .arg("-filter")
+                .arg(format!("volume={volume_adjustment}dB"));
+        }
+    }
+
+    if args.tags {
+        let mut provenance = Provenance::new("dialogue", format!("dialnorm {dialnorm} dB"))
+            .reference(args.reference);
+        if let Some(loudness) = loudness {
+            provenance = provenance.input("input_i", loudness, "LUFS");
+        }
+        if args.fallback == Some(Fallback::Gain) {
+            provenance = provenance.gain(volume_adjustment);
+        }
+        ffmpeg.add_metadata(output_file, &args.input_file_info, &provenance.tags());
+    }
+
+    ffmpeg.add_common_args(&args.output_codec, args.ffmpeg_args);
+
+    // output is a temporary file owned by us
+    ffmpeg.cmd().arg("-y").arg(output_file);
+
+    ffmpeg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dialnorm_of_loudness() {
+        assert_eq!(dialnorm(-23.6), Some(-24.0));
+        assert_eq!(dialnorm(-23.4), Some(-23.0));
+        assert_eq!(dialnorm(-24.5), Some(-25.0));
+        // clamped to the dialnorm range
+        assert_eq!(dialnorm(-45.0), Some(-31.0));
+        assert_eq!(dialnorm(-31.4), Some(-31.0));
+        assert_eq!(dialnorm(-0.4), Some(-1.0));
+        assert_eq!(dialnorm(3.0), Some(-1.0));
+        // silence
+        assert_eq!(dialnorm(f64::NEG_INFINITY), None);
+        assert_eq!(dialnorm(f64::INFINITY), None);
+        assert_eq!(dialnorm(f64::NAN), None);
+    }
+}
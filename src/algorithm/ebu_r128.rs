@@ -1,14 +1,86 @@
-use crate::io::to_stdout;
+use crate::algorithm::range::{MeasureRange, WHOLE};
+use crate::algorithm::{
+    native::{Method, Rounding},
+    probe_input, probe_reference, select_output, speech,
+};
+use crate::io::to_progress;
+use crate::plan::{Plan, Value};
+use crate::progress::{Measurement, Pass, Phase, Progress};
+use crate::provenance::{self, Provenance};
+use crate::tool::codec::{CodecOptions, OutputCodec};
 use crate::tool::ffmpeg::FFmpeg;
-use crate::tool::ffprobe::{AudioStream, FFprobe};
+use crate::tool::ffprobe::Probe;
 use anyhow::{Context, Result};
+use clap::ValueEnum;
 use lazy_static::lazy_static;
 use regex::Regex;
-use serde::{de::Error, Deserialize, Deserializer};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use std::time::Duration;
 use std::{io::BufRead, path::Path};
 
 lazy_static! {
     static ref RE_VALUES: Regex = Regex::new(r#"^\s*"(\S+)"\s*:\s*"(\S+)",?\s*$"#).unwrap();
+    // true peak of the ebur128 summary, e.g. "    Peak:         -1.3 dBFS"
+    static ref RE_TRUE_PEAK: Regex = Regex::new(r#"^\s*Peak:\s*(\S+)\s+dBFS\s*$"#).unwrap();
+}
+
+const PASS1: Pass = Pass {
+    number: 1,
+    count: 2,
+    phase: Phase::Measure,
+    description: "Processing audio file to measure loudness values",
+};
+const PASS2: Pass = Pass {
+    number: 2,
+    count: 2,
+    phase: Phase::Normalize,
+    description: "EBU R128 Normalizing audio file",
+};
+const SPEECH_PASS: Pass = Pass {
+    number: 1,
+    count: 3,
+    phase: Phase::Measure,
+    description: "Processing audio file to detect speech",
+};
+const DIALOGUE_PASS2: Pass = Pass {
+    number: 2,
+    count: 3,
+    phase: Phase::Measure,
+    description: "Processing audio file to measure loudness values",
+};
+const DIALOGUE_PASS3: Pass = Pass {
+    number: 3,
+    count: 3,
+    phase: Phase::Normalize,
+    description: "EBU R128 Normalizing audio file",
+};
+const REFERENCE_PASS: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Measure,
+    description: "Processing reference file to measure loudness values",
+};
+const REFERENCE_SPEECH_PASS: Pass = Pass {
+    number: 1,
+    count: 2,
+    phase: Phase::Measure,
+    description: "Processing reference file to detect speech",
+};
+const REFERENCE_DIALOGUE_PASS2: Pass = Pass {
+    number: 2,
+    count: 2,
+    phase: Phase::Measure,
+    description: "Processing reference file to measure loudness values",
+};
+
+/// What the integrated loudness is measured over
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Gating {
+    /// The whole program with the gates of EBU R128
+    Program,
+    /// Only the speech regions found by a speech detection pass
+    Dialogue,
 }
 
 #[derive(Deserialize)]
@@ -35,107 +107,566 @@ pub struct NormalizationArgs<'a> {
     pub verbose: bool,
     pub input_file: &'a Path,
     pub output_file: &'a Path,
-    pub overwrite: bool,
     pub target_level: f64,
     pub loudness_range_target: f64,
     pub true_peak: f64,
     pub offset: f64,
+    pub gating: Gating,
+    /// Measure the target level from this file instead of `target_level`
+    pub reference: Option<&'a Path>,
+    /// Region of the input that pass 1 measures
+    pub measure_range: &'a MeasureRange,
     pub ffmpeg_args: &'a [String],
+    pub codec_options: &'a CodecOptions,
+    pub progress: &'a dyn Progress,
 }
 
 struct NormalizationCommonArgs<'a> {
     verbose: bool,
     input_file: &'a Path,
-    input_file_info: AudioStream,
-    target_level: f64,
+    input_file_info: Probe,
+    output_codec: OutputCodec,
+    /// Apply the gain without encoding with ffmpeg
+    native: Option<Method>,
+    /// Known in pass 1 unless it is measured from the reference file of `--match`
+    target_level: Value,
     loudness_range_target: f64,
     true_peak: f64,
     offset: f64,
+    gating: Gating,
+    /// Pass detecting speech of the dialogue gating
+    speech_pass: &'static Pass,
+    /// Pass measuring the loudness values
+    measure_pass: &'static Pass,
+    /// Pass normalizing the audio file
+    normalize_pass: &'static Pass,
+    /// Write provenance tags to the output file
+    tags: bool,
+    reference: Option<&'a Path>,
+    measure_range: &'a MeasureRange,
     ffmpeg_args: &'a [String],
+    progress: &'a dyn Progress,
 }
 
 struct NormalizationPass1Args<'a> {
     common_args: &'a NormalizationCommonArgs<'a>,
+    /// Filters in front of loudnorm that keep only the measured segments
+    range_filter: Option<String>,
+    /// Duration of the input, the true peak is measured over all of it
+    duration: Option<Duration>,
 }
 
 struct NormalizationPass2Args<'a> {
     common_args: &'a NormalizationCommonArgs<'a>,
-    measured_i: f64,
-    measured_lra: f64,
-    measured_tp: f64,
-    measured_thresh: f64,
-    target_offset: f64,
+    measured_i: Value,
+    measured_lra: Value,
+    measured_tp: Value,
+    measured_thresh: Value,
+    target_offset: Value,
+    tags: Vec<(String, String)>,
     output_file: &'a Path,
-    overwrite: bool,
 }
 
 pub fn normalize(args: NormalizationArgs) -> Result<()> {
-    // get input file information
-    let input_file_info =
-        FFprobe::info(args.input_file).with_context(|| "Failed to get input file information")?;
+    let mut common_args = common_args(&args)?;
+
+    let target_level = match args.reference {
+        Some(reference) => measure_reference(&args, reference)?,
+        None => args.target_level,
+    };
+    common_args.target_level = Value::Known(target_level);
+
+    let values = measure(&common_args, true)?;
+
+    let loudness_label = match args.gating {
+        Gating::Program => "Integrated loudness",
+        Gating::Dialogue => "Dialogue loudness",
+    };
+
+    [
+        ("input_i", loudness_label, values.input_i, "LUFS"),
+        ("input_lra", "Loudness range", values.input_lra, "LU"),
+        ("input_tp", "True peak", values.input_tp, "dBTP"),
+        ("input_thresh", "Threshold", values.input_thresh, "LUFS"),
+        ("target_offset", "Target offset", values.target_offset, "LU"),
+    ]
+    .into_iter()
+    .for_each(|(name, label, value, unit)| {
+        args.progress.measurement(&Measurement {
+            name,
+            label,
+            value,
+            unit,
+        })
+    });
+
+    let provenance = provenance(
+        &common_args,
+        Some([
+            Value::Known(values.input_i),
+            Value::Known(values.input_lra),
+            Value::Known(values.input_tp),
+        ]),
+    );
+
+    if let Some(method) = &common_args.native {
+        let (gain, max_gain) = linear_gain(&common_args, target_level, &values);
+
+        args.progress.measurement(&Measurement {
+            name: "volume_adjustment",
+            label: "Volume adjustment",
+            value: gain,
+            unit: "dB",
+        });
+
+        return method
+            .apply(
+                args.input_file,
+                args.output_file,
+                gain,
+                Rounding::AtMost(Value::Known(max_gain)),
+                Some(values.input_i),
+                provenance.as_ref(),
+                common_args.normalize_pass,
+                common_args.input_file_info.stream.duration,
+                args.progress,
+            )
+            .with_context(|| normalize_error(&common_args));
+    }
+
+    pass2(NormalizationPass2Args {
+        common_args: &common_args,
+        measured_i: Value::Known(values.input_i),
+        measured_lra: Value::Known(values.input_lra),
+        measured_tp: Value::Known(values.input_tp),
+        measured_thresh: Value::Known(values.input_thresh),
+        target_offset: Value::Known(values.target_offset),
+        tags: provenance
+            .map(|provenance| {
+                // the gain is only known if loudnorm applies it linearly
+                if is_linear(&common_args, target_level, &values) {
+                    provenance
+                        .gain(Value::Known(target_level - values.input_i))
+                        .tags()
+                } else {
+                    provenance.tags()
+                }
+            })
+            .unwrap_or_default(),
+        output_file: args.output_file,
+    })
+    .with_context(|| normalize_error(&common_args))?;
+
+    Ok(())
+}
+
+pub fn plan(args: NormalizationArgs, plan: &mut Plan) -> Result<()> {
+    let mut common_args = common_args(&args)?;
+
+    if let Some(reference) = args.reference {
+        let reference_args = reference_args(&args, reference)?;
+        plan_measure(&reference_args, plan);
+        plan.placeholder(
+            "REFERENCE_LEVEL",
+            &format!("{} of the reference file", loudness_source(&reference_args)),
+        );
+        common_args.target_level = Value::Placeholder("REFERENCE_LEVEL");
+    }
+
+    plan_measure(&common_args, plan);
+
+    // the plan of the native gain has no placeholders of the measured values
+    let provenance = provenance(
+        &common_args,
+        common_args.native.is_none().then_some([
+            Value::Placeholder("MEASURED_I"),
+            Value::Placeholder("MEASURED_LRA"),
+            Value::Placeholder("MEASURED_TP"),
+        ]),
+    );
+
+    if let Some(method) = &common_args.native {
+        method.plan(
+            plan,
+            common_args.normalize_pass,
+            args.input_file,
+            args.output_file,
+            Value::Placeholder("VOLUME_ADJUSTMENT"),
+            Rounding::AtMost(Value::Placeholder("MAX_GAIN")),
+            &format!(
+                "{} minus {}, at most {} minus \"{}\"",
+                target(&common_args),
+                loudness_source(&common_args),
+                args.true_peak,
+                true_peak_key(&common_args)
+            ),
+            provenance.as_ref(),
+        );
+        return Ok(());
+    }
+
+    plan.add(
+        common_args.normalize_pass,
+        &pass2_command(&NormalizationPass2Args {
+            common_args: &common_args,
+            measured_i: Value::Placeholder("MEASURED_I"),
+            measured_lra: Value::Placeholder("MEASURED_LRA"),
+            measured_tp: Value::Placeholder("MEASURED_TP"),
+            measured_thresh: Value::Placeholder("MEASURED_THRESH"),
+            target_offset: Value::Placeholder("TARGET_OFFSET"),
+            tags: provenance
+                .map(|provenance| provenance.tags())
+                .unwrap_or_default(),
+            output_file: args.output_file,
+        }),
+    );
+    plan.placeholder("MEASURED_I", &loudness_source(&common_args));
+    [
+        ("MEASURED_LRA", "input_lra"),
+        ("MEASURED_TP", true_peak_key(&common_args)),
+        ("MEASURED_THRESH", "input_thresh"),
+        ("TARGET_OFFSET", "target_offset"),
+    ]
+    .into_iter()
+    .for_each(|(name, key)| {
+        plan.placeholder(
+            name,
+            &format!(
+                "\"{key}\" measured by pass {}",
+                common_args.measure_pass.number
+            ),
+        );
+    });
+
+    Ok(())
+}
+
+/// Key of the true peak in the output of the measure pass, the `ebur128` summary measures it
+/// over the whole input if the loudness is measured over a part of it
+fn true_peak_key(args: &NormalizationCommonArgs) -> &'static str {
+    if args.measure_range.filter().is_some() {
+        "Peak"
+    } else {
+        "input_tp"
+    }
+}
+
+fn common_args<'a>(args: &NormalizationArgs<'a>) -> Result<NormalizationCommonArgs<'a>> {
+    let input_file_info = probe_input(args.input_file, args.verbose, args.progress)?;
+    let (output_codec, native) = select_output(
+        &input_file_info,
+        args.output_file,
+        args.codec_options,
+        args.ffmpeg_args,
+        args.progress,
+    )?;
+    let (measure_pass, normalize_pass) = match args.gating {
+        Gating::Program => (&PASS1, &PASS2),
+        Gating::Dialogue => (&DIALOGUE_PASS2, &DIALOGUE_PASS3),
+    };
 
-    let common_args = NormalizationCommonArgs {
+    Ok(NormalizationCommonArgs {
         verbose: args.verbose,
         input_file: args.input_file,
         input_file_info,
-        target_level: args.target_level,
+        output_codec,
+        native,
+        target_level: Value::Known(args.target_level),
         loudness_range_target: args.loudness_range_target,
         true_peak: args.true_peak,
         offset: args.offset,
+        gating: args.gating,
+        speech_pass: &SPEECH_PASS,
+        measure_pass,
+        normalize_pass,
+        tags: provenance::is_enabled(args.output_file, args.codec_options.tags),
+        reference: args.reference,
+        measure_range: args.measure_range,
         ffmpeg_args: args.ffmpeg_args,
-    };
+        progress: args.progress,
+    })
+}
 
-    let values = pass1(NormalizationPass1Args {
-        common_args: &common_args,
+/// Provenance of the output with the measured input values if known,
+/// `None` if tags are not written
+fn provenance(args: &NormalizationCommonArgs, input: Option<[Value; 3]>) -> Option<Provenance> {
+    args.tags.then(|| {
+        let mut target = format!(
+            "I={} LUFS, LRA={} LU, TP={} dBTP",
+            target(args),
+            args.loudness_range_target,
+            args.true_peak
+        );
+        if args.gating == Gating::Dialogue {
+            target += ", dialogue gating";
+        }
+        let provenance = Provenance::new("ebu", target).reference(args.reference);
+        match input {
+            Some([input_i, input_lra, input_tp]) => provenance
+                .input("input_i", input_i, "LUFS")
+                .input("input_lra", input_lra, "LU")
+                .input("input_tp", input_tp, "dBTP"),
+            None => provenance,
+        }
     })
-    .with_context(|| "Failed to run pass 1 to measure loudness values")?;
+}
 
-    pass2(NormalizationPass2Args {
-        common_args: &common_args,
-        measured_i: values.input_i,
-        measured_lra: values.input_lra,
-        measured_tp: values.input_tp,
-        measured_thresh: values.input_thresh,
-        target_offset: values.target_offset,
-        output_file: args.output_file,
-        overwrite: args.overwrite,
+fn normalize_error(args: &NormalizationCommonArgs) -> String {
+    format!(
+        "Failed to run pass {} to normalize audio file",
+        args.normalize_pass.number
+    )
+}
+
+/// Loudness values of the measured range and the true peak of the whole input. With dialogue
+/// gating the integrated loudness is the one of the speech regions in the measured range.
+/// The detected speech is reported if `report` is set.
+fn measure(args: &NormalizationCommonArgs, report: bool) -> Result<LoudnessValues> {
+    let dialogue_loudness = match args.gating {
+        Gating::Program => None,
+        Gating::Dialogue => {
+            let (speech, dialogue_loudness) = detect_speech(args).with_context(|| {
+                format!(
+                    "Failed to run pass {} to detect speech",
+                    args.speech_pass.number
+                )
+            })?;
+            if report {
+                report_speech(args, &speech);
+            }
+            dialogue_loudness
+        }
+    };
+
+    let mut values = pass1(NormalizationPass1Args {
+        common_args: args,
+        range_filter: args.measure_range.filter(),
+        duration: args.input_file_info.stream.duration,
     })
-    .with_context(|| "Failed to run pass 2 to normalize audio file")?;
+    .with_context(|| {
+        format!(
+            "Failed to run pass {} to measure loudness values",
+            args.measure_pass.number
+        )
+    })?;
 
-    Ok(())
+    if let Some(dialogue_loudness) = dialogue_loudness {
+        values.input_i = dialogue_loudness;
+    }
+
+    Ok(values)
 }
 
-fn pass1(args: NormalizationPass1Args) -> Result<LoudnessValues> {
-    let mut ffmpeg = FFmpeg::new(args.common_args.input_file);
+/// Add the passes of `measure` to the plan
+fn plan_measure(args: &NormalizationCommonArgs, plan: &mut Plan) {
+    if args.gating == Gating::Dialogue {
+        plan.add(
+            args.speech_pass,
+            &speech::command(args.input_file, args.input_file_info.stream.sample_rate),
+        );
+    }
 
-    ffmpeg.cmd().arg("-filter_complex").arg(format!(
-        "loudnorm=i={}:lra={}:tp={}:offset={}:print_format=json",
-        args.common_args.target_level,
-        args.common_args.loudness_range_target,
-        args.common_args.true_peak,
-        args.common_args.offset
+    plan.add(
+        args.measure_pass,
+        &pass1_command(&NormalizationPass1Args {
+            common_args: args,
+            range_filter: args.measure_range.filter(),
+            duration: None,
+        }),
+    );
+}
+
+/// Where the integrated loudness of the plan comes from
+fn loudness_source(args: &NormalizationCommonArgs) -> String {
+    match args.gating {
+        Gating::Program => format!("\"input_i\" measured by pass {}", args.measure_pass.number),
+        Gating::Dialogue => format!(
+            "the dialogue loudness of the speech regions detected by pass {}, \"input_i\" of pass {} if there are none",
+            args.speech_pass.number, args.measure_pass.number
+        ),
+    }
+}
+
+/// Speech regions of the input and the dialogue loudness of the measured range,
+/// `None` if no speech is detected in it
+fn detect_speech(args: &NormalizationCommonArgs) -> Result<(speech::Speech, Option<f64>)> {
+    let mut ffmpeg = speech::command(args.input_file, args.input_file_info.stream.sample_rate);
+
+    let reader = ffmpeg
+        .exec(
+            args.speech_pass,
+            args.verbose,
+            args.input_file_info.stream.duration,
+            args.progress,
+        )
+        .with_context(|| "Failed to processing audio file to detect speech")?;
+
+    let speech =
+        speech::result(reader).with_context(|| "Failed to parse speech detection result")?;
+
+    let dialogue_loudness = speech.dialogue_loudness(args.measure_range);
+    if dialogue_loudness.is_none() {
+        args.progress.message(&format!(
+            "Warning: no speech is detected in the measured range of {}, the loudness of the whole range is measured",
+            args.input_file.display()
+        ));
+    }
+
+    Ok((speech, dialogue_loudness))
+}
+
+/// Report the program loudness and the speech duration of the input
+fn report_speech(args: &NormalizationCommonArgs, speech: &speech::Speech) {
+    let speech_duration = speech.speech_duration();
+
+    if let Some(program_loudness) = speech.program_loudness {
+        args.progress.measurement(&Measurement {
+            name: "program_i",
+            label: "Program loudness",
+            value: program_loudness,
+            unit: "LUFS",
+        });
+    }
+    [
+        ("speech_duration", "Speech duration", speech_duration, "s"),
+        (
+            "speech_ratio",
+            "Speech ratio",
+            100.0 * speech_duration / speech.duration,
+            "%",
+        ),
+    ]
+    .into_iter()
+    .for_each(|(name, label, value, unit)| {
+        args.progress.measurement(&Measurement {
+            name,
+            label,
+            value,
+            unit,
+        })
+    });
+
+    args.progress.message(&format!(
+        "Detected speech in {} regions, {speech_duration:.1} s of {:.1} s",
+        speech.regions.len(),
+        speech.duration
     ));
+}
 
-    ffmpeg.add_common_args(
-        &args.common_args.input_file_info,
-        args.common_args.ffmpeg_args,
-    );
+/// Common args to measure the reference file of `--match` like the input
+fn reference_args<'a>(
+    args: &NormalizationArgs<'a>,
+    reference: &'a Path,
+) -> Result<NormalizationCommonArgs<'a>> {
+    let measure_pass = match args.gating {
+        Gating::Program => &REFERENCE_PASS,
+        Gating::Dialogue => &REFERENCE_DIALOGUE_PASS2,
+    };
 
-    ffmpeg.cmd().arg("-f").arg("null").arg("-");
+    Ok(NormalizationCommonArgs {
+        verbose: args.verbose,
+        input_file: reference,
+        input_file_info: probe_reference(reference, args.verbose, args.progress)?,
+        // the reference is only decoded
+        output_codec: OutputCodec::default(),
+        native: None,
+        target_level: Value::Known(args.target_level),
+        loudness_range_target: args.loudness_range_target,
+        true_peak: args.true_peak,
+        offset: args.offset,
+        gating: args.gating,
+        speech_pass: &REFERENCE_SPEECH_PASS,
+        measure_pass,
+        // not run
+        normalize_pass: &PASS2,
+        tags: false,
+        reference: None,
+        measure_range: &WHOLE,
+        ffmpeg_args: &[],
+        progress: args.progress,
+    })
+}
+
+/// Integrated loudness of the reference file, the target level of `--match`
+fn measure_reference(args: &NormalizationArgs, reference: &Path) -> Result<f64> {
+    let values = measure(&reference_args(args, reference)?, false)
+        .with_context(|| "Failed to measure reference file")?;
+
+    args.progress.measurement(&Measurement {
+        name: "reference_i",
+        label: "Reference integrated loudness",
+        value: values.input_i,
+        unit: "LUFS",
+    });
+
+    Ok(values.input_i)
+}
+
+/// Target level plus offset, the placeholder of the reference level in a plan of `--match`
+fn target(args: &NormalizationCommonArgs) -> String {
+    match args.target_level {
+        Value::Known(target_level) => (target_level + args.offset).to_string(),
+        Value::Placeholder(_) if args.offset != 0.0 => {
+            format!("{}{:+}", args.target_level, args.offset)
+        }
+        Value::Placeholder(_) => args.target_level.to_string(),
+    }
+}
+
+/// Gain to the target level, limited by the true peak, and the limit
+fn linear_gain(
+    args: &NormalizationCommonArgs,
+    target_level: f64,
+    values: &LoudnessValues,
+) -> (f64, f64) {
+    let gain = target_level + args.offset - values.input_i;
+    let limit = args.true_peak - values.input_tp;
+
+    if gain > limit {
+        args.progress.message(&format!(
+            "Warning: gain is limited to {limit} dB by true peak, the output is {} LU below target",
+            gain - limit
+        ));
+    }
+
+    (gain.min(limit), limit)
+}
+
+/// Whether loudnorm normalizes linearly with the measured values of pass 2 or falls back to its
+/// dynamic mode, the same condition as in its `init`
+fn is_linear(args: &NormalizationCommonArgs, target_level: f64, values: &LoudnessValues) -> bool {
+    // loudnorm ignores the offset in linear mode
+    let gain = target_level - values.input_i;
+
+    // loudnorm takes its defaults of the measured values as not measured
+    let measured = values.input_tp != 99.0
+        && values.input_thresh != -70.0
+        && values.input_lra != 0.0
+        && values.input_i != 0.0;
+
+    measured
+        && values.input_tp + gain <= args.true_peak
+        && values.input_lra <= args.loudness_range_target
+}
+
+fn pass1(args: NormalizationPass1Args) -> Result<LoudnessValues> {
+    let mut ffmpeg = pass1_command(&args);
 
     let reader = ffmpeg
         .exec(
-            "[1/2] Processing audio file to measure loudness values:",
+            args.common_args.measure_pass,
             args.common_args.verbose,
-            args.common_args.input_file_info.duration,
+            args.duration,
+            args.common_args.progress,
         )
         .with_context(|| "Failed to processing audio file to measure loudness values")?;
 
     let mut is_json = false;
+    let mut true_peak = None;
 
     let lines: Vec<String> = reader
         .lines()
-        .filter_map(|line| line.ok())
+        .map_while(Result::ok)
         .filter(|line| match line.as_str() {
             "{" => {
                 is_json = true;
@@ -145,15 +676,74 @@ fn pass1(args: NormalizationPass1Args) -> Result<LoudnessValues> {
                 is_json = false;
                 true
             }
-            _ => is_json,
+            _ => {
+                if let Some(captures) = RE_TRUE_PEAK.captures(line) {
+                    true_peak = captures[1].parse::<f64>().ok();
+                }
+                is_json
+            }
         })
         .collect();
 
-    serde_json::from_str(lines.join("\n").as_str())
-        .with_context(|| "Failed to parse measure result - invalid JSON")
+    let mut values: LoudnessValues = serde_json::from_str(lines.join("\n").as_str())
+        .with_context(|| "Failed to parse measure result - invalid JSON")?;
+
+    if args.range_filter.is_some() {
+        values.input_tp =
+            true_peak.with_context(|| "Failed to parse true peak of the whole input")?;
+    }
+
+    Ok(values)
+}
+
+fn pass1_command(args: &NormalizationPass1Args) -> FFmpeg {
+    let mut ffmpeg = FFmpeg::new(args.common_args.input_file);
+
+    let filter = format!(
+        "loudnorm=i={}:lra={}:tp={}:offset={}:print_format=json",
+        args.common_args.target_level,
+        args.common_args.loudness_range_target,
+        args.common_args.true_peak,
+        args.common_args.offset
+    );
+
+    ffmpeg
+        .cmd()
+        .arg("-filter_complex")
+        .arg(match &args.range_filter {
+            // I, LRA and threshold of the measured range, true peak of the whole input
+            Some(range_filter) => format!(
+                "asplit=2[range][whole];[range]{range_filter},{filter};\
+                 [whole]ebur128=peak=true:framelog=quiet"
+            ),
+            None => filter,
+        });
+
+    ffmpeg.add_common_args(&args.common_args.output_codec, args.common_args.ffmpeg_args);
+
+    ffmpeg.cmd().arg("-f").arg("null").arg("-");
+
+    ffmpeg
 }
 
 fn pass2(args: NormalizationPass2Args) -> Result<()> {
+    let mut ffmpeg = pass2_command(&args);
+
+    let reader = ffmpeg
+        .exec(
+            args.common_args.normalize_pass,
+            args.common_args.verbose,
+            args.common_args.input_file_info.stream.duration,
+            args.common_args.progress,
+        )
+        .with_context(|| "Failed to normalizing audio file")?;
+
+    to_progress(reader, args.common_args.progress);
+
+    Ok(())
+}
+
+fn pass2_command(args: &NormalizationPass2Args) -> FFmpeg {
     let mut ffmpeg = FFmpeg::new(args.common_args.input_file);
 
     let mut filter = format!(
@@ -175,25 +765,16 @@ fn pass2(args: NormalizationPass2Args) -> Result<()> {
         .arg("-filter_complex")
         .arg(filter + ":linear=true:print_format=json");
 
-    ffmpeg.add_common_args(
+    ffmpeg.add_metadata(
+        args.output_file,
         &args.common_args.input_file_info,
-        args.common_args.ffmpeg_args,
+        &args.tags,
     );
 
-    if args.overwrite {
-        ffmpeg.cmd().arg("-y");
-    }
-    ffmpeg.cmd().arg(args.output_file);
-
-    let reader = ffmpeg
-        .exec(
-            "[2/2] EBU R128 Normalizing audio file:",
-            args.common_args.verbose,
-            args.common_args.input_file_info.duration,
-        )
-        .with_context(|| "Failed to normalizing audio file")?;
+    ffmpeg.add_common_args(&args.common_args.output_codec, args.common_args.ffmpeg_args);
 
-    to_stdout(reader);
+    // output is a temporary file owned by us
+    ffmpeg.cmd().arg("-y").arg(args.output_file);
 
-    Ok(())
+    ffmpeg
 }
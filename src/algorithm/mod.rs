@@ -1,4 +1,84 @@
+pub mod bext;
+pub mod check;
 pub mod dialogue;
 pub mod ebu_r128;
+mod native;
 pub mod peak;
+pub mod range;
 pub mod rms;
+mod speech;
+pub mod timeline;
+
+use crate::progress::Progress;
+use crate::provenance;
+use crate::tool::codec::{self, CodecOptions, OutputCodec};
+use crate::tool::ffprobe::{FFprobe, Probe};
+use anyhow::{Context, Result};
+use native::Method;
+use std::path::Path;
+
+/// Get input file information
+fn probe_input(input_file: &Path, verbose: bool, progress: &dyn Progress) -> Result<Probe> {
+    let probe =
+        FFprobe::probe(input_file).with_context(|| "Failed to get input file information")?;
+
+    if verbose {
+        progress.message(&format!("Input file: {probe}"));
+    }
+
+    if let Some(tags) = provenance::read(&probe) {
+        progress.message(&format!(
+            "Warning: input file is already normalized ({tags}), use --skip-normalized to skip it"
+        ));
+    }
+
+    Ok(probe)
+}
+
+/// Get reference file information, the reference of `--match` is measured like the input
+fn probe_reference(reference: &Path, verbose: bool, progress: &dyn Progress) -> Result<Probe> {
+    let probe =
+        FFprobe::probe(reference).with_context(|| "Failed to get reference file information")?;
+
+    if verbose {
+        progress.message(&format!("Reference file: {probe}"));
+    }
+
+    Ok(probe)
+}
+
+/// Select the codec to encode the output with
+fn select_codec(
+    probe: &Probe,
+    output_file: &Path,
+    options: &CodecOptions,
+    ffmpeg_args: &[String],
+    progress: &dyn Progress,
+) -> Result<OutputCodec> {
+    let (codec, messages) = codec::select(&probe.stream, output_file, options, ffmpeg_args)
+        .with_context(|| "Failed to select output codec")?;
+
+    messages
+        .iter()
+        .for_each(|message| progress.message(message));
+
+    Ok(codec)
+}
+
+/// Select a native method to apply the gain with, otherwise the codec to encode the output with
+fn select_output(
+    probe: &Probe,
+    output_file: &Path,
+    options: &CodecOptions,
+    ffmpeg_args: &[String],
+    progress: &dyn Progress,
+) -> Result<(OutputCodec, Option<Method>)> {
+    match Method::select(probe, output_file, options, ffmpeg_args, progress) {
+        // measure passes decode only
+        Some(method) => Ok((OutputCodec::default(), Some(method))),
+        None => Ok((
+            select_codec(probe, output_file, options, ffmpeg_args, progress)?,
+            None,
+        )),
+    }
+}
@@ -0,0 +1,450 @@
+use crate::bitstream::gain::Settings;
+use crate::bitstream::{aac, flac, mp3, opus, wav};
+use crate::plan::{Plan, Value};
+use crate::progress::{Measurement, Pass, Progress, Status};
+use crate::provenance::Provenance;
+use crate::tool::codec::CodecOptions;
+use crate::tool::ffprobe::Probe;
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::Duration;
+
+/// Way to apply gain to the output file without encoding with ffmpeg
+#[derive(Clone, Copy)]
+pub struct Method {
+    format: Format,
+    /// Maximum sample peak in dBFS of decoded formats
+    limiter: Option<f64>,
+    /// Dither integer samples of decoded formats
+    dither: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    /// Change `global_gain` of MP3 frames in steps of 1.5 dB
+    Mp3,
+    /// Change `global_gain` of the AAC channel streams of ADTS frames in steps of 1.5 dB
+    Adts,
+    /// Change `global_gain` of the AAC channel streams of MP4 samples in steps of 1.5 dB
+    Mp4,
+    /// Change the output gain of the `OpusHead` header and the R128 gain tags
+    Opus,
+    /// Multiply the PCM samples
+    Wav,
+    /// Decode, multiply and encode the samples
+    Flac,
+}
+
+/// How a gain is rounded to the 1.5 dB steps of MP3 and AAC
+#[derive(Clone, Copy)]
+pub enum Rounding {
+    /// To the nearest step, the gain is a target, e.g. an RMS level
+    Nearest,
+    /// Down, the gain is a ceiling that must not be exceeded, e.g. a peak level
+    Down,
+    /// To the nearest step that does not exceed a maximum gain, e.g. of a true peak limit
+    AtMost(Value),
+}
+
+impl Rounding {
+    fn steps(self, gain: f64) -> i32 {
+        let nearest = (gain / mp3::GAIN_STEP).round();
+        (match self {
+            Rounding::Nearest | Rounding::AtMost(Value::Placeholder(_)) => nearest,
+            Rounding::Down => (gain / mp3::GAIN_STEP).floor(),
+            Rounding::AtMost(Value::Known(max)) => nearest.min((max / mp3::GAIN_STEP).floor()),
+        }) as i32
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Rounding::Nearest => "rounded",
+            Rounding::Down => "rounded down",
+            Rounding::AtMost(_) => "rounded, rounded down if that exceeds the maximum",
+        }
+    }
+}
+
+/// Input format and codecs, and output file extensions of each format
+const FORMATS: &[(&str, &[&str], &[&str], Format)] = &[
+    ("mp3", &["mp3"], &["mp3"], Format::Mp3),
+    ("aac", &["aac"], &["aac", "adts"], Format::Adts),
+    (
+        "mov,mp4,m4a,3gp,3g2,mj2",
+        &["aac"],
+        &["m4a", "m4b", "mp4"],
+        Format::Mp4,
+    ),
+    ("ogg", &["opus"], &["opus", "ogg"], Format::Opus),
+    (
+        "wav",
+        &[
+            "pcm_u8",
+            "pcm_s16le",
+            "pcm_s24le",
+            "pcm_s32le",
+            "pcm_f32le",
+            "pcm_f64le",
+        ],
+        &["wav"],
+        Format::Wav,
+    ),
+    ("flac", &["flac"], &["flac"], Format::Flac),
+];
+
+impl Method {
+    /// Method to apply gain to the input with `--native`,
+    /// `None` if the output must be encoded with ffmpeg.
+    pub fn select(
+        probe: &Probe,
+        output_file: &Path,
+        options: &CodecOptions,
+        ffmpeg_args: &[String],
+        progress: &dyn Progress,
+    ) -> Option<Method> {
+        if !options.native {
+            return None;
+        }
+
+        let codec = probe.stream.codec_name.as_str();
+        let extension = output_file
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let method = FORMATS
+            .iter()
+            .find(|(format, codecs, extensions, _)| {
+                probe.format.format_name == *format
+                    && codecs.contains(&codec)
+                    && extensions.contains(&extension.as_str())
+            })
+            .map(|(_, _, _, format)| Method {
+                format: *format,
+                limiter: options.limiter,
+                dither: options.dither,
+            });
+
+        let reason = if method.is_none() {
+            Some(format!(
+                "\"{codec}\" audio in \"{}\" format to \".{extension}\" output file is not supported",
+                probe.format.format_name
+            ))
+        } else if !ffmpeg_args.is_empty() {
+            Some("ffmpeg arguments are given".to_string())
+        } else if options.bit_rate.is_some() || options.quality.is_some() {
+            Some("--bitrate or --quality is given".to_string())
+        } else if options.codec_map.iter().any(|(name, _)| name == codec) {
+            Some(format!("--codec-map is given for \"{codec}\""))
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => {
+                progress.message(&format!(
+                    "Warning: --native is ignored, {reason}. The output is encoded with ffmpeg"
+                ));
+                None
+            }
+            None => method,
+        }
+    }
+
+    /// Apply `gain` in dB to the input and write the output file.
+    /// `rounding` applies to formats with gain steps.
+    /// `loudness` is the integrated loudness of the input if it was measured.
+    /// `provenance` is written with the applied gain if the format has tags.
+    #[allow(clippy::too_many_arguments)]
+    pub fn apply(
+        &self,
+        input_file: &Path,
+        output_file: &Path,
+        gain: f64,
+        rounding: Rounding,
+        loudness: Option<f64>,
+        provenance: Option<&Provenance>,
+        pass: &Pass,
+        duration: Option<Duration>,
+        progress: &dyn Progress,
+    ) -> Result<()> {
+        progress.start(pass, duration);
+
+        let tags = |applied: f64| {
+            provenance
+                .map(|provenance| provenance.clone().gain(Value::Known(applied)).tags())
+                .unwrap_or_default()
+        };
+
+        match self.format {
+            Format::Mp3 => {
+                let steps = rounding.steps(gain);
+                let applied = f64::from(steps) * mp3::GAIN_STEP;
+                let summary = mp3::rewrite_file(input_file, output_file, steps, &tags(applied))
+                    .with_context(|| "Failed to change gain of MP3 frames")?;
+
+                finish(output_file, summary.duration, progress);
+
+                progress.measurement(&Measurement {
+                    name: "applied_gain",
+                    label: "Applied gain",
+                    value: applied,
+                    unit: "dB",
+                });
+                progress.message(&format!(
+                    "Gain of {applied} dB ({steps} steps of {} dB) is applied to {} frames without re-encoding",
+                    mp3::GAIN_STEP,
+                    summary.frames
+                ));
+                if summary.clamped > 0 {
+                    progress.message(&format!(
+                        "Warning: gain of {} granules is out of range and limited, undo is not exact",
+                        summary.clamped
+                    ));
+                }
+            }
+            Format::Adts | Format::Mp4 => {
+                let steps = rounding.steps(gain);
+                let applied = f64::from(steps) * mp3::GAIN_STEP;
+                let summary = aac::rewrite_file(input_file, output_file, steps, &tags(applied))
+                    .with_context(|| "Failed to change gain of AAC frames")?;
+
+                finish(output_file, summary.duration, progress);
+
+                progress.measurement(&Measurement {
+                    name: "applied_gain",
+                    label: "Applied gain",
+                    value: applied,
+                    unit: "dB",
+                });
+                progress.message(&format!(
+                    "Gain of {applied} dB ({steps} steps of {} dB) is applied to {} frames without re-encoding",
+                    mp3::GAIN_STEP,
+                    summary.frames
+                ));
+                if summary.clamped > 0 {
+                    progress.message(&format!(
+                        "Warning: gain of {} channel streams is out of range and limited, undo is not exact",
+                        summary.clamped
+                    ));
+                }
+            }
+            Format::Opus => {
+                let applied = (gain * 256.0).round() / 256.0;
+                let summary =
+                    opus::rewrite_file(input_file, output_file, gain, loudness, &tags(applied))
+                        .with_context(|| "Failed to change output gain of Opus header")?;
+
+                finish(output_file, summary.duration, progress);
+
+                progress.measurement(&Measurement {
+                    name: "applied_gain",
+                    label: "Applied gain",
+                    value: applied,
+                    unit: "dB",
+                });
+                progress.message(&format!(
+                    "Output gain of {} Opus stream(s) is changed by {applied} dB from {} dB without re-encoding",
+                    summary.streams,
+                    summary
+                        .previous
+                        .iter()
+                        .map(|gain| gain.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+                if summary.clamped > 0 {
+                    progress.message(&format!(
+                        "Warning: output gain of {} Opus stream(s) is out of range and limited",
+                        summary.clamped
+                    ));
+                }
+            }
+            Format::Wav | Format::Flac => {
+                let settings = Settings {
+                    gain,
+                    limiter: self.limiter,
+                    dither: self.dither,
+                };
+                let summary = match self.format {
+                    Format::Wav => {
+                        wav::rewrite_file(input_file, output_file, &settings, &tags(gain))
+                            .with_context(|| "Failed to change gain of WAV samples")?
+                    }
+                    _ => flac::rewrite_file(input_file, output_file, &settings, &tags(gain))
+                        .with_context(|| "Failed to change gain of FLAC samples")?,
+                };
+
+                finish(output_file, summary.duration, progress);
+
+                progress.measurement(&Measurement {
+                    name: "applied_gain",
+                    label: "Applied gain",
+                    value: gain,
+                    unit: "dB",
+                });
+                progress.message(&format!(
+                    "Gain of {gain} dB is applied to {} samples without ffmpeg",
+                    summary.samples
+                ));
+                if summary.limited > 0 {
+                    progress.message(&format!(
+                        "Limiter reduced the gain of {} sample frames to keep peaks below {} dBFS",
+                        summary.limited,
+                        self.limiter.unwrap_or_default()
+                    ));
+                }
+                if summary.clipped > 0 {
+                    progress.message(&format!(
+                        "Warning: {} samples exceed full scale, use --limiter to avoid clipping",
+                        summary.clipped
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the equivalent commands of `apply` to the plan.
+    #[allow(clippy::too_many_arguments)]
+    pub fn plan(
+        &self,
+        plan: &mut Plan,
+        pass: &Pass,
+        input_file: &Path,
+        output_file: &Path,
+        gain: Value,
+        rounding: Rounding,
+        gain_description: &str,
+        provenance: Option<&Provenance>,
+    ) {
+        match self.format {
+            Format::Mp3 | Format::Mp4 => {
+                let steps = match gain {
+                    Value::Known(gain) => rounding.steps(gain).to_string(),
+                    Value::Placeholder(_) => Value::Placeholder("GAIN_STEPS").to_string(),
+                };
+                plan.add_command(
+                    pass,
+                    [
+                        OsStr::new("cp"),
+                        OsStr::new("-f"),
+                        input_file.as_os_str(),
+                        output_file.as_os_str(),
+                    ]
+                    .into_iter(),
+                );
+                plan.append_command(
+                    [
+                        OsStr::new(match self.format {
+                            Format::Mp3 => "mp3gain",
+                            _ => "aacgain",
+                        }),
+                        OsStr::new("-c"),
+                        OsStr::new("-g"),
+                        OsStr::new(&steps),
+                        output_file.as_os_str(),
+                    ]
+                    .into_iter(),
+                );
+                if let Value::Placeholder(_) = gain {
+                    plan.placeholder(
+                        "GAIN_STEPS",
+                        &format!(
+                            "{gain_description}, divided by {} and {}",
+                            mp3::GAIN_STEP,
+                            rounding.describe()
+                        ),
+                    );
+                }
+            }
+            Format::Adts | Format::Opus | Format::Wav | Format::Flac => {
+                plan.add_command(
+                    pass,
+                    [
+                        OsStr::new("cp"),
+                        OsStr::new("-f"),
+                        input_file.as_os_str(),
+                        output_file.as_os_str(),
+                    ]
+                    .into_iter(),
+                );
+                plan.note(&match self.format {
+                    Format::Adts => format!(
+                        "The global gain of the AAC frames is changed by {} steps of {} dB \
+                         without re-encoding, which has no equivalent command",
+                        match gain {
+                            Value::Known(gain) => rounding.steps(gain).to_string(),
+                            Value::Placeholder(_) => Value::Placeholder("GAIN_STEPS").to_string(),
+                        },
+                        mp3::GAIN_STEP
+                    ),
+                    Format::Opus => format!(
+                        "The output gain of the OpusHead header is changed by {gain} dB without re-encoding, \
+                         which has no equivalent command"
+                    ),
+                    _ => format!(
+                        "The samples are multiplied by a gain of {gain} dB without ffmpeg{}{}, \
+                         keeping all metadata, which has no equivalent command",
+                        self.limiter
+                            .map(|level| format!(", limited to {level} dBFS"))
+                            .unwrap_or_default(),
+                        if self.dither { ", dithered" } else { "" }
+                    ),
+                });
+                match (self.format, gain) {
+                    (Format::Adts, Value::Placeholder(_)) => plan.placeholder(
+                        "GAIN_STEPS",
+                        &format!(
+                            "{gain_description}, divided by {} and {}",
+                            mp3::GAIN_STEP,
+                            rounding.describe()
+                        ),
+                    ),
+                    (_, Value::Placeholder(name)) => plan.placeholder(name, gain_description),
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(provenance) = provenance {
+            let (container, applied) = match (self.format, gain) {
+                (Format::Mp3 | Format::Adts, Value::Known(gain)) => (
+                    "APEv2 tag",
+                    Value::Known(f64::from(rounding.steps(gain)) * mp3::GAIN_STEP),
+                ),
+                (Format::Mp3 | Format::Adts, _) => ("APEv2 tag", gain),
+                (Format::Mp4, Value::Known(gain)) => (
+                    "iTunes tags",
+                    Value::Known(f64::from(rounding.steps(gain)) * mp3::GAIN_STEP),
+                ),
+                (Format::Mp4, _) => ("iTunes tags", gain),
+                (Format::Opus, _) => ("OpusTags header", gain),
+                (Format::Flac, _) => ("VORBIS_COMMENT block", gain),
+                (Format::Wav, _) => ("coding history of the bext chunk", gain),
+            };
+            let tags: Vec<String> = provenance
+                .clone()
+                .gain(applied)
+                .tags()
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+            plan.note(&format!(
+                "The tags {} are added to the {container}",
+                tags.join(", ")
+            ));
+        }
+    }
+}
+
+fn finish(output_file: &Path, duration: Duration, progress: &dyn Progress) {
+    progress.finish(&Status {
+        out_time: duration,
+        total_size: output_file.metadata().ok().map(|metadata| metadata.len()),
+        end: true,
+        ..Default::default()
+    });
+}
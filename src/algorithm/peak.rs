@@ -1,108 +1,327 @@
-use crate::io::to_stdout;
-use crate::tool::ffmpeg::FFmpeg;
-use crate::tool::ffprobe::{AudioStream, FFprobe};
+use crate::algorithm::{
+    native::{Method, Rounding},
+    probe_input, probe_reference, select_output,
+};
+use crate::io::to_progress;
+use crate::plan::{Plan, Value};
+use crate::progress::{Measurement, Pass, Phase, Progress};
+use crate::provenance::{self, Provenance};
+use crate::tool::codec::{CodecOptions, OutputCodec};
+use crate::tool::ffmpeg::{FFmpeg, Log};
+use crate::tool::ffprobe::Probe;
 use anyhow::{bail, Context, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::fmt::Write as _;
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
 use std::path::Path;
-use std::process::ChildStderr;
 
 lazy_static! {
     static ref RE_VALUES: Regex =
         Regex::new(r#"^\s*.*\s*Peak\s+level\s+dB\s*:\s*(.+)\s*$"#).unwrap();
 }
 
+const PASS1: Pass = Pass {
+    number: 1,
+    count: 2,
+    phase: Phase::Measure,
+    description: "Processing audio file to measure loudness values",
+};
+const PASS2: Pass = Pass {
+    number: 2,
+    count: 2,
+    phase: Phase::Normalize,
+    description: "Peak Normalizing audio file",
+};
+const REFERENCE_PASS: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Measure,
+    description: "Processing reference file to measure loudness values",
+};
+
 pub struct NormalizationArgs<'a> {
     pub verbose: bool,
     pub input_file: &'a Path,
     pub output_file: &'a Path,
-    pub overwrite: bool,
     pub target_level: f64,
+    /// Measure the target level from this file instead of `target_level`
+    pub reference: Option<&'a Path>,
     pub ffmpeg_args: &'a [String],
+    pub codec_options: &'a CodecOptions,
+    pub progress: &'a dyn Progress,
 }
 
 struct NormalizationCommonArgs<'a> {
     verbose: bool,
     input_file: &'a Path,
-    input_file_info: AudioStream,
+    input_file_info: Probe,
+    output_codec: OutputCodec,
+    /// Apply the gain without encoding with ffmpeg
+    native: Option<Method>,
     ffmpeg_args: &'a [String],
+    progress: &'a dyn Progress,
 }
 
 struct NormalizationPass1Args<'a> {
     common_args: &'a NormalizationCommonArgs<'a>,
+    pass: &'a Pass,
 }
 
 struct NormalizationPass2Args<'a> {
     common_args: &'a NormalizationCommonArgs<'a>,
-    volume_adjustment: f64,
+    volume_adjustment: Value,
+    tags: Vec<(String, String)>,
     output_file: &'a Path,
-    overwrite: bool,
 }
 
 pub fn normalize(args: NormalizationArgs) -> Result<()> {
-    // get input file information
-    let input_file_info =
-        FFprobe::info(args.input_file).with_context(|| "Failed to get input file information")?;
+    let common_args = common_args(&args)?;
 
-    let common_args = NormalizationCommonArgs {
-        verbose: args.verbose,
-        input_file: args.input_file,
-        input_file_info,
-        ffmpeg_args: args.ffmpeg_args,
+    let target_level = match args.reference {
+        Some(reference) => measure_reference(&args, reference)?,
+        None => args.target_level,
     };
 
     let value = pass1(NormalizationPass1Args {
         common_args: &common_args,
+        pass: &PASS1,
     })
     .with_context(|| "Failed to run pass 1 to measure loudness values")?;
 
-    pass2(NormalizationPass2Args {
-        common_args: &common_args,
-        volume_adjustment: args.target_level - value,
-        output_file: args.output_file,
-        overwrite: args.overwrite,
-    })
+    args.progress.measurement(&Measurement {
+        name: "peak_level",
+        label: "Peak level",
+        value,
+        unit: "dB",
+    });
+
+    let volume_adjustment = target_level - value;
+
+    args.progress.measurement(&Measurement {
+        name: "volume_adjustment",
+        label: "Volume adjustment",
+        value: volume_adjustment,
+        unit: "dB",
+    });
+
+    let provenance = provenance(&args, Value::Known(target_level), Some(value));
+
+    match &common_args.native {
+        Some(method) => method.apply(
+            args.input_file,
+            args.output_file,
+            volume_adjustment,
+            Rounding::Down,
+            None,
+            provenance.as_ref(),
+            &PASS2,
+            common_args.input_file_info.stream.duration,
+            args.progress,
+        ),
+        None => pass2(NormalizationPass2Args {
+            common_args: &common_args,
+            volume_adjustment: Value::Known(volume_adjustment),
+            tags: provenance
+                .map(|provenance| provenance.gain(Value::Known(volume_adjustment)).tags())
+                .unwrap_or_default(),
+            output_file: args.output_file,
+        }),
+    }
     .with_context(|| "Failed to run pass 2 to normalize audio file")?;
 
     Ok(())
 }
 
+pub fn plan(args: NormalizationArgs, plan: &mut Plan) -> Result<()> {
+    let common_args = common_args(&args)?;
+
+    let target_level = match args.reference {
+        Some(reference) => {
+            plan.add(
+                &REFERENCE_PASS,
+                &pass1_command(&NormalizationPass1Args {
+                    common_args: &reference_args(&args, reference)?,
+                    pass: &REFERENCE_PASS,
+                }),
+            );
+            plan.placeholder(
+                "REFERENCE_LEVEL",
+                "\"Peak level dB\" of the reference file measured by this pass",
+            );
+            Value::Placeholder("REFERENCE_LEVEL")
+        }
+        None => Value::Known(args.target_level),
+    };
+
+    plan.add(
+        &PASS1,
+        &pass1_command(&NormalizationPass1Args {
+            common_args: &common_args,
+            pass: &PASS1,
+        }),
+    );
+
+    let description = format!("{target_level} minus \"Peak level dB\" measured by pass 1");
+
+    let provenance = provenance(&args, target_level, None);
+
+    match &common_args.native {
+        Some(method) => method.plan(
+            plan,
+            &PASS2,
+            args.input_file,
+            args.output_file,
+            Value::Placeholder("VOLUME_ADJUSTMENT"),
+            Rounding::Down,
+            &description,
+            provenance.as_ref(),
+        ),
+        None => {
+            plan.add(
+                &PASS2,
+                &pass2_command(&NormalizationPass2Args {
+                    common_args: &common_args,
+                    volume_adjustment: Value::Placeholder("VOLUME_ADJUSTMENT"),
+                    tags: provenance
+                        .map(|provenance| {
+                            provenance
+                                .gain(Value::Placeholder("VOLUME_ADJUSTMENT"))
+                                .tags()
+                        })
+                        .unwrap_or_default(),
+                    output_file: args.output_file,
+                }),
+            );
+            plan.placeholder("VOLUME_ADJUSTMENT", &description);
+        }
+    }
+
+    Ok(())
+}
+
+fn common_args<'a>(args: &NormalizationArgs<'a>) -> Result<NormalizationCommonArgs<'a>> {
+    let input_file_info = probe_input(args.input_file, args.verbose, args.progress)?;
+    let (output_codec, native) = select_output(
+        &input_file_info,
+        args.output_file,
+        args.codec_options,
+        args.ffmpeg_args,
+        args.progress,
+    )?;
+
+    Ok(NormalizationCommonArgs {
+        verbose: args.verbose,
+        input_file: args.input_file,
+        input_file_info,
+        output_codec,
+        native,
+        ffmpeg_args: args.ffmpeg_args,
+        progress: args.progress,
+    })
+}
+
+/// Common args to measure the reference file of `--match` with pass 1
+fn reference_args<'a>(
+    args: &NormalizationArgs<'a>,
+    reference: &'a Path,
+) -> Result<NormalizationCommonArgs<'a>> {
+    Ok(NormalizationCommonArgs {
+        verbose: args.verbose,
+        input_file: reference,
+        input_file_info: probe_reference(reference, args.verbose, args.progress)?,
+        // the reference is only decoded
+        output_codec: OutputCodec::default(),
+        native: None,
+        ffmpeg_args: &[],
+        progress: args.progress,
+    })
+}
+
+/// Peak level of the reference file, the target level of `--match`
+fn measure_reference(args: &NormalizationArgs, reference: &Path) -> Result<f64> {
+    let level = pass1(NormalizationPass1Args {
+        common_args: &reference_args(args, reference)?,
+        pass: &REFERENCE_PASS,
+    })
+    .with_context(|| "Failed to measure reference file")?;
+
+    args.progress.measurement(&Measurement {
+        name: "reference_peak_level",
+        label: "Reference peak level",
+        value: level,
+        unit: "dB",
+    });
+
+    Ok(level)
+}
+
+/// Provenance of the output with the measured Peak level if known, `None` if tags are not written
+fn provenance(
+    args: &NormalizationArgs,
+    target_level: Value,
+    level: Option<f64>,
+) -> Option<Provenance> {
+    provenance::is_enabled(args.output_file, args.codec_options.tags).then(|| {
+        let provenance =
+            Provenance::new("peak", format!("{target_level} dB")).reference(args.reference);
+        match level {
+            Some(level) => provenance.input("peak_level", Value::Known(level), "dB"),
+            None => provenance,
+        }
+    })
+}
+
 fn pass1(args: NormalizationPass1Args) -> Result<f64> {
+    let mut ffmpeg = pass1_command(&args);
+
+    let reader = ffmpeg
+        .exec(
+            args.pass,
+            args.common_args.verbose,
+            args.common_args.input_file_info.stream.duration,
+            args.common_args.progress,
+        )
+        .with_context(|| "Failed to processing audio file to measure loudness values")?;
+
+    result_pass1(reader).with_context(|| "Failed to parse Peak level measure result")
+}
+
+fn pass1_command(args: &NormalizationPass1Args) -> FFmpeg {
     let mut ffmpeg = FFmpeg::new(args.common_args.input_file);
 
+    // the whole input is measured, pass 2 must not lift a peak outside a measured range above
+    // the target
     ffmpeg
         .cmd()
         .arg("-filter")
         .arg("astats=measure_overall=Peak_level:measure_perchannel=0");
 
-    ffmpeg.add_common_args(
-        &args.common_args.input_file_info,
-        args.common_args.ffmpeg_args,
-    );
+    ffmpeg.add_common_args(&args.common_args.output_codec, args.common_args.ffmpeg_args);
 
     ffmpeg.cmd().arg("-f").arg("null").arg("-");
 
+    ffmpeg
+}
+
+fn pass2(args: NormalizationPass2Args) -> Result<()> {
+    let mut ffmpeg = pass2_command(&args);
+
     let reader = ffmpeg
         .exec(
-            "[1/2] Processing audio file to measure loudness values:",
+            &PASS2,
             args.common_args.verbose,
-            args.common_args.input_file_info.duration,
+            args.common_args.input_file_info.stream.duration,
+            args.common_args.progress,
         )
-        .with_context(|| "Failed to processing audio file to measure loudness values")?;
-
-    let level =
-        result_pass1(reader).with_context(|| "Failed to parse Peak level measure result")?;
+        .with_context(|| "Failed to normalizing audio file")?;
 
-    if args.common_args.verbose {
-        println!("  Peak level = {}dB", level);
-    }
+    to_progress(reader, args.common_args.progress);
 
-    Ok(level)
+    Ok(())
 }
 
-fn pass2(args: NormalizationPass2Args) -> Result<()> {
+fn pass2_command(args: &NormalizationPass2Args) -> FFmpeg {
     let mut ffmpeg = FFmpeg::new(args.common_args.input_file);
 
     ffmpeg
@@ -110,56 +329,40 @@ fn pass2(args: NormalizationPass2Args) -> Result<()> {
         .arg("-filter")
         .arg(format!("volume={}dB", args.volume_adjustment));
 
-    ffmpeg.add_common_args(
+    ffmpeg.add_metadata(
+        args.output_file,
         &args.common_args.input_file_info,
-        args.common_args.ffmpeg_args,
+        &args.tags,
     );
 
-    if args.overwrite {
-        ffmpeg.cmd().arg("-y");
-    }
-    ffmpeg.cmd().arg(args.output_file);
+    ffmpeg.add_common_args(&args.common_args.output_codec, args.common_args.ffmpeg_args);
 
-    let reader = ffmpeg
-        .exec(
-            "[2/2] Peak Normalizing audio file:",
-            args.common_args.verbose,
-            args.common_args.input_file_info.duration,
-        )
-        .with_context(|| "Failed to normalizing audio file")?;
-
-    if args.common_args.verbose {
-        println!("  Volume adjustment = {}dB", args.volume_adjustment);
-    }
-
-    to_stdout(reader);
+    // output is a temporary file owned by us
+    ffmpeg.cmd().arg("-y").arg(args.output_file);
 
-    Ok(())
+    ffmpeg
 }
 
-fn result_pass1(reader: BufReader<ChildStderr>) -> Result<f64> {
+fn result_pass1(reader: Log) -> Result<f64> {
     let mut err_log = String::new();
     let mut err_parse = String::new();
     let mut value = 0.0f64;
     let mut values_found = false;
 
-    reader
-        .lines()
-        .filter_map(|line| line.ok())
-        .for_each(|line| {
-            if let Some(m) = RE_VALUES.captures(&line).and_then(|caps| caps.get(1)) {
-                if let Ok(v) = m.as_str().parse::<f64>() {
-                    value = v;
-                    values_found = true;
-                } else {
-                    let _ = writeln!(err_parse, "Failed to parse Peak level value: {}", line);
-                }
+    reader.lines().map_while(Result::ok).for_each(|line| {
+        if let Some(m) = RE_VALUES.captures(&line).and_then(|caps| caps.get(1)) {
+            if let Ok(v) = m.as_str().parse::<f64>() {
+                value = v;
+                values_found = true;
             } else {
-                // log error in case of problems
-                err_log += &line;
-                err_log += "\n";
+                let _ = writeln!(err_parse, "Failed to parse Peak level value: {}", line);
             }
-        });
+        } else {
+            // log error in case of problems
+            err_log += &line;
+            err_log += "\n";
+        }
+    });
 
     if !values_found {
         if !err_parse.is_empty() {
@@ -168,6 +371,60 @@ fn result_pass1(reader: BufReader<ChildStderr>) -> Result<f64> {
             bail!("Failed run to ffmpeg to measure Peak level value: \n{err_log}");
         }
     }
+    // the gain to lift silence is infinite
+    if !value.is_finite() {
+        bail!("Peak level cannot be measured, the audio is silent");
+    }
 
     Ok(value)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress;
+    use crate::tool::ffprobe;
+    use std::ffi::OsStr;
+    use std::io::Cursor;
+
+    #[test]
+    fn pass1_measures_whole_input() {
+        let progress = progress::new(progress::Format::Plain, None, false).unwrap();
+        let common_args = NormalizationCommonArgs {
+            verbose: false,
+            input_file: Path::new("in.wav"),
+            input_file_info: ffprobe::parse(br#"{"streams": [{"codec_name": "pcm_s16le"}]}"#)
+                .unwrap(),
+            output_codec: OutputCodec::default(),
+            native: None,
+            ffmpeg_args: &[],
+            progress: progress.as_ref(),
+        };
+
+        let ffmpeg = pass1_command(&NormalizationPass1Args {
+            common_args: &common_args,
+            pass: &PASS1,
+        });
+        let args: Vec<_> = ffmpeg.command_line().collect();
+        let filter = args.iter().position(|arg| *arg == "-filter").unwrap();
+        assert_eq!(
+            args[filter + 1],
+            OsStr::new("astats=measure_overall=Peak_level:measure_perchannel=0")
+        );
+    }
+
+    #[test]
+    fn result_of_astats() {
+        let log = "[Parsed_astats_0 @ 0x1] Overall\n\
+                   [Parsed_astats_0 @ 0x1] Peak level dB: -3.250000\n\
+                   size=N/A time=00:01:00.00 bitrate=N/A speed= 500x\n";
+        assert_eq!(result_pass1(Cursor::new(log.into())).unwrap(), -3.25);
+
+        let err = result_pass1(Cursor::new("Peak level dB: loud\n".into())).unwrap_err();
+        assert!(err.to_string().contains("strange Peak level"), "{err}");
+        let err = result_pass1(Cursor::new("Peak level dB: -inf\n".into())).unwrap_err();
+        assert!(err.to_string().contains("silent"), "{err}");
+        let err = result_pass1(Cursor::new("No such file\n".into())).unwrap_err();
+        assert!(err.to_string().contains("No such file"), "{err}");
+    }
+}
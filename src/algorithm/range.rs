@@ -0,0 +1,275 @@
+use anyhow::{anyhow, bail, Result};
+use std::time::Duration;
+
+/// Region of the input that pass 1 measures. Pass 2 applies the gain to the whole file.
+#[derive(Default)]
+pub struct MeasureRange {
+    /// Segments to measure in seconds, the end of the last one is open if `None`
+    segments: Vec<(f64, Option<f64>)>,
+}
+
+/// The whole input, e.g. of the reference of `--match`
+pub static WHOLE: MeasureRange = MeasureRange {
+    segments: Vec::new(),
+};
+
+impl MeasureRange {
+    /// Range from `start` to `end` without the `exclude` ranges, all in seconds
+    pub fn new(start: Option<f64>, end: Option<f64>, exclude: &[(f64, f64)]) -> Result<Self> {
+        let start = start.unwrap_or_default();
+        if end.is_some_and(|end| end <= start) {
+            bail!("--measure-end must be after --measure-start");
+        }
+        if start == 0.0 && end.is_none() && exclude.is_empty() {
+            return Ok(MeasureRange::default());
+        }
+
+        let mut exclude = exclude.to_vec();
+        exclude.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut segments = Vec::new();
+        let mut from = start;
+        for (exclude_start, exclude_end) in exclude {
+            if end.is_some_and(|end| exclude_start >= end) {
+                break;
+            }
+            if exclude_start > from {
+                segments.push((from, Some(exclude_start)));
+            }
+            from = from.max(exclude_end);
+        }
+        if end.is_none_or(|end| from < end) {
+            segments.push((from, end));
+        }
+
+        if segments.is_empty() {
+            bail!("--measure-exclude excludes the whole measured range");
+        }
+
+        Ok(MeasureRange { segments })
+    }
+
+    /// Range of the parts of `regions` inside this range, `None` if there are none.
+    /// `regions` are sorted start and end times in seconds that do not overlap.
+    pub fn restrict(&self, regions: &[(f64, f64)]) -> Option<MeasureRange> {
+        let segments: Vec<(f64, Option<f64>)> = if self.segments.is_empty() {
+            regions
+                .iter()
+                .map(|(start, end)| (*start, Some(*end)))
+                .collect()
+        } else {
+            regions
+                .iter()
+                .flat_map(|(start, end)| {
+                    self.segments.iter().filter_map(move |(from, to)| {
+                        let from = start.max(*from);
+                        let to = to.map_or(*end, |to| to.min(*end));
+                        (from < to).then_some((from, Some(to)))
+                    })
+                })
+                .collect()
+        };
+
+        (!segments.is_empty()).then_some(MeasureRange { segments })
+    }
+
+    /// Whether the time span from `start` to `end` in seconds is inside one of the segments
+    pub fn contains(&self, start: f64, end: f64) -> bool {
+        self.segments.is_empty()
+            || self
+                .segments
+                .iter()
+                .any(|(from, to)| *from <= start && to.is_none_or(|to| end <= to))
+    }
+
+    /// Filters in front of the measure filter of pass 1 that keep only the measured segments,
+    /// `None` for the whole input
+    pub fn filter(&self) -> Option<String> {
+        let trim = |(start, end): &(f64, Option<f64>)| {
+            let mut trim = format!("atrim=start={start}");
+            if let Some(end) = end {
+                trim += &format!(":end={end}");
+            }
+            trim + ",asetpts=PTS-STARTPTS"
+        };
+
+        match self.segments.as_slice() {
+            [] => None,
+            [segment] => Some(trim(segment)),
+            segments => {
+                let count = segments.len();
+                let split: String = (0..count).map(|i| format!("[m{i}]")).collect();
+                let trims: String = segments
+                    .iter()
+                    .enumerate()
+                    .map(|(i, segment)| format!("[m{i}]{}[s{i}];", trim(segment)))
+                    .collect();
+                let concat: String = (0..count).map(|i| format!("[s{i}]")).collect();
+                Some(format!(
+                    "asplit={count}{split};{trims}{concat}concat=n={count}:v=0:a=1"
+                ))
+            }
+        }
+    }
+
+    /// `measure` filter chain preceded by the filter of the measured segments
+    pub fn with_filter(&self, measure: &str) -> String {
+        match self.filter() {
+            Some(filter) => format!("{filter},{measure}"),
+            None => measure.to_string(),
+        }
+    }
+
+    /// Duration of the measured segments of an input of `duration`
+    pub fn duration(&self, duration: Option<Duration>) -> Option<Duration> {
+        if self.segments.is_empty() {
+            return duration;
+        }
+
+        let total = duration.map(|duration| duration.as_secs_f64());
+        self.segments
+            .iter()
+            .map(|(start, end)| {
+                let end = match (end, total) {
+                    (Some(end), Some(total)) => end.min(total),
+                    (Some(end), None) => *end,
+                    (None, total) => total?,
+                };
+                Some((end - start).max(0.0))
+            })
+            .sum::<Option<f64>>()
+            .map(Duration::from_secs_f64)
+    }
+}
+
+/// Parse a time in seconds or as "[HH:]MM:SS[.ms]", e.g. "90", "1:30" or "00:01:30.5".
+pub fn parse_time(value: &str) -> Result<f64> {
+    let error = || anyhow!("expected time in seconds or [HH:]MM:SS[.ms], e.g. 90 or 1:30");
+
+    let mut parts = value.trim().rsplit(':');
+    let seconds = parts
+        .next()
+        .and_then(|seconds| seconds.parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .ok_or_else(error)?;
+    let time =
+        parts
+            .enumerate()
+            .try_fold(seconds, |time, (i, part)| match (i, part.parse::<u32>()) {
+                (0 | 1, Ok(value)) => Ok(time + f64::from(value) * 60f64.powi(i as i32 + 1)),
+                _ => Err(error()),
+            })?;
+
+    Ok(time)
+}
+
+/// Parse a time range "<START>-<END>", e.g. "1:30-2:00".
+pub fn parse_time_range(value: &str) -> Result<(f64, f64)> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| anyhow!("expected <START>-<END>, e.g. 1:30-2:00"))?;
+    let (start, end) = (parse_time(start)?, parse_time(end)?);
+
+    if end <= start {
+        bail!("end of the range must be after its start");
+    }
+
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_times() {
+        assert_eq!(parse_time("90").unwrap(), 90.0);
+        assert_eq!(parse_time(" 1:30 ").unwrap(), 90.0);
+        assert_eq!(parse_time("01:02:03.5").unwrap(), 3723.5);
+        for value in ["", "-5", "1:2:3:4", "a:30", "1.5:30", "inf", "1:-30"] {
+            assert!(parse_time(value).is_err(), "{value}");
+        }
+
+        assert_eq!(parse_time_range("1:30-2:00").unwrap(), (90.0, 120.0));
+        for value in ["90", "2:00-1:30", "90-90", "90-", "a-b"] {
+            assert!(parse_time_range(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn new_removes_excluded_ranges() {
+        // nothing excluded is the whole input
+        assert!(MeasureRange::new(None, None, &[])
+            .unwrap()
+            .segments
+            .is_empty());
+
+        // unsorted overlapping ranges and a range past the end
+        let range = MeasureRange::new(
+            Some(5.0),
+            Some(100.0),
+            &[(150.0, 160.0), (20.0, 40.0), (10.0, 30.0), (90.0, 120.0)],
+        )
+        .unwrap();
+        assert_eq!(range.segments, [(5.0, Some(10.0)), (40.0, Some(90.0))]);
+
+        let range = MeasureRange::new(None, None, &[(0.0, 10.0)]).unwrap();
+        assert_eq!(range.segments, [(10.0, None)]);
+
+        assert!(MeasureRange::new(Some(10.0), Some(10.0), &[]).is_err());
+        assert!(MeasureRange::new(Some(10.0), Some(20.0), &[(5.0, 25.0)]).is_err());
+    }
+
+    #[test]
+    fn filter_of_segments() {
+        assert_eq!(WHOLE.filter(), None);
+        assert_eq!(WHOLE.with_filter("ebur128"), "ebur128");
+
+        let range = MeasureRange::new(Some(1.5), Some(60.0), &[]).unwrap();
+        assert_eq!(
+            range.with_filter("ebur128").as_str(),
+            "atrim=start=1.5:end=60,asetpts=PTS-STARTPTS,ebur128"
+        );
+
+        let range = MeasureRange::new(None, None, &[(10.0, 20.0)]).unwrap();
+        assert_eq!(
+            range.filter().unwrap(),
+            "asplit=2[m0][m1];\
+             [m0]atrim=start=0:end=10,asetpts=PTS-STARTPTS[s0];\
+             [m1]atrim=start=20,asetpts=PTS-STARTPTS[s1];\
+             [s0][s1]concat=n=2:v=0:a=1"
+        );
+    }
+
+    #[test]
+    fn duration_of_segments() {
+        let minute = Some(Duration::from_secs(60));
+        assert_eq!(WHOLE.duration(minute), minute);
+        assert_eq!(WHOLE.duration(None), None);
+
+        // the end of the input limits the segments
+        let range = MeasureRange::new(None, Some(90.0), &[(10.0, 20.0)]).unwrap();
+        assert_eq!(range.duration(minute), Some(Duration::from_secs(50)));
+        assert_eq!(range.duration(None), Some(Duration::from_secs(80)));
+
+        // an open end is unknown without the duration of the input
+        let range = MeasureRange::new(Some(15.0), None, &[]).unwrap();
+        assert_eq!(range.duration(minute), Some(Duration::from_secs(45)));
+        assert_eq!(range.duration(None), None);
+
+        let range = MeasureRange::new(Some(70.0), None, &[]).unwrap();
+        assert_eq!(range.duration(minute), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn restrict_to_regions() {
+        let range = MeasureRange::new(Some(10.0), Some(30.0), &[]).unwrap();
+        let speech = range
+            .restrict(&[(0.0, 12.0), (20.0, 40.0), (50.0, 60.0)])
+            .unwrap();
+        assert_eq!(speech.segments, [(10.0, Some(12.0)), (20.0, Some(30.0))]);
+        assert!(speech.contains(20.0, 20.4));
+        assert!(!speech.contains(11.8, 12.2));
+        assert!(range.restrict(&[(40.0, 50.0)]).is_none());
+    }
+}
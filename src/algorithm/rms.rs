@@ -1,164 +1,509 @@
-use crate::io::to_stdout;
-use crate::tool::ffmpeg::FFmpeg;
-use crate::tool::ffprobe::{AudioStream, FFprobe};
+use crate::algorithm::range::{MeasureRange, WHOLE};
+use crate::algorithm::{
+    native::{Method, Rounding},
+    probe_input, probe_reference, select_output,
+};
+use crate::io::to_progress;
+use crate::plan::{Plan, Value};
+use crate::progress::{Measurement, Pass, Phase, Progress};
+use crate::provenance::{self, Provenance};
+use crate::tool::codec::{CodecOptions, OutputCodec};
+use crate::tool::ffmpeg::{FFmpeg, Log};
+use crate::tool::ffprobe::{FFprobe, Probe};
 use anyhow::{bail, Context, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::fmt::Write as _;
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
 use std::path::Path;
-use std::process::ChildStderr;
 
 lazy_static! {
     static ref RE_VALUES: Regex =
         Regex::new(r#"^\s*.*\s*RMS\s+level\s+dB\s*:\s*(.+)\s*$"#).unwrap();
+    // RMS level of a window printed by ametadata, e.g. "lavfi.astats.Overall.RMS_level=-20.5"
+    static ref RE_WINDOW: Regex =
+        Regex::new(r#"lavfi\.astats\.Overall\.RMS_level=(\S+)\s*$"#).unwrap();
 }
 
+/// Length of the windows whose RMS level is compared with the gate in seconds
+const GATE_WINDOW: f64 = 0.4;
+/// Sample rate to size the windows with if the input sample rate is unknown
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+const PASS1: Pass = Pass {
+    number: 1,
+    count: 2,
+    phase: Phase::Measure,
+    description: "Processing audio file to measure loudness values",
+};
+const PASS2: Pass = Pass {
+    number: 2,
+    count: 2,
+    phase: Phase::Normalize,
+    description: "RMS Normalizing audio file",
+};
+const REFERENCE_PASS: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Measure,
+    description: "Processing reference file to measure loudness values",
+};
+const OUTPUT_PASS: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Measure,
+    description: "Processing normalized audio file to measure RMS level for the report",
+};
+
 pub struct NormalizationArgs<'a> {
     pub verbose: bool,
     pub input_file: &'a Path,
     pub output_file: &'a Path,
-    pub overwrite: bool,
     pub target_level: f64,
+    /// Ignore windows below this RMS level in dBFS, e.g. silence
+    pub gate: Option<f64>,
+    /// Measure the target level from this file instead of `target_level`
+    pub reference: Option<&'a Path>,
+    /// Region of the input that pass 1 measures
+    pub measure_range: &'a MeasureRange,
     pub ffmpeg_args: &'a [String],
+    pub codec_options: &'a CodecOptions,
+    pub progress: &'a dyn Progress,
+}
+
+/// Args to measure the RMS level of the output like pass 1 measured the input
+pub struct MeasureArgs<'a> {
+    pub verbose: bool,
+    pub input_file: &'a Path,
+    pub output_file: &'a Path,
+    pub gate: Option<f64>,
+    pub measure_range: &'a MeasureRange,
+    pub progress: &'a dyn Progress,
 }
 
 struct NormalizationCommonArgs<'a> {
     verbose: bool,
     input_file: &'a Path,
-    input_file_info: AudioStream,
+    input_file_info: Probe,
+    output_codec: OutputCodec,
+    /// Apply the gain without encoding with ffmpeg
+    native: Option<Method>,
+    gate: Option<f64>,
+    measure_range: &'a MeasureRange,
     ffmpeg_args: &'a [String],
+    progress: &'a dyn Progress,
 }
 
 struct NormalizationPass1Args<'a> {
     common_args: &'a NormalizationCommonArgs<'a>,
+    pass: &'a Pass,
 }
 
 struct NormalizationPass2Args<'a> {
     common_args: &'a NormalizationCommonArgs<'a>,
-    volume_adjustment: f64,
+    volume_adjustment: Value,
+    tags: Vec<(String, String)>,
     output_file: &'a Path,
-    overwrite: bool,
 }
 
 pub fn normalize(args: NormalizationArgs) -> Result<()> {
-    // get input file information
-    let input_file_info =
-        FFprobe::info(args.input_file).with_context(|| "Failed to get input file information")?;
+    let common_args = common_args(&args)?;
 
-    let common_args = NormalizationCommonArgs {
-        verbose: args.verbose,
-        input_file: args.input_file,
-        input_file_info,
-        ffmpeg_args: args.ffmpeg_args,
+    let target_level = match args.reference {
+        Some(reference) => measure_reference(&args, reference)?,
+        None => args.target_level,
     };
 
-    let value = pass1(NormalizationPass1Args {
+    let (value, ungated) = pass1(NormalizationPass1Args {
         common_args: &common_args,
+        pass: &PASS1,
     })
     .with_context(|| "Failed to run pass 1 to measure loudness values")?;
 
-    pass2(NormalizationPass2Args {
-        common_args: &common_args,
-        volume_adjustment: args.target_level - value,
-        output_file: args.output_file,
-        overwrite: args.overwrite,
-    })
+    if let Some(ungated) = ungated {
+        args.progress.measurement(&Measurement {
+            name: "rms_level_ungated",
+            label: "Ungated RMS level",
+            value: ungated,
+            unit: "dB",
+        });
+    }
+    args.progress.measurement(&Measurement {
+        name: "rms_level",
+        label: "RMS level",
+        value,
+        unit: "dB",
+    });
+
+    let volume_adjustment = target_level - value;
+
+    args.progress.measurement(&Measurement {
+        name: "volume_adjustment",
+        label: "Volume adjustment",
+        value: volume_adjustment,
+        unit: "dB",
+    });
+
+    let provenance = provenance(&args, Value::Known(target_level), Some(value));
+
+    match &common_args.native {
+        Some(method) => method.apply(
+            args.input_file,
+            args.output_file,
+            volume_adjustment,
+            Rounding::Nearest,
+            None,
+            provenance.as_ref(),
+            &PASS2,
+            common_args.input_file_info.stream.duration,
+            args.progress,
+        ),
+        None => pass2(NormalizationPass2Args {
+            common_args: &common_args,
+            volume_adjustment: Value::Known(volume_adjustment),
+            tags: provenance
+                .map(|provenance| provenance.gain(Value::Known(volume_adjustment)).tags())
+                .unwrap_or_default(),
+            output_file: args.output_file,
+        }),
+    }
     .with_context(|| "Failed to run pass 2 to normalize audio file")?;
 
     Ok(())
 }
 
-fn pass1(args: NormalizationPass1Args) -> Result<f64> {
-    let mut ffmpeg = FFmpeg::new(args.common_args.input_file);
+pub fn plan(args: NormalizationArgs, plan: &mut Plan) -> Result<()> {
+    let common_args = common_args(&args)?;
 
-    ffmpeg
-        .cmd()
-        .arg("-filter")
-        .arg("astats=measure_overall=RMS_level:measure_perchannel=0");
+    let target_level = match args.reference {
+        Some(reference) => {
+            plan.add(
+                &REFERENCE_PASS,
+                &pass1_command(&NormalizationPass1Args {
+                    common_args: &reference_args(&args, reference)?,
+                    pass: &REFERENCE_PASS,
+                }),
+            );
+            plan.placeholder(
+                "REFERENCE_LEVEL",
+                &format!(
+                    "{} of the reference file measured by this pass",
+                    level_description(args.gate)
+                ),
+            );
+            Value::Placeholder("REFERENCE_LEVEL")
+        }
+        None => Value::Known(args.target_level),
+    };
 
-    ffmpeg.add_common_args(
-        &args.common_args.input_file_info,
-        args.common_args.ffmpeg_args,
+    plan.add(
+        &PASS1,
+        &pass1_command(&NormalizationPass1Args {
+            common_args: &common_args,
+            pass: &PASS1,
+        }),
     );
 
-    ffmpeg.cmd().arg("-f").arg("null").arg("-");
+    let description = format!(
+        "{target_level} minus {} measured by pass 1",
+        level_description(args.gate)
+    );
+
+    let provenance = provenance(&args, target_level, None);
+
+    match &common_args.native {
+        Some(method) => method.plan(
+            plan,
+            &PASS2,
+            args.input_file,
+            args.output_file,
+            Value::Placeholder("VOLUME_ADJUSTMENT"),
+            Rounding::Nearest,
+            &description,
+            provenance.as_ref(),
+        ),
+        None => {
+            plan.add(
+                &PASS2,
+                &pass2_command(&NormalizationPass2Args {
+                    common_args: &common_args,
+                    volume_adjustment: Value::Placeholder("VOLUME_ADJUSTMENT"),
+                    tags: provenance
+                        .map(|provenance| {
+                            provenance
+                                .gain(Value::Placeholder("VOLUME_ADJUSTMENT"))
+                                .tags()
+                        })
+                        .unwrap_or_default(),
+                    output_file: args.output_file,
+                }),
+            );
+            plan.placeholder("VOLUME_ADJUSTMENT", &description);
+        }
+    }
+
+    Ok(())
+}
+
+fn common_args<'a>(args: &NormalizationArgs<'a>) -> Result<NormalizationCommonArgs<'a>> {
+    let input_file_info = probe_input(args.input_file, args.verbose, args.progress)?;
+    let (output_codec, native) = select_output(
+        &input_file_info,
+        args.output_file,
+        args.codec_options,
+        args.ffmpeg_args,
+        args.progress,
+    )?;
+
+    Ok(NormalizationCommonArgs {
+        verbose: args.verbose,
+        input_file: args.input_file,
+        input_file_info,
+        output_codec,
+        native,
+        gate: args.gate,
+        measure_range: args.measure_range,
+        ffmpeg_args: args.ffmpeg_args,
+        progress: args.progress,
+    })
+}
+
+/// Common args to measure the reference file of `--match` with pass 1
+fn reference_args<'a>(
+    args: &NormalizationArgs<'a>,
+    reference: &'a Path,
+) -> Result<NormalizationCommonArgs<'a>> {
+    Ok(NormalizationCommonArgs {
+        verbose: args.verbose,
+        input_file: reference,
+        input_file_info: probe_reference(reference, args.verbose, args.progress)?,
+        // the reference is only decoded
+        output_codec: OutputCodec::default(),
+        native: None,
+        gate: args.gate,
+        measure_range: &WHOLE,
+        ffmpeg_args: &[],
+        progress: args.progress,
+    })
+}
+
+/// RMS level of the reference file with the same gate, the target level of `--match`
+fn measure_reference(args: &NormalizationArgs, reference: &Path) -> Result<f64> {
+    let (level, _) = pass1(NormalizationPass1Args {
+        common_args: &reference_args(args, reference)?,
+        pass: &REFERENCE_PASS,
+    })
+    .with_context(|| "Failed to measure reference file")?;
+
+    args.progress.measurement(&Measurement {
+        name: "reference_rms_level",
+        label: "Reference RMS level",
+        value: level,
+        unit: "dB",
+    });
+
+    Ok(level)
+}
+
+/// Measure the RMS level of the normalized output with the gate and in the range of pass 1, e.g.
+/// to check it against the target in the report
+pub fn measure_output(args: MeasureArgs) -> Result<()> {
+    let input_file_info = FFprobe::probe(args.output_file)
+        .with_context(|| "Failed to get normalized audio file information")?;
+
+    let (level, _) = pass1(NormalizationPass1Args {
+        common_args: &output_args(&args, input_file_info),
+        pass: &OUTPUT_PASS,
+    })
+    .with_context(|| "Failed to measure RMS level of normalized audio file")?;
+
+    args.progress.measurement(&Measurement {
+        name: "output_rms_level",
+        label: "Output RMS level",
+        value: level,
+        unit: "dB",
+    });
+
+    Ok(())
+}
+
+pub fn plan_output(args: MeasureArgs, plan: &mut Plan) -> Result<()> {
+    // the output does not exist yet, it keeps the sample rate of the input
+    let input_file_info =
+        FFprobe::probe(args.input_file).with_context(|| "Failed to get input file information")?;
+
+    plan.add(
+        &OUTPUT_PASS,
+        &pass1_command(&NormalizationPass1Args {
+            common_args: &output_args(&args, input_file_info),
+            pass: &OUTPUT_PASS,
+        }),
+    );
+
+    Ok(())
+}
+
+/// Common args to measure the normalized output of `input_file_info` with pass 1
+fn output_args<'a>(args: &MeasureArgs<'a>, input_file_info: Probe) -> NormalizationCommonArgs<'a> {
+    NormalizationCommonArgs {
+        verbose: args.verbose,
+        input_file: args.output_file,
+        input_file_info,
+        // the output is only decoded
+        output_codec: OutputCodec::default(),
+        native: None,
+        gate: args.gate,
+        measure_range: args.measure_range,
+        ffmpeg_args: &[],
+        progress: args.progress,
+    }
+}
+
+/// Measured RMS level in the placeholder descriptions of the plan
+fn level_description(gate: Option<f64>) -> String {
+    match gate {
+        Some(gate) => format!(
+            "the mean of the \"lavfi.astats.Overall.RMS_level\" power values above {gate} dB"
+        ),
+        None => "\"RMS level dB\"".to_string(),
+    }
+}
+
+/// Provenance of the output with the measured RMS level if known, `None` if tags are not written
+fn provenance(
+    args: &NormalizationArgs,
+    target_level: Value,
+    level: Option<f64>,
+) -> Option<Provenance> {
+    provenance::is_enabled(args.output_file, args.codec_options.tags).then(|| {
+        let target = match args.gate {
+            Some(gate) => format!("{target_level} dB, gate {gate} dB"),
+            None => format!("{target_level} dB"),
+        };
+        let provenance = Provenance::new("rms", target).reference(args.reference);
+        match level {
+            Some(level) => provenance.input("rms_level", Value::Known(level), "dB"),
+            None => provenance,
+        }
+    })
+}
+
+/// RMS level and the ungated RMS level if a gate is set
+fn pass1(args: NormalizationPass1Args) -> Result<(f64, Option<f64>)> {
+    let mut ffmpeg = pass1_command(&args);
 
     let reader = ffmpeg
         .exec(
-            "[1/2] Processing audio file to measure loudness values:",
+            args.pass,
             args.common_args.verbose,
-            args.common_args.input_file_info.duration,
+            args.common_args
+                .measure_range
+                .duration(args.common_args.input_file_info.stream.duration),
+            args.common_args.progress,
         )
         .with_context(|| "Failed to processing audio file to measure loudness values")?;
 
-    let level = result_pass1(reader).with_context(|| "Failed to parse RMS level measure result")?;
-
-    if args.common_args.verbose {
-        println!("  RMS level = {}dB", level);
+    match args.common_args.gate {
+        Some(gate) => result_gated(reader, gate, args.common_args.progress)
+            .map(|(level, ungated)| (level, Some(ungated))),
+        None => result_pass1(reader).map(|level| (level, None)),
     }
-
-    Ok(level)
+    .with_context(|| "Failed to parse RMS level measure result")
 }
 
-fn pass2(args: NormalizationPass2Args) -> Result<()> {
+fn pass1_command(args: &NormalizationPass1Args) -> FFmpeg {
     let mut ffmpeg = FFmpeg::new(args.common_args.input_file);
 
+    let filter = match args.common_args.gate {
+        Some(_) => {
+            // RMS level of each window, reset after each frame of one window
+            let sample_rate = args
+                .common_args
+                .input_file_info
+                .stream
+                .sample_rate
+                .unwrap_or(DEFAULT_SAMPLE_RATE);
+            format!(
+                "asetnsamples=n={}:p=0,\
+                 astats=metadata=1:reset=1:measure_overall=RMS_level:measure_perchannel=0,\
+                 ametadata=mode=print:key=lavfi.astats.Overall.RMS_level",
+                (f64::from(sample_rate) * GATE_WINDOW).round()
+            )
+        }
+        None => "astats=measure_overall=RMS_level:measure_perchannel=0".to_string(),
+    };
+
     ffmpeg
         .cmd()
         .arg("-filter")
-        .arg(format!("volume={}dB", args.volume_adjustment));
+        .arg(args.common_args.measure_range.with_filter(&filter));
 
-    ffmpeg.add_common_args(
-        &args.common_args.input_file_info,
-        args.common_args.ffmpeg_args,
-    );
+    ffmpeg.add_common_args(&args.common_args.output_codec, args.common_args.ffmpeg_args);
 
-    if args.overwrite {
-        ffmpeg.cmd().arg("-y");
-    }
-    ffmpeg.cmd().arg(args.output_file);
+    ffmpeg.cmd().arg("-f").arg("null").arg("-");
+
+    ffmpeg
+}
+
+fn pass2(args: NormalizationPass2Args) -> Result<()> {
+    let mut ffmpeg = pass2_command(&args);
 
     let reader = ffmpeg
         .exec(
-            "[2/2] RMS Normalizing audio file:",
+            &PASS2,
             args.common_args.verbose,
-            args.common_args.input_file_info.duration,
+            args.common_args.input_file_info.stream.duration,
+            args.common_args.progress,
         )
         .with_context(|| "Failed to normalizing audio file")?;
 
-    if args.common_args.verbose {
-        println!("  Volume adjustment = {}dB", args.volume_adjustment);
-    }
-
-    to_stdout(reader);
+    to_progress(reader, args.common_args.progress);
 
     Ok(())
 }
 
-fn result_pass1(reader: BufReader<ChildStderr>) -> Result<f64> {
+fn pass2_command(args: &NormalizationPass2Args) -> FFmpeg {
+    let mut ffmpeg = FFmpeg::new(args.common_args.input_file);
+
+    ffmpeg
+        .cmd()
+        .arg("-filter")
+        .arg(format!("volume={}dB", args.volume_adjustment));
+
+    ffmpeg.add_metadata(
+        args.output_file,
+        &args.common_args.input_file_info,
+        &args.tags,
+    );
+
+    ffmpeg.add_common_args(&args.common_args.output_codec, args.common_args.ffmpeg_args);
+
+    // output is a temporary file owned by us
+    ffmpeg.cmd().arg("-y").arg(args.output_file);
+
+    ffmpeg
+}
+
+fn result_pass1(reader: Log) -> Result<f64> {
     let mut err_log = String::new();
     let mut err_parse = String::new();
     let mut value = 0.0f64;
     let mut values_found = false;
 
-    reader
-        .lines()
-        .filter_map(|line| line.ok())
-        .for_each(|line| {
-            if let Some(m) = RE_VALUES.captures(&line).and_then(|caps| caps.get(1)) {
-                if let Ok(v) = m.as_str().parse::<f64>() {
-                    value = v;
-                    values_found = true;
-                } else {
-                    let _ = writeln!(err_parse, "Failed to parse RMS level value: {}", line);
-                }
+    reader.lines().map_while(Result::ok).for_each(|line| {
+        if let Some(m) = RE_VALUES.captures(&line).and_then(|caps| caps.get(1)) {
+            if let Ok(v) = m.as_str().parse::<f64>() {
+                value = v;
+                values_found = true;
             } else {
-                // log error in case of problems
-                err_log += &line;
-                err_log += "\n";
+                let _ = writeln!(err_parse, "Failed to parse RMS level value: {}", line);
             }
-        });
+        } else {
+            // log error in case of problems
+            err_log += &line;
+            err_log += "\n";
+        }
+    });
 
     if !values_found {
         if !err_parse.is_empty() {
@@ -167,6 +512,166 @@ fn result_pass1(reader: BufReader<ChildStderr>) -> Result<f64> {
             bail!("Failed run to ffmpeg to measure RMS level value: \n{err_log}");
         }
     }
+    // the gain to lift silence is infinite
+    if !value.is_finite() {
+        bail!("RMS level cannot be measured, the audio is silent");
+    }
 
     Ok(value)
 }
+
+/// RMS level of the windows above `gate` and the ungated level of all windows
+fn result_gated(reader: Log, gate: f64, progress: &dyn Progress) -> Result<(f64, f64)> {
+    let mut err_log = String::new();
+    let mut levels = Vec::new();
+
+    reader.lines().map_while(Result::ok).for_each(|line| {
+        match RE_WINDOW.captures(&line).map(|caps| caps[1].to_string()) {
+            Some(value) if value == "-inf" => levels.push(f64::NEG_INFINITY),
+            Some(value) => match value.parse::<f64>() {
+                Ok(level) => levels.push(level),
+                Err(_) => {
+                    let _ = writeln!(err_log, "Failed to parse RMS level value: {}", line);
+                }
+            },
+            // log error in case of problems
+            None => {
+                err_log += &line;
+                err_log += "\n";
+            }
+        }
+    });
+
+    if levels.is_empty() {
+        bail!("Failed run to ffmpeg to measure RMS level values of windows: \n{err_log}");
+    }
+
+    // mean power of the windows
+    let mean = |levels: &[f64]| {
+        10.0 * (levels
+            .iter()
+            .map(|level| 10f64.powf(level / 10.0))
+            .sum::<f64>()
+            / levels.len() as f64)
+            .log10()
+    };
+    let ungated = mean(&levels);
+    // the gain to lift silence is infinite
+    if !ungated.is_finite() {
+        bail!(
+            "RMS level cannot be measured, all {} windows are silent",
+            levels.len()
+        );
+    }
+    let gated: Vec<f64> = levels
+        .iter()
+        .copied()
+        .filter(|level| *level > gate)
+        .collect();
+
+    if gated.is_empty() {
+        progress.message(&format!(
+            "Warning: all {} windows are below the gate of {gate} dB, the ungated RMS level is used",
+            levels.len()
+        ));
+        return Ok((ungated, ungated));
+    }
+
+    let level = mean(&gated);
+    progress.message(&format!(
+        "Gated RMS level is {level:.2} dB ({} of {} windows above {gate} dB), ungated {ungated:.2} dB",
+        gated.len(),
+        levels.len()
+    ));
+
+    Ok((level, ungated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{self, Format, Recorder};
+    use std::io::Cursor;
+
+    /// Log of the RMS levels of windows printed by ametadata
+    fn log(levels: &[&str]) -> Log {
+        let log: String = levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| {
+                format!(
+                    "[Parsed_ametadata_2 @ 0x1] frame:{i} pts:{i} pts_time:{:.1}\n\
+                     [Parsed_ametadata_2 @ 0x1] lavfi.astats.Overall.RMS_level={level}\n",
+                    i as f64 * 0.4
+                )
+            })
+            .collect();
+        Cursor::new(log.into_bytes())
+    }
+
+    #[test]
+    fn result_gated_leaves_out_quiet_windows() {
+        let progress = progress::new(Format::Plain, None, false).unwrap();
+
+        // silence and windows below the gate only lower the ungated level
+        let (level, ungated) = result_gated(
+            log(&["-20.0", "-inf", "-20.0", "-60.0"]),
+            -50.0,
+            progress.as_ref(),
+        )
+        .unwrap();
+        assert!((level + 20.0).abs() < 1e-9, "{level}");
+        let expected = 10.0 * ((2.0 * 0.01 + 1e-6) / 4.0f64).log10();
+        assert!((ungated - expected).abs() < 1e-9, "{ungated}");
+
+        // windows at the gate are left out, the mean is of the power
+        let (level, _) =
+            result_gated(log(&["-10.0", "-20.0", "-50.0"]), -50.0, progress.as_ref()).unwrap();
+        let expected = 10.0 * ((0.1 + 0.01) / 2.0f64).log10();
+        assert!((level - expected).abs() < 1e-9, "{level}");
+    }
+
+    #[test]
+    fn result_gated_falls_back_to_ungated() {
+        let progress = progress::new(Format::Plain, None, false).unwrap();
+        let recorder = Recorder::new(progress.as_ref());
+
+        let (level, ungated) =
+            result_gated(log(&["-60.0", "-inf", "-60.0"]), -50.0, &recorder).unwrap();
+        assert_eq!(level, ungated);
+        assert!(
+            (level + 60.0 + 10.0 * 1.5f64.log10()).abs() < 1e-9,
+            "{level}"
+        );
+        assert!(recorder.messages()[0].starts_with("Warning: all 3 windows are below the gate"));
+    }
+
+    #[test]
+    fn silence_is_an_error() {
+        let progress = progress::new(Format::Plain, None, false).unwrap();
+        let err = result_gated(log(&["-inf", "-inf"]), -50.0, progress.as_ref())
+            .map(|_| ())
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("all 2 windows are silent"),
+            "{err}"
+        );
+
+        let log = "[Parsed_astats_0 @ 0x1] Overall\n[Parsed_astats_0 @ 0x1] RMS level dB: -inf\n";
+        let err = result_pass1(Cursor::new(log.into())).unwrap_err();
+        assert!(err.to_string().contains("silent"), "{err}");
+    }
+
+    #[test]
+    fn result_gated_without_windows() {
+        let progress = progress::new(Format::Plain, None, false).unwrap();
+        let err = result_gated(
+            Cursor::new(b"in.wav: No such file or directory\n".to_vec()),
+            -50.0,
+            progress.as_ref(),
+        )
+        .map(|_| ())
+        .unwrap_err();
+        assert!(err.to_string().contains("No such file"), "{err}");
+    }
+}
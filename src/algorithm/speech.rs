@@ -0,0 +1,348 @@
+use crate::algorithm::range::MeasureRange;
+use crate::tool::ffmpeg::{FFmpeg, Log};
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::io::BufRead;
+use std::path::Path;
+
+lazy_static! {
+    // RMS level of a channel of a frame printed by ametadata, e.g. "lavfi.astats.2.RMS_level=-31.2",
+    // channel 1 is the full band, channel 2 the speech band
+    static ref RE_FRAME: Regex =
+        Regex::new(r#"lavfi\.astats\.([12])\.RMS_level=(\S+)\s*$"#).unwrap();
+    // momentary loudness of a frame logged by ebur128, e.g. "t: 1.2  TARGET:-23 LUFS  M: -24.5 S: ..."
+    static ref RE_BLOCK: Regex = Regex::new(r#"\bt:\s*(\S+)\s.*\bM:\s*(\S+)\s"#).unwrap();
+    // integrated loudness of the ebur128 summary, e.g. "    I:         -23.0 LUFS"
+    static ref RE_PROGRAM: Regex = Regex::new(r#"^\s*I:\s*(\S+)\s+LUFS\s*$"#).unwrap();
+}
+
+/// Length of the frames whose levels are measured in seconds
+const FRAME: f64 = 0.05;
+/// Frames per block that is classified as speech or not
+const BLOCK_FRAMES: usize = 10;
+/// Band of the speech energy in Hz
+const SPEECH_BAND: (u32, u32) = (300, 3400);
+/// Minimum level of the speech band of an active frame in dBFS
+const MIN_LEVEL: f64 = -50.0;
+/// Minimum level of an active frame above the noise floor in dB
+const MIN_SNR: f64 = 10.0;
+/// Maximum difference of the speech band to the full band of an active frame in dB
+const MAX_BAND_LOSS: f64 = 6.0;
+/// Minimum share of active frames of a speech block
+const MIN_ACTIVE: f64 = 0.5;
+/// Minimum standard deviation of the frame levels of a speech block in dB.
+/// Syllables modulate speech, sustained music and noise are steadier.
+const MIN_MODULATION: f64 = 3.0;
+/// Pauses between speech regions up to this length in seconds are bridged
+const HANGOVER: f64 = 0.5;
+/// Length of the gating blocks of the momentary loudness in seconds
+const LOUDNESS_BLOCK: f64 = 0.4;
+/// Absolute gate of the gating blocks in LUFS (ITU-R BS.1770)
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Relative gate of the gating blocks below their loudness in LU (ITU-R BS.1770)
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Sample rate to size the frames with if the input sample rate is unknown
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+/// Speech regions of the input
+pub struct Speech {
+    /// Start and end of the speech regions in seconds
+    pub regions: Vec<(f64, f64)>,
+    /// Duration of the analyzed input in seconds
+    pub duration: f64,
+    /// Integrated loudness of the whole program in LUFS
+    pub program_loudness: Option<f64>,
+    /// End time in seconds and momentary loudness in LUFS of the gating blocks,
+    /// which overlap by 75%
+    blocks: Vec<(f64, f64)>,
+}
+
+impl Speech {
+    pub fn speech_duration(&self) -> f64 {
+        self.regions.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// Integrated loudness of the gating blocks inside the speech regions of `range`,
+    /// `None` if there is no speech in it or all of it is below the absolute gate
+    pub fn dialogue_loudness(&self, range: &MeasureRange) -> Option<f64> {
+        self.loudness(&range.restrict(&self.regions)?)
+    }
+
+    /// Integrated loudness of the gating blocks inside `range`, `None` if all of them are below
+    /// the absolute gate
+    pub fn loudness(&self, range: &MeasureRange) -> Option<f64> {
+        let levels: Vec<f64> = self
+            .blocks
+            .iter()
+            .filter(|(end, _)| range.contains(end - LOUDNESS_BLOCK, *end))
+            .map(|(_, level)| *level)
+            .collect();
+
+        integrated_loudness(&levels)
+    }
+}
+
+/// Command measuring the levels of the full band and the speech band of each frame of the
+/// input downmixed to mono, the momentary loudness of the gating blocks and the program loudness
+pub fn command(input_file: &Path, sample_rate: Option<u32>) -> FFmpeg {
+    let mut ffmpeg = FFmpeg::new(input_file);
+    let frame = (f64::from(sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE)) * FRAME).round() as u32;
+
+    ffmpeg.cmd().arg("-filter_complex").arg(format!(
+        "ebur128=framelog=info,aformat=channel_layouts=mono,asplit=2[full][band];\
+         [band]highpass=f={}:poles=2,lowpass=f={}:poles=2[speech];\
+         [full][speech]amerge=inputs=2,asetnsamples=n={frame}:p=0,\
+         astats=metadata=1:reset=1:measure_perchannel=RMS_level:measure_overall=none,\
+         ametadata=mode=print",
+        SPEECH_BAND.0, SPEECH_BAND.1
+    ));
+
+    ffmpeg.cmd().arg("-f").arg("null").arg("-");
+
+    ffmpeg
+}
+
+/// Classify the frames printed by `command` and return the speech regions
+pub fn result(reader: Log) -> Result<Speech> {
+    let mut err_log = String::new();
+    let mut frames: Vec<(f64, f64)> = Vec::new();
+    let mut program_loudness = None;
+    let mut blocks = Vec::new();
+    let parse = |value: &str| match value {
+        "-inf" => Some(f64::NEG_INFINITY),
+        value => value.parse::<f64>().ok(),
+    };
+
+    reader.lines().map_while(Result::ok).for_each(|line| {
+        if let Some(caps) = RE_FRAME.captures(&line) {
+            let level = parse(&caps[2]).unwrap_or(f64::NEG_INFINITY);
+            // the full band is printed first
+            match &caps[1] {
+                "1" => frames.push((level, f64::NEG_INFINITY)),
+                _ => {
+                    if let Some(frame) = frames.last_mut() {
+                        frame.1 = level;
+                    }
+                }
+            }
+        } else if let Some(caps) = RE_BLOCK.captures(&line) {
+            if let (Some(end), Some(level)) = (caps[1].parse::<f64>().ok(), parse(&caps[2])) {
+                blocks.push((end, level));
+            }
+        } else if let Some(caps) = RE_PROGRAM.captures(&line) {
+            program_loudness = parse(&caps[1]);
+        } else {
+            // log error in case of problems
+            err_log += &line;
+            err_log += "\n";
+        }
+    });
+
+    if frames.is_empty() {
+        bail!("Failed run to ffmpeg to measure speech band levels: \n{err_log}");
+    }
+
+    Ok(Speech {
+        regions: classify(&frames),
+        duration: frames.len() as f64 * FRAME,
+        program_loudness,
+        blocks,
+    })
+}
+
+/// Integrated loudness of the momentary loudness of gating blocks with the absolute and the
+/// relative gate of ITU-R BS.1770, `None` if all blocks are below the absolute gate
+fn integrated_loudness(levels: &[f64]) -> Option<f64> {
+    let power = |level: &f64| 10f64.powf((level + 0.691) / 10.0);
+    let mean_loudness = |levels: &[f64]| {
+        -0.691 + 10.0 * (levels.iter().map(power).sum::<f64>() / levels.len() as f64).log10()
+    };
+
+    let levels: Vec<f64> = levels
+        .iter()
+        .copied()
+        .filter(|level| *level > ABSOLUTE_GATE)
+        .collect();
+    if levels.is_empty() {
+        return None;
+    }
+
+    let threshold = mean_loudness(&levels) + RELATIVE_GATE;
+    let levels: Vec<f64> = levels
+        .into_iter()
+        .filter(|level| *level > threshold)
+        .collect();
+
+    Some(mean_loudness(&levels))
+}
+
+/// Speech regions of frames of full band and speech band levels
+fn classify(frames: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    // the quietest tenth of the frames is the noise floor
+    let mut levels: Vec<f64> = frames
+        .iter()
+        .map(|(_, band)| *band)
+        .filter(|band| band.is_finite())
+        .collect();
+    levels.sort_by(f64::total_cmp);
+    let floor = levels
+        .get(levels.len() / 10)
+        .copied()
+        .unwrap_or(f64::NEG_INFINITY);
+
+    let is_active = |(full, band): &(f64, f64)| {
+        *band > MIN_LEVEL && *band > floor + MIN_SNR && *band > full - MAX_BAND_LOSS
+    };
+
+    let mut regions: Vec<(f64, f64)> = Vec::new();
+    frames
+        .chunks(BLOCK_FRAMES)
+        .enumerate()
+        .filter(|(_, block)| {
+            let active = block.iter().filter(|frame| is_active(frame)).count();
+            let levels: Vec<f64> = block
+                .iter()
+                .map(|(_, band)| band.max(MIN_LEVEL + floor.min(0.0)))
+                .collect();
+            let mean = levels.iter().sum::<f64>() / levels.len() as f64;
+            let deviation = (levels
+                .iter()
+                .map(|level| (level - mean).powi(2))
+                .sum::<f64>()
+                / levels.len() as f64)
+                .sqrt();
+            active as f64 >= MIN_ACTIVE * block.len() as f64 && deviation >= MIN_MODULATION
+        })
+        .for_each(|(i, block)| {
+            let start = (i * BLOCK_FRAMES) as f64 * FRAME;
+            let end = start + block.len() as f64 * FRAME;
+            match regions.last_mut() {
+                Some(region) if start - region.1 <= HANGOVER => region.1 = end,
+                _ => regions.push((start, end)),
+            }
+        });
+
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::range::WHOLE;
+    use std::io::Cursor;
+
+    /// Frames of syllables in 7 of every 10 frames, the full band 1 dB above the speech band
+    fn speech(frames: usize, level: f64) -> Vec<(f64, f64)> {
+        (0..frames)
+            .map(|i| match i % 10 < 7 {
+                true => (level + 1.0, level),
+                false => (level - 19.0, level - 20.0),
+            })
+            .collect()
+    }
+
+    /// Frames of a sustained sound like music
+    fn steady(frames: usize, level: f64) -> Vec<(f64, f64)> {
+        vec![(level + 1.0, level); frames]
+    }
+
+    fn silence(frames: usize) -> Vec<(f64, f64)> {
+        vec![(f64::NEG_INFINITY, f64::NEG_INFINITY); frames]
+    }
+
+    #[test]
+    fn classify_modulated_frames() {
+        let frames = [speech(40, -20.0), steady(40, -16.0), speech(40, -20.0)].concat();
+        assert_eq!(
+            classify(&frames),
+            [(0.0, 40.0 * FRAME), (80.0 * FRAME, 120.0 * FRAME)]
+        );
+
+        // steady sounds, speech below the minimum level and silence are no speech
+        assert_eq!(classify(&steady(40, -16.0)), []);
+        assert_eq!(classify(&speech(40, -60.0)), []);
+        assert_eq!(classify(&silence(40)), []);
+
+        // the speech band has to carry most of the energy
+        let hum: Vec<(f64, f64)> = speech(40, -20.0)
+            .into_iter()
+            .map(|(_, band)| (band + 10.0, band))
+            .collect();
+        assert_eq!(classify(&hum), []);
+    }
+
+    #[test]
+    fn classify_bridges_short_pauses() {
+        let frames = [speech(10, -20.0), silence(10), speech(10, -20.0)].concat();
+        assert_eq!(classify(&frames), [(0.0, 30.0 * FRAME)]);
+
+        let frames = [speech(10, -20.0), silence(20), speech(10, -20.0)].concat();
+        assert_eq!(
+            classify(&frames),
+            [(0.0, 10.0 * FRAME), (30.0 * FRAME, 40.0 * FRAME)]
+        );
+    }
+
+    #[test]
+    fn integrated_loudness_gates() {
+        assert_eq!(integrated_loudness(&[]), None);
+        assert_eq!(integrated_loudness(&[-80.0, f64::NEG_INFINITY]), None);
+        // blocks below the absolute gate and 10 LU below the mean are left out
+        let loudness = integrated_loudness(&[-20.0, -20.0, -75.0, -45.0]).unwrap();
+        assert!((loudness + 20.0).abs() < 1e-9, "{loudness}");
+    }
+
+    #[test]
+    fn result_gates_dialogue() {
+        // speech at -20 LUFS around music at -14 LUFS
+        let frames = [speech(40, -20.0), steady(40, -16.0), speech(40, -20.0)].concat();
+        let mut log = String::new();
+        for (i, (full, band)) in frames.iter().enumerate() {
+            let t = (i + 1) as f64 * FRAME;
+            log += &format!("[Parsed_ametadata_9 @ 0x1] frame:{i} pts:{i} pts_time:{t:.2}\n");
+            log += &format!("[Parsed_ametadata_9 @ 0x1] lavfi.astats.1.RMS_level={full}\n");
+            log += &format!("[Parsed_ametadata_9 @ 0x1] lavfi.astats.2.RMS_level={band}\n");
+        }
+        for i in 1..=60 {
+            let t = f64::from(i) * 0.1;
+            let level = if (2.0..4.0).contains(&(t - 0.05)) {
+                -14.0
+            } else {
+                -20.0
+            };
+            log += &format!(
+                "[Parsed_ebur128_0 @ 0x1] t: {t:.1}    TARGET:-23 LUFS    M: {level:.1} S: {level:.1}\n"
+            );
+        }
+        log += "[Parsed_ebur128_0 @ 0x1] Summary:\n  Integrated loudness:\n    I:         -16.5 LUFS\n";
+
+        let speech = result(Cursor::new(log.into_bytes())).unwrap();
+        assert_eq!(speech.duration, 120.0 * FRAME);
+        assert_eq!(speech.speech_duration(), 80.0 * FRAME);
+        assert_eq!(speech.program_loudness, Some(-16.5));
+
+        // the dialogue is measured without the music, the program with it
+        let dialogue = speech.dialogue_loudness(&WHOLE).unwrap();
+        assert!((dialogue + 20.0).abs() < 1e-9, "{dialogue}");
+        let program = speech.loudness(&WHOLE).unwrap();
+        assert!(program > dialogue + 2.0, "{program}");
+
+        // a range without speech has no dialogue loudness
+        let music = MeasureRange::new(Some(2.5), Some(3.5), &[]).unwrap();
+        assert_eq!(speech.dialogue_loudness(&music), None);
+        let loudness = speech.loudness(&music).unwrap();
+        assert!((loudness + 14.0).abs() < 1e-9, "{loudness}");
+    }
+
+    #[test]
+    fn result_without_frames() {
+        let log = Cursor::new(b"Error opening input file\n".to_vec());
+        let err = result(log).map(|_| ()).unwrap_err();
+        assert!(
+            err.to_string().contains("Error opening input file"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use super::range::{MeasureRange, WHOLE};
+use crate::bitstream::bext::Loudness;
+use crate::plan::Plan;
+use crate::progress::{Measurement, Pass, Phase, Progress};
+use crate::tool::ffmpeg::{FFmpeg, Log};
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::BufRead;
+use std::path::Path;
+
+lazy_static! {
+    // loudness of a frame, e.g.
+    // "t: 0.4  TARGET:-23 LUFS    M: -25.3 S:-120.7     I: -25.3 LUFS       LRA:   0.0 LU  SPK: -5.0 -5.1 dBFS  FTPK: -5.1 -5.2 dBFS  TPK: -5.1 -5.2 dBFS"
+    static ref RE_FRAME: Regex = Regex::new(
+        r#"\bt:\s*(\S+)\s.*\bM:\s*(\S+)\s+S:\s*(\S+)\s+I:\s*(\S+)\s+LUFS\s+LRA:\s*(\S+)\s+LU\b(?:.*\bFTPK:((?:\s*(?:-?inf|-?[\d.]+))+)\s*dBFS)?"#
+    )
+    .unwrap();
+    // summary values, e.g. "    I:         -23.0 LUFS", "    LRA:         7.1 LU", "    Peak:       -1.2 dBFS"
+    static ref RE_SUMMARY: Regex =
+        Regex::new(r#"^\s*(I|LRA|Peak):\s*(\S+)\s+(?:LUFS|LU|dBFS)\s*$"#).unwrap();
+    // section of the following peak of the summary, e.g. "  Sample peak:" or "  True peak:"
+    static ref RE_PEAK_SECTION: Regex = Regex::new(r#"^\s*(Sample|True) peak:\s*$"#).unwrap();
+}
+
+const PASS: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Measure,
+    description: "Processing normalized audio file to measure loudness",
+};
+
+/// Loudness of a 100 ms frame of the ebur128 filter, `None` for silence (`-inf` of ebur128),
+/// which is an empty CSV field and `null` in JSON
+#[derive(Serialize)]
+pub struct Frame {
+    /// End of the frame in seconds
+    pub time: f64,
+    /// Momentary loudness of the last 400 ms in LUFS
+    pub momentary: Option<f64>,
+    /// Short-term loudness of the last 3 s in LUFS
+    pub short_term: Option<f64>,
+    /// Integrated loudness up to the frame in LUFS
+    pub integrated: Option<f64>,
+    /// Loudness range up to the frame in LU
+    pub loudness_range: Option<f64>,
+    /// Highest true peak of all channels in the frame in dBTP
+    pub true_peak: Option<f64>,
+}
+
+/// Loudness of the normalized output over time
+pub struct Timeline {
+    pub loudness: Loudness,
+    /// Highest sample peak of all channels in dBFS
+    pub sample_peak: Option<f64>,
+    pub frames: Vec<Frame>,
+}
+
+/// Timeline export format, selected by the file extension
+#[derive(Clone, Copy)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+pub struct MeasureArgs<'a> {
+    pub verbose: bool,
+    pub output_file: &'a Path,
+    pub progress: &'a dyn Progress,
+}
+
+#[derive(Serialize)]
+struct Export<'a> {
+    integrated: Option<f64>,
+    loudness_range: Option<f64>,
+    max_true_peak: Option<f64>,
+    max_momentary: Option<f64>,
+    max_short_term: Option<f64>,
+    frames: &'a [Frame],
+}
+
+/// Format of a timeline file, fail early if it is neither CSV nor JSON
+pub fn check(timeline_file: &Path) -> Result<Format> {
+    let extension = timeline_file
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("csv") => Ok(Format::Csv),
+        Some("json") => Ok(Format::Json),
+        _ => bail!("--timeline requires a .csv or .json file"),
+    }
+}
+
+/// Measure the loudness of the normalized output frame by frame.
+pub fn measure(args: MeasureArgs) -> Result<Timeline> {
+    let timeline = measure_file(args.output_file, &WHOLE, &PASS, args.verbose, args.progress)
+        .with_context(|| "Failed to processing normalized audio file to measure loudness")?;
+    let loudness = &timeline.loudness;
+
+    [
+        ("output_i", "Integrated loudness", loudness.value, "LUFS"),
+        ("output_lra", "Loudness range", loudness.range, "LU"),
+        ("output_tp", "True peak", loudness.max_true_peak, "dBTP"),
+        (
+            "output_max_m",
+            "Max momentary loudness",
+            loudness.max_momentary,
+            "LUFS",
+        ),
+        (
+            "output_max_s",
+            "Max short-term loudness",
+            loudness.max_short_term,
+            "LUFS",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, label, value, unit)| value.map(|value| (name, label, value, unit)))
+    .for_each(|(name, label, value, unit)| {
+        args.progress.measurement(&Measurement {
+            name,
+            label,
+            value,
+            unit,
+        })
+    });
+
+    Ok(timeline)
+}
+
+pub fn plan(args: MeasureArgs, plan: &mut Plan) {
+    plan.add(&PASS, &command(args.output_file, &WHOLE));
+}
+
+/// Measure the loudness of the measured range of a file frame by frame in `pass`
+pub fn measure_file(
+    file: &Path,
+    measure_range: &MeasureRange,
+    pass: &Pass,
+    verbose: bool,
+    progress: &dyn Progress,
+) -> Result<Timeline> {
+    let mut ffmpeg = command(file, measure_range);
+
+    let reader = ffmpeg.exec(pass, verbose, None, progress)?;
+
+    result(reader)
+}
+
+pub fn plan_file(file: &Path, measure_range: &MeasureRange, pass: &Pass, plan: &mut Plan) {
+    plan.add(pass, &command(file, measure_range));
+}
+
+/// Add a note of the timeline export to the plan
+pub fn plan_export(timeline_file: &Path, plan: &mut Plan) {
+    plan.note(&format!(
+        "The momentary and short-term loudness of every frame is written to \"{}\"",
+        timeline_file.display()
+    ));
+}
+
+/// Write the timeline as CSV or JSON
+pub fn export(timeline: &Timeline, timeline_file: &Path) -> Result<()> {
+    let loudness = &timeline.loudness;
+
+    let data = match check(timeline_file)? {
+        Format::Csv => {
+            // the summary is written as comment lines in front of the frames
+            let mut data = String::new();
+            [
+                ("integrated", loudness.value, "LUFS"),
+                ("loudness_range", loudness.range, "LU"),
+                ("max_true_peak", loudness.max_true_peak, "dBTP"),
+                ("max_momentary", loudness.max_momentary, "LUFS"),
+                ("max_short_term", loudness.max_short_term, "LUFS"),
+            ]
+            .into_iter()
+            .filter_map(|(name, value, unit)| value.map(|value| (name, value, unit)))
+            .for_each(|(name, value, unit)| {
+                let _ = writeln!(data, "# {name}={value} {unit}");
+            });
+            data += "time,momentary,short_term,integrated,loudness_range,true_peak\n";
+            // silence is an empty field
+            let field =
+                |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+            timeline.frames.iter().for_each(|frame| {
+                let _ = writeln!(
+                    data,
+                    "{},{},{},{},{},{}",
+                    frame.time,
+                    field(frame.momentary),
+                    field(frame.short_term),
+                    field(frame.integrated),
+                    field(frame.loudness_range),
+                    field(frame.true_peak)
+                );
+            });
+            data
+        }
+        Format::Json => serde_json::to_string_pretty(&Export {
+            integrated: loudness.value,
+            loudness_range: loudness.range,
+            max_true_peak: loudness.max_true_peak,
+            max_momentary: loudness.max_momentary,
+            max_short_term: loudness.max_short_term,
+            frames: &timeline.frames,
+        })?,
+    };
+
+    fs::write(timeline_file, data).with_context(|| {
+        format!(
+            "Failed to write loudness timeline \"{}\"",
+            timeline_file.display()
+        )
+    })
+}
+
+fn command(file: &Path, measure_range: &MeasureRange) -> FFmpeg {
+    let mut ffmpeg = FFmpeg::new(file);
+
+    ffmpeg
+        .cmd()
+        .arg("-filter")
+        .arg(measure_range.with_filter("ebur128=peak=sample+true:framelog=info"))
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+
+    ffmpeg
+}
+
+fn result(reader: Log) -> Result<Timeline> {
+    let mut err_log = String::new();
+    let mut frames = Vec::new();
+    let mut sample_peak = None;
+    let mut peak_section = String::new();
+    let mut loudness = Loudness {
+        value: None,
+        range: None,
+        max_true_peak: None,
+        max_momentary: None,
+        max_short_term: None,
+    };
+    let parse = |value: &str| match value {
+        "-inf" => Some(f64::NEG_INFINITY),
+        value => value.parse::<f64>().ok(),
+    };
+    let max = |current: Option<f64>, value: Option<f64>| match (current, value) {
+        (Some(current), Some(value)) => Some(current.max(value)),
+        (current, value) => current.or(value),
+    };
+
+    reader.lines().map_while(Result::ok).for_each(|line| {
+        if let Some(caps) = RE_FRAME.captures(&line) {
+            // silence is `None`
+            let value = |i: usize| parse(&caps[i]).filter(|value| value.is_finite());
+            let frame = Frame {
+                time: value(1).unwrap_or_default(),
+                momentary: value(2),
+                short_term: value(3),
+                integrated: value(4),
+                loudness_range: value(5),
+                // one value per channel, silent channels are `-inf`
+                true_peak: caps.get(6).and_then(|true_peak| {
+                    true_peak
+                        .as_str()
+                        .split_whitespace()
+                        .filter_map(parse)
+                        .filter(|value| value.is_finite())
+                        .reduce(f64::max)
+                }),
+            };
+            loudness.max_momentary = max(loudness.max_momentary, frame.momentary);
+            loudness.max_short_term = max(loudness.max_short_term, frame.short_term);
+            frames.push(frame);
+        } else if let Some(caps) = RE_SUMMARY.captures(&line) {
+            let value = parse(&caps[2]);
+            match &caps[1] {
+                "I" => loudness.value = value,
+                "LRA" => loudness.range = value,
+                _ if peak_section == "Sample" => sample_peak = value,
+                _ => loudness.max_true_peak = value,
+            }
+        } else if let Some(caps) = RE_PEAK_SECTION.captures(&line) {
+            peak_section = caps[1].to_string();
+        } else {
+            // log error in case of problems
+            err_log += &line;
+            err_log += "\n";
+        }
+    });
+
+    if loudness.value.is_none() {
+        bail!("Failed run to ffmpeg to measure loudness: \n{err_log}");
+    }
+
+    Ok(Timeline {
+        loudness,
+        sample_peak,
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn result_of_framelog() {
+        let log = "\
+[Parsed_ebur128_0 @ 0x1] t: 0.1    TARGET:-23 LUFS    M:-120.7 S:-120.7     I: -70.0 LUFS       LRA:   0.0 LU  FTPK:  -inf  -inf dBFS  TPK:  -inf  -inf dBFS
+[Parsed_ebur128_0 @ 0x1] t: 0.2    TARGET:-23 LUFS    M: -inf S: -inf     I: -inf LUFS       LRA:   0.0 LU  FTPK:  -4.0  -2.5 dBFS  TPK:  -4.0  -2.5 dBFS
+[Parsed_ebur128_0 @ 0x1] t: 0.3    TARGET:-23 LUFS    M: -21.0 S: -24.0     I: -23.0 LUFS       LRA:   1.0 LU  FTPK:  -3.0  -3.5 dBFS  TPK:  -2.5  -2.5 dBFS
+[Parsed_ebur128_0 @ 0x1] t: 0.4    TARGET:-23 LUFS    M: -25.0 S: -22.0     I: -23.0 LUFS       LRA:   1.0 LU
+[Parsed_ebur128_0 @ 0x1] t: 0.5    TARGET:-23 LUFS    M: -24.0 S: -22.5     I: -23.0 LUFS       LRA:   1.0 LU  FTPK:  -3.0  -3.5  -inf dBFS  TPK:  -2.5  -2.5  -inf dBFS
+[Parsed_ebur128_0 @ 0x1] Summary:
+  Integrated loudness:
+    I:         -23.0 LUFS
+    Threshold: -33.0 LUFS
+  Loudness range:
+    LRA:         1.0 LU
+  Sample peak:
+    Peak:       -3.0 dBFS
+  True peak:
+    Peak:       -2.5 dBFS
+";
+        let timeline = result(Cursor::new(log.as_bytes().to_vec())).unwrap();
+
+        assert_eq!(timeline.frames.len(), 5);
+        assert_eq!(timeline.frames[0].momentary, Some(-120.7));
+        // silence of ebur128 is not measured
+        assert_eq!(timeline.frames[1].momentary, None);
+        assert_eq!(timeline.frames[1].integrated, None);
+        // the true peak of the frame is the highest of its channels
+        assert_eq!(timeline.frames[1].true_peak, Some(-2.5));
+        assert_eq!(timeline.frames[2].true_peak, Some(-3.0));
+        assert_eq!(timeline.frames[3].true_peak, None);
+        // a silent channel, e.g. the LFE of 5.1
+        assert_eq!(timeline.frames[4].true_peak, Some(-3.0));
+        assert_eq!(timeline.frames[0].true_peak, None);
+
+        let loudness = &timeline.loudness;
+        assert_eq!(loudness.value, Some(-23.0));
+        assert_eq!(loudness.range, Some(1.0));
+        assert_eq!(loudness.max_true_peak, Some(-2.5));
+        assert_eq!(loudness.max_momentary, Some(-21.0));
+        assert_eq!(loudness.max_short_term, Some(-22.0));
+        assert_eq!(timeline.sample_peak, Some(-3.0));
+    }
+
+    #[test]
+    fn result_without_summary() {
+        let log = Cursor::new(b"in.wav: Invalid data found when processing input\n".to_vec());
+        let err = result(log).map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("Invalid data"), "{err}");
+    }
+}
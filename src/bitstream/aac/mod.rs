@@ -0,0 +1,931 @@
+//! Lossless gain of AAC-LC files by changing `global_gain` of every channel stream,
+//! like aacgain. ADTS streams and MP4 files are supported.
+//!
+//! `global_gain` is the start of the scalefactors, which are coded as differences, so every
+//! channel stream is parsed up to its end to find the next one. The spectral data is only
+//! skipped, it is not dequantized.
+
+mod codebooks;
+
+use super::ape::ApeTag;
+use super::mp3::Summary;
+use super::mp4::{self, Atom};
+use super::{id3v2_tag_size, read_bits, read_full, write_bits};
+use anyhow::{bail, Context, Result};
+use codebooks::{Tree, SCALEFACTOR_TREE, SPECTRUM_TREES};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Tag item with the applied steps of both channels like mp3gain, e.g. "+002,+002,N"
+const UNDO_KEY: &str = "MP3GAIN_UNDO";
+
+/// Largest ADTS frame by its 13 bit frame length
+const MAX_FRAME_SIZE: usize = 8191;
+/// Bytes of ADTS frames read at a time
+const READ_SIZE: usize = 1 << 16;
+/// Bytes of an ADTS header without CRC
+const HEADER_SIZE: usize = 7;
+
+/// Samples per frame, frames of 960 samples are not supported
+const FRAME_SAMPLES: u32 = 1024;
+
+/// Sample rates by sampling frequency index
+const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// Audio object type and ADTS profile of AAC-LC
+const OBJECT_TYPE_LC: u32 = 2;
+/// Audio object types of HE-AAC and HE-AAC v2
+const OBJECT_TYPES_SBR: [u32; 2] = [5, 29];
+
+/// Syntactic elements of a raw data block
+const ID_SCE: u32 = 0;
+const ID_CPE: u32 = 1;
+const ID_CCE: u32 = 2;
+const ID_LFE: u32 = 3;
+const ID_DSE: u32 = 4;
+const ID_PCE: u32 = 5;
+const ID_FIL: u32 = 6;
+const ID_END: u32 = 7;
+
+/// Extension payloads of fill elements with SBR data
+const EXT_SBR_DATA: [u32; 2] = [13, 14];
+
+const EIGHT_SHORT_SEQUENCE: u32 = 2;
+
+/// Section codebooks without spectral data
+const ZERO_HCB: u8 = 0;
+const RESERVED_HCB: u8 = 12;
+const NOISE_HCB: u8 = 13;
+const INTENSITY_HCBS: [u8; 2] = [14, 15];
+/// Escape value of codebook 11
+const ESCAPE: usize = 16;
+
+/// Scalefactor band offsets of long windows
+const BANDS_96K: &[u16] = &[
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 44, 48, 52, 56, 64, 72, 80, 88, 96, 108, 120, 132,
+    144, 156, 172, 188, 212, 240, 276, 320, 384, 448, 512, 576, 640, 704, 768, 832, 896, 960, 1024,
+];
+const BANDS_64K: &[u16] = &[
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 44, 48, 52, 56, 64, 72, 80, 88, 100, 112, 124, 140,
+    156, 172, 192, 216, 240, 268, 304, 344, 384, 424, 464, 504, 544, 584, 624, 664, 704, 744, 784,
+    824, 864, 904, 944, 984, 1024,
+];
+const BANDS_48K: &[u16] = &[
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 48, 56, 64, 72, 80, 88, 96, 108, 120, 132, 144, 160,
+    176, 196, 216, 240, 264, 292, 320, 352, 384, 416, 448, 480, 512, 544, 576, 608, 640, 672, 704,
+    736, 768, 800, 832, 864, 896, 928, 1024,
+];
+const BANDS_32K: &[u16] = &[
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 48, 56, 64, 72, 80, 88, 96, 108, 120, 132, 144, 160,
+    176, 196, 216, 240, 264, 292, 320, 352, 384, 416, 448, 480, 512, 544, 576, 608, 640, 672, 704,
+    736, 768, 800, 832, 864, 896, 928, 960, 992, 1024,
+];
+const BANDS_24K: &[u16] = &[
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 44, 52, 60, 68, 76, 84, 92, 100, 108, 116, 124, 136,
+    148, 160, 172, 188, 204, 220, 240, 260, 284, 308, 336, 364, 396, 432, 468, 508, 552, 600, 652,
+    704, 768, 832, 896, 960, 1024,
+];
+const BANDS_16K: &[u16] = &[
+    0, 8, 16, 24, 32, 40, 48, 56, 64, 72, 80, 88, 100, 112, 124, 136, 148, 160, 172, 184, 196, 212,
+    228, 244, 260, 280, 300, 320, 344, 368, 396, 424, 456, 492, 532, 572, 616, 664, 716, 772, 832,
+    896, 960, 1024,
+];
+const BANDS_8K: &[u16] = &[
+    0, 12, 24, 36, 48, 60, 72, 84, 96, 108, 120, 132, 144, 156, 172, 188, 204, 220, 236, 252, 268,
+    288, 308, 328, 348, 372, 396, 420, 448, 476, 508, 544, 580, 620, 664, 712, 764, 820, 880, 944,
+    1024,
+];
+
+/// Scalefactor band offsets of short windows
+const SHORT_BANDS_96K: &[u16] = &[0, 4, 8, 12, 16, 20, 24, 32, 40, 48, 64, 92, 128];
+const SHORT_BANDS_48K: &[u16] = &[0, 4, 8, 12, 16, 20, 28, 36, 44, 56, 68, 80, 96, 112, 128];
+const SHORT_BANDS_24K: &[u16] = &[
+    0, 4, 8, 12, 16, 20, 24, 28, 36, 44, 52, 64, 76, 92, 108, 128,
+];
+const SHORT_BANDS_16K: &[u16] = &[
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 40, 48, 60, 72, 88, 108, 128,
+];
+const SHORT_BANDS_8K: &[u16] = &[
+    0, 4, 8, 12, 16, 20, 24, 28, 36, 44, 52, 60, 72, 88, 108, 128,
+];
+
+/// Long and short scalefactor bands by sampling frequency index
+const BANDS: [(&[u16], &[u16]); 13] = [
+    (BANDS_96K, SHORT_BANDS_96K),
+    (BANDS_96K, SHORT_BANDS_96K),
+    (BANDS_64K, SHORT_BANDS_96K),
+    (BANDS_48K, SHORT_BANDS_48K),
+    (BANDS_48K, SHORT_BANDS_48K),
+    (BANDS_32K, SHORT_BANDS_48K),
+    (BANDS_24K, SHORT_BANDS_24K),
+    (BANDS_24K, SHORT_BANDS_24K),
+    (BANDS_16K, SHORT_BANDS_16K),
+    (BANDS_16K, SHORT_BANDS_16K),
+    (BANDS_16K, SHORT_BANDS_16K),
+    (BANDS_8K, SHORT_BANDS_8K),
+    (BANDS_8K, SHORT_BANDS_8K),
+];
+
+/// Window layout of a channel stream
+#[derive(Clone)]
+struct IcsInfo {
+    bands: &'static [u16],
+    max_sfb: usize,
+    /// Number of windows of each window group
+    groups: Vec<usize>,
+}
+
+/// `global_gain` of a channel stream
+struct GlobalGain {
+    /// Bit position
+    pos: usize,
+    value: i32,
+    /// Lowest and highest scalefactor, which have to stay within 0 to 255
+    range: (i32, i32),
+}
+
+/// Bit reader of a raw data block, which fails at the end of the block
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl Reader<'_> {
+    fn bits(&mut self, count: usize) -> Result<u32> {
+        if self.pos + count > self.end {
+            bail!("truncated raw data block");
+        }
+        let value = read_bits(self.data, self.pos, count);
+        self.pos += count;
+        Ok(value)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<()> {
+        if self.pos + count > self.end {
+            bail!("truncated raw data block");
+        }
+        self.pos += count;
+        Ok(())
+    }
+
+    fn flag(&mut self) -> Result<bool> {
+        self.bits(1).map(|bit| bit != 0)
+    }
+
+    fn byte_align(&mut self) {
+        self.pos = self.pos.next_multiple_of(8);
+    }
+
+    fn decode(&mut self, tree: &Tree) -> Result<usize> {
+        tree.decode(|| self.bits(1))
+    }
+}
+
+/// Change the gain of an ADTS or MP4 file by `steps` of 1.5 dB and store undo information
+/// in an APEv2 tag (ADTS) or an iTunes tag (MP4). `tags` are added to the same tag.
+/// ADTS files are streamed, MP4 files are read into memory to follow their sample table.
+pub fn rewrite_file(
+    input: &Path,
+    output: &Path,
+    steps: i32,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let mut reader = BufReader::new(
+        File::open(input)
+            .with_context(|| format!("Failed to read input file \"{}\"", input.display()))?,
+    );
+    let mut signature = [0u8; 8];
+    let read = read_full(&mut reader, &mut signature)
+        .with_context(|| format!("Failed to read input file \"{}\"", input.display()))?;
+    let is_mp4 = read == signature.len() && signature[4..8] == *b"ftyp";
+
+    if is_mp4 {
+        let data = fs::read(input)
+            .with_context(|| format!("Failed to read input file \"{}\"", input.display()))?;
+        let (data, summary) = apply_gain_mp4(data, steps, tags)
+            .with_context(|| format!("Failed to change gain of \"{}\"", input.display()))?;
+        fs::write(output, data)
+            .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?;
+        return Ok(summary);
+    }
+
+    let mut writer = BufWriter::new(
+        File::create(output)
+            .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?,
+    );
+
+    let summary = apply_gain_adts(&mut reader, &mut writer, steps, tags)
+        .with_context(|| format!("Failed to change gain of \"{}\"", input.display()))?;
+
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?;
+
+    Ok(summary)
+}
+
+/// Copy the ADTS stream from `input` to `output` and change the gain of its frames by `steps`.
+/// Only the frames in flight and the tags at the end are held in memory.
+fn apply_gain_adts(
+    input: &mut (impl Read + Seek),
+    output: &mut impl Write,
+    steps: i32,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let mut end = input.seek(SeekFrom::End(0))?;
+    input.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; 10];
+    let read = read_full(input, &mut header)?;
+    let start = (id3v2_tag_size(&header[..read]) as u64).min(end);
+
+    let mut id3v1 = vec![0u8; 128];
+    if end >= start + 128 {
+        input.seek(SeekFrom::Start(end - 128))?;
+        input.read_exact(&mut id3v1)?;
+    }
+    let id3v1 = if &id3v1[..3] == b"TAG" {
+        end -= 128;
+        id3v1
+    } else {
+        Vec::new()
+    };
+
+    let (mut tag, tag_start) = match ApeTag::read_from(input, end)? {
+        Some((tag, tag_start)) => (tag, tag_start.max(start)),
+        None => (ApeTag::default(), end),
+    };
+    end = tag_start;
+
+    // the ID3v2 tag is kept unchanged
+    input.seek(SeekFrom::Start(0))?;
+    io::copy(&mut input.by_ref().take(start), output)?;
+
+    let mut summary = Summary {
+        frames: 0,
+        clamped: 0,
+        duration: Duration::ZERO,
+    };
+    let mut frames = input.by_ref().take(end - start);
+    let mut buffer = Vec::with_capacity(READ_SIZE);
+    // start of the bytes in `buffer` that are not written yet, and the file offset of `buffer`
+    let mut pos = 0;
+    let mut offset = start as usize;
+
+    loop {
+        if buffer.len() - pos < MAX_FRAME_SIZE && frames.limit() > 0 {
+            buffer.drain(..pos);
+            offset += pos;
+            pos = 0;
+            let size = (READ_SIZE - buffer.len()) as u64;
+            frames.by_ref().take(size).read_to_end(&mut buffer)?;
+        }
+        if buffer.len() - pos < HEADER_SIZE {
+            break;
+        }
+
+        let data = &mut buffer[pos..];
+        // sync word and layer 0
+        let size = read_bits(data, 30, 13) as usize;
+        if read_bits(data, 0, 12) != 0xfff
+            || read_bits(data, 13, 2) != 0
+            || size < HEADER_SIZE
+            || size > data.len()
+        {
+            // junk between frames or a truncated last frame
+            output.write_all(&data[..1])?;
+            pos += 1;
+            continue;
+        }
+
+        let frame_offset = offset + pos;
+        let profile = read_bits(data, 16, 2) + 1;
+        if profile != OBJECT_TYPE_LC {
+            bail!("AAC object type {profile} at byte offset {frame_offset} is not supported, only AAC-LC");
+        }
+        let rate_index = read_bits(data, 18, 4) as usize;
+        let Some(sample_rate) = SAMPLE_RATES.get(rate_index) else {
+            bail!("Reserved sample rate in frame at byte offset {frame_offset}");
+        };
+        if read_bits(data, 15, 1) == 0 {
+            bail!("ADTS frames with CRC are not supported");
+        }
+        if read_bits(data, 54, 2) != 0 {
+            bail!("ADTS frames with several raw data blocks are not supported");
+        }
+
+        let frame = &mut data[..size];
+        summary.clamped += change_gain(frame, HEADER_SIZE * 8, size * 8, rate_index, steps)
+            .with_context(|| format!("Invalid frame at byte offset {frame_offset}"))?;
+        output.write_all(frame)?;
+        summary.frames += 1;
+        summary.duration +=
+            Duration::from_secs_f64(f64::from(FRAME_SAMPLES) / f64::from(*sample_rate));
+        pos += size;
+    }
+    output.write_all(&buffer[pos..])?;
+
+    if summary.frames == 0 {
+        bail!("No ADTS frames found");
+    }
+
+    let undo = add_undo(tag.get(UNDO_KEY).as_deref(), steps);
+    tag.set(UNDO_KEY, &undo);
+    tags.iter().for_each(|(key, value)| tag.set(key, value));
+
+    output.write_all(&tag.to_bytes())?;
+    output.write_all(&id3v1)?;
+
+    Ok(summary)
+}
+
+fn apply_gain_mp4(
+    mut data: Vec<u8>,
+    steps: i32,
+    tags: &[(String, String)],
+) -> Result<(Vec<u8>, Summary)> {
+    let boxes = mp4::boxes(&data, 0..data.len())?;
+    if boxes.iter().any(|(kind, _, _)| kind == b"moof") {
+        bail!("Fragmented MP4 files are not supported");
+    }
+    let Some((_, moov_range, header)) = boxes.into_iter().find(|(kind, _, _)| kind == b"moov")
+    else {
+        bail!("No movie box found");
+    };
+    let mut moov = Atom::parse(&data, moov_range.clone(), header)?;
+
+    let Some(track) = mp4::audio_tracks(&moov, data.len())?.into_iter().next() else {
+        bail!("No audio track found");
+    };
+    if track.format != *b"mp4a" {
+        bail!(
+            "Audio track format \"{}\" is not supported, only AAC",
+            String::from_utf8_lossy(&track.format)
+        );
+    }
+    let Some(config) = track.decoder_config else {
+        bail!("Audio track has no AAC decoder configuration");
+    };
+    let rate_index = audio_specific_config(&config)?;
+
+    let mut summary = Summary {
+        frames: 0,
+        clamped: 0,
+        duration: Duration::ZERO,
+    };
+    for sample in track.samples {
+        let Some(frame) = data.get_mut(sample.clone()) else {
+            bail!(
+                "Sample at byte offset {} is outside of the file",
+                sample.start
+            );
+        };
+        summary.clamped += change_gain(frame, 0, frame.len() * 8, rate_index, steps)
+            .with_context(|| format!("Invalid sample at byte offset {}", sample.start))?;
+        summary.frames += 1;
+    }
+    summary.duration = Duration::from_secs_f64(
+        summary.frames as f64 * f64::from(FRAME_SAMPLES) / f64::from(SAMPLE_RATES[rate_index]),
+    );
+
+    let undo = add_undo(mp4::tag(&moov, UNDO_KEY).as_deref(), steps);
+    mp4::set_tags(
+        &mut moov,
+        &[vec![(UNDO_KEY.to_string(), undo)], tags.to_vec()].concat(),
+    );
+
+    // media data behind the movie box moves with its size
+    let delta = moov.to_bytes().len() as isize - moov_range.len() as isize;
+    mp4::shift_chunk_offsets(&mut moov, moov_range.end, delta)?;
+
+    let data = [
+        &data[..moov_range.start],
+        &moov.to_bytes(),
+        &data[moov_range.end..],
+    ]
+    .concat();
+
+    Ok((data, summary))
+}
+
+/// Steps of an earlier undo item plus `steps`
+fn add_undo(undo: Option<&str>, steps: i32) -> String {
+    let undo = undo
+        .and_then(|undo| {
+            let mut values = undo.split(',').map(|value| value.trim().parse::<i32>());
+            Some((values.next()?.ok()?, values.next()?.ok()?))
+        })
+        .unwrap_or_default();
+    format!("{:+04},{:+04},N", undo.0 + steps, undo.1 + steps)
+}
+
+/// Sampling frequency index of an AAC-LC `AudioSpecificConfig`
+fn audio_specific_config(config: &[u8]) -> Result<usize> {
+    let mut reader = Reader {
+        data: config,
+        pos: 0,
+        end: config.len() * 8,
+    };
+    let mut object_type = reader.bits(5)?;
+    if object_type == 31 {
+        object_type = 32 + reader.bits(6)?;
+    }
+    let mut rate_index = reader.bits(4)? as usize;
+    if rate_index == 15 {
+        let sample_rate = reader.bits(24)?;
+        rate_index = SAMPLE_RATES
+            .iter()
+            .position(|rate| *rate == sample_rate)
+            .with_context(|| format!("Sample rate {sample_rate} Hz is not supported"))?;
+    }
+    if OBJECT_TYPES_SBR.contains(&object_type) {
+        bail!("HE-AAC is not supported, the gain of its SBR data cannot be changed");
+    }
+    if object_type != OBJECT_TYPE_LC {
+        bail!("AAC object type {object_type} is not supported, only AAC-LC");
+    }
+    // channel configuration and frame length flag
+    reader.skip(4)?;
+    if reader.flag()? {
+        bail!("AAC frames of 960 samples are not supported");
+    }
+    if rate_index >= SAMPLE_RATES.len() {
+        bail!("Reserved sample rate in AAC decoder configuration");
+    }
+
+    Ok(rate_index)
+}
+
+/// Change `global_gain` of all channel streams of the raw data block in `start..end` bits,
+/// returns the number of clamped channel streams.
+fn change_gain(
+    frame: &mut [u8],
+    start: usize,
+    end: usize,
+    rate_index: usize,
+    steps: i32,
+) -> Result<usize> {
+    let mut reader = Reader {
+        data: frame,
+        pos: start,
+        end,
+    };
+    let gains = raw_data_block(&mut reader, rate_index)?;
+
+    let mut clamped = 0;
+    for gain in gains {
+        // every scalefactor moves with global_gain
+        let lowest = gain.value.min(gain.range.0);
+        let highest = gain.value.max(gain.range.1);
+        if lowest < 0 || highest > 255 {
+            bail!("scalefactor out of range");
+        }
+        let applied = steps.clamp(-lowest, 255 - highest);
+        if applied != steps {
+            clamped += 1;
+        }
+        write_bits(frame, gain.pos, 8, (gain.value + applied) as u32);
+    }
+
+    Ok(clamped)
+}
+
+fn raw_data_block(reader: &mut Reader, rate_index: usize) -> Result<Vec<GlobalGain>> {
+    let mut gains = Vec::new();
+
+    loop {
+        match reader.bits(3)? {
+            ID_SCE | ID_LFE => {
+                // element instance tag
+                reader.skip(4)?;
+                gains.push(channel_stream(reader, rate_index, None)?);
+            }
+            ID_CPE => {
+                reader.skip(4)?;
+                let common = if reader.flag()? {
+                    let info = ics_info(reader, rate_index)?;
+                    // one ms_used flag per band if ms_mask_present is 1
+                    if reader.bits(2)? == 1 {
+                        reader.skip(info.groups.len() * info.max_sfb)?;
+                    }
+                    Some(info)
+                } else {
+                    None
+                };
+                gains.push(channel_stream(reader, rate_index, common.clone())?);
+                gains.push(channel_stream(reader, rate_index, common)?);
+            }
+            ID_CCE => bail!("coupling channel elements are not supported"),
+            ID_DSE => {
+                reader.skip(4)?;
+                let align = reader.flag()?;
+                let mut count = reader.bits(8)? as usize;
+                if count == 255 {
+                    count += reader.bits(8)? as usize;
+                }
+                if align {
+                    reader.byte_align();
+                }
+                reader.skip(count * 8)?;
+            }
+            ID_PCE => program_config(reader)?,
+            ID_FIL => {
+                let mut count = reader.bits(4)? as usize;
+                if count == 15 {
+                    count += reader.bits(8)? as usize;
+                    count -= 1;
+                }
+                if count > 0 {
+                    if EXT_SBR_DATA.contains(&reader.bits(4)?) {
+                        bail!(
+                            "HE-AAC is not supported, the gain of its SBR data cannot be changed"
+                        );
+                    }
+                    reader.skip(count * 8 - 4)?;
+                }
+            }
+            ID_END => break,
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(gains)
+}
+
+fn ics_info(reader: &mut Reader, rate_index: usize) -> Result<IcsInfo> {
+    // reserved bit
+    reader.skip(1)?;
+    let window_sequence = reader.bits(2)?;
+    // window shape
+    reader.skip(1)?;
+
+    let (long, short) = BANDS[rate_index];
+    let info = if window_sequence == EIGHT_SHORT_SEQUENCE {
+        let max_sfb = reader.bits(4)? as usize;
+        // a set bit adds the next window to the group of the previous one
+        let grouping = reader.bits(7)?;
+        let mut groups = vec![1];
+        (0..7).rev().for_each(|bit| match (grouping >> bit) & 1 {
+            1 => *groups.last_mut().unwrap() += 1,
+            _ => groups.push(1),
+        });
+        IcsInfo {
+            bands: short,
+            max_sfb,
+            groups,
+        }
+    } else {
+        let max_sfb = reader.bits(6)? as usize;
+        if reader.flag()? {
+            bail!("prediction of AAC Main and LTP is not supported");
+        }
+        IcsInfo {
+            bands: long,
+            max_sfb,
+            groups: vec![1],
+        }
+    };
+
+    if info.max_sfb >= info.bands.len() {
+        bail!("invalid number of scalefactor bands {}", info.max_sfb);
+    }
+
+    Ok(info)
+}
+
+/// Parse an `individual_channel_stream` up to its end
+fn channel_stream(
+    reader: &mut Reader,
+    rate_index: usize,
+    common: Option<IcsInfo>,
+) -> Result<GlobalGain> {
+    let pos = reader.pos;
+    let global_gain = reader.bits(8)? as i32;
+    let info = match common {
+        Some(info) => info,
+        None => ics_info(reader, rate_index)?,
+    };
+    let is_long = info.groups.len() == 1 && info.groups[0] == 1;
+
+    // section data: codebook of each band by window group
+    let section_bits = if is_long { 5 } else { 3 };
+    let escape = (1 << section_bits) - 1;
+    let mut codebooks = vec![Vec::with_capacity(info.max_sfb); info.groups.len()];
+    for bands in codebooks.iter_mut() {
+        while bands.len() < info.max_sfb {
+            let codebook = reader.bits(4)? as u8;
+            if codebook == RESERVED_HCB {
+                bail!("reserved section codebook");
+            }
+            let mut len = 0;
+            loop {
+                let increment = reader.bits(section_bits)?;
+                len += increment as usize;
+                if increment != escape {
+                    break;
+                }
+            }
+            if bands.len() + len > info.max_sfb {
+                bail!("section exceeds the scalefactor bands");
+            }
+            bands.extend(std::iter::repeat_n(codebook, len));
+        }
+    }
+
+    // scalefactor data, differences to the previous scalefactor
+    let mut scalefactor = global_gain;
+    let mut range = (global_gain, global_gain);
+    let mut is_first_noise = true;
+    for codebook in codebooks.iter().flatten() {
+        match *codebook {
+            ZERO_HCB => {}
+            codebook if INTENSITY_HCBS.contains(&codebook) => {
+                reader.decode(&SCALEFACTOR_TREE)?;
+            }
+            NOISE_HCB if is_first_noise => {
+                is_first_noise = false;
+                reader.skip(9)?;
+            }
+            NOISE_HCB => {
+                reader.decode(&SCALEFACTOR_TREE)?;
+            }
+            _ => {
+                scalefactor += reader.decode(&SCALEFACTOR_TREE)? as i32 - 60;
+                range = (range.0.min(scalefactor), range.1.max(scalefactor));
+            }
+        }
+    }
+
+    // pulse data
+    if reader.flag()? {
+        if !is_long {
+            bail!("pulse data in short windows");
+        }
+        let count = reader.bits(2)? as usize + 1;
+        // start band, then offset and amplitude of each pulse
+        reader.skip(6 + count * 9)?;
+    }
+
+    // temporal noise shaping data
+    if reader.flag()? {
+        let windows: usize = info.groups.iter().sum();
+        for _ in 0..windows {
+            let filters = reader.bits(if is_long { 2 } else { 1 })?;
+            if filters == 0 {
+                continue;
+            }
+            let coef_res = reader.bits(1)? as usize;
+            for _ in 0..filters {
+                // length
+                reader.skip(if is_long { 6 } else { 4 })?;
+                let order = reader.bits(if is_long { 5 } else { 3 })? as usize;
+                if order > 0 {
+                    // direction
+                    reader.skip(1)?;
+                    let compress = reader.bits(1)? as usize;
+                    reader.skip(order * (3 + coef_res - compress))?;
+                }
+            }
+        }
+    }
+
+    if reader.flag()? {
+        bail!("gain control data of AAC SSR is not supported");
+    }
+
+    // spectral data
+    for (bands, windows) in codebooks.iter().zip(&info.groups) {
+        for (band, codebook) in bands.iter().enumerate() {
+            if !(1..=11).contains(codebook) {
+                continue;
+            }
+            let lines = usize::from(info.bands[band + 1] - info.bands[band]) * windows;
+            let values = if *codebook <= 4 { 4 } else { 2 };
+            for _ in 0..lines / values {
+                spectral_codeword(reader, *codebook)?;
+            }
+        }
+    }
+
+    Ok(GlobalGain {
+        pos,
+        value: global_gain,
+        range,
+    })
+}
+
+/// Skip a codeword of spectral data with its sign bits and escape sequences
+fn spectral_codeword(reader: &mut Reader, codebook: u8) -> Result<()> {
+    let index = reader.decode(&SPECTRUM_TREES[usize::from(codebook) - 1])?;
+
+    // values of unsigned codebooks, signed codebooks have no sign bits
+    let values = match codebook {
+        3 | 4 => vec![index / 27, index / 9 % 3, index / 3 % 3, index % 3],
+        7 | 8 => vec![index / 8, index % 8],
+        9 | 10 => vec![index / 13, index % 13],
+        11 => vec![index / 17, index % 17],
+        _ => return Ok(()),
+    };
+    reader.skip(values.iter().filter(|value| **value != 0).count())?;
+
+    if codebook == 11 {
+        for _ in values.iter().filter(|value| **value == ESCAPE) {
+            // escape prefix of N ones and a zero, then N + 4 bits
+            let mut count = 0;
+            while reader.flag()? {
+                count += 1;
+                if count > 8 {
+                    bail!("invalid escape sequence");
+                }
+            }
+            reader.skip(count + 4)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Skip a `program_config_element`
+fn program_config(reader: &mut Reader) -> Result<()> {
+    // element instance tag, object type and sampling frequency index
+    reader.skip(10)?;
+    let front = reader.bits(4)? as usize;
+    let side = reader.bits(4)? as usize;
+    let back = reader.bits(4)? as usize;
+    let lfe = reader.bits(2)? as usize;
+    let assoc_data = reader.bits(3)? as usize;
+    let valid_cc = reader.bits(4)? as usize;
+    // mono and stereo mixdown element numbers, matrix mixdown index and pseudo surround
+    for bits in [4, 4, 3] {
+        if reader.flag()? {
+            reader.skip(bits)?;
+        }
+    }
+    reader.skip((front + side + back) * 5 + lfe * 4 + assoc_data * 4 + valid_cc * 5)?;
+    reader.byte_align();
+    let comment = reader.bits(8)? as usize;
+    reader.skip(comment * 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::ops::Range;
+
+    /// 12 stereo frames of 44.1 kHz with all section codebooks, long and grouped short windows,
+    /// M/S, pulse, TNS, PCE, DSE and fill elements. Channels of 4 frames have scalefactors
+    /// of 136 to 163 while `global_gain` is at most 123, and the lowest scalefactor is 79.
+    fn sample(extension: &str) -> Vec<u8> {
+        let path = format!("test/12_frames.{extension}");
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap()
+    }
+
+    /// ADTS frame of 44.1 kHz mono AAC-LC, a single channel element without bands
+    fn frame(global_gain: u32) -> Vec<u8> {
+        let mut frame = vec![0u8; 11];
+        write_bits(&mut frame, 0, 12, 0xfff);
+        // protection absent, profile LC, 44.1 kHz, mono
+        write_bits(&mut frame, 15, 1, 1);
+        write_bits(&mut frame, 16, 2, OBJECT_TYPE_LC - 1);
+        write_bits(&mut frame, 18, 4, 4);
+        write_bits(&mut frame, 23, 3, 1);
+        write_bits(&mut frame, 30, 13, 11);
+        write_bits(&mut frame, 43, 11, 0x7ff);
+        // element id and instance tag of ID_SCE are 0, max_sfb is 0, no pulse, TNS or gain control
+        write_bits(&mut frame, 63, 8, global_gain);
+        write_bits(&mut frame, 85, 3, ID_END);
+        frame
+    }
+
+    fn apply_gain(data: &[u8], steps: i32) -> Result<(Vec<u8>, Summary)> {
+        let mut output = Vec::new();
+        let summary = apply_gain_adts(&mut Cursor::new(data), &mut output, steps, &[])?;
+        Ok((output, summary))
+    }
+
+    #[test]
+    fn apply_gain_adts_round_trip() {
+        let original = [frame(100), frame(120)].concat();
+
+        let (data, summary) = apply_gain(&original, 2).unwrap();
+        assert_eq!(summary.frames, 2);
+        assert_eq!(summary.clamped, 0);
+        assert_eq!(read_bits(&data, 63, 8), 102);
+        assert_eq!(read_bits(&data, 88 + 63, 8), 122);
+
+        let (data, _) = apply_gain(&data, -2).unwrap();
+        assert_eq!(data[..original.len()], original[..]);
+        let (tag, _) = ApeTag::read(&data, data.len()).unwrap().unwrap();
+        assert_eq!(tag.get(UNDO_KEY).as_deref(), Some("+000,+000,N"));
+    }
+
+    #[test]
+    fn apply_gain_adts_clamps_global_gain() {
+        let (data, summary) = apply_gain(&frame(250), 10).unwrap();
+        assert_eq!(summary.clamped, 1);
+        assert_eq!(read_bits(&data, 63, 8), 255);
+    }
+
+    #[test]
+    fn apply_gain_adts_sample_round_trip() {
+        let original = sample("aac");
+
+        let (data, summary) = apply_gain(&original, 5).unwrap();
+        assert_eq!(summary.frames, 12);
+        assert_eq!(summary.clamped, 0);
+        assert_eq!(summary.duration.as_millis(), 278);
+        assert_ne!(data[..original.len()], original[..]);
+
+        let (data, summary) = apply_gain(&data, -5).unwrap();
+        assert_eq!(summary.clamped, 0);
+        assert_eq!(data[..original.len()], original[..]);
+        let (tag, _) = ApeTag::read(&data, data.len()).unwrap().unwrap();
+        assert_eq!(tag.get(UNDO_KEY).as_deref(), Some("+000,+000,N"));
+    }
+
+    #[test]
+    fn apply_gain_adts_sample_clamps_scalefactors() {
+        let original = sample("aac");
+        assert_eq!(apply_gain(&original, 92).unwrap().1.clamped, 0);
+        assert_eq!(apply_gain(&original, 120).unwrap().1.clamped, 4);
+        assert_eq!(apply_gain(&original, -79).unwrap().1.clamped, 0);
+        assert_eq!(apply_gain(&original, -80).unwrap().1.clamped, 1);
+    }
+
+    /// Movie box and samples of an MP4 file
+    fn parse_mp4(data: &[u8]) -> (Atom, Vec<Range<usize>>) {
+        let (_, range, header) = mp4::boxes(data, 0..data.len())
+            .unwrap()
+            .into_iter()
+            .find(|(kind, _, _)| kind == b"moov")
+            .unwrap();
+        let moov = Atom::parse(data, range, header).unwrap();
+        let track = mp4::audio_tracks(&moov, data.len()).unwrap().remove(0);
+        (moov, track.samples)
+    }
+
+    #[test]
+    fn apply_gain_mp4_sample_round_trip() {
+        let original = sample("m4a");
+        let (moov, samples) = parse_mp4(&original);
+        assert_eq!(samples.len(), 12);
+        assert_eq!(mp4::tag(&moov, UNDO_KEY), None);
+
+        let tags = [("REPLAYGAIN_TRACK_GAIN".to_string(), "-7.50 dB".to_string())];
+        let (data, summary) = apply_gain_mp4(original.clone(), 5, &tags).unwrap();
+        assert_eq!(summary.frames, 12);
+        assert_eq!(summary.clamped, 0);
+
+        // the tags grow the movie box in front of the media data, the chunk offsets follow it
+        let (moov, moved) = parse_mp4(&data);
+        let delta = data.len() - original.len();
+        assert!(delta > 0);
+        assert!(moved
+            .iter()
+            .zip(&samples)
+            .all(|(moved, sample)| moved.start == sample.start + delta
+                && moved.len() == sample.len()));
+        assert_eq!(mp4::tag(&moov, UNDO_KEY).as_deref(), Some("+005,+005,N"));
+        assert_eq!(
+            mp4::tag(&moov, "replaygain_track_gain").as_deref(),
+            Some("-7.50 dB")
+        );
+
+        // the undo tag is replaced, the movie box keeps its size
+        let (data, summary) = apply_gain_mp4(data, -5, &[]).unwrap();
+        assert_eq!(summary.clamped, 0);
+        assert_eq!(data.len(), original.len() + delta);
+        let (moov, _) = parse_mp4(&data);
+        assert_eq!(mp4::tag(&moov, UNDO_KEY).as_deref(), Some("+000,+000,N"));
+        // the media data is the same as before
+        let mdat = |data: &[u8]| data[data.len() - (original.len() - samples[0].start)..].to_vec();
+        assert_eq!(mdat(&data), mdat(&original));
+    }
+
+    #[test]
+    fn apply_gain_mp4_sample_clamps_scalefactors() {
+        let (_, summary) = apply_gain_mp4(sample("m4a"), 120, &[]).unwrap();
+        assert_eq!(summary.clamped, 4);
+    }
+
+    #[test]
+    fn apply_gain_mp4_rejects_other_formats() {
+        let mut data = sample("m4a");
+        let pos = data.windows(4).position(|kind| kind == b"mp4a").unwrap();
+        data[pos..pos + 4].copy_from_slice(b"alac");
+        let err = apply_gain_mp4(data, 5, &[]).map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("only AAC"), "{err}");
+    }
+
+    #[test]
+    fn apply_gain_adts_rejects_crc() {
+        let mut data = frame(100);
+        write_bits(&mut data, 15, 1, 0);
+        assert!(apply_gain(&data, 2).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! Dialnorm of raw AC-3 and E-AC-3 bitstreams (ATSC A/52).
+
+use super::{crc16, read_bits, read_full, write_bits};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const SYNC_WORD: u32 = 0x0B77;
+
+/// Bytes of the sync info and the bit stream information up to dialnorm2
+const MIN_FRAME_SIZE: usize = 16;
+
+/// AC-3 bitrates in kbit/s by `frmsizecod / 2`
+const BIT_RATES: [u32; 19] = [
+    32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640,
+];
+
+/// Sample rates by `fscod`
+const SAMPLE_RATES: [u32; 3] = [48000, 44100, 32000];
+
+/// E-AC-3 sample rates by `fscod2` if `fscod` is 3
+const REDUCED_SAMPLE_RATES: [u32; 3] = [24000, 22050, 16000];
+
+/// E-AC-3 audio blocks per frame by `numblkscod`
+const BLOCKS: [u32; 4] = [1, 2, 3, 6];
+
+/// Samples per audio block
+const BLOCK_SAMPLES: u32 = 256;
+
+/// Result of a dialnorm rewrite
+pub struct Summary {
+    pub frames: usize,
+    /// Distinct dialnorm values before the rewrite in order of appearance
+    pub previous: Vec<i8>,
+    pub duration: Duration,
+}
+
+enum Syntax {
+    /// CRC1 covers the first 5/8 of the frame, which include the bit stream information
+    Ac3 { crc1_end: usize },
+    /// CRC2 covers the whole frame
+    Eac3,
+}
+
+struct Frame {
+    size: usize,
+    syntax: Syntax,
+    /// Bit positions of `dialnorm` and `dialnorm2` (dual mono)
+    dialnorm: Vec<usize>,
+    /// Duration of an independent frame
+    samples: Option<(u32, u32)>,
+}
+
+/// Set dialnorm of a raw AC-3 or E-AC-3 file, in dB from -31 to -1.
+pub fn rewrite_file(input: &Path, output: &Path, dialnorm: i8) -> Result<Summary> {
+    let mut reader = BufReader::new(
+        File::open(input)
+            .with_context(|| format!("Failed to read input file \"{}\"", input.display()))?,
+    );
+    let mut writer = BufWriter::new(
+        File::create(output)
+            .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?,
+    );
+
+    let summary = set_dialnorm(&mut reader, &mut writer, dialnorm)
+        .with_context(|| format!("Failed to rewrite dialnorm of \"{}\"", input.display()))?;
+
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?;
+
+    Ok(summary)
+}
+
+/// Copy a raw AC-3 or E-AC-3 bitstream from `input` to `output`, set dialnorm of all frames
+/// and recompute the CRCs. Only the frame in flight is held in memory.
+pub fn set_dialnorm(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    dialnorm: i8,
+) -> Result<Summary> {
+    if !(-31..=-1).contains(&dialnorm) {
+        bail!("dialnorm {dialnorm} is out of range -31 to -1");
+    }
+    let value = u32::from(dialnorm.unsigned_abs());
+
+    let mut summary = Summary {
+        frames: 0,
+        previous: Vec::new(),
+        duration: Duration::ZERO,
+    };
+    // x^-n multipliers by CRC1 length
+    let mut inverses = HashMap::new();
+    let mut offset = 0;
+    let mut frame_data = Vec::new();
+
+    loop {
+        frame_data.resize(MIN_FRAME_SIZE, 0);
+        let read = read_full(input, &mut frame_data)?;
+        if read == 0 {
+            break;
+        }
+        let frame = parse_frame(&frame_data[..read])
+            .with_context(|| format!("Invalid frame at byte offset {offset}"))?;
+        frame_data.resize(frame.size, 0);
+        if read_full(input, &mut frame_data[MIN_FRAME_SIZE..])? < frame.size - MIN_FRAME_SIZE {
+            bail!("Invalid frame at byte offset {offset}: truncated frame");
+        }
+
+        if !check_crc(&frame_data, &frame) {
+            bail!("CRC error in frame at byte offset {offset}, the file is corrupt");
+        }
+
+        frame.dialnorm.iter().for_each(|pos| {
+            // 0 is reserved and means -31 dB
+            let previous = match read_bits(&frame_data, *pos, 5) {
+                0 => -31,
+                previous => -(previous as i8),
+            };
+            if !summary.previous.contains(&previous) {
+                summary.previous.push(previous);
+            }
+            write_bits(&mut frame_data, *pos, 5, value);
+        });
+
+        match frame.syntax {
+            Syntax::Ac3 { crc1_end } => {
+                // CRC1 is stored in front of the data it covers
+                let inverse = *inverses
+                    .entry(crc1_end)
+                    .or_insert_with(|| inverse_power((crc1_end - 2) * 8));
+                let crc = multiply(crc16(0, &frame_data[4..crc1_end]), inverse);
+                frame_data[2..4].copy_from_slice(&crc.to_be_bytes());
+            }
+            Syntax::Eac3 => {
+                let end = frame.size - 2;
+                let crc = crc16(0, &frame_data[2..end]);
+                frame_data[end..].copy_from_slice(&crc.to_be_bytes());
+            }
+        }
+
+        if !check_crc(&frame_data, &frame) {
+            bail!("Failed to recompute CRC of frame at byte offset {offset}");
+        }
+        output.write_all(&frame_data)?;
+
+        if let Some((samples, sample_rate)) = frame.samples {
+            summary.duration +=
+                Duration::from_secs_f64(f64::from(samples) / f64::from(sample_rate));
+        }
+        summary.frames += 1;
+        offset += frame.size;
+    }
+
+    if summary.frames == 0 {
+        bail!("No AC-3 or E-AC-3 frames found");
+    }
+
+    Ok(summary)
+}
+
+/// Frame of the sync info and bit stream information at the start of `data`
+fn parse_frame(data: &[u8]) -> Result<Frame> {
+    if data.len() < MIN_FRAME_SIZE {
+        bail!("truncated frame");
+    }
+    if read_bits(data, 0, 16) != SYNC_WORD {
+        bail!("no sync word");
+    }
+
+    let bsid = read_bits(data, 40, 5);
+    let frame = match bsid {
+        0..=10 => parse_ac3(data, bsid)?,
+        11..=16 => parse_eac3(data)?,
+        _ => bail!("unsupported bitstream id {bsid}"),
+    };
+
+    if frame.size < MIN_FRAME_SIZE {
+        bail!("invalid frame size {}", frame.size);
+    }
+
+    Ok(frame)
+}
+
+fn parse_ac3(data: &[u8], bsid: u32) -> Result<Frame> {
+    let fscod = read_bits(data, 32, 2) as usize;
+    let frmsizecod = read_bits(data, 34, 6) as usize;
+
+    let sample_rate = *SAMPLE_RATES
+        .get(fscod)
+        .ok_or_else(|| anyhow!("reserved sample rate code"))?;
+    let bit_rate = *BIT_RATES
+        .get(frmsizecod / 2)
+        .ok_or_else(|| anyhow!("invalid frame size code {frmsizecod}"))?;
+
+    // 16 bit words per frame
+    let words = match fscod {
+        0 => bit_rate * 2,
+        1 => bit_rate * 320 / 147 + (frmsizecod as u32 & 1),
+        _ => bit_rate * 3,
+    } as usize;
+    let size = words * 2;
+
+    let acmod = read_bits(data, 48, 3);
+    let mut pos = 51;
+    // cmixlev
+    if acmod & 1 != 0 && acmod != 1 {
+        pos += 2;
+    }
+    // surmixlev
+    if acmod & 4 != 0 {
+        pos += 2;
+    }
+    // dsurmod
+    if acmod == 2 {
+        pos += 2;
+    }
+    // lfeon
+    pos += 1;
+
+    let mut dialnorm = vec![pos];
+    pos += 5;
+    if acmod == 0 {
+        // compr, langcod, mixlevel and roomtyp
+        for (flag, len) in [(1, 8), (1, 8), (1, 7)] {
+            let present = read_bits(data, pos, flag) != 0;
+            pos += flag + if present { len } else { 0 };
+        }
+        dialnorm.push(pos);
+    }
+
+    Ok(Frame {
+        size,
+        syntax: Syntax::Ac3 {
+            crc1_end: (words / 2 + words / 8) * 2,
+        },
+        dialnorm,
+        // bsid 9 and 10 are half and quarter sample rate
+        samples: Some((6 * BLOCK_SAMPLES, sample_rate >> bsid.saturating_sub(8))),
+    })
+}
+
+fn parse_eac3(data: &[u8]) -> Result<Frame> {
+    let strmtyp = read_bits(data, 16, 2);
+    let frmsiz = read_bits(data, 21, 11) as usize;
+    let fscod = read_bits(data, 32, 2) as usize;
+
+    let (blocks, sample_rate) = if fscod == 3 {
+        let fscod2 = read_bits(data, 34, 2) as usize;
+        let sample_rate = *REDUCED_SAMPLE_RATES
+            .get(fscod2)
+            .ok_or_else(|| anyhow!("reserved sample rate code"))?;
+        (6, sample_rate)
+    } else {
+        (BLOCKS[read_bits(data, 34, 2) as usize], SAMPLE_RATES[fscod])
+    };
+
+    let acmod = read_bits(data, 36, 3);
+    let mut dialnorm = vec![45];
+    if acmod == 0 {
+        // compr
+        let compre = read_bits(data, 50, 1) != 0;
+        dialnorm.push(if compre { 59 } else { 51 });
+    }
+
+    Ok(Frame {
+        size: (frmsiz + 1) * 2,
+        syntax: Syntax::Eac3,
+        dialnorm,
+        // dependent substreams extend the independent one
+        samples: (strmtyp != 1).then_some((blocks * BLOCK_SAMPLES, sample_rate)),
+    })
+}
+
+fn check_crc(data: &[u8], frame: &Frame) -> bool {
+    match frame.syntax {
+        Syntax::Ac3 { crc1_end } => crc16(0, &data[2..crc1_end]) == 0 && crc16(0, &data[2..]) == 0,
+        Syntax::Eac3 => crc16(0, &data[2..]) == 0,
+    }
+}
+
+/// Product of polynomials modulo the CRC polynomial
+fn multiply(a: u16, b: u16) -> u16 {
+    (0..16).rev().fold(0u16, |product, bit| {
+        let product = if product & 0x8000 != 0 {
+            (product << 1) ^ 0x8005
+        } else {
+            product << 1
+        };
+        if (b >> bit) & 1 != 0 {
+            product ^ a
+        } else {
+            product
+        }
+    })
+}
+
+/// x^-n modulo the CRC polynomial. CRC1 followed by m data bits has a remainder of 0
+/// if it is the CRC of the data multiplied by x^-(m + 16).
+fn inverse_power(n: usize) -> u16 {
+    (0..n).fold(1u32, |value, _| {
+        // divide by x, the polynomial has a constant term
+        let value = if value & 1 != 0 {
+            value ^ 0x18005
+        } else {
+            value
+        };
+        value >> 1
+    }) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn sample() -> Vec<u8> {
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("test/10_seconds.ac3")).unwrap()
+    }
+
+    fn rewrite(data: &[u8], dialnorm: i8) -> Result<(Vec<u8>, Summary)> {
+        let mut output = Vec::new();
+        let summary = set_dialnorm(&mut Cursor::new(data), &mut output, dialnorm)?;
+        Ok((output, summary))
+    }
+
+    #[test]
+    fn set_dialnorm_round_trip() {
+        let original = sample();
+
+        let (data, first) = rewrite(&original, -20).unwrap();
+        assert!(first.frames > 0);
+        assert!((first.duration.as_secs_f64() - 10.0).abs() < 0.1);
+        assert_eq!(data.len(), original.len());
+
+        let (data, second) = rewrite(&data, first.previous[0]).unwrap();
+        assert_eq!(second.frames, first.frames);
+        assert_eq!(second.previous, vec![-20]);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn set_dialnorm_rejects_out_of_range() {
+        let data = sample();
+        assert!(rewrite(&data, 0).is_err());
+        assert!(rewrite(&data, -32).is_err());
+    }
+
+    #[test]
+    fn truncated_frames() {
+        // frmsiz 0 is a frame of 2 bytes, shorter than its own bit stream information
+        let mut data = [0u8; 32];
+        data[..6].copy_from_slice(&[0x0b, 0x77, 0x00, 0x00, 0x30, 0x80]);
+        assert!(rewrite(&data, -20).is_err());
+
+        // the last frame is cut
+        let mut data = sample();
+        data.truncate(data.len() - 100);
+        let err = rewrite(&data, -20).map(|_| ()).unwrap_err();
+        assert!(format!("{err:#}").contains("truncated frame"), "{err:#}");
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(rewrite(&[], -20).is_err());
+    }
+}
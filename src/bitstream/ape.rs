@@ -0,0 +1,167 @@
+//! APEv2 tags as written by mp3gain at the end of MP3 files.
+
+use anyhow::{bail, Result};
+use std::io::{Read, Seek, SeekFrom};
+
+const PREAMBLE: &[u8] = b"APETAGEX";
+const VERSION: u32 = 2000;
+const HEADER_SIZE: usize = 32;
+
+/// The tag has a header in front of the items
+const FLAG_HAS_HEADER: u32 = 1 << 31;
+/// Set in the header, not in the footer
+const FLAG_IS_HEADER: u32 = 1 << 29;
+
+pub struct Item {
+    pub key: String,
+    pub flags: u32,
+    pub value: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct ApeTag {
+    pub items: Vec<Item>,
+}
+
+impl ApeTag {
+    /// Find a tag whose footer ends at `end`. Returns the tag and its start offset.
+    pub fn read(data: &[u8], end: usize) -> Result<Option<(ApeTag, usize)>> {
+        if end < HEADER_SIZE || &data[end - HEADER_SIZE..end - HEADER_SIZE + 8] != PREAMBLE {
+            return Ok(None);
+        }
+
+        let footer = &data[end - HEADER_SIZE..end];
+        let size = u32_at(footer, 12) as usize;
+        let count = u32_at(footer, 16) as usize;
+        let flags = u32_at(footer, 20);
+
+        if size < HEADER_SIZE || size > end {
+            bail!("Invalid APEv2 tag size {size}");
+        }
+        let items_start = end - size;
+        let start = if flags & FLAG_HAS_HEADER != 0 {
+            items_start.saturating_sub(HEADER_SIZE)
+        } else {
+            items_start
+        };
+
+        let mut tag = ApeTag::default();
+        let mut pos = items_start;
+        let items_end = end - HEADER_SIZE;
+
+        for _ in 0..count {
+            if pos + 8 > items_end {
+                bail!("Truncated APEv2 tag item");
+            }
+            let len = u32_at(data, pos) as usize;
+            let flags = u32_at(data, pos + 4);
+            let key_end = data[pos + 8..items_end]
+                .iter()
+                .position(|byte| *byte == 0)
+                .map(|len| pos + 8 + len)
+                .filter(|key_end| key_end + 1 + len <= items_end);
+            let Some(key_end) = key_end else {
+                bail!("Truncated APEv2 tag item");
+            };
+
+            tag.items.push(Item {
+                key: String::from_utf8_lossy(&data[pos + 8..key_end]).to_string(),
+                flags,
+                value: data[key_end + 1..key_end + 1 + len].to_vec(),
+            });
+            pos = key_end + 1 + len;
+        }
+
+        Ok(Some((tag, start)))
+    }
+
+    /// Find a tag whose footer ends at `end` of a file. Only the tag is read.
+    /// Returns the tag and its start offset.
+    pub fn read_from(reader: &mut (impl Read + Seek), end: u64) -> Result<Option<(ApeTag, u64)>> {
+        if end < HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+
+        let mut footer = [0u8; HEADER_SIZE];
+        reader.seek(SeekFrom::Start(end - HEADER_SIZE as u64))?;
+        reader.read_exact(&mut footer)?;
+        if &footer[..8] != PREAMBLE {
+            return Ok(None);
+        }
+
+        // the tag with its header, limited to the file
+        let size = (u64::from(u32_at(&footer, 12)) + HEADER_SIZE as u64).min(end);
+        let mut data = vec![0u8; size as usize];
+        reader.seek(SeekFrom::Start(end - size))?;
+        reader.read_exact(&mut data)?;
+
+        Ok(ApeTag::read(&data, data.len())?.map(|(tag, start)| (tag, end - size + start as u64)))
+    }
+
+    /// Text value of an item, keys are case insensitive
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.items
+            .iter()
+            .find(|item| item.key.eq_ignore_ascii_case(key))
+            .map(|item| String::from_utf8_lossy(&item.value).to_string())
+    }
+
+    /// Set a text item, replacing an item of the same key
+    pub fn set(&mut self, key: &str, value: &str) {
+        let item = Item {
+            key: key.to_string(),
+            flags: 0,
+            value: value.as_bytes().to_vec(),
+        };
+        match self
+            .items
+            .iter_mut()
+            .find(|item| item.key.eq_ignore_ascii_case(key))
+        {
+            Some(existing) => *existing = item,
+            None => self.items.push(item),
+        }
+    }
+
+    /// Tag with header and footer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let items: Vec<u8> = self
+            .items
+            .iter()
+            .flat_map(|item| {
+                [
+                    &(item.value.len() as u32).to_le_bytes()[..],
+                    &item.flags.to_le_bytes(),
+                    item.key.as_bytes(),
+                    &[0],
+                    &item.value,
+                ]
+                .concat()
+            })
+            .collect();
+
+        let size = (items.len() + HEADER_SIZE) as u32;
+        let header = |flags: u32| {
+            [
+                PREAMBLE,
+                &VERSION.to_le_bytes(),
+                &size.to_le_bytes(),
+                &(self.items.len() as u32).to_le_bytes(),
+                &flags.to_le_bytes(),
+                &[0; 8],
+            ]
+            .concat()
+        };
+
+        [
+            header(FLAG_HAS_HEADER | FLAG_IS_HEADER),
+            items,
+            header(FLAG_HAS_HEADER),
+        ]
+        .concat()
+    }
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
@@ -0,0 +1,313 @@
+//! Loudness metadata of the broadcast extension chunk of BWF files (EBU Tech 3285 v2).
+
+use super::riff;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const CHUNK_ID: &[u8] = b"bext";
+
+/// Size of the fixed fields up to the coding history
+const FIXED_SIZE: usize = 602;
+const VERSION: usize = 346;
+/// Offset of `LoudnessValue`, followed by `LoudnessRange`, `MaxTruePeakLevel`,
+/// `MaxMomentaryLoudness` and `MaxShortTermLoudness`
+const LOUDNESS: usize = 412;
+/// Version that added the loudness fields
+const LOUDNESS_VERSION: u16 = 2;
+/// Size of the blocks the chunks behind a new or larger bext chunk are moved in
+const MOVE_BLOCK: usize = 1 << 16;
+/// Value of a loudness field that is not set
+const UNSET: i16 = 0x7fff;
+/// Field of a coding history line with free text, which holds the tags
+const TEXT_FIELD: &str = "T=";
+/// Separator of the tags in the coding history line
+const TAG_SEPARATOR: &str = "; ";
+
+/// Loudness fields in LUFS, LU and dBTP, `None` if not measured
+pub struct Loudness {
+    pub value: Option<f64>,
+    pub range: Option<f64>,
+    pub max_true_peak: Option<f64>,
+    pub max_momentary: Option<f64>,
+    pub max_short_term: Option<f64>,
+}
+
+/// Set the loudness fields of the bext chunk of a WAV file. The other fields are taken
+/// from the bext chunk of the file, or of `source` if the file has none.
+/// The file is changed in place, only the chunks behind a new or larger bext chunk are moved.
+pub fn write_file(file: &Path, source: Option<&Path>, loudness: &Loudness) -> Result<()> {
+    let mut data = File::options()
+        .read(true)
+        .write(true)
+        .open(file)
+        .with_context(|| format!("Failed to open file \"{}\"", file.display()))?;
+
+    let fields = match find(&mut data)? {
+        Some(fields) => Some(fields),
+        // a source that is not a WAV file has no bext chunk
+        None => source
+            .and_then(|source| File::open(source).ok())
+            .and_then(|mut source| find(&mut source).ok().flatten()),
+    };
+
+    set_loudness(&mut data, fields, loudness)
+        .with_context(|| format!("Failed to write bext chunk to \"{}\"", file.display()))
+}
+
+/// Data of the bext chunk
+fn find(data: &mut (impl Read + Seek)) -> Result<Option<Vec<u8>>> {
+    riff::chunks(data)?
+        .into_iter()
+        .find(|chunk| chunk.id == CHUNK_ID)
+        .map(|chunk| {
+            let mut fields = vec![0; chunk.data.len()];
+            data.seek(SeekFrom::Start(chunk.data.start as u64))?;
+            data.read_exact(&mut fields)?;
+            Ok(fields)
+        })
+        .transpose()
+}
+
+/// Replace the bext chunk, or insert it in front of the fmt chunk
+fn set_loudness(
+    data: &mut (impl Read + Write + Seek),
+    fields: Option<Vec<u8>>,
+    loudness: &Loudness,
+) -> Result<()> {
+    let chunks = riff::chunks(data)?;
+
+    let mut fields = fields.unwrap_or_default();
+    if fields.len() < FIXED_SIZE {
+        fields.resize(FIXED_SIZE, 0);
+    }
+
+    let version = u16::from_le_bytes([fields[VERSION], fields[VERSION + 1]]);
+    fields[VERSION..VERSION + 2].copy_from_slice(&version.max(LOUDNESS_VERSION).to_le_bytes());
+    [
+        loudness.value,
+        loudness.range,
+        loudness.max_true_peak,
+        loudness.max_momentary,
+        loudness.max_short_term,
+    ]
+    .iter()
+    .enumerate()
+    .for_each(|(i, value)| {
+        let value = value
+            .filter(|value| value.is_finite())
+            .map_or(UNSET, |value| {
+                (value * 100.0).round().clamp(-32768.0, 32766.0) as i16
+            });
+        fields[LOUDNESS + i * 2..LOUDNESS + i * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    });
+
+    let chunk = riff::chunk(CHUNK_ID, &fields);
+
+    let (start, end) = match chunks.iter().find(|chunk| chunk.id == CHUNK_ID) {
+        Some(existing) => (existing.start, existing.end),
+        None => match chunks.iter().find(|chunk| &chunk.id == b"fmt ") {
+            Some(fmt) => (fmt.start, fmt.start),
+            None => bail!("No fmt chunk"),
+        },
+    };
+
+    // the fields are never shorter than the ones of the existing chunk
+    make_room(data, end as u64, (start + chunk.len() - end) as u64)?;
+    data.seek(SeekFrom::Start(start as u64))?;
+    data.write_all(&chunk)?;
+
+    let riff_size = (data.seek(SeekFrom::End(0))? - 8) as u32;
+    data.seek(SeekFrom::Start(4))?;
+    data.write_all(&riff_size.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Move the data from `pos` to the end of the file `growth` bytes back. It is moved in blocks
+/// from the end, so no block is overwritten before it is moved.
+fn make_room(data: &mut (impl Read + Write + Seek), pos: u64, growth: u64) -> io::Result<()> {
+    if growth == 0 {
+        return Ok(());
+    }
+
+    let mut buffer = vec![0u8; MOVE_BLOCK];
+    let mut end = data.seek(SeekFrom::End(0))?;
+    while end > pos {
+        let size = (end - pos).min(MOVE_BLOCK as u64) as usize;
+        end -= size as u64;
+        data.seek(SeekFrom::Start(end))?;
+        data.read_exact(&mut buffer[..size])?;
+        data.seek(SeekFrom::Start(end + growth))?;
+        data.write_all(&buffer[..size])?;
+    }
+
+    Ok(())
+}
+
+/// Coding history line of `tags`, e.g. "T=NORMALIZER=ffmpeg-audio-normalizer 1.2.0; NORMALIZER_ALGORITHM=ebu"
+/// with CR LF as in EBU R 98
+pub fn coding_history(tags: &[(String, String)]) -> String {
+    let tags: Vec<String> = tags
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    format!("{TEXT_FIELD}{}\r\n", tags.join(TAG_SEPARATOR))
+}
+
+/// Tags of the last coding history line whose first tag is `key`
+pub fn history_tags(history: &str, key: &str) -> Vec<(String, String)> {
+    history
+        .lines()
+        .rev()
+        .filter_map(|line| line.trim_end_matches('\r').strip_prefix(TEXT_FIELD))
+        .find(|text| text.starts_with(&format!("{key}=")))
+        .map(|text| {
+            text.split(TAG_SEPARATOR)
+                .filter_map(|tag| tag.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Set the coding history line of `tags` in the fields of a bext chunk, replacing an earlier
+/// line with the same first tag
+pub fn set_coding_history(fields: &mut Vec<u8>, tags: &[(String, String)]) {
+    if fields.len() < FIXED_SIZE {
+        fields.resize(FIXED_SIZE, 0);
+    }
+    if tags.is_empty() {
+        return;
+    }
+
+    let history = add_coding_history(&String::from_utf8_lossy(&fields[FIXED_SIZE..]), tags);
+
+    fields.truncate(FIXED_SIZE);
+    fields.extend(history.as_bytes());
+}
+
+/// Coding history `history` followed by the line of `tags`, replacing an earlier line with the
+/// same first tag
+pub fn add_coding_history(history: &str, tags: &[(String, String)]) -> String {
+    let Some((key, _)) = tags.first() else {
+        return history.to_string();
+    };
+
+    let prefix = format!("{TEXT_FIELD}{key}=");
+    let mut history: String = history
+        .trim_end_matches('\0')
+        .split_inclusive('\n')
+        .filter(|line| !line.starts_with(&prefix))
+        .collect();
+    // a history without a line end, e.g. of a tag
+    if !history.is_empty() && !history.ends_with('\n') {
+        history += "\r\n";
+    }
+    history + &coding_history(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(gain: &str) -> Vec<(String, String)> {
+        [
+            ("NORMALIZER", "ffmpeg-audio-normalizer"),
+            (
+                "NORMALIZER_INPUT",
+                "input_i=-18.20 LUFS, input_tp=-0.50 dBTP",
+            ),
+            ("NORMALIZER_GAIN", gain),
+        ]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn coding_history_round_trip() {
+        let mut fields = vec![0; FIXED_SIZE];
+        fields.extend(b"A=PCM,F=48000,W=24,M=stereo\r\n\0\0");
+
+        set_coding_history(&mut fields, &tags("-3.00 dB"));
+        set_coding_history(&mut fields, &tags("-1.00 dB"));
+
+        let history = String::from_utf8_lossy(&fields[FIXED_SIZE..]);
+        assert!(history.starts_with("A=PCM,F=48000,W=24,M=stereo\r\nT=NORMALIZER="));
+        assert_eq!(history.lines().count(), 2);
+        assert_eq!(history_tags(&history, "NORMALIZER"), tags("-1.00 dB"));
+
+        // a history without a line end, e.g. of a tag
+        let history = add_coding_history("A=PCM,F=48000", &tags("-3.00 dB"));
+        assert!(
+            history.starts_with("A=PCM,F=48000\r\nT=NORMALIZER="),
+            "{history}"
+        );
+        assert_eq!(add_coding_history("A=PCM\r\n", &[]), "A=PCM\r\n");
+        assert!(history_tags(&history, "OTHER").is_empty());
+    }
+
+    /// WAV file of 16 bit stereo with `samples` bytes of sample data
+    fn wav(samples: &[u8]) -> Vec<u8> {
+        let fmt = [
+            &1u16.to_le_bytes()[..],
+            &2u16.to_le_bytes(),
+            &48000u32.to_le_bytes(),
+            &192000u32.to_le_bytes(),
+            &4u16.to_le_bytes(),
+            &16u16.to_le_bytes(),
+        ]
+        .concat();
+        let chunks = [riff::chunk(b"fmt ", &fmt), riff::chunk(b"data", samples)].concat();
+        [
+            &b"RIFF"[..],
+            &(chunks.len() as u32 + 4).to_le_bytes(),
+            b"WAVE",
+            &chunks,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn write_file_inserts_and_updates_chunk() {
+        let samples: Vec<u8> = (0..3 * MOVE_BLOCK + 100).map(|i| (i % 251) as u8).collect();
+        let file = std::env::temp_dir().join(format!("bext-{}.wav", std::process::id()));
+        std::fs::write(&file, wav(&samples)).unwrap();
+        let loudness = |value| Loudness {
+            value: Some(value),
+            range: Some(5.5),
+            max_true_peak: None,
+            max_momentary: None,
+            max_short_term: None,
+        };
+
+        write_file(&file, None, &loudness(-23.0)).unwrap();
+        let size = std::fs::metadata(&file).unwrap().len();
+        write_file(&file, None, &loudness(-16.0)).unwrap();
+        let data = std::fs::read(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(data.len() as u64, size);
+        assert_eq!(riff::u32_at(&data, 4) as usize, data.len() - 8);
+        let chunks = riff::chunks(&mut io::Cursor::new(&data)).unwrap();
+        let ids: Vec<&[u8]> = chunks.iter().map(|chunk| &chunk.id[..]).collect();
+        assert_eq!(ids, [CHUNK_ID, b"fmt ", b"data"]);
+        assert_eq!(data[chunks[2].data.clone()], samples[..]);
+        let fields = &data[chunks[0].data.clone()];
+        assert_eq!(fields.len(), FIXED_SIZE);
+        assert_eq!(
+            i16::from_le_bytes([fields[LOUDNESS], fields[LOUDNESS + 1]]),
+            -1600
+        );
+        assert_eq!(
+            i16::from_le_bytes([fields[LOUDNESS + 2], fields[LOUDNESS + 3]]),
+            550
+        );
+        assert_eq!(
+            i16::from_le_bytes([fields[LOUDNESS + 4], fields[LOUDNESS + 5]]),
+            UNSET
+        );
+    }
+}
@@ -0,0 +1,1237 @@
+//! Gain of FLAC files by decoding and encoding the frames. All metadata blocks are kept,
+//! the seek table is updated to the new frame offsets and tags are added to the comments.
+//! The encoder only uses the fixed predictors, not LPC, so the output is usually larger
+//! than an input of an LPC encoder such as libFLAC.
+
+use super::gain::{Processor, Quantizer, Settings, Summary};
+use super::md5::Md5;
+use super::vorbis::Comments;
+use super::{crc16, id3v2_tag_size, read_full};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{crate_name, crate_version};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const MAGIC: &[u8] = b"fLaC";
+
+const BLOCK_STREAMINFO: u8 = 0;
+const BLOCK_SEEKTABLE: u8 = 3;
+const BLOCK_VORBIS_COMMENT: u8 = 4;
+/// Seek point without a target
+const PLACEHOLDER: u64 = u64::MAX;
+
+/// Bits per sample by sample size code of the frame header, 0 for STREAMINFO or reserved
+const SAMPLE_SIZES: [u32; 8] = [0, 8, 12, 0, 16, 20, 24, 32];
+
+/// Sample rates by sample rate code 1 to 11 of the frame header
+const SAMPLE_RATES: [u32; 11] = [
+    88200, 176400, 192000, 8000, 16000, 22050, 24000, 32000, 44100, 48000, 96000,
+];
+
+/// Channel assignments with a side channel
+const LEFT_SIDE: u8 = 8;
+const SIDE_RIGHT: u8 = 9;
+const MID_SIDE: u8 = 10;
+
+/// Coefficients of the fixed predictors by order
+const FIXED_COEFFICIENTS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+/// Highest partition order tried by the encoder
+const MAX_PARTITION_ORDER: u32 = 8;
+
+/// Bytes read ahead to decode a frame, more if a frame is larger
+const READ_AHEAD: usize = 1 << 16;
+
+struct MetadataBlock {
+    kind: u8,
+    data: Vec<u8>,
+}
+
+struct StreamInfo {
+    sample_rate: u32,
+    channels: usize,
+    bits: u32,
+}
+
+struct Frame {
+    /// First sample of the frame
+    sample: u64,
+    block_size: usize,
+    /// The header codes the sample number instead of the frame number
+    variable: bool,
+}
+
+/// Input of the frames, which have no size field and are decoded to find their end
+struct Input<'a, R: Read> {
+    reader: &'a mut R,
+    buffer: Vec<u8>,
+    /// Position in `buffer`
+    pos: usize,
+    /// Bytes dropped from the start of `buffer`
+    offset: u64,
+    eof: bool,
+}
+
+/// Encoder of the frames with the block sizes of the input
+struct Encoder<'a> {
+    info: &'a StreamInfo,
+    quantizer: Quantizer,
+    /// Quantized samples of each channel that are not encoded yet
+    samples: Vec<Vec<i32>>,
+    /// Number of encoded frames
+    count: usize,
+    /// Byte offset of each encoded frame from the first frame
+    offsets: Vec<u64>,
+    size: u64,
+    /// Smallest and largest frame size
+    frame_sizes: (u32, u32),
+    /// Signature of the encoded samples
+    md5: Md5,
+}
+
+/// Apply gain to the samples of a FLAC file and add `tags` to its comments.
+pub fn rewrite_file(
+    input: &Path,
+    output: &Path,
+    settings: &Settings,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let mut reader = BufReader::new(
+        File::open(input)
+            .with_context(|| format!("Failed to read input file \"{}\"", input.display()))?,
+    );
+    let mut writer = BufWriter::new(
+        File::create(output)
+            .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?,
+    );
+
+    let summary = apply_gain(&mut reader, &mut writer, settings, tags)
+        .with_context(|| format!("Failed to change gain of \"{}\"", input.display()))?;
+
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?;
+
+    Ok(summary)
+}
+
+/// Copy the FLAC file from `input` to `output` frame by frame, apply gain to its samples
+/// and add `tags` to its comments. The metadata blocks are written again at the end.
+pub fn apply_gain(
+    input: &mut impl Read,
+    output: &mut (impl Write + Seek),
+    settings: &Settings,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let mut input = Input::new(input);
+
+    let size = id3v2_tag_size(input.fill(10)?);
+    let id3v2 = input.fill(size)?;
+    let size = size.min(id3v2.len());
+    output.write_all(&id3v2[..size])?;
+    input.consume(size);
+
+    if !input.fill(MAGIC.len())?.starts_with(MAGIC) {
+        bail!("No FLAC stream, FLAC in Ogg is not supported");
+    }
+    input.consume(MAGIC.len());
+
+    let mut blocks = Vec::new();
+    loop {
+        let header = input.fill(4)?;
+        if header.len() < 4 {
+            bail!("Truncated metadata block");
+        }
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7f;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let block = input
+            .fill(4 + size)?
+            .get(4..4 + size)
+            .ok_or_else(|| anyhow!("Truncated metadata block"))?;
+        blocks.push(MetadataBlock {
+            kind,
+            data: block.to_vec(),
+        });
+        input.consume(4 + size);
+        if last {
+            break;
+        }
+    }
+
+    let (info, max_frame_size) = match blocks.first() {
+        Some(block) if block.kind == BLOCK_STREAMINFO && block.data.len() >= 34 => (
+            StreamInfo {
+                sample_rate: (u32::from_be_bytes(block.data[10..14].try_into()?) >> 12),
+                channels: usize::from((block.data[12] >> 1) & 0x07) + 1,
+                bits: u32::from(((block.data[12] & 0x01) << 4) | (block.data[13] >> 4)) + 1,
+            },
+            u32::from_be_bytes([0, block.data[7], block.data[8], block.data[9]]) as usize,
+        ),
+        _ => bail!("No STREAMINFO metadata block"),
+    };
+    if info.sample_rate == 0 {
+        bail!("Invalid sample rate in STREAMINFO");
+    }
+
+    if !tags.is_empty() {
+        set_tags(&mut blocks, tags).with_context(|| "Invalid VORBIS_COMMENT metadata block")?;
+    }
+
+    // the frame sizes, seek points and signature are updated when all frames are encoded
+    let metadata = output.stream_position()?;
+    write_metadata(output, &blocks)?;
+
+    let scale = 2f64.powi(info.bits as i32 - 1);
+    let mut processor = Processor::new(settings, info.channels, info.sample_rate);
+    let mut encoder = Encoder::new(&info, settings.dither);
+    let mut decoded = vec![Vec::new(); info.channels];
+    let mut values = Vec::new();
+    let mut processed = Vec::new();
+    let mut frames = Vec::new();
+    let mut sample = 0;
+
+    loop {
+        let pos = input.position();
+        let mut read_ahead = READ_AHEAD.max(max_frame_size);
+        let frame = loop {
+            input.fill(read_ahead)?;
+            let (data, eof) = (input.available(), input.eof);
+            // an ID3v1 tag at the end
+            if data.is_empty() || (eof && data.len() == 128 && data.starts_with(b"TAG")) {
+                break None;
+            }
+            decoded.iter_mut().for_each(Vec::clear);
+            match decode_frame(data, &info, &mut decoded) {
+                Ok(frame) => break Some(frame),
+                // the frame may be larger than the data read ahead
+                Err(_) if !eof && (max_frame_size == 0 || data.len() < max_frame_size) => {
+                    read_ahead = data.len() * 2;
+                }
+                Err(err) => return Err(err.context(format!("Invalid frame at byte offset {pos}"))),
+            }
+        };
+        let Some((block_size, variable, size)) = frame else {
+            break;
+        };
+        input.consume(size);
+        frames.push(Frame {
+            sample,
+            block_size,
+            variable,
+        });
+        sample += block_size as u64;
+
+        // apply the gain to interleaved samples
+        values.clear();
+        (0..block_size).for_each(|i| {
+            decoded
+                .iter()
+                .for_each(|samples| values.push(f64::from(samples[i]) / scale))
+        });
+        processed.clear();
+        processor.process(&values, &mut processed);
+        encoder.write(output, &processed, &frames)?;
+    }
+    if frames.is_empty() {
+        bail!("No FLAC frames found");
+    }
+
+    processed.clear();
+    processor.finish(&mut processed);
+    encoder.write(output, &processed, &frames)?;
+    output.write_all(input.fill(128)?)?;
+    let end = output.stream_position()?;
+
+    // update frame sizes, signature and seek points
+    for block in blocks.iter_mut() {
+        match block.kind {
+            BLOCK_STREAMINFO => {
+                let (min, max) = encoder.frame_sizes;
+                block.data[4..7].copy_from_slice(&min.to_be_bytes()[1..]);
+                block.data[7..10].copy_from_slice(&max.to_be_bytes()[1..]);
+            }
+            BLOCK_SEEKTABLE => block.data.chunks_exact_mut(18).for_each(|point| {
+                let target = u64::from_be_bytes(point[..8].try_into().unwrap());
+                if target == PLACEHOLDER {
+                    return;
+                }
+                let index = frames
+                    .partition_point(|frame| frame.sample <= target)
+                    .saturating_sub(1);
+                point[..8].copy_from_slice(&frames[index].sample.to_be_bytes());
+                point[8..16].copy_from_slice(&encoder.offsets[index].to_be_bytes());
+            }),
+            _ => {}
+        }
+    }
+    blocks[0].data[18..34].copy_from_slice(&encoder.md5.finish());
+
+    output.seek(SeekFrom::Start(metadata))?;
+    write_metadata(output, &blocks)?;
+    output.seek(SeekFrom::Start(end))?;
+
+    Ok(Summary {
+        samples: sample as usize * info.channels,
+        limited: processor.limited,
+        clipped: encoder.quantizer.clipped,
+        duration: Duration::from_secs_f64(sample as f64 / f64::from(info.sample_rate)),
+    })
+}
+
+fn write_metadata(output: &mut impl Write, blocks: &[MetadataBlock]) -> io::Result<()> {
+    output.write_all(MAGIC)?;
+    let last = blocks.len() - 1;
+    for (i, block) in blocks.iter().enumerate() {
+        output.write_all(&[u8::from(i == last) << 7 | block.kind])?;
+        output.write_all(&(block.data.len() as u32).to_be_bytes()[1..])?;
+        output.write_all(&block.data)?;
+    }
+    Ok(())
+}
+
+impl<'a, R: Read> Input<'a, R> {
+    fn new(reader: &'a mut R) -> Input<'a, R> {
+        Input {
+            reader,
+            buffer: Vec::new(),
+            pos: 0,
+            offset: 0,
+            eof: false,
+        }
+    }
+
+    /// At least `count` bytes from the current position, fewer at the end of the input
+    fn fill(&mut self, count: usize) -> Result<&[u8]> {
+        if self.buffer.len() - self.pos < count && !self.eof {
+            self.buffer.drain(..self.pos);
+            self.offset += self.pos as u64;
+            self.pos = 0;
+
+            // read twice the bytes to drop the consumed bytes less often
+            let available = self.buffer.len();
+            self.buffer.resize(available.max(2 * count), 0);
+            let read = read_full(self.reader, &mut self.buffer[available..])?;
+            self.eof = available + read < self.buffer.len();
+            self.buffer.truncate(available + read);
+        }
+        Ok(self.available())
+    }
+
+    /// Bytes from the current position that are read
+    fn available(&self) -> &[u8] {
+        &self.buffer[self.pos..]
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.pos += count;
+    }
+
+    /// Byte offset of the current position
+    fn position(&self) -> u64 {
+        self.offset + self.pos as u64
+    }
+}
+
+impl Encoder<'_> {
+    fn new(info: &StreamInfo, dither: bool) -> Encoder<'_> {
+        Encoder {
+            info,
+            quantizer: Quantizer::new(info.bits, dither),
+            samples: vec![Vec::new(); info.channels],
+            count: 0,
+            offsets: Vec::new(),
+            size: 0,
+            frame_sizes: (u32::MAX, 0),
+            md5: Md5::new(),
+        }
+    }
+
+    /// Quantize the interleaved `values` and encode the `frames` whose samples are complete
+    fn write(&mut self, output: &mut impl Write, values: &[f64], frames: &[Frame]) -> Result<()> {
+        values.chunks(self.info.channels).for_each(|frame| {
+            frame
+                .iter()
+                .zip(self.samples.iter_mut())
+                .for_each(|(value, samples)| samples.push(self.quantizer.quantize(*value)))
+        });
+
+        while let Some(frame) = frames
+            .get(self.count)
+            .filter(|frame| frame.block_size <= self.samples[0].len())
+        {
+            let samples: Vec<&[i32]> = self
+                .samples
+                .iter()
+                .map(|samples| &samples[..frame.block_size])
+                .collect();
+            let number = if frame.variable {
+                frame.sample
+            } else {
+                self.count as u64
+            };
+            let mut data = Vec::new();
+            encode_frame(&mut data, &samples, self.info, frame.variable, number);
+            output.write_all(&data)?;
+
+            // the signature is of interleaved little endian samples of whole bytes
+            let bytes = self.info.bits.div_ceil(8) as usize;
+            let mut signature = Vec::with_capacity(frame.block_size * samples.len() * bytes);
+            (0..frame.block_size).for_each(|i| {
+                samples
+                    .iter()
+                    .for_each(|samples| signature.extend(&samples[i].to_le_bytes()[..bytes]))
+            });
+            self.md5.update(&signature);
+
+            let size = data.len() as u32;
+            self.offsets.push(self.size);
+            self.size += u64::from(size);
+            self.frame_sizes = (self.frame_sizes.0.min(size), self.frame_sizes.1.max(size));
+            self.samples.iter_mut().for_each(|samples| {
+                samples.drain(..frame.block_size);
+            });
+            self.count += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Set `tags` in the comment block, which is added after STREAMINFO if there is none
+fn set_tags(blocks: &mut Vec<MetadataBlock>, tags: &[(String, String)]) -> Result<()> {
+    let index = match blocks
+        .iter()
+        .position(|block| block.kind == BLOCK_VORBIS_COMMENT)
+    {
+        Some(index) => index,
+        None => {
+            blocks.insert(
+                1,
+                MetadataBlock {
+                    kind: BLOCK_VORBIS_COMMENT,
+                    data: Comments::new(&format!("{} {}", crate_name!(), crate_version!()))
+                        .to_bytes(),
+                },
+            );
+            1
+        }
+    };
+
+    let (mut comments, _) = Comments::parse(&blocks[index].data)?;
+    tags.iter()
+        .for_each(|(key, value)| comments.set(key, value));
+    blocks[index].data = comments.to_bytes();
+
+    Ok(())
+}
+
+/// Decode a frame and append its samples to `channels`.
+/// Returns the block size, whether the stream has a variable block size and the frame size.
+fn decode_frame(
+    data: &[u8],
+    info: &StreamInfo,
+    channels: &mut [Vec<i32>],
+) -> Result<(usize, bool, usize)> {
+    let mut reader = BitReader::new(data);
+
+    if reader.read(15)? != 0x7ffc {
+        bail!("no frame sync code");
+    }
+    let variable = reader.read(1)? != 0;
+    let block_size_code = reader.read(4)?;
+    let sample_rate_code = reader.read(4)?;
+    let assignment = reader.read(4)? as u8;
+    let sample_size_code = reader.read(3)? as usize;
+    reader.read(1)?;
+
+    // coded frame or sample number
+    let first = reader.read(8)?;
+    let extra = (first as u8).leading_ones().saturating_sub(1);
+    reader.read(extra * 8)?;
+
+    let block_size = match block_size_code {
+        0 => bail!("reserved block size"),
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => reader.read(8)? as usize + 1,
+        7 => reader.read(16)? as usize + 1,
+        _ => 256 << (block_size_code - 8),
+    };
+    match sample_rate_code {
+        12 => {
+            reader.read(8)?;
+        }
+        13 | 14 => {
+            reader.read(16)?;
+        }
+        15 => bail!("invalid sample rate"),
+        _ => {}
+    }
+    let bits = match sample_size_code {
+        0 => info.bits,
+        3 => bail!("reserved sample size"),
+        code => SAMPLE_SIZES[code],
+    };
+    if bits != info.bits {
+        bail!("sample size changes from {} to {bits} bits", info.bits);
+    }
+
+    let header_size = reader.pos / 8;
+    if reader.read(8)? as u8 != crc8(&data[..header_size]) {
+        bail!("CRC error in frame header, the file is corrupt");
+    }
+
+    let count = match assignment {
+        0..=7 => usize::from(assignment) + 1,
+        LEFT_SIDE | SIDE_RIGHT | MID_SIDE => 2,
+        _ => bail!("reserved channel assignment"),
+    };
+    if count != info.channels {
+        bail!(
+            "number of channels changes from {} to {count}",
+            info.channels
+        );
+    }
+
+    let mut subframes = Vec::with_capacity(count);
+    for channel in 0..count {
+        // the side channel has one more bit
+        let side = matches!(
+            (assignment, channel),
+            (LEFT_SIDE, 1) | (SIDE_RIGHT, 0) | (MID_SIDE, 1)
+        );
+        subframes.push(decode_subframe(
+            &mut reader,
+            block_size,
+            bits + u32::from(side),
+        )?);
+    }
+
+    reader.align();
+    let size = reader.pos / 8 + 2;
+    if size > data.len() {
+        bail!("truncated frame");
+    }
+    if crc16(0, &data[..size]) != 0 {
+        bail!("CRC error, the file is corrupt");
+    }
+
+    match assignment {
+        LEFT_SIDE => {
+            let (left, side) = (&subframes[0], &subframes[1]);
+            channels[0].extend(left.iter().map(|left| *left as i32));
+            channels[1].extend(
+                left.iter()
+                    .zip(side)
+                    .map(|(left, side)| (left - side) as i32),
+            );
+        }
+        SIDE_RIGHT => {
+            let (side, right) = (&subframes[0], &subframes[1]);
+            channels[0].extend(
+                side.iter()
+                    .zip(right)
+                    .map(|(side, right)| (side + right) as i32),
+            );
+            channels[1].extend(right.iter().map(|right| *right as i32));
+        }
+        MID_SIDE => {
+            let (mid, side) = (&subframes[0], &subframes[1]);
+            mid.iter().zip(side).for_each(|(mid, side)| {
+                let mid = (mid << 1) | (side & 1);
+                channels[0].push(((mid + side) >> 1) as i32);
+                channels[1].push(((mid - side) >> 1) as i32);
+            });
+        }
+        _ => subframes
+            .iter()
+            .zip(channels.iter_mut())
+            .for_each(|(subframe, channel)| channel.extend(subframe.iter().map(|v| *v as i32))),
+    }
+
+    Ok((block_size, variable, size))
+}
+
+fn decode_subframe(reader: &mut BitReader, block_size: usize, bits: u32) -> Result<Vec<i64>> {
+    if reader.read(1)? != 0 {
+        bail!("invalid subframe padding");
+    }
+    let kind = reader.read(6)? as usize;
+    let wasted = if reader.read(1)? != 0 {
+        reader.unary()? + 1
+    } else {
+        0
+    };
+    let bits = bits
+        .checked_sub(wasted)
+        .ok_or_else(|| anyhow!("invalid wasted bits"))?;
+
+    let mut samples = match kind {
+        0 => vec![reader.signed(bits)?; block_size],
+        1 => (0..block_size)
+            .map(|_| reader.signed(bits))
+            .collect::<Result<_>>()?,
+        8..=12 => {
+            let order = kind - 8;
+            let mut samples = warm_up(reader, order, block_size, bits)?;
+            decode_residual(reader, block_size, order, &mut samples)?;
+            predict(&mut samples, order, FIXED_COEFFICIENTS[order], 0);
+            samples
+        }
+        32..=63 => {
+            let order = kind - 31;
+            let mut samples = warm_up(reader, order, block_size, bits)?;
+            let precision = reader.read(4)? as u32 + 1;
+            if precision == 16 {
+                bail!("invalid LPC precision");
+            }
+            let shift = reader.signed(5)?;
+            if shift < 0 {
+                bail!("negative LPC shift");
+            }
+            let coefficients = (0..order)
+                .map(|_| reader.signed(precision))
+                .collect::<Result<Vec<_>>>()?;
+            decode_residual(reader, block_size, order, &mut samples)?;
+            predict(&mut samples, order, &coefficients, shift as u32);
+            samples
+        }
+        _ => bail!("reserved subframe type {kind}"),
+    };
+
+    if wasted > 0 {
+        samples.iter_mut().for_each(|sample| *sample <<= wasted);
+    }
+
+    Ok(samples)
+}
+
+fn warm_up(reader: &mut BitReader, order: usize, block_size: usize, bits: u32) -> Result<Vec<i64>> {
+    if order > block_size {
+        bail!("predictor order {order} exceeds block size {block_size}");
+    }
+    let mut samples = Vec::with_capacity(block_size);
+    for _ in 0..order {
+        samples.push(reader.signed(bits)?);
+    }
+    Ok(samples)
+}
+
+/// Append the residual of a predicted subframe to `samples`
+fn decode_residual(
+    reader: &mut BitReader,
+    block_size: usize,
+    order: usize,
+    samples: &mut Vec<i64>,
+) -> Result<()> {
+    let (parameter_bits, escape) = match reader.read(2)? {
+        0 => (4, 0x0f),
+        1 => (5, 0x1f),
+        _ => bail!("reserved residual coding method"),
+    };
+    let partition_order = reader.read(4)?;
+    let partition_size = block_size >> partition_order;
+    if partition_size << partition_order != block_size || partition_size < order {
+        bail!("invalid partition order {partition_order}");
+    }
+
+    for partition in 0..1usize << partition_order {
+        let count = if partition == 0 {
+            partition_size - order
+        } else {
+            partition_size
+        };
+        let parameter = reader.read(parameter_bits)? as u32;
+        if parameter == escape {
+            let bits = reader.read(5)? as u32;
+            for _ in 0..count {
+                samples.push(reader.signed(bits)?);
+            }
+        } else {
+            for _ in 0..count {
+                let value = (u64::from(reader.unary()?) << parameter) | reader.read(parameter)?;
+                samples.push((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace the residual after the warm-up samples by the predicted samples
+fn predict(samples: &mut [i64], order: usize, coefficients: &[i64], shift: u32) {
+    for n in order..samples.len() {
+        let prediction = coefficients
+            .iter()
+            .enumerate()
+            .map(|(j, coefficient)| coefficient * samples[n - 1 - j])
+            .sum::<i64>();
+        samples[n] += prediction >> shift;
+    }
+}
+
+fn encode_frame(
+    output: &mut Vec<u8>,
+    channels: &[&[i32]],
+    info: &StreamInfo,
+    variable: bool,
+    number: u64,
+) {
+    let block_size = channels[0].len();
+    let mut writer = BitWriter::default();
+
+    writer.write(0x3ffe, 14);
+    writer.write(0, 1);
+    writer.write(u64::from(variable), 1);
+
+    let (block_size_code, block_size_bits) = match block_size {
+        192 => (1, 0),
+        576 | 1152 | 2304 | 4608 => (2 + (block_size / 576).trailing_zeros(), 0),
+        256 | 512 | 1024 | 2048 | 4096 | 8192 | 16384 | 32768 => {
+            (8 + (block_size / 256).trailing_zeros(), 0)
+        }
+        1..=256 => (6, 8),
+        _ => (7, 16),
+    };
+    writer.write(u64::from(block_size_code), 4);
+    let sample_rate_code = SAMPLE_RATES
+        .iter()
+        .position(|rate| *rate == info.sample_rate)
+        .map_or(0, |code| code + 1);
+    writer.write(sample_rate_code as u64, 4);
+    writer.write(info.channels as u64 - 1, 4);
+    let sample_size_code = SAMPLE_SIZES
+        .iter()
+        .position(|bits| *bits == info.bits)
+        .unwrap_or(0);
+    writer.write(sample_size_code as u64, 3);
+    writer.write(0, 1);
+
+    // frame or sample number coded like UTF-8
+    if number < 0x80 {
+        writer.write(number, 8);
+    } else {
+        let bytes = (64 - number.leading_zeros()).saturating_sub(2) / 5 + 1;
+        writer.write(
+            (0xff00u64 >> bytes) & 0xff | (number >> ((bytes - 1) * 6)),
+            8,
+        );
+        (0..bytes - 1).rev().for_each(|i| {
+            writer.write(0x80 | ((number >> (i * 6)) & 0x3f), 8);
+        });
+    }
+    if block_size_bits > 0 {
+        writer.write(block_size as u64 - 1, block_size_bits);
+    }
+    let crc = crc8(&writer.data);
+    writer.write(u64::from(crc), 8);
+
+    channels
+        .iter()
+        .for_each(|samples| encode_subframe(&mut writer, samples, info.bits));
+
+    writer.align();
+    let crc = crc16(0, &writer.data);
+    writer.write(u64::from(crc), 16);
+
+    output.extend(writer.data);
+}
+
+fn encode_subframe(writer: &mut BitWriter, samples: &[i32], bits: u32) {
+    let mask = (1u64 << bits) - 1;
+
+    if samples.iter().all(|sample| *sample == samples[0]) {
+        writer.write(0, 8);
+        writer.write(samples[0] as u64 & mask, bits);
+        return;
+    }
+
+    // fixed predictor with the smallest residual
+    let best = (0..=4.min(samples.len() - 1))
+        .filter_map(|order| {
+            let residual: Vec<i64> = (order..samples.len())
+                .map(|n| {
+                    i64::from(samples[n])
+                        - FIXED_COEFFICIENTS[order]
+                            .iter()
+                            .enumerate()
+                            .map(|(j, coefficient)| coefficient * i64::from(samples[n - 1 - j]))
+                            .sum::<i64>()
+                })
+                .collect();
+            // the residual must fit in 32 bits
+            residual
+                .iter()
+                .all(|value| i32::try_from(*value).is_ok())
+                .then_some((order, residual))
+        })
+        .min_by_key(|(_, residual)| {
+            residual
+                .iter()
+                .map(|value| value.unsigned_abs())
+                .sum::<u64>()
+        });
+
+    let rice = best.as_ref().map(|(order, residual)| {
+        let (method, partition_order, parameters, size) =
+            rice_parameters(residual, *order, samples.len());
+        (order, residual, method, partition_order, parameters, size)
+    });
+
+    match rice {
+        Some((order, residual, method, partition_order, parameters, size))
+            if size + order * (bits as usize) < samples.len() * (bits as usize) =>
+        {
+            writer.write((8 + *order as u64) << 1, 8);
+            samples[..*order]
+                .iter()
+                .for_each(|sample| writer.write(*sample as u64 & mask, bits));
+            writer.write(method, 2);
+            writer.write(u64::from(partition_order), 4);
+
+            let parameter_bits = if method == 0 { 4 } else { 5 };
+            let partition_size = samples.len() >> partition_order;
+            let mut values = residual.iter();
+            parameters
+                .iter()
+                .enumerate()
+                .for_each(|(partition, parameter)| {
+                    writer.write(u64::from(*parameter), parameter_bits);
+                    let count = partition_size - if partition == 0 { *order } else { 0 };
+                    values.by_ref().take(count).for_each(|value| {
+                        let value = zigzag(*value);
+                        writer.unary(value >> parameter);
+                        writer.write(value & ((1 << parameter) - 1), *parameter);
+                    });
+                });
+        }
+        _ => {
+            writer.write(1 << 1, 8);
+            samples
+                .iter()
+                .for_each(|sample| writer.write(*sample as u64 & mask, bits));
+        }
+    }
+}
+
+/// Coding method, partition order, Rice parameter of each partition and the size in bits
+/// of the residual with the smallest size
+fn rice_parameters(
+    residual: &[i64],
+    order: usize,
+    block_size: usize,
+) -> (u64, u32, Vec<u32>, usize) {
+    let values: Vec<u64> = residual.iter().map(|value| zigzag(*value)).collect();
+
+    (0..=MAX_PARTITION_ORDER)
+        .take_while(|partition_order| {
+            let size = block_size >> partition_order;
+            size << partition_order == block_size && size >= order && size > 0
+        })
+        .map(|partition_order| {
+            let partition_size = block_size >> partition_order;
+            let mut start = 0;
+            let parameters: Vec<(u32, usize)> = (0..1usize << partition_order)
+                .map(|partition| {
+                    let count = partition_size - if partition == 0 { order } else { 0 };
+                    let sum = values[start..start + count].iter().sum::<u64>();
+                    start += count;
+                    // estimated size of each parameter
+                    (0..=30u32)
+                        .map(|parameter| {
+                            (
+                                parameter,
+                                count * (parameter as usize + 1) + (sum >> parameter) as usize,
+                            )
+                        })
+                        .min_by_key(|(_, size)| *size)
+                        .unwrap()
+                })
+                .collect();
+
+            let method = u64::from(parameters.iter().any(|(parameter, _)| *parameter >= 15));
+            let parameter_bits = if method == 0 { 4 } else { 5 };
+            let size = 6 + parameters
+                .iter()
+                .map(|(_, size)| parameter_bits + size)
+                .sum::<usize>();
+            (
+                method,
+                partition_order,
+                parameters
+                    .into_iter()
+                    .map(|(parameter, _)| parameter)
+                    .collect(),
+                size,
+            )
+        })
+        .min_by_key(|(_, _, _, size)| *size)
+        .unwrap()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// CRC-8 with polynomial 0x07 of frame headers
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+
+    /// Read `count` bits MSB first, up to 57
+    fn read(&mut self, count: u32) -> Result<u64> {
+        let mut value = 0u64;
+        let mut remaining = count;
+        while remaining > 0 {
+            let byte = *self
+                .data
+                .get(self.pos / 8)
+                .ok_or_else(|| anyhow!("truncated frame"))?;
+            let available = 8 - (self.pos % 8) as u32;
+            let take = available.min(remaining);
+            let bits = (u64::from(byte) >> (available - take)) & ((1 << take) - 1);
+            value = (value << take) | bits;
+            self.pos += take as usize;
+            remaining -= take;
+        }
+        Ok(value)
+    }
+
+    /// Read a two's complement value of `count` bits
+    fn signed(&mut self, count: u32) -> Result<i64> {
+        if count == 0 {
+            return Ok(0);
+        }
+        let value = self.read(count)?;
+        Ok(((value << (64 - count)) as i64) >> (64 - count))
+    }
+
+    /// Count zero bits up to the next one bit
+    fn unary(&mut self) -> Result<u32> {
+        let mut count = 0;
+        loop {
+            let byte = *self
+                .data
+                .get(self.pos / 8)
+                .ok_or_else(|| anyhow!("truncated frame"))?;
+            let offset = (self.pos % 8) as u32;
+            let zeros = (byte << offset).leading_zeros().min(8 - offset);
+            count += zeros;
+            self.pos += zeros as usize;
+            if zeros < 8 - offset {
+                self.pos += 1;
+                return Ok(count);
+            }
+        }
+    }
+
+    fn align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    data: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Write the lowest `count` bits of `value` MSB first, up to 32
+    fn write(&mut self, value: u64, count: u32) {
+        if count == 0 {
+            return;
+        }
+        self.buffer = (self.buffer << count) | (value & ((1 << count) - 1));
+        self.bits += count;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.data.push((self.buffer >> self.bits) as u8);
+        }
+    }
+
+    /// Write `count` zero bits followed by a one bit
+    fn unary(&mut self, count: u64) {
+        (0..count / 32).for_each(|_| self.write(0, 32));
+        self.write(1, (count % 32) as u32 + 1);
+    }
+
+    /// Pad with zero bits to a byte boundary
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// Deterministic noise of `bits` bits
+    fn noise(count: usize, bits: u32, seed: u64) -> Vec<i32> {
+        let mut random = seed;
+        (0..count)
+            .map(|_| {
+                random ^= random << 13;
+                random ^= random >> 7;
+                random ^= random << 17;
+                ((random as i64) >> (64 - bits)) as i32
+            })
+            .collect()
+    }
+
+    fn sine(count: usize, amplitude: f64) -> Vec<i32> {
+        (0..count)
+            .map(|n| (amplitude * (n as f64 * 0.05).sin()).round() as i32)
+            .collect()
+    }
+
+    fn info(channels: usize, bits: u32) -> StreamInfo {
+        StreamInfo {
+            sample_rate: SAMPLE_RATE,
+            channels,
+            bits,
+        }
+    }
+
+    fn signature(channels: &[Vec<i32>], bits: u32) -> [u8; 16] {
+        let bytes = bits.div_ceil(8) as usize;
+        let mut md5 = Md5::new();
+        (0..channels[0].len()).for_each(|i| {
+            channels
+                .iter()
+                .for_each(|samples| md5.update(&samples[i].to_le_bytes()[..bytes]))
+        });
+        md5.finish()
+    }
+
+    /// FLAC stream of fixed size blocks with a seek point at each of `seek_points`
+    fn stream(channels: &[Vec<i32>], bits: u32, block_size: usize, seek_points: &[u64]) -> Vec<u8> {
+        let info = info(channels.len(), bits);
+        let total = channels[0].len();
+
+        let mut streaminfo = vec![0u8; 34];
+        streaminfo[..2].copy_from_slice(&(block_size as u16).to_be_bytes());
+        streaminfo[2..4].copy_from_slice(&(block_size as u16).to_be_bytes());
+        let fields = u64::from(SAMPLE_RATE) << 44
+            | (channels.len() as u64 - 1) << 41
+            | u64::from(bits - 1) << 36
+            | total as u64;
+        streaminfo[10..18].copy_from_slice(&fields.to_be_bytes());
+        streaminfo[18..34].copy_from_slice(&signature(channels, bits));
+
+        let mut frames = Vec::new();
+        let mut offsets = Vec::new();
+        (0..total)
+            .step_by(block_size)
+            .enumerate()
+            .for_each(|(number, start)| {
+                offsets.push(frames.len() as u64);
+                let samples: Vec<&[i32]> = channels
+                    .iter()
+                    .map(|samples| &samples[start..(start + block_size).min(total)])
+                    .collect();
+                encode_frame(&mut frames, &samples, &info, false, number as u64);
+            });
+
+        let mut seektable: Vec<u8> = seek_points
+            .iter()
+            .flat_map(|sample| {
+                let index = *sample as usize / block_size;
+                [
+                    &((index * block_size) as u64).to_be_bytes()[..],
+                    &offsets[index].to_be_bytes(),
+                    &(block_size as u16).to_be_bytes(),
+                ]
+                .concat()
+            })
+            .collect();
+        seektable.extend(PLACEHOLDER.to_be_bytes());
+        seektable.extend([0; 10]);
+
+        let mut data = Vec::new();
+        write_metadata(
+            &mut data,
+            &[
+                MetadataBlock {
+                    kind: BLOCK_STREAMINFO,
+                    data: streaminfo,
+                },
+                MetadataBlock {
+                    kind: BLOCK_SEEKTABLE,
+                    data: seektable,
+                },
+            ],
+        )
+        .unwrap();
+        data.extend(frames);
+        data
+    }
+
+    /// Metadata blocks, samples of each channel and byte offset of each frame
+    fn decode(
+        data: &[u8],
+        channels: usize,
+        bits: u32,
+    ) -> (Vec<MetadataBlock>, Vec<Vec<i32>>, Vec<u64>) {
+        let mut blocks = Vec::new();
+        let mut pos = MAGIC.len();
+        loop {
+            let size =
+                u32::from_be_bytes([0, data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+            blocks.push(MetadataBlock {
+                kind: data[pos] & 0x7f,
+                data: data[pos + 4..pos + 4 + size].to_vec(),
+            });
+            pos += 4 + size;
+            if data[pos - 4 - size] & 0x80 != 0 {
+                break;
+            }
+        }
+
+        let info = info(channels, bits);
+        let mut samples = vec![Vec::new(); channels];
+        let mut offsets = Vec::new();
+        let first = pos;
+        while pos < data.len() {
+            offsets.push((pos - first) as u64);
+            let (_, _, size) = decode_frame(&data[pos..], &info, &mut samples).unwrap();
+            pos += size;
+        }
+        (blocks, samples, offsets)
+    }
+
+    fn apply(data: &[u8], gain: f64) -> Vec<u8> {
+        let settings = Settings {
+            gain,
+            limiter: None,
+            dither: false,
+        };
+        let mut output = Cursor::new(Vec::new());
+        apply_gain(&mut &data[..], &mut output, &settings, &[]).unwrap();
+        output.into_inner()
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let signals = [
+            (vec![vec![0; 4096]], 16),
+            (vec![sine(4096, 20000.0), sine(4096, -3000.0)], 16),
+            (vec![noise(1000, 24, 1), noise(1000, 24, 2)], 24),
+            (vec![noise(100, 8, 3)], 8),
+            (
+                vec![(0..576)
+                    .map(|n| if n % 2 == 0 { -8388608 } else { 8388607 })
+                    .collect()],
+                24,
+            ),
+        ];
+
+        for (channels, bits) in signals {
+            let info = info(channels.len(), bits);
+            let samples: Vec<&[i32]> = channels.iter().map(Vec::as_slice).collect();
+            let mut data = Vec::new();
+            encode_frame(&mut data, &samples, &info, false, 300);
+
+            let mut decoded = vec![Vec::new(); channels.len()];
+            let (block_size, variable, size) = decode_frame(&data, &info, &mut decoded).unwrap();
+            assert_eq!(block_size, channels[0].len());
+            assert!(!variable);
+            assert_eq!(size, data.len());
+            assert_eq!(decoded, channels);
+        }
+    }
+
+    #[test]
+    fn apply_gain_keeps_samples_and_signature() {
+        let channels = vec![sine(10000, 8000.0), noise(10000, 12, 4)];
+        let data = stream(&channels, 16, 4096, &[]);
+
+        let output = apply(&data, 0.0);
+        let (blocks, samples, _) = decode(&output, 2, 16);
+        assert_eq!(samples, channels);
+        assert_eq!(blocks[0].data[18..34], signature(&channels, 16));
+
+        let output = apply(&data, 20.0 * 2f64.log10());
+        let (blocks, samples, _) = decode(&output, 2, 16);
+        let doubled: Vec<Vec<i32>> = channels
+            .iter()
+            .map(|samples| samples.iter().map(|sample| sample * 2).collect())
+            .collect();
+        assert_eq!(samples, doubled);
+        assert_eq!(blocks[0].data[18..34], signature(&doubled, 16));
+    }
+
+    #[test]
+    fn apply_gain_updates_seek_table() {
+        let channels = vec![noise(20000, 10, 5)];
+        let data = stream(&channels, 16, 1152, &[0, 5000, 12000, 19999]);
+
+        let output = apply(&data, 12.0);
+        let (blocks, _, offsets) = decode(&output, 1, 16);
+        assert_ne!(offsets, decode(&data, 1, 16).2);
+
+        let points: Vec<&[u8]> = blocks[1].data.chunks_exact(18).collect();
+        assert_eq!(points.len(), 5);
+        for (point, target) in points.iter().zip([0, 5000, 12000, 19999]) {
+            let index = target / 1152;
+            assert_eq!(point[..8], ((index * 1152) as u64).to_be_bytes());
+            assert_eq!(point[8..16], offsets[index].to_be_bytes());
+        }
+        assert_eq!(points[4][..8], PLACEHOLDER.to_be_bytes());
+    }
+
+    #[test]
+    fn apply_gain_round_trip_of_24_bit() {
+        let channels = vec![sine(9000, 3_000_000.0), noise(9000, 20, 7)];
+        let data = stream(&channels, 24, 4608, &[]);
+
+        let doubled = apply(&data, 20.0 * 2f64.log10());
+        let output = apply(&doubled, -20.0 * 2f64.log10());
+        let (blocks, samples, _) = decode(&output, 2, 24);
+        assert_eq!(samples, channels);
+        assert_eq!(blocks[0].data[18..34], signature(&channels, 24));
+    }
+
+    #[test]
+    fn apply_gain_rejects_corrupt_frame() {
+        let mut data = stream(&[noise(5000, 16, 6)], 16, 4096, &[]);
+        let last = data.len() - 10;
+        data[last] ^= 0xff;
+
+        let mut output = Cursor::new(Vec::new());
+        let settings = Settings {
+            gain: 1.0,
+            limiter: None,
+            dither: false,
+        };
+        assert!(apply_gain(&mut &data[..], &mut output, &settings, &[]).is_err());
+    }
+}
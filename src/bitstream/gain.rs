@@ -0,0 +1,215 @@
+//! Gain of decoded samples with an optional peak limiter and TPDF dither.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Time before a peak in which the limiter reduces the gain
+const ATTACK: f64 = 0.005;
+/// Time in which the limiter recovers from a gain reduction
+const RELEASE: f64 = 0.1;
+
+/// How to apply the gain
+#[derive(Clone, Copy)]
+pub struct Settings {
+    /// Gain in dB
+    pub gain: f64,
+    /// Maximum sample peak in dBFS
+    pub limiter: Option<f64>,
+    /// Add triangular dither when quantizing to integer samples
+    pub dither: bool,
+}
+
+/// Result of a gain change of decoded samples
+pub struct Summary {
+    pub samples: usize,
+    /// Sample frames whose gain is reduced by the limiter
+    pub limited: usize,
+    /// Samples exceeding full scale, clipped if they are integers
+    pub clipped: usize,
+    pub duration: Duration,
+}
+
+/// Gain and limiter of a stream of interleaved samples with a full scale of 1.0.
+/// The limiter holds back the sample frames of its attack time to reduce the gain before
+/// a peak, its release only depends on earlier frames.
+pub struct Processor {
+    factor: f64,
+    /// Maximum sample peak of the limiter
+    ceiling: Option<f64>,
+    channels: usize,
+    /// Gain change per sample frame
+    attack: f64,
+    release: f64,
+    /// Sample frames of the attack time
+    lookahead: usize,
+    /// Samples of the frames whose gain can still be reduced by a later peak
+    pending: VecDeque<f64>,
+    /// Gain of each pending frame
+    gains: VecDeque<f64>,
+    /// Gain of the last output frame
+    gain: f64,
+    /// Sample frames whose gain is reduced by the limiter
+    pub limited: usize,
+}
+
+impl Processor {
+    pub fn new(settings: &Settings, channels: usize, sample_rate: u32) -> Processor {
+        let attack = (ATTACK * f64::from(sample_rate)).max(1.0);
+        let release = (RELEASE * f64::from(sample_rate)).max(1.0);
+        Processor {
+            factor: 10f64.powf(settings.gain / 20.0),
+            ceiling: settings.limiter.map(|level| 10f64.powf(level / 20.0)),
+            channels,
+            attack: 1.0 / attack,
+            release: 1.0 / release,
+            lookahead: attack.ceil() as usize,
+            pending: VecDeque::new(),
+            gains: VecDeque::new(),
+            gain: 1.0,
+            limited: 0,
+        }
+    }
+
+    /// Apply the gain to whole sample frames and append the frames that are done to `output`
+    pub fn process(&mut self, samples: &[f64], output: &mut Vec<f64>) {
+        let Some(ceiling) = self.ceiling else {
+            output.extend(samples.iter().map(|sample| sample * self.factor));
+            return;
+        };
+
+        samples.chunks(self.channels).for_each(|frame| {
+            self.pending
+                .extend(frame.iter().map(|sample| sample * self.factor));
+            let peak = self
+                .pending
+                .range(self.pending.len() - frame.len()..)
+                .fold(0f64, |peak, sample| peak.max(sample.abs()));
+            let gain = if peak > ceiling { ceiling / peak } else { 1.0 };
+            self.gains.push_back(gain);
+
+            // ramp the gain down before the peak, earlier frames already ramp to the later ones
+            let mut next = gain;
+            for previous in self.gains.iter_mut().rev().skip(1) {
+                if *previous <= next + self.attack {
+                    break;
+                }
+                *previous = next + self.attack;
+                next = *previous;
+            }
+
+            // a peak after the attack time cannot reduce the gain of the first frame
+            if self.gains.len() > self.lookahead {
+                self.output_frame(output);
+            }
+        });
+    }
+
+    /// Append the remaining frames to `output` at the end of the stream
+    pub fn finish(&mut self, output: &mut Vec<f64>) {
+        while !self.gains.is_empty() {
+            self.output_frame(output);
+        }
+    }
+
+    fn output_frame(&mut self, output: &mut Vec<f64>) {
+        let Some(gain) = self.gains.pop_front() else {
+            return;
+        };
+        self.gain = gain.min(self.gain + self.release);
+        if self.gain < 1.0 {
+            self.limited += 1;
+        }
+        let gain = self.gain;
+        output.extend(
+            self.pending
+                .drain(..self.channels)
+                .map(|sample| sample * gain),
+        );
+    }
+}
+
+/// Conversion to integer samples
+pub struct Quantizer {
+    scale: f64,
+    min: f64,
+    max: f64,
+    dither: bool,
+    random: u64,
+    /// Samples that exceeded full scale and were clipped
+    pub clipped: usize,
+}
+
+impl Quantizer {
+    pub fn new(bits: u32, dither: bool) -> Quantizer {
+        let scale = 2f64.powi(bits as i32 - 1);
+        Quantizer {
+            scale,
+            min: -scale,
+            max: scale - 1.0,
+            dither,
+            random: 0x2545_f491_4f6c_dd1d,
+            clipped: 0,
+        }
+    }
+
+    /// Integer sample of `bits` bits of a sample with a full scale of 1.0
+    pub fn quantize(&mut self, sample: f64) -> i32 {
+        let mut value = sample * self.scale;
+        if self.dither {
+            // triangular distribution of +/- 1 LSB
+            value += self.random() - self.random();
+        }
+        let value = value.round();
+        if value < self.min || value > self.max {
+            self.clipped += 1;
+        }
+        value.clamp(self.min, self.max) as i32
+    }
+
+    /// Uniform random number from 0 to 1 by xorshift64*
+    fn random(&mut self) -> f64 {
+        self.random ^= self.random >> 12;
+        self.random ^= self.random << 25;
+        self.random ^= self.random >> 27;
+        (self.random.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiter_is_independent_of_chunks() {
+        let settings = Settings {
+            gain: 6.0,
+            limiter: Some(-1.0),
+            dither: false,
+        };
+        let ceiling = 10f64.powf(-1.0 / 20.0);
+        // stereo sine with a peak in one channel
+        let mut samples: Vec<f64> = (0..20000)
+            .map(|n| 0.3 * ((n / 2) as f64 * 0.01).sin())
+            .collect();
+        samples[12001] = 0.9;
+
+        let mut whole = Vec::new();
+        let mut processor = Processor::new(&settings, 2, 48000);
+        processor.process(&samples, &mut whole);
+        processor.finish(&mut whole);
+
+        let mut chunked = Vec::new();
+        let mut processor = Processor::new(&settings, 2, 48000);
+        samples
+            .chunks(2 * 37)
+            .for_each(|chunk| processor.process(chunk, &mut chunked));
+        processor.finish(&mut chunked);
+
+        assert_eq!(whole, chunked);
+        assert_eq!(whole.len(), samples.len());
+        assert!(whole.iter().all(|sample| sample.abs() <= ceiling + 1e-12));
+        // the gain is already reduced 100 frames before the peak
+        assert!(whole[11801].abs() < samples[11801].abs() * 10f64.powf(6.0 / 20.0));
+        assert!(processor.limited > 0);
+    }
+}
@@ -0,0 +1,94 @@
+//! Native editors of audio bitstreams to change their gain or loudness metadata without ffmpeg.
+
+pub mod aac;
+pub mod ac3;
+mod ape;
+pub mod bext;
+pub mod flac;
+pub mod gain;
+mod md5;
+pub mod mp3;
+mod mp4;
+mod ogg;
+pub mod opus;
+mod riff;
+mod vorbis;
+pub mod wav;
+
+use std::io::{self, Read};
+
+/// CRC-16 lookup table of polynomial x^16 + x^15 + x^2 + 1
+const CRC16_TABLE: [u16; 256] = crc16_table(0x8005);
+
+const fn crc16_table(poly: u16) -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ poly
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-16 with polynomial 0x8005, MSB first, without final XOR
+fn crc16(init: u16, data: &[u8]) -> u16 {
+    data.iter().fold(init, |crc, byte| {
+        (crc << 8) ^ CRC16_TABLE[usize::from((crc >> 8) as u8 ^ byte)]
+    })
+}
+
+/// Read `count` bits MSB first starting at bit `pos`
+fn read_bits(data: &[u8], pos: usize, count: usize) -> u32 {
+    (pos..pos + count).fold(0, |value, bit| {
+        (value << 1) | u32::from((data[bit / 8] >> (7 - bit % 8)) & 1)
+    })
+}
+
+/// Write the lowest `count` bits of `value` MSB first starting at bit `pos`
+fn write_bits(data: &mut [u8], pos: usize, count: usize, value: u32) {
+    (pos..pos + count).enumerate().for_each(|(i, bit)| {
+        let mask = 1u8 << (7 - bit % 8);
+        if (value >> (count - 1 - i)) & 1 != 0 {
+            data[bit / 8] |= mask;
+        } else {
+            data[bit / 8] &= !mask;
+        }
+    });
+}
+
+/// Size of an ID3v2 tag by its 10 byte header, which may exceed `data`
+fn id3v2_tag_size(data: &[u8]) -> usize {
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return 0;
+    }
+    // syncsafe integer, plus the header and an optional footer
+    let size = data[6..10]
+        .iter()
+        .fold(0usize, |size, byte| (size << 7) | usize::from(byte & 0x7f));
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    size + 10 + footer
+}
+
+/// Fill `buffer` from `reader`, returns the number of bytes, which is less at the end
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+    while count < buffer.len() {
+        match reader.read(&mut buffer[count..]) {
+            Ok(0) => break,
+            Ok(read) => count += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(count)
+}
@@ -0,0 +1,417 @@
+//! Lossless gain of MPEG audio layer 3 files by changing `global_gain` of every granule,
+//! compatible with mp3gain.
+
+use super::ape::ApeTag;
+use super::{crc16, id3v2_tag_size, read_bits, read_full, write_bits};
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Gain of one `global_gain` step in dB
+pub const GAIN_STEP: f64 = 1.5;
+
+/// APEv2 item with the applied steps of both channels, e.g. "+002,+002,N"
+const UNDO_KEY: &str = "MP3GAIN_UNDO";
+
+/// Largest layer 3 frame, 320 kbit/s at 32 kHz or 160 kbit/s at 8 kHz with padding
+const MAX_FRAME_SIZE: usize = 1441;
+/// Bytes of frames read at a time
+const READ_SIZE: usize = 1 << 16;
+
+/// Layer 3 bitrates in kbit/s of MPEG-1 and MPEG-2/2.5 by bitrate index
+const BIT_RATES: [[u32; 15]; 2] = [
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// Sample rates of MPEG-1, MPEG-2 and MPEG-2.5 by sample rate index
+const SAMPLE_RATES: [[u32; 3]; 3] = [
+    [44100, 48000, 32000],
+    [22050, 24000, 16000],
+    [11025, 12000, 8000],
+];
+
+/// Result of a gain change of MP3 or AAC frames
+pub struct Summary {
+    pub frames: usize,
+    /// Granules or channel streams whose `global_gain` was limited to 0 .. 255
+    pub clamped: usize,
+    pub duration: Duration,
+}
+
+struct Header {
+    size: usize,
+    /// MPEG-1, otherwise MPEG-2 or 2.5 with one granule per frame
+    mpeg1: bool,
+    protected: bool,
+    channels: usize,
+    samples: u32,
+    sample_rate: u32,
+}
+
+/// Change the gain of an MP3 file by `steps` of 1.5 dB and store undo information in an APEv2 tag.
+/// `tags` are added to the APEv2 tag.
+pub fn rewrite_file(
+    input: &Path,
+    output: &Path,
+    steps: i32,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let mut reader = BufReader::new(
+        File::open(input)
+            .with_context(|| format!("Failed to read input file \"{}\"", input.display()))?,
+    );
+    let mut writer = BufWriter::new(
+        File::create(output)
+            .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?,
+    );
+
+    let summary = apply_gain(&mut reader, &mut writer, steps, tags)
+        .with_context(|| format!("Failed to change gain of \"{}\"", input.display()))?;
+
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?;
+
+    Ok(summary)
+}
+
+/// Copy the MP3 file from `input` to `output` and change the gain of its frames by `steps`.
+/// Only the frames in flight and the tags at the end are held in memory.
+pub fn apply_gain(
+    input: &mut (impl Read + Seek),
+    output: &mut impl Write,
+    steps: i32,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let mut end = input.seek(SeekFrom::End(0))?;
+    input.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; 10];
+    let read = read_full(input, &mut header)?;
+    let start = (id3v2_tag_size(&header[..read]) as u64).min(end);
+
+    let mut id3v1 = vec![0u8; 128];
+    if end >= start + 128 {
+        input.seek(SeekFrom::Start(end - 128))?;
+        input.read_exact(&mut id3v1)?;
+    }
+    let id3v1 = if &id3v1[..3] == b"TAG" {
+        end -= 128;
+        id3v1
+    } else {
+        Vec::new()
+    };
+
+    let (mut tag, tag_start) = match ApeTag::read_from(input, end)? {
+        Some((tag, tag_start)) => (tag, tag_start.max(start)),
+        None => (ApeTag::default(), end),
+    };
+    end = tag_start;
+
+    // the ID3v2 tag is kept unchanged
+    input.seek(SeekFrom::Start(0))?;
+    io::copy(&mut input.by_ref().take(start), output)?;
+
+    let mut summary = Summary {
+        frames: 0,
+        clamped: 0,
+        duration: Duration::ZERO,
+    };
+    let mut frames = input.by_ref().take(end - start);
+    let mut buffer = Vec::with_capacity(READ_SIZE);
+    // start of the bytes in `buffer` that are not written yet
+    let mut pos = 0;
+
+    loop {
+        if buffer.len() - pos < MAX_FRAME_SIZE && frames.limit() > 0 {
+            buffer.drain(..pos);
+            pos = 0;
+            let size = (READ_SIZE - buffer.len()) as u64;
+            frames.by_ref().take(size).read_to_end(&mut buffer)?;
+        }
+        if buffer.len() - pos < 4 {
+            break;
+        }
+
+        let data = &mut buffer[pos..];
+        let header = match parse_header(data) {
+            Some(header) if header.size <= data.len() => header,
+            // skip junk between frames and a truncated last frame
+            _ => {
+                output.write_all(&data[..1])?;
+                pos += 1;
+                continue;
+            }
+        };
+
+        let frame = &mut data[..header.size];
+        summary.clamped += change_gain(frame, &header, steps);
+        output.write_all(frame)?;
+        summary.frames += 1;
+        summary.duration +=
+            Duration::from_secs_f64(f64::from(header.samples) / f64::from(header.sample_rate));
+        pos += header.size;
+    }
+    output.write_all(&buffer[pos..])?;
+
+    if summary.frames == 0 {
+        bail!("No MPEG audio layer 3 frames found, free format is not supported");
+    }
+
+    // steps add up with an earlier change
+    let undo = tag
+        .get(UNDO_KEY)
+        .and_then(|undo| {
+            let mut values = undo.split(',').map(|value| value.trim().parse::<i32>());
+            Some((values.next()?.ok()?, values.next()?.ok()?))
+        })
+        .unwrap_or_default();
+    tag.set(
+        UNDO_KEY,
+        &format!("{:+04},{:+04},N", undo.0 + steps, undo.1 + steps),
+    );
+    tags.iter().for_each(|(key, value)| tag.set(key, value));
+
+    output.write_all(&tag.to_bytes())?;
+    output.write_all(&id3v1)?;
+
+    Ok(summary)
+}
+
+/// Layer 3 frame header, `None` if there is none at the start of `data`
+fn parse_header(data: &[u8]) -> Option<Header> {
+    if read_bits(data, 0, 11) != 0x7ff {
+        return None;
+    }
+
+    let version = read_bits(data, 11, 2);
+    let layer = read_bits(data, 13, 2);
+    let bit_rate_index = read_bits(data, 16, 4) as usize;
+    let sample_rate_index = read_bits(data, 20, 2) as usize;
+
+    // reserved values, other layers and free format are not supported
+    if version == 1
+        || layer != 1
+        || bit_rate_index == 0
+        || bit_rate_index == 15
+        || sample_rate_index == 3
+    {
+        return None;
+    }
+
+    let mpeg1 = version == 3;
+    let bit_rate = BIT_RATES[usize::from(!mpeg1)][bit_rate_index] * 1000;
+    let sample_rate = SAMPLE_RATES[match version {
+        3 => 0,
+        2 => 1,
+        _ => 2,
+    }][sample_rate_index];
+    let padding = read_bits(data, 22, 1) as usize;
+    let samples = if mpeg1 { 1152 } else { 576 };
+
+    Some(Header {
+        size: (samples / 8 * bit_rate / sample_rate) as usize + padding,
+        mpeg1,
+        protected: read_bits(data, 15, 1) == 0,
+        channels: if read_bits(data, 24, 2) == 3 { 1 } else { 2 },
+        samples,
+        sample_rate,
+    })
+}
+
+/// Change `global_gain` of all granules with audio data, returns the number of clamped values.
+fn change_gain(frame: &mut [u8], header: &Header, steps: i32) -> usize {
+    let side_info = if header.protected { 6 } else { 4 };
+    let side_info_size = match (header.mpeg1, header.channels) {
+        (true, 1) => 17,
+        (true, _) => 32,
+        (false, 1) => 9,
+        (false, _) => 17,
+    };
+    if frame.len() < side_info + side_info_size {
+        return 0;
+    }
+    let crc_valid =
+        header.protected && crc(frame, side_info_size) == read_bits(frame, 32, 16) as u16;
+
+    // main_data_begin, private_bits and scfsi
+    let (granules, mut pos, granule_bits) = match (header.mpeg1, header.channels) {
+        (true, 1) => (2, 18, 59),
+        (true, _) => (2, 20, 59),
+        (false, 1) => (1, 9, 63),
+        (false, _) => (1, 10, 63),
+    };
+    pos += side_info * 8;
+
+    let mut clamped = 0;
+    for _ in 0..granules * header.channels {
+        let part2_3_length = read_bits(frame, pos, 12);
+        // granules without audio data, e.g. of the Xing header frame
+        if part2_3_length > 0 {
+            let global_gain = read_bits(frame, pos + 21, 8) as i32 + steps;
+            if !(0..=255).contains(&global_gain) {
+                clamped += 1;
+            }
+            write_bits(frame, pos + 21, 8, global_gain.clamp(0, 255) as u32);
+        }
+        pos += granule_bits;
+    }
+
+    // keep an invalid CRC invalid
+    if crc_valid {
+        let crc = crc(frame, side_info_size);
+        frame[4..6].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    clamped
+}
+
+/// CRC of the last two header bytes and the side information
+fn crc(frame: &[u8], side_info_size: usize) -> u16 {
+    crc16(crc16(0xffff, &frame[2..4]), &frame[6..6 + side_info_size])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// MPEG-1 layer 3 frame of 128 kbit/s at 44.1 kHz mono with the `global_gain` of both
+    /// granules, with a CRC if `protected`
+    fn frame(global_gain: [u32; 2], protected: bool) -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xff, if protected { 0xfa } else { 0xfb }, 0x90, 0xc0]);
+        let side_info = if protected { 48 } else { 32 };
+        global_gain.iter().enumerate().for_each(|(i, gain)| {
+            let pos = side_info + 18 + i * 59;
+            write_bits(&mut frame, pos, 12, 100);
+            write_bits(&mut frame, pos + 21, 8, *gain);
+        });
+        if protected {
+            let crc = crc(&frame, 17);
+            frame[4..6].copy_from_slice(&crc.to_be_bytes());
+        }
+        frame
+    }
+
+    fn global_gain(frame: &[u8], granule: usize) -> u32 {
+        read_bits(frame, 32 + 18 + granule * 59 + 21, 8)
+    }
+
+    fn run(data: &[u8], steps: i32, tags: &[(String, String)]) -> (Vec<u8>, Summary) {
+        let mut output = Vec::new();
+        let summary = apply_gain(&mut Cursor::new(data), &mut output, steps, tags).unwrap();
+        (output, summary)
+    }
+
+    fn undo(data: &[u8]) -> Option<String> {
+        let (tag, _) = ApeTag::read(data, data.len()).unwrap()?;
+        tag.get(UNDO_KEY)
+    }
+
+    #[test]
+    fn apply_gain_round_trip() {
+        // junk between the frames and an ID3v1 tag at the end
+        let frames = [
+            frame([100, 110], false),
+            vec![0; 3],
+            frame([120, 130], false),
+        ]
+        .concat();
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, b' ');
+        let original = [frames.clone(), id3v1.clone()].concat();
+
+        let (data, summary) = run(&original, 2, &[]);
+        assert_eq!(summary.frames, 2);
+        assert_eq!(summary.clamped, 0);
+        assert_eq!(
+            summary.duration,
+            Duration::from_secs_f64(2.0 * 1152.0 / 44100.0)
+        );
+        assert_eq!(global_gain(&data, 0), 102);
+        assert_eq!(global_gain(&data, 1), 112);
+        assert_eq!(global_gain(&data[420..], 0), 122);
+        assert_eq!(global_gain(&data[420..], 1), 132);
+        assert_eq!(data[data.len() - 128..], id3v1[..]);
+        assert_eq!(
+            undo(&data[..data.len() - 128]).as_deref(),
+            Some("+002,+002,N")
+        );
+
+        let (data, _) = run(&data, -2, &[]);
+        assert_eq!(data[..frames.len()], frames[..]);
+        assert_eq!(
+            undo(&data[..data.len() - 128]).as_deref(),
+            Some("+000,+000,N")
+        );
+    }
+
+    #[test]
+    fn apply_gain_clamps_global_gain() {
+        let (data, summary) = run(&frame([250, 5], false), 10, &[]);
+        assert_eq!(summary.clamped, 1);
+        assert_eq!(global_gain(&data, 0), 255);
+        assert_eq!(global_gain(&data, 1), 15);
+
+        let (data, summary) = run(&frame([250, 5], false), -10, &[]);
+        assert_eq!(summary.clamped, 1);
+        assert_eq!(global_gain(&data, 0), 240);
+        assert_eq!(global_gain(&data, 1), 0);
+    }
+
+    #[test]
+    fn apply_gain_updates_crc() {
+        let (data, _) = run(&frame([100, 110], true), 3, &[]);
+        assert_eq!(read_bits(&data, 48 + 18 + 21, 8), 103);
+        assert_eq!(crc(&data, 17), u16::from_be_bytes([data[4], data[5]]));
+    }
+
+    #[test]
+    fn apply_gain_adds_to_undo_tag() {
+        let mut tag = ApeTag::default();
+        tag.set("MP3GAIN_MINMAX", "080,200");
+        tag.set(UNDO_KEY, "+003,+003,N");
+        let original = [frame([100, 110], false), tag.to_bytes()].concat();
+        let tags = [(
+            "NORMALIZER".to_string(),
+            "ffmpeg-audio-normalizer".to_string(),
+        )];
+
+        let (data, _) = run(&original, -5, &tags);
+        assert_eq!(global_gain(&data, 0), 95);
+        let (tag, start) = ApeTag::read(&data, data.len()).unwrap().unwrap();
+        assert_eq!(start, 417);
+        assert_eq!(tag.get("mp3gain_undo").as_deref(), Some("-002,-002,N"));
+        assert_eq!(tag.get("MP3GAIN_MINMAX").as_deref(), Some("080,200"));
+        assert_eq!(
+            tag.get("NORMALIZER").as_deref(),
+            Some("ffmpeg-audio-normalizer")
+        );
+    }
+
+    #[test]
+    fn apply_gain_of_more_frames_than_read_at_a_time() {
+        let count = 2 * READ_SIZE / 417;
+        let original: Vec<u8> = (0..count)
+            .flat_map(|i| frame([i as u32 % 200, 50], false))
+            .collect();
+
+        let (data, summary) = run(&original, 1, &[]);
+        assert_eq!(summary.frames, count);
+        data[..original.len()]
+            .chunks(417)
+            .enumerate()
+            .for_each(|(i, frame)| assert_eq!(global_gain(frame, 0), i as u32 % 200 + 1));
+    }
+
+    #[test]
+    fn apply_gain_rejects_no_frames() {
+        let mut output = Vec::new();
+        assert!(apply_gain(&mut Cursor::new(vec![0u8; 1000]), &mut output, 2, &[]).is_err());
+    }
+}
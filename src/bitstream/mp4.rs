@@ -0,0 +1,631 @@
+//! Boxes of MP4 (ISO base media) files: the sample table of audio tracks and iTunes tags,
+//! to change the audio samples in place.
+
+use anyhow::{anyhow, bail, Result};
+use std::ops::Range;
+
+/// Boxes that contain boxes, with the size of the fields in front of them
+const CONTAINERS: &[(&[u8; 4], usize)] = &[
+    (b"moov", 0),
+    (b"trak", 0),
+    (b"mdia", 0),
+    (b"minf", 0),
+    (b"stbl", 0),
+    (b"udta", 0),
+    (b"meta", 4),
+    (b"ilst", 0),
+];
+
+/// Mean of iTunes freeform tags
+const ITUNES: &[u8] = b"com.apple.iTunes";
+
+/// Type of UTF-8 text in `data` boxes
+const UTF8: u32 = 1;
+
+/// Box with its payload, split into children if it is a container
+pub struct Atom {
+    pub kind: [u8; 4],
+    /// Fields in front of the children, or the whole payload if it has no children
+    pub data: Vec<u8>,
+    pub children: Vec<Atom>,
+}
+
+/// Audio track of the sample table
+pub struct Track {
+    /// Sample entry, e.g. "mp4a"
+    pub format: [u8; 4],
+    /// `DecoderSpecificInfo` of the `esds` box, e.g. the AAC `AudioSpecificConfig`
+    pub decoder_config: Option<Vec<u8>>,
+    /// Byte ranges of the samples in the file
+    pub samples: Vec<Range<usize>>,
+}
+
+/// Type, byte range including the header, and header size of a box
+pub type BoxRange = ([u8; 4], Range<usize>, usize);
+
+/// Boxes in `range`
+pub fn boxes(data: &[u8], range: Range<usize>) -> Result<Vec<BoxRange>> {
+    let mut boxes = Vec::new();
+    let mut pos = range.start;
+
+    while pos < range.end {
+        if pos + 8 > range.end {
+            bail!("Truncated box at byte offset {pos}");
+        }
+        let kind: [u8; 4] = data[pos + 4..pos + 8].try_into()?;
+        let (size, header) = match u32_at(data, pos) {
+            0 => (range.end - pos, 8),
+            1 if pos + 16 <= range.end => (u64_at(data, pos + 8) as usize, 16),
+            size => (size as usize, 8),
+        };
+        if size < header || size > range.end - pos {
+            bail!(
+                "Invalid size {size} of box \"{}\" at byte offset {pos}",
+                String::from_utf8_lossy(&kind)
+            );
+        }
+        boxes.push((kind, pos..pos + size, header));
+        pos += size;
+    }
+
+    Ok(boxes)
+}
+
+impl Atom {
+    /// Box of the byte range including its header
+    pub fn parse(data: &[u8], range: Range<usize>, header: usize) -> Result<Atom> {
+        let kind: [u8; 4] = data[range.start + 4..range.start + 8].try_into()?;
+        let payload = range.start + header..range.end;
+
+        let prefix = match CONTAINERS.iter().find(|(name, _)| **name == kind) {
+            // QuickTime writes `meta` without version and flags
+            Some((b"meta", _))
+                if data.get(payload.start + 4..payload.start + 8) == Some(&b"hdlr"[..]) =>
+            {
+                Some(0)
+            }
+            Some((_, prefix)) => Some(*prefix),
+            None => None,
+        };
+
+        match prefix {
+            Some(prefix) if payload.len() >= prefix => Ok(Atom {
+                kind,
+                data: data[payload.start..payload.start + prefix].to_vec(),
+                children: boxes(data, payload.start + prefix..payload.end)?
+                    .into_iter()
+                    .map(|(_, range, header)| Atom::parse(data, range, header))
+                    .collect::<Result<_>>()?,
+            }),
+            _ => Ok(Atom {
+                kind,
+                data: data[payload].to_vec(),
+                children: Vec::new(),
+            }),
+        }
+    }
+
+    fn new(kind: &[u8; 4], data: Vec<u8>, children: Vec<Atom>) -> Atom {
+        Atom {
+            kind: *kind,
+            data,
+            children,
+        }
+    }
+
+    pub fn child(&self, kind: &[u8; 4]) -> Option<&Atom> {
+        self.children.iter().find(|child| child.kind == *kind)
+    }
+
+    fn child_mut(&mut self, kind: &[u8; 4]) -> Option<&mut Atom> {
+        self.children.iter_mut().find(|child| child.kind == *kind)
+    }
+
+    /// Child of the kind, added if it does not exist
+    fn child_or_insert(&mut self, kind: &[u8; 4], atom: impl FnOnce() -> Atom) -> &mut Atom {
+        let pos = match self.children.iter().position(|child| child.kind == *kind) {
+            Some(pos) => pos,
+            None => {
+                self.children.push(atom());
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[pos]
+    }
+
+    fn find(&self, path: &[&[u8; 4]]) -> Option<&Atom> {
+        path.iter().try_fold(self, |atom, kind| atom.child(kind))
+    }
+
+    /// Box with header, the size is 64 bit if it does not fit 32 bit
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let payload: Vec<u8> = self
+            .data
+            .iter()
+            .copied()
+            .chain(self.children.iter().flat_map(Atom::to_bytes))
+            .collect();
+
+        match u32::try_from(payload.len() + 8) {
+            Ok(size) => [&size.to_be_bytes()[..], &self.kind, &payload].concat(),
+            Err(_) => [
+                &1u32.to_be_bytes()[..],
+                &self.kind,
+                &(payload.len() as u64 + 16).to_be_bytes(),
+                &payload,
+            ]
+            .concat(),
+        }
+    }
+}
+
+/// Audio tracks of the movie box of a file of `file_size` bytes in order
+pub fn audio_tracks(moov: &Atom, file_size: usize) -> Result<Vec<Track>> {
+    moov.children
+        .iter()
+        .filter(|trak| trak.kind == *b"trak")
+        .filter(|trak| {
+            // handler type after version, flags and pre_defined
+            trak.find(&[b"mdia", b"hdlr"])
+                .is_some_and(|hdlr| hdlr.data.get(8..12) == Some(&b"soun"[..]))
+        })
+        .map(|trak| {
+            let stbl = trak
+                .find(&[b"mdia", b"minf", b"stbl"])
+                .ok_or_else(|| anyhow!("Audio track without sample table"))?;
+            let stsd = stbl
+                .child(b"stsd")
+                .ok_or_else(|| anyhow!("Audio track without sample description"))?;
+            let (format, decoder_config) = sample_entry(&stsd.data)?;
+
+            Ok(Track {
+                format,
+                decoder_config,
+                samples: samples(stbl, file_size)?,
+            })
+        })
+        .collect()
+}
+
+/// Format and decoder configuration of the first sample entry of a `stsd` box
+fn sample_entry(stsd: &[u8]) -> Result<([u8; 4], Option<Vec<u8>>)> {
+    // version, flags and entry count
+    let entries = boxes(stsd, 8.min(stsd.len())..stsd.len())?;
+    let Some((format, range, _)) = entries.first() else {
+        bail!("No sample entry in sample description");
+    };
+
+    // reserved, data reference index and the audio fields of QuickTime version 0, 1 or 2
+    let version = stsd
+        .get(range.start + 16..range.start + 18)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+    let fields = match version {
+        Some(1) => 44,
+        Some(2) => 64,
+        _ => 28,
+    };
+    let start = (range.start + 8 + fields).min(range.end);
+
+    Ok((
+        *format,
+        esds(stsd, start..range.end)?.and_then(|esds| decoder_config(&esds)),
+    ))
+}
+
+/// Payload of the `esds` box in the boxes of `range`, also inside a QuickTime `wave` box
+fn esds(data: &[u8], range: Range<usize>) -> Result<Option<Vec<u8>>> {
+    // a sample entry may end with padding that is no box
+    let Ok(boxes) = boxes(data, range) else {
+        return Ok(None);
+    };
+
+    for (kind, range, header) in boxes {
+        match &kind {
+            b"esds" => return Ok(Some(data[range.start + header..range.end].to_vec())),
+            b"wave" => {
+                if let Some(esds) = esds(data, range.start + header..range.end)? {
+                    return Ok(Some(esds));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+/// `DecoderSpecificInfo` of the `ES_Descriptor` in an `esds` payload
+fn decoder_config(esds: &[u8]) -> Option<Vec<u8>> {
+    // version and flags
+    let mut pos = 4;
+
+    let descriptor = |pos: &mut usize| -> Option<(u8, usize)> {
+        let tag = *esds.get(*pos)?;
+        *pos += 1;
+        let mut len = 0;
+        for _ in 0..4 {
+            let byte = *esds.get(*pos)?;
+            *pos += 1;
+            len = (len << 7) | usize::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Some((tag, len))
+    };
+
+    let (tag, _) = descriptor(&mut pos)?;
+    if tag != 3 {
+        return None;
+    }
+    // ES_ID and the flags of the optional fields
+    let flags = *esds.get(pos + 2)?;
+    pos += 3;
+    if flags & 0x80 != 0 {
+        pos += 2;
+    }
+    if flags & 0x40 != 0 {
+        pos += 1 + usize::from(*esds.get(pos)?);
+    }
+    if flags & 0x20 != 0 {
+        pos += 2;
+    }
+
+    let (tag, _) = descriptor(&mut pos)?;
+    if tag != 4 {
+        return None;
+    }
+    // object type, stream type, buffer size and bitrates
+    pos += 13;
+
+    let (tag, len) = descriptor(&mut pos)?;
+    if tag != 5 {
+        return None;
+    }
+    esds.get(pos..pos + len).map(<[u8]>::to_vec)
+}
+
+/// Byte ranges of the samples of a sample table of a file of `file_size` bytes
+fn samples(stbl: &Atom, file_size: usize) -> Result<Vec<Range<usize>>> {
+    let table = |kind: &[u8; 4]| {
+        stbl.child(kind)
+            .map(|atom| atom.data.as_slice())
+            .ok_or_else(|| {
+                anyhow!(
+                    "No \"{}\" box in sample table",
+                    String::from_utf8_lossy(kind)
+                )
+            })
+    };
+    let entries = |data: &[u8], pos: usize, size: usize| -> Result<usize> {
+        let count = data
+            .get(pos..pos + 4)
+            .map_or(0, |_| u32_at(data, pos) as usize);
+        if data.len() < pos + 4 + count * size {
+            bail!("Truncated sample table");
+        }
+        Ok(count)
+    };
+
+    let stsz = table(b"stsz")?;
+    if stsz.len() < 12 {
+        bail!("Truncated sample table");
+    }
+    let sample_size = u32_at(stsz, 4) as usize;
+    let count = u32_at(stsz, 8) as usize;
+    let size = |sample: usize| {
+        if sample_size != 0 {
+            sample_size
+        } else {
+            u32_at(stsz, 12 + sample * 4) as usize
+        }
+    };
+    if sample_size == 0 && stsz.len() < 12 + count * 4 {
+        bail!("Truncated sample table");
+    }
+    // samples of a fixed size are not listed, a corrupt count must not exceed the file
+    if sample_size != 0 && count > file_size / sample_size {
+        bail!("Sample table has {count} samples of {sample_size} bytes, more than the file holds");
+    }
+
+    let stsc = table(b"stsc")?;
+    let runs = entries(stsc, 4, 12)?;
+    // first chunk (counted from 1) and samples per chunk
+    let runs: Vec<(usize, usize)> = (0..runs)
+        .map(|i| {
+            (
+                u32_at(stsc, 8 + i * 12) as usize,
+                u32_at(stsc, 12 + i * 12) as usize,
+            )
+        })
+        .collect();
+
+    let chunks: Vec<usize> = match (table(b"stco"), table(b"co64")) {
+        (Ok(stco), _) => (0..entries(stco, 4, 4)?)
+            .map(|i| u32_at(stco, 8 + i * 4) as usize)
+            .collect(),
+        (_, Ok(co64)) => (0..entries(co64, 4, 8)?)
+            .map(|i| u64_at(co64, 8 + i * 8) as usize)
+            .collect(),
+        (Err(err), _) => return Err(err),
+    };
+
+    let mut samples = Vec::with_capacity(count);
+    for (chunk, offset) in chunks.iter().enumerate() {
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= chunk + 1)
+            .map_or(0, |(_, per_chunk)| *per_chunk);
+        let mut pos = *offset;
+        for _ in 0..per_chunk.min(count - samples.len()) {
+            let size = size(samples.len());
+            samples.push(pos..pos + size);
+            pos += size;
+        }
+    }
+    if samples.len() != count {
+        bail!(
+            "Sample table has {count} samples, but its chunks only {}",
+            samples.len()
+        );
+    }
+
+    Ok(samples)
+}
+
+/// Value of an iTunes freeform tag, e.g. "----:com.apple.iTunes:MP3GAIN_UNDO"
+pub fn tag(moov: &Atom, key: &str) -> Option<String> {
+    moov.find(&[b"udta", b"meta", b"ilst"])?
+        .children
+        .iter()
+        .filter_map(freeform)
+        .find(|(name, _)| name.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value)
+}
+
+/// Set iTunes freeform tags, replacing tags of the same name
+pub fn set_tags(moov: &mut Atom, tags: &[(String, String)]) {
+    let ilst = moov
+        .child_or_insert(b"udta", || Atom::new(b"udta", Vec::new(), Vec::new()))
+        .child_or_insert(b"meta", || {
+            // metadata handler of iTunes: pre_defined, "mdir", "appl", reserved and empty name
+            let hdlr = [&[0; 8][..], b"mdirappl", &[0; 9]].concat();
+            Atom::new(
+                b"meta",
+                vec![0; 4],
+                vec![Atom::new(
+                    b"hdlr",
+                    [&[0; 4][..], &hdlr].concat(),
+                    Vec::new(),
+                )],
+            )
+        })
+        .child_or_insert(b"ilst", || Atom::new(b"ilst", Vec::new(), Vec::new()));
+
+    tags.iter().for_each(|(key, value)| {
+        ilst.children
+            .retain(|item| freeform(item).is_none_or(|(name, _)| !name.eq_ignore_ascii_case(key)));
+
+        let full = |kind: &[u8; 4], payload: &[u8]| {
+            Atom::new(kind, [&[0; 4][..], payload].concat(), Vec::new()).to_bytes()
+        };
+        let data = [
+            full(b"mean", ITUNES),
+            full(b"name", key.as_bytes()),
+            // type and locale
+            Atom::new(
+                b"data",
+                [&UTF8.to_be_bytes()[..], &[0; 4], value.as_bytes()].concat(),
+                Vec::new(),
+            )
+            .to_bytes(),
+        ]
+        .concat();
+        ilst.children.push(Atom::new(b"----", data, Vec::new()));
+    });
+}
+
+/// Name and text of an iTunes freeform item
+fn freeform(item: &Atom) -> Option<(String, String)> {
+    if item.kind != *b"----" {
+        return None;
+    }
+
+    let mut name = None;
+    let mut value = None;
+    let mut is_itunes = false;
+    for (kind, range, header) in boxes(&item.data, 0..item.data.len()).ok()? {
+        let payload = item.data.get(range.start + header + 4..range.end)?;
+        match &kind {
+            b"mean" => is_itunes = payload == ITUNES,
+            b"name" => name = Some(String::from_utf8_lossy(payload).to_string()),
+            // after the type follows the locale
+            b"data" => value = Some(String::from_utf8_lossy(payload.get(4..)?).to_string()),
+            _ => {}
+        }
+    }
+
+    is_itunes.then_some((name?, value?))
+}
+
+/// Add `delta` to the chunk offsets from `from` of all tracks,
+/// e.g. of the media data behind the movie box when its size changes
+pub fn shift_chunk_offsets(moov: &mut Atom, from: usize, delta: isize) -> Result<()> {
+    for trak in moov
+        .children
+        .iter_mut()
+        .filter(|trak| trak.kind == *b"trak")
+    {
+        let Some(stbl) = trak
+            .child_mut(b"mdia")
+            .and_then(|mdia| mdia.child_mut(b"minf"))
+            .and_then(|minf| minf.child_mut(b"stbl"))
+        else {
+            continue;
+        };
+
+        for table in stbl.children.iter_mut() {
+            let size = match &table.kind {
+                b"stco" => 4,
+                b"co64" => 8,
+                _ => continue,
+            };
+            let count = table
+                .data
+                .get(4..8)
+                .map_or(0, |_| u32_at(&table.data, 4) as usize);
+            if table.data.len() < 8 + count * size {
+                bail!("Truncated chunk offset table");
+            }
+
+            for entry in table.data[8..8 + count * size].chunks_exact_mut(size) {
+                let offset = if size == 4 {
+                    u32_at(entry, 0) as usize
+                } else {
+                    u64_at(entry, 0) as usize
+                };
+                if offset < from {
+                    continue;
+                }
+                let offset = offset.wrapping_add_signed(delta);
+                if size == 4 {
+                    let offset =
+                        u32::try_from(offset).map_err(|_| anyhow!("Chunk offset exceeds 4 GiB"))?;
+                    entry.copy_from_slice(&offset.to_be_bytes());
+                } else {
+                    entry.copy_from_slice(&(offset as u64).to_be_bytes());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn u64_at(data: &[u8], pos: usize) -> u64 {
+    (u64::from(u32_at(data, pos)) << 32) | u64::from(u32_at(data, pos + 4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Full box of a sample table with version and flags 0
+    fn table(kind: &[u8; 4], values: &[u32]) -> Atom {
+        let data = [0u32]
+            .iter()
+            .chain(values)
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        Atom::new(kind, data, Vec::new())
+    }
+
+    fn stbl(stsz: &[u32], chunks: Atom) -> Atom {
+        Atom::new(
+            b"stbl",
+            Vec::new(),
+            vec![
+                table(b"stsz", stsz),
+                // 2 samples in chunk 1, then 1 sample per chunk
+                table(b"stsc", &[2, 1, 2, 1, 2, 1, 1]),
+                chunks,
+            ],
+        )
+    }
+
+    #[test]
+    fn samples_of_chunks() {
+        let stco = table(b"stco", &[2, 100, 200]);
+        assert_eq!(
+            samples(&stbl(&[0, 3, 10, 20, 30], stco), 1000).unwrap(),
+            [100..110, 110..130, 200..230]
+        );
+
+        // 64 bit offsets and samples of a fixed size
+        let co64 = table(b"co64", &[2, 0, 100, 1, 0]);
+        assert_eq!(
+            samples(&stbl(&[4, 3], co64), 1 << 33).unwrap(),
+            [100..104, 104..108, (1 << 32)..(1 << 32) + 4]
+        );
+
+        // more samples than the chunks hold
+        let stco = table(b"stco", &[1, 100]);
+        assert!(samples(&stbl(&[4, 3], stco), 1000).is_err());
+    }
+
+    #[test]
+    fn samples_of_corrupt_count() {
+        // a fixed size sample count that does not fit the file fails instead of allocating
+        let stco = table(b"stco", &[2, 100, 200]);
+        let err = samples(&stbl(&[4, u32::MAX], stco), 1000).unwrap_err();
+        assert!(
+            err.to_string().contains("more than the file holds"),
+            "{err}"
+        );
+
+        // the sizes of variable size samples are missing
+        let stco = table(b"stco", &[2, 100, 200]);
+        assert!(samples(&stbl(&[0, u32::MAX, 10], stco), 1000).is_err());
+    }
+
+    fn moov(chunks: Atom) -> Atom {
+        let stbl = stbl(&[4, 3], chunks);
+        let minf = Atom::new(b"minf", Vec::new(), vec![stbl]);
+        let mdia = Atom::new(b"mdia", Vec::new(), vec![minf]);
+        Atom::new(
+            b"moov",
+            Vec::new(),
+            vec![Atom::new(b"trak", Vec::new(), vec![mdia])],
+        )
+    }
+
+    fn chunks(moov: &Atom) -> Vec<u8> {
+        let stbl = moov.find(&[b"trak", b"mdia", b"minf", b"stbl"]).unwrap();
+        stbl.children[2].data.clone()
+    }
+
+    #[test]
+    fn shift_chunk_offsets_behind() {
+        // only the chunks behind the movie box move
+        let mut stco = moov(table(b"stco", &[2, 100, 200]));
+        shift_chunk_offsets(&mut stco, 150, -20).unwrap();
+        assert_eq!(chunks(&stco), table(b"stco", &[2, 100, 180]).data);
+
+        let mut co64 = moov(table(b"co64", &[2, 0, 100, 1, 0]));
+        shift_chunk_offsets(&mut co64, 150, 16).unwrap();
+        assert_eq!(chunks(&co64), table(b"co64", &[2, 0, 100, 1, 16]).data);
+
+        // 32 bit offsets that overflow fail instead of wrapping
+        let mut stco = moov(table(b"stco", &[1, u32::MAX - 8]));
+        assert!(shift_chunk_offsets(&mut stco, 0, 16).is_err());
+    }
+
+    #[test]
+    fn set_tags_replaces_freeform_items() {
+        let mut moov = moov(table(b"stco", &[2, 100, 200]));
+        assert_eq!(tag(&moov, "MP3GAIN_UNDO"), None);
+
+        let tags = |value: &str| vec![("MP3GAIN_UNDO".to_string(), value.to_string())];
+        set_tags(&mut moov, &tags("+002,+002,N"));
+        let size = moov.to_bytes().len();
+        set_tags(&mut moov, &tags("+004,+004,N"));
+        assert_eq!(moov.to_bytes().len(), size);
+
+        // the tags survive writing and parsing the movie box
+        let data = moov.to_bytes();
+        let moov = Atom::parse(&data, 0..data.len(), 8).unwrap();
+        assert_eq!(tag(&moov, "mp3gain_undo").as_deref(), Some("+004,+004,N"));
+        assert_eq!(
+            moov.find(&[b"udta", b"meta", b"ilst"])
+                .unwrap()
+                .children
+                .len(),
+            1
+        );
+    }
+}
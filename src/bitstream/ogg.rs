@@ -0,0 +1,217 @@
+//! Pages of Ogg bitstreams (RFC 3533).
+
+use anyhow::{bail, Result};
+
+const CAPTURE_PATTERN: &[u8] = b"OggS";
+const HEADER_SIZE: usize = 27;
+/// Lacing values per page
+const MAX_SEGMENTS: usize = 255;
+
+/// The first packet on the page continues from the previous page
+const FLAG_CONTINUED: u8 = 0x01;
+/// First page of a logical bitstream
+pub const FLAG_BOS: u8 = 0x02;
+
+/// Granule position of a page on which no packet ends
+pub const NO_GRANULE: u64 = u64::MAX;
+
+/// CRC-32 lookup table of polynomial 0x04C11DB7
+const CRC32_TABLE: [u32; 256] = crc32_table(0x04c1_1db7);
+
+pub struct Page {
+    pub flags: u8,
+    pub granule: u64,
+    pub serial: u32,
+    pub sequence: u32,
+    /// Lacing values
+    pub segments: Vec<u8>,
+    pub body: Vec<u8>,
+}
+
+impl Page {
+    /// Parse the page at the start of `data` and check its CRC. Returns the page and its size.
+    pub fn parse(data: &[u8]) -> Result<(Page, usize)> {
+        if data.len() < HEADER_SIZE || &data[..4] != CAPTURE_PATTERN {
+            bail!("no Ogg page");
+        }
+        if data[4] != 0 {
+            bail!("unsupported Ogg version {}", data[4]);
+        }
+
+        let segments_end = HEADER_SIZE + usize::from(data[26]);
+        if data.len() < segments_end {
+            bail!("truncated page");
+        }
+        let segments = data[HEADER_SIZE..segments_end].to_vec();
+        let size = segments_end
+            + segments
+                .iter()
+                .map(|segment| usize::from(*segment))
+                .sum::<usize>();
+        if data.len() < size {
+            bail!("truncated page");
+        }
+
+        let page = Page {
+            flags: data[5],
+            granule: u64::from_le_bytes(data[6..14].try_into()?),
+            serial: u32::from_le_bytes(data[14..18].try_into()?),
+            sequence: u32::from_le_bytes(data[18..22].try_into()?),
+            segments,
+            body: data[segments_end..size].to_vec(),
+        };
+
+        let crc = u32::from_le_bytes(data[22..26].try_into()?);
+        if crc != page.crc() {
+            bail!("CRC error, the file is corrupt");
+        }
+
+        Ok((page, size))
+    }
+
+    /// Whether the last packet on the page ends on it
+    pub fn is_complete(&self) -> bool {
+        self.segments.last().is_none_or(|segment| *segment < 255)
+    }
+
+    /// Page with header, lacing values and body
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.header();
+        data[22..26].copy_from_slice(&self.crc().to_le_bytes());
+        data.extend(&self.body);
+        data
+    }
+
+    /// Header with a CRC field of 0
+    fn header(&self) -> Vec<u8> {
+        [
+            CAPTURE_PATTERN,
+            &[0, self.flags],
+            &self.granule.to_le_bytes(),
+            &self.serial.to_le_bytes(),
+            &self.sequence.to_le_bytes(),
+            &[0; 4],
+            &[self.segments.len() as u8],
+            &self.segments,
+        ]
+        .concat()
+    }
+
+    fn crc(&self) -> u32 {
+        crc32(crc32(0, &self.header()), &self.body)
+    }
+}
+
+/// Pages of a packet that starts on a new page and ends its last page
+pub fn paginate(packet: &[u8], serial: u32, sequence: u32, granule: u64) -> Vec<Page> {
+    // a packet whose size is a multiple of 255 ends with a lacing value of 0
+    let mut segments: Vec<u8> = vec![255; packet.len() / 255];
+    segments.push((packet.len() % 255) as u8);
+
+    let mut offset = 0;
+    let pages = segments.chunks(MAX_SEGMENTS).count();
+
+    segments
+        .chunks(MAX_SEGMENTS)
+        .enumerate()
+        .map(|(i, segments)| {
+            let size = segments
+                .iter()
+                .map(|segment| usize::from(*segment))
+                .sum::<usize>();
+            let page = Page {
+                flags: if i > 0 { FLAG_CONTINUED } else { 0 },
+                granule: if i + 1 == pages { granule } else { NO_GRANULE },
+                serial,
+                sequence: sequence.wrapping_add(i as u32),
+                segments: segments.to_vec(),
+                body: packet[offset..offset + size].to_vec(),
+            };
+            offset += size;
+            page
+        })
+        .collect()
+}
+
+const fn crc32_table(poly: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ poly
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 with polynomial 0x04C11DB7, MSB first, without final XOR
+fn crc32(init: u32, data: &[u8]) -> u32 {
+    data.iter().fold(init, |crc, byte| {
+        (crc << 8) ^ CRC32_TABLE[usize::from((crc >> 24) as u8 ^ byte)]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// First page of a stream with the packet "hello" and its CRC
+    const PAGE: [u8; 33] = [
+        b'O', b'g', b'g', b'S', 0, FLAG_BOS, 0, 0, 0, 0, 0, 0, 0, 0, 0x78, 0x56, 0x34, 0x12, 0, 0,
+        0, 0, 0x4b, 0x77, 0x28, 0x06, 1, 5, b'h', b'e', b'l', b'l', b'o',
+    ];
+
+    #[test]
+    fn crc32_check_value() {
+        // CRC-32/POSIX of "123456789" is 0x765e7680 with its final XOR
+        assert_eq!(crc32(0, b"123456789"), !0x765e_7680);
+    }
+
+    #[test]
+    fn parse_page_round_trip() {
+        let (page, size) = Page::parse(&[&PAGE[..], b"OggS"].concat()).unwrap();
+        assert_eq!(size, PAGE.len());
+        assert_eq!(page.flags, FLAG_BOS);
+        assert_eq!(page.serial, 0x1234_5678);
+        assert_eq!(page.body, b"hello");
+        assert!(page.is_complete());
+        assert_eq!(page.to_bytes(), PAGE);
+    }
+
+    #[test]
+    fn parse_rejects_corrupt_page() {
+        let mut data = PAGE;
+        data[32] = b'O';
+        assert!(Page::parse(&data).is_err());
+        assert!(Page::parse(&PAGE[..30]).is_err());
+    }
+
+    #[test]
+    fn paginate_long_packet() {
+        let packet: Vec<u8> = (0..255 * 255 + 10).map(|i| i as u8).collect();
+
+        let pages = paginate(&packet, 7, 3, 960);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].segments, vec![255; 255]);
+        assert_eq!(pages[0].granule, NO_GRANULE);
+        assert!(!pages[0].is_complete());
+        assert_eq!(pages[1].flags, FLAG_CONTINUED);
+        assert_eq!(pages[1].sequence, 4);
+        assert_eq!(pages[1].granule, 960);
+        assert_eq!(pages[1].segments, [10]);
+
+        let data: Vec<u8> = pages.iter().flat_map(Page::to_bytes).collect();
+        let (first, size) = Page::parse(&data).unwrap();
+        let (second, _) = Page::parse(&data[size..]).unwrap();
+        assert_eq!([first.body, second.body].concat(), packet);
+    }
+}
@@ -0,0 +1,397 @@
+//! Output gain of Ogg Opus files (RFC 7845) in the `OpusHead` header and the R128 gain tags.
+
+use super::ogg::{self, Page, FLAG_BOS, NO_GRANULE};
+use super::vorbis::{self, Comments};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const HEAD_MAGIC: &[u8] = b"OpusHead";
+const TAGS_MAGIC: &[u8] = b"OpusTags";
+
+/// Byte offset of the Q7.8 output gain in `OpusHead`
+const OUTPUT_GAIN: usize = 16;
+
+/// Reference level of the R128 gain tags in LUFS
+const REFERENCE_LEVEL: f64 = -23.0;
+const TRACK_GAIN_KEY: &str = "R128_TRACK_GAIN";
+const ALBUM_GAIN_KEY: &str = "R128_ALBUM_GAIN";
+
+/// Opus is always decoded at 48 kHz
+const SAMPLE_RATE: f64 = 48000.0;
+
+/// Result of an output gain change
+pub struct Summary {
+    pub streams: usize,
+    /// Distinct output gains in dB before the change in order of appearance
+    pub previous: Vec<f64>,
+    /// Streams whose output gain was limited to the range of Q7.8 values
+    pub clamped: usize,
+    pub duration: Duration,
+}
+
+enum State {
+    Head,
+    /// Collecting the comment header that starts on page `sequence`
+    Tags {
+        packet: Vec<u8>,
+        sequence: u32,
+        pages: u32,
+    },
+    Audio,
+}
+
+struct Stream {
+    state: State,
+    /// Q7.8 change of the output gain
+    delta: i32,
+    pre_skip: u64,
+    /// Change of page sequence numbers by a longer or shorter comment header
+    shift: u32,
+    granule: u64,
+}
+
+/// Add `gain` in dB to the output gain of an Ogg Opus file. If `loudness` is the measured integrated
+/// loudness of the input in LUFS, `R128_TRACK_GAIN` is set, otherwise it is adjusted if present.
+/// `tags` are added to the comment header.
+pub fn rewrite_file(
+    input: &Path,
+    output: &Path,
+    gain: f64,
+    loudness: Option<f64>,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let data = fs::read(input)
+        .with_context(|| format!("Failed to read input file \"{}\"", input.display()))?;
+
+    let (data, summary) = apply_gain(&data, gain, loudness, tags)
+        .with_context(|| format!("Failed to change output gain of \"{}\"", input.display()))?;
+
+    fs::write(output, data)
+        .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?;
+
+    Ok(summary)
+}
+
+pub fn apply_gain(
+    data: &[u8],
+    gain: f64,
+    loudness: Option<f64>,
+    tags: &[(String, String)],
+) -> Result<(Vec<u8>, Summary)> {
+    let gain = q78(gain);
+    let mut output = Vec::with_capacity(data.len());
+    let mut streams: HashMap<u32, Stream> = HashMap::new();
+    let mut summary = Summary {
+        streams: 0,
+        previous: Vec::new(),
+        clamped: 0,
+        duration: Duration::ZERO,
+    };
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let (mut page, size) = Page::parse(&data[offset..])
+            .with_context(|| format!("Invalid Ogg page at byte offset {offset}"))?;
+        let raw = &data[offset..offset + size];
+
+        if page.flags & FLAG_BOS != 0 && page.body.starts_with(HEAD_MAGIC) {
+            // a chained stream may reuse the serial number
+            if let Some(stream) = streams.remove(&page.serial) {
+                summary.duration += stream.duration();
+            }
+            streams.insert(
+                page.serial,
+                Stream {
+                    state: State::Head,
+                    delta: 0,
+                    pre_skip: 0,
+                    shift: 0,
+                    granule: 0,
+                },
+            );
+        }
+
+        let Some(stream) = streams.get_mut(&page.serial) else {
+            // other logical streams are kept
+            output.extend(raw);
+            offset += size;
+            continue;
+        };
+
+        match &mut stream.state {
+            State::Head => {
+                let head = &mut page.body;
+                if head.len() < 19 || page.segments.len() != 1 {
+                    bail!("Invalid OpusHead header at byte offset {offset}");
+                }
+                if head[8] & 0xf0 != 0 {
+                    bail!("Unsupported Opus version {}", head[8]);
+                }
+
+                let previous = i16::from_le_bytes([head[OUTPUT_GAIN], head[OUTPUT_GAIN + 1]]);
+                let changed = i32::from(previous) + gain;
+                let output_gain = changed.clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+                if i32::from(output_gain) != changed {
+                    summary.clamped += 1;
+                }
+                head[OUTPUT_GAIN..OUTPUT_GAIN + 2].copy_from_slice(&output_gain.to_le_bytes());
+
+                stream.delta = i32::from(output_gain) - i32::from(previous);
+                let previous = f64::from(previous) / 256.0;
+                if !summary.previous.contains(&previous) {
+                    summary.previous.push(previous);
+                }
+                stream.pre_skip = u64::from(u16::from_le_bytes([head[10], head[11]]));
+                summary.streams += 1;
+
+                output.extend(page.to_bytes());
+                stream.state = State::Tags {
+                    packet: Vec::new(),
+                    sequence: page.sequence.wrapping_add(1),
+                    pages: 0,
+                };
+            }
+            State::Tags {
+                packet,
+                sequence,
+                pages,
+            } => {
+                packet.extend(&page.body);
+                *pages += 1;
+                if !page.is_complete() {
+                    offset += size;
+                    continue;
+                }
+                if page
+                    .segments
+                    .iter()
+                    .rev()
+                    .skip(1)
+                    .any(|segment| *segment < 255)
+                {
+                    bail!("OpusTags header does not end its page at byte offset {offset}");
+                }
+
+                let applied = f64::from(stream.delta) / 256.0;
+                let track_gain = loudness.map(|loudness| q78(REFERENCE_LEVEL - loudness - applied));
+                let tags = change_tags(packet, stream.delta, track_gain, tags)
+                    .with_context(|| format!("Invalid OpusTags header at byte offset {offset}"))?;
+
+                let tag_pages = ogg::paginate(&tags, page.serial, *sequence, 0);
+                stream.shift = (tag_pages.len() as u32).wrapping_sub(*pages);
+                tag_pages
+                    .iter()
+                    .for_each(|page| output.extend(page.to_bytes()));
+                stream.state = State::Audio;
+            }
+            State::Audio => {
+                if page.granule != NO_GRANULE {
+                    stream.granule = page.granule;
+                }
+                if stream.shift == 0 {
+                    output.extend(raw);
+                } else {
+                    page.sequence = page.sequence.wrapping_add(stream.shift);
+                    output.extend(page.to_bytes());
+                }
+            }
+        }
+
+        offset += size;
+    }
+
+    if summary.streams == 0 {
+        bail!("No Opus stream found");
+    }
+    if streams
+        .values()
+        .any(|stream| !matches!(stream.state, State::Audio))
+    {
+        bail!("Truncated Opus headers");
+    }
+    summary.duration += streams.values().map(Stream::duration).sum::<Duration>();
+
+    Ok((output, summary))
+}
+
+impl Stream {
+    fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.granule.saturating_sub(self.pre_skip) as f64 / SAMPLE_RATE)
+    }
+}
+
+/// Adjust the R128 gain tags, which are relative to the output gain, by `-delta`.
+/// `track_gain` replaces `R128_TRACK_GAIN` if given, `tags` are set.
+fn change_tags(
+    packet: &[u8],
+    delta: i32,
+    track_gain: Option<i32>,
+    tags: &[(String, String)],
+) -> Result<Vec<u8>> {
+    if !packet.starts_with(TAGS_MAGIC) {
+        bail!("no OpusTags magic");
+    }
+
+    let (mut comments, size) = Comments::parse(&packet[TAGS_MAGIC.len()..])?;
+
+    let mut has_track_gain = false;
+    comments.comments.iter_mut().for_each(|comment| {
+        let (key, value) = vorbis::split(comment);
+        let is_track_gain = key.eq_ignore_ascii_case(TRACK_GAIN_KEY.as_bytes());
+        has_track_gain |= is_track_gain;
+
+        let gain = match (track_gain, is_track_gain) {
+            (Some(track_gain), true) => Some(track_gain),
+            _ if is_track_gain || key.eq_ignore_ascii_case(ALBUM_GAIN_KEY.as_bytes()) => {
+                // keep a tag that cannot be parsed
+                std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<i32>().ok())
+                    .map(|gain| gain - delta)
+            }
+            _ => None,
+        };
+        if let Some(gain) = gain {
+            *comment = gain_tag(key, gain);
+        }
+    });
+    if let (Some(track_gain), false) = (track_gain, has_track_gain) {
+        comments
+            .comments
+            .push(gain_tag(TRACK_GAIN_KEY.as_bytes(), track_gain));
+    }
+    tags.iter()
+        .for_each(|(key, value)| comments.set(key, value));
+
+    // binary data of other applications after the comments
+    Ok([
+        TAGS_MAGIC,
+        &comments.to_bytes(),
+        &packet[TAGS_MAGIC.len() + size..],
+    ]
+    .concat())
+}
+
+fn gain_tag(key: &[u8], gain: i32) -> Vec<u8> {
+    let gain = gain.clamp(i16::MIN.into(), i16::MAX.into());
+    [key, b"=", gain.to_string().as_bytes()].concat()
+}
+
+/// Gain in dB as Q7.8 fixed point
+fn q78(gain: f64) -> i32 {
+    (gain * 256.0).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERIAL: u32 = 0x1234_5678;
+
+    /// Ogg Opus stream of stereo with `output_gain` in Q7.8, `comments` and one second of audio
+    fn stream(output_gain: i16, comments: &[&str]) -> Vec<u8> {
+        let head = [
+            HEAD_MAGIC,
+            &[1, 2],
+            &312u16.to_le_bytes(),
+            &48000u32.to_le_bytes(),
+            &output_gain.to_le_bytes(),
+            &[0],
+        ]
+        .concat();
+        let mut tags = Comments::new("test");
+        tags.comments = comments
+            .iter()
+            .map(|comment| comment.as_bytes().to_vec())
+            .collect();
+        let tags = [TAGS_MAGIC, &tags.to_bytes()].concat();
+
+        let mut pages = ogg::paginate(&head, SERIAL, 0, 0);
+        pages[0].flags = FLAG_BOS;
+        pages.extend(ogg::paginate(&tags, SERIAL, 1, 0));
+        pages.extend(ogg::paginate(&[0xfc; 20], SERIAL, 2, 48000 + 312));
+        pages.iter().flat_map(Page::to_bytes).collect()
+    }
+
+    fn output_gain(data: &[u8]) -> i16 {
+        let (page, _) = Page::parse(data).unwrap();
+        i16::from_le_bytes([page.body[OUTPUT_GAIN], page.body[OUTPUT_GAIN + 1]])
+    }
+
+    fn comments(data: &[u8]) -> Vec<String> {
+        let (_, size) = Page::parse(data).unwrap();
+        let (page, _) = Page::parse(&data[size..]).unwrap();
+        let (comments, _) = Comments::parse(&page.body[TAGS_MAGIC.len()..]).unwrap();
+        comments
+            .comments
+            .iter()
+            .map(|comment| String::from_utf8_lossy(comment).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn apply_gain_round_trip() {
+        let original = stream(-256, &["TITLE=test"]);
+
+        let (data, summary) = apply_gain(&original, 3.5, None, &[]).unwrap();
+        assert_eq!(summary.streams, 1);
+        assert_eq!(summary.previous, [-1.0]);
+        assert_eq!(summary.clamped, 0);
+        assert_eq!(summary.duration, Duration::from_secs(1));
+        assert_eq!(output_gain(&data), 640);
+
+        let (data, summary) = apply_gain(&data, -3.5, None, &[]).unwrap();
+        assert_eq!(summary.previous, [2.5]);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn apply_gain_saturates_output_gain() {
+        let (data, summary) = apply_gain(&stream(32000, &[]), 10.0, None, &[]).unwrap();
+        assert_eq!(summary.clamped, 1);
+        assert_eq!(output_gain(&data), i16::MAX);
+
+        let (data, summary) = apply_gain(&stream(-32000, &[]), -10.0, None, &[]).unwrap();
+        assert_eq!(summary.clamped, 1);
+        assert_eq!(output_gain(&data), i16::MIN);
+    }
+
+    #[test]
+    fn apply_gain_sets_track_gain() {
+        let original = stream(0, &["TITLE=test", "R128_ALBUM_GAIN=100"]);
+        let tags = [(
+            "NORMALIZER".to_string(),
+            "ffmpeg-audio-normalizer".to_string(),
+        )];
+
+        // the track gain is relative to the new output gain of -3 dB
+        let (data, _) = apply_gain(&original, -3.0, Some(-18.0), &tags).unwrap();
+        assert_eq!(output_gain(&data), -768);
+        assert_eq!(
+            comments(&data),
+            [
+                "TITLE=test",
+                "R128_ALBUM_GAIN=868",
+                "R128_TRACK_GAIN=-512",
+                "NORMALIZER=ffmpeg-audio-normalizer"
+            ]
+        );
+
+        // an existing track gain is adjusted by the gain change
+        let (data, _) = apply_gain(&data, 1.0, None, &[]).unwrap();
+        assert!(comments(&data).contains(&"R128_TRACK_GAIN=-768".to_string()));
+    }
+
+    #[test]
+    fn apply_gain_rejects_other_streams() {
+        let mut pages = ogg::paginate(b"\x01vorbis", SERIAL, 0, 0);
+        pages[0].flags = FLAG_BOS;
+        let data: Vec<u8> = pages.iter().flat_map(Page::to_bytes).collect();
+
+        let err = apply_gain(&data, 1.0, None, &[]).err().unwrap();
+        assert_eq!(err.to_string(), "No Opus stream found");
+    }
+}
@@ -0,0 +1,88 @@
+//! Chunks of RIFF WAVE files.
+
+use super::read_full;
+use anyhow::{bail, Result};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// Size of the RIFF header with the WAVE form type
+pub const HEADER_SIZE: usize = 12;
+/// Size of the id and size of a chunk
+pub const CHUNK_HEADER_SIZE: usize = 8;
+
+/// Chunk of a file
+pub struct Chunk {
+    pub id: [u8; 4],
+    /// Start of the chunk header
+    pub start: usize,
+    /// Chunk data without the pad byte
+    pub data: Range<usize>,
+    /// End of the chunk data including the pad byte
+    pub end: usize,
+}
+
+/// Fail if `header` is not the start of a RIFF WAVE file
+pub fn check_header(header: &[u8]) -> Result<()> {
+    if header.len() < HEADER_SIZE || &header[..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        bail!("No RIFF WAVE file, RF64 is not supported");
+    }
+    Ok(())
+}
+
+/// Id and data size of a chunk header
+pub fn chunk_header(header: &[u8]) -> ([u8; 4], u32) {
+    (
+        [header[0], header[1], header[2], header[3]],
+        u32_at(header, 4),
+    )
+}
+
+/// Size of chunk data, which is padded to an even size
+pub fn padded(size: u64) -> u64 {
+    size + (size & 1)
+}
+
+/// Chunk of `data` with its header and pad byte
+pub fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut chunk = [id, &(data.len() as u32).to_le_bytes(), data].concat();
+    if !data.len().is_multiple_of(2) {
+        chunk.push(0);
+    }
+    chunk
+}
+
+/// Chunks of a RIFF WAVE file, the last one may be truncated. Only the chunk headers are read.
+pub fn chunks(reader: &mut (impl Read + Seek)) -> Result<Vec<Chunk>> {
+    let len = reader.seek(SeekFrom::End(0))? as usize;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; HEADER_SIZE];
+    let read = read_full(reader, &mut header)?;
+    check_header(&header[..read])?;
+
+    let mut chunks = Vec::new();
+    let mut pos = HEADER_SIZE;
+    while pos + CHUNK_HEADER_SIZE <= len {
+        let mut header = [0u8; CHUNK_HEADER_SIZE];
+        reader.seek(SeekFrom::Start(pos as u64))?;
+        reader.read_exact(&mut header)?;
+        let (id, size) = chunk_header(&header);
+        let start = pos + CHUNK_HEADER_SIZE;
+        // the size of streamed files may be unknown
+        let end = start
+            .saturating_add(padded(u64::from(size)) as usize)
+            .min(len);
+        chunks.push(Chunk {
+            id,
+            start: pos,
+            data: start..start.saturating_add(size as usize).min(len),
+            end,
+        });
+        pos = end;
+    }
+
+    Ok(chunks)
+}
+
+pub fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
@@ -0,0 +1,87 @@
+//! Vorbis comments of the `OpusTags` header and the FLAC `VORBIS_COMMENT` metadata block.
+
+use anyhow::{Context, Result};
+
+pub struct Comments {
+    pub vendor: Vec<u8>,
+    /// "KEY=value" fields in order of appearance
+    pub comments: Vec<Vec<u8>>,
+}
+
+impl Comments {
+    pub fn new(vendor: &str) -> Comments {
+        Comments {
+            vendor: vendor.as_bytes().to_vec(),
+            comments: Vec::new(),
+        }
+    }
+
+    /// Parse the comments at the start of `data`. Returns the comments and their size.
+    pub fn parse(data: &[u8]) -> Result<(Comments, usize)> {
+        let mut pos = 0;
+        let vendor = read_field(data, &mut pos)?.to_vec();
+        let count = u32_at(data, pos)?;
+        pos += 4;
+
+        let comments = (0..count)
+            .map(|_| read_field(data, &mut pos).map(<[u8]>::to_vec))
+            .collect::<Result<_>>()?;
+
+        Ok((Comments { vendor, comments }, pos))
+    }
+
+    /// Set a comment, replacing all comments of the same key, which is case insensitive
+    pub fn set(&mut self, key: &str, value: &str) {
+        let comment = [key.as_bytes(), b"=", value.as_bytes()].concat();
+        let has_key = |comment: &Vec<u8>| split(comment).0.eq_ignore_ascii_case(key.as_bytes());
+
+        match self.comments.iter().position(has_key) {
+            Some(pos) => {
+                self.comments[pos] = comment;
+                let rest = self.comments.split_off(pos + 1);
+                self.comments
+                    .extend(rest.into_iter().filter(|comment| !has_key(comment)));
+            }
+            None => self.comments.push(comment),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_field(&mut data, &self.vendor);
+        data.extend((self.comments.len() as u32).to_le_bytes());
+        self.comments
+            .iter()
+            .for_each(|comment| write_field(&mut data, comment));
+        data
+    }
+}
+
+/// Key and value of a comment, a comment without "=" has an empty value
+pub fn split(comment: &[u8]) -> (&[u8], &[u8]) {
+    match comment.iter().position(|byte| *byte == b'=') {
+        Some(i) => (&comment[..i], &comment[i + 1..]),
+        None => (comment, &[][..]),
+    }
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Result<u32> {
+    data.get(pos..pos + 4)
+        .map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+        .with_context(|| "truncated comments")
+}
+
+/// Field with a 32 bit length in front
+fn read_field<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8]> {
+    let len = u32_at(data, *pos)? as usize;
+    let field = data
+        .get(*pos + 4..*pos + 4 + len)
+        .with_context(|| "truncated comments")?;
+    *pos += 4 + len;
+    Ok(field)
+}
+
+fn write_field(data: &mut Vec<u8>, field: &[u8]) {
+    data.extend((field.len() as u32).to_le_bytes());
+    data.extend(field);
+}
@@ -0,0 +1,457 @@
+//! Gain of the samples of RIFF WAVE files. All chunks but the sample data and the coding
+//! history of the bext chunk are kept unchanged.
+
+use super::gain::{Processor, Quantizer, Settings, Summary};
+use super::{bext, read_full, riff};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Sample frames processed at a time
+const FRAMES_PER_CHUNK: usize = 4096;
+
+enum Encoding {
+    /// 8 bit samples with an offset of 128
+    Unsigned,
+    /// `bits` valid bits, left-justified in `bytes` bytes
+    Signed {
+        bytes: usize,
+        bits: u32,
+    },
+    Float {
+        bytes: usize,
+    },
+}
+
+struct Format {
+    encoding: Encoding,
+    channels: usize,
+    sample_rate: u32,
+}
+
+/// Apply gain to the samples of a WAV file and add `tags` to the coding history of its bext chunk.
+pub fn rewrite_file(
+    input: &Path,
+    output: &Path,
+    settings: &Settings,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let mut reader = BufReader::new(
+        File::open(input)
+            .with_context(|| format!("Failed to read input file \"{}\"", input.display()))?,
+    );
+    let mut writer = BufWriter::new(
+        File::create(output)
+            .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?,
+    );
+
+    let summary = apply_gain(&mut reader, &mut writer, settings, tags)
+        .with_context(|| format!("Failed to change gain of \"{}\"", input.display()))?;
+
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write output file \"{}\"", output.display()))?;
+
+    Ok(summary)
+}
+
+/// Copy the WAV file from `input` to `output` and apply gain to the samples of its data chunk.
+/// `tags` are added to the coding history of the bext chunk, which is appended if there is none.
+pub fn apply_gain(
+    input: &mut impl Read,
+    output: &mut (impl Write + Seek),
+    settings: &Settings,
+    tags: &[(String, String)],
+) -> Result<Summary> {
+    let start = output.stream_position()?;
+    let mut header = [0u8; riff::HEADER_SIZE];
+    let read = read_full(input, &mut header)?;
+    riff::check_header(&header[..read])?;
+    output.write_all(&header)?;
+
+    let mut format = None;
+    let mut summary = None;
+    let mut has_bext = false;
+    // the data chunk ends with the file if its size is unknown
+    let mut is_complete = true;
+    // change of the size of the RIFF chunk by the bext chunk
+    let mut growth = 0i64;
+
+    let rest = loop {
+        let mut chunk = [0u8; riff::CHUNK_HEADER_SIZE];
+        let read = read_full(input, &mut chunk)?;
+        if read < chunk.len() {
+            break chunk[..read].to_vec();
+        }
+        let (id, size) = riff::chunk_header(&chunk);
+        let size = u64::from(size);
+        let padding = riff::padded(size) - size;
+
+        match &id {
+            b"fmt " => {
+                let mut data = Vec::new();
+                input.by_ref().take(size + padding).read_to_end(&mut data)?;
+                output.write_all(&chunk)?;
+                output.write_all(&data)?;
+                format = Some(parse_format(&data[..data.len().min(size as usize)])?);
+            }
+            b"data" => {
+                let Some(format) = &format else {
+                    bail!("No fmt chunk before the data chunk");
+                };
+                output.write_all(&chunk)?;
+                let mut data = input.by_ref().take(size);
+                summary = Some(process(&mut data, output, format, settings)?);
+                is_complete = data.limit() == 0;
+                io::copy(&mut input.by_ref().take(padding), output)?;
+            }
+            id if id == bext::CHUNK_ID && !tags.is_empty() => {
+                let mut fields = Vec::new();
+                input
+                    .by_ref()
+                    .take(size + padding)
+                    .read_to_end(&mut fields)?;
+                let previous = fields.len();
+                fields.truncate(size as usize);
+                bext::set_coding_history(&mut fields, tags);
+                let chunk = riff::chunk(bext::CHUNK_ID, &fields);
+                output.write_all(&chunk)?;
+                growth += chunk.len() as i64 - (riff::CHUNK_HEADER_SIZE + previous) as i64;
+                has_bext = true;
+            }
+            _ => {
+                output.write_all(&chunk)?;
+                io::copy(&mut input.by_ref().take(size + padding), output)?;
+            }
+        }
+    };
+
+    if format.is_none() {
+        bail!("No fmt chunk");
+    }
+    let summary = summary.ok_or_else(|| anyhow!("No data chunk"))?;
+
+    if !has_bext && !tags.is_empty() && is_complete {
+        let mut fields = Vec::new();
+        bext::set_coding_history(&mut fields, tags);
+        let chunk = riff::chunk(bext::CHUNK_ID, &fields);
+        output.write_all(&chunk)?;
+        growth += chunk.len() as i64;
+    }
+    output.write_all(&rest)?;
+
+    if growth != 0 {
+        let end = output.stream_position()?;
+        let size = i64::from(riff::u32_at(&header, 4)) + growth;
+        output.seek(SeekFrom::Start(start + 4))?;
+        output.write_all(&(size.clamp(0, i64::from(u32::MAX)) as u32).to_le_bytes())?;
+        output.seek(SeekFrom::Start(end))?;
+    }
+
+    Ok(summary)
+}
+
+/// Apply gain to the samples of the data chunk, a partial sample frame at the end is kept
+fn process(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    format: &Format,
+    settings: &Settings,
+) -> Result<Summary> {
+    let bytes = match format.encoding {
+        Encoding::Unsigned => 1,
+        Encoding::Signed { bytes, .. } | Encoding::Float { bytes } => bytes,
+    };
+    let frame = bytes * format.channels;
+
+    let mut processor = Processor::new(settings, format.channels, format.sample_rate);
+    let mut quantizer = match format.encoding {
+        Encoding::Unsigned => Quantizer::new(8, settings.dither),
+        Encoding::Signed { bits, .. } => Quantizer::new(bits, settings.dither),
+        Encoding::Float { .. } => Quantizer::new(32, false),
+    };
+    let mut clipped = 0;
+    let mut encode = |values: &[f64], output: &mut dyn Write| -> io::Result<()> {
+        let mut data = vec![0u8; values.len() * bytes];
+        data.chunks_exact_mut(bytes)
+            .zip(values)
+            .for_each(|(sample, value)| match format.encoding {
+                Encoding::Unsigned => sample[0] = (quantizer.quantize(*value) + 128) as u8,
+                Encoding::Signed { bytes, bits } => {
+                    let value = quantizer.quantize(*value) << (32 - bits);
+                    sample.copy_from_slice(&value.to_le_bytes()[4 - bytes..]);
+                }
+                Encoding::Float { bytes } => {
+                    // float samples can exceed full scale
+                    if value.abs() > 1.0 {
+                        clipped += 1;
+                    }
+                    if bytes == 4 {
+                        sample.copy_from_slice(&(*value as f32).to_le_bytes());
+                    } else {
+                        sample.copy_from_slice(&value.to_le_bytes());
+                    }
+                }
+            });
+        output.write_all(&data)
+    };
+
+    let mut buffer = vec![0u8; FRAMES_PER_CHUNK * frame];
+    let mut values = Vec::with_capacity(FRAMES_PER_CHUNK * format.channels);
+    let mut processed = Vec::with_capacity(values.capacity());
+    let mut count = 0;
+
+    let rest = loop {
+        let read = read_full(input, &mut buffer)?;
+        let whole = read / frame * frame;
+
+        values.clear();
+        values.extend(
+            buffer[..whole]
+                .chunks_exact(bytes)
+                .map(|sample| decode(&format.encoding, sample)),
+        );
+        count += values.len();
+
+        processed.clear();
+        processor.process(&values, &mut processed);
+        encode(&processed, output)?;
+
+        if read < buffer.len() {
+            break buffer[whole..read].to_vec();
+        }
+    };
+
+    processed.clear();
+    processor.finish(&mut processed);
+    encode(&processed, output)?;
+    output.write_all(&rest)?;
+
+    Ok(Summary {
+        samples: count,
+        limited: processor.limited,
+        clipped: clipped + quantizer.clipped,
+        duration: Duration::from_secs_f64(
+            (count / format.channels) as f64 / f64::from(format.sample_rate),
+        ),
+    })
+}
+
+/// Sample with a full scale of 1.0
+fn decode(encoding: &Encoding, sample: &[u8]) -> f64 {
+    match encoding {
+        Encoding::Unsigned => (f64::from(sample[0]) - 128.0) / 128.0,
+        Encoding::Signed { bytes, .. } => {
+            // sign extend the little endian value
+            let mut value = [0u8; 4];
+            value[4 - bytes..].copy_from_slice(sample);
+            f64::from(i32::from_le_bytes(value)) / 2f64.powi(31)
+        }
+        Encoding::Float { bytes: 4 } => f64::from(f32::from_le_bytes(sample.try_into().unwrap())),
+        Encoding::Float { .. } => f64::from_le_bytes(sample.try_into().unwrap()),
+    }
+}
+
+fn parse_format(chunk: &[u8]) -> Result<Format> {
+    if chunk.len() < 16 {
+        bail!("Truncated fmt chunk");
+    }
+
+    let mut tag = u16_at(chunk, 0);
+    let channels = usize::from(u16_at(chunk, 2));
+    let sample_rate = riff::u32_at(chunk, 4);
+    let block_align = usize::from(u16_at(chunk, 12));
+    let mut bits = u32::from(u16_at(chunk, 14));
+
+    if tag == FORMAT_EXTENSIBLE {
+        if chunk.len() < 26 {
+            bail!("Truncated fmt chunk");
+        }
+        let valid_bits = u32::from(u16_at(chunk, 18));
+        if valid_bits > 0 {
+            bits = bits.min(valid_bits);
+        }
+        // the first two bytes of the sub format GUID are the format tag
+        tag = u16_at(chunk, 24);
+    }
+
+    if channels == 0 || sample_rate == 0 || block_align % channels != 0 {
+        bail!("Invalid fmt chunk");
+    }
+    let bytes = block_align / channels;
+
+    let encoding = match (tag, bytes) {
+        (FORMAT_PCM, 1) => Encoding::Unsigned,
+        (FORMAT_PCM, 2..=4) if bits > 1 && bits as usize <= bytes * 8 => {
+            Encoding::Signed { bytes, bits }
+        }
+        (FORMAT_FLOAT, 4 | 8) => Encoding::Float { bytes },
+        _ => bail!("Unsupported sample format {tag:#06x} with {bytes} bytes per sample"),
+    };
+
+    Ok(Format {
+        encoding,
+        channels,
+        sample_rate,
+    })
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Gain that doubles the samples
+    const DOUBLE: f64 = 6.020599913279624;
+
+    /// WAV file of `format` and `bits` with the sample `data` and a LIST chunk in front of it
+    fn wav(format: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits.div_ceil(8);
+        let fmt = [
+            &format.to_le_bytes()[..],
+            &channels.to_le_bytes(),
+            &48000u32.to_le_bytes(),
+            &(48000 * u32::from(block_align)).to_le_bytes(),
+            &block_align.to_le_bytes(),
+            &bits.to_le_bytes(),
+        ]
+        .concat();
+        let chunks = [
+            riff::chunk(b"fmt ", &fmt),
+            riff::chunk(b"LIST", b"INFOISFT\x03\0\0\0abc\0"),
+            riff::chunk(b"data", data),
+        ]
+        .concat();
+        [
+            &b"RIFF"[..],
+            &(chunks.len() as u32 + 4).to_le_bytes(),
+            b"WAVE",
+            &chunks,
+        ]
+        .concat()
+    }
+
+    fn pcm16(samples: &[i16]) -> Vec<u8> {
+        samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect()
+    }
+
+    fn run(data: &[u8], gain: f64, dither: bool, tags: &[(String, String)]) -> (Vec<u8>, Summary) {
+        let settings = Settings {
+            gain,
+            limiter: None,
+            dither,
+        };
+        let mut output = Cursor::new(Vec::new());
+        let summary = apply_gain(&mut &data[..], &mut output, &settings, tags).unwrap();
+        (output.into_inner(), summary)
+    }
+
+    /// Data of the chunk `id`
+    fn chunk<'a>(data: &'a [u8], id: &[u8]) -> &'a [u8] {
+        let chunks = riff::chunks(&mut Cursor::new(data)).unwrap();
+        let chunk = chunks.iter().find(|chunk| chunk.id == id).unwrap();
+        &data[chunk.data.clone()]
+    }
+
+    #[test]
+    fn apply_gain_round_trip() {
+        let samples: Vec<i16> = (0..2000).map(|n| (n * 7 % 20001 - 10000) as i16).collect();
+        let original = wav(FORMAT_PCM, 2, 16, &pcm16(&samples));
+
+        let (data, summary) = run(&original, DOUBLE, false, &[]);
+        assert_eq!(summary.samples, 2000);
+        assert_eq!(summary.clipped, 0);
+        assert_eq!(summary.duration, Duration::from_secs_f64(1000.0 / 48000.0));
+        let doubled: Vec<i16> = samples.iter().map(|sample| sample * 2).collect();
+        assert_eq!(chunk(&data, b"data"), pcm16(&doubled));
+        assert_eq!(chunk(&data, b"LIST"), chunk(&original, b"LIST"));
+
+        let (data, _) = run(&data, -DOUBLE, false, &[]);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn apply_gain_of_other_formats() {
+        // 8 bit unsigned, 24 bit and 32 bit float samples
+        let unsigned: Vec<u8> = (0..256).map(|n| (n / 2 + 64) as u8).collect();
+        let signed: Vec<u8> = (0..300i32)
+            .flat_map(|n| ((n - 150) * 20000).to_le_bytes()[..3].to_vec())
+            .collect();
+        let float: Vec<u8> = (0..100)
+            .flat_map(|n| (n as f32 / 200.0 - 0.25).to_le_bytes())
+            .collect();
+
+        for (format, bits, data) in [
+            (FORMAT_PCM, 8, unsigned),
+            (FORMAT_PCM, 24, signed),
+            (FORMAT_FLOAT, 32, float),
+        ] {
+            let original = wav(format, 1, bits, &data);
+            let (doubled, summary) = run(&original, DOUBLE, false, &[]);
+            assert_eq!(summary.clipped, 0);
+            assert_ne!(chunk(&doubled, b"data"), data);
+            let (data, _) = run(&doubled, -DOUBLE, false, &[]);
+            assert_eq!(data, original);
+        }
+    }
+
+    #[test]
+    fn apply_gain_dithers_by_one_step() {
+        let samples: Vec<i16> = (0..10000).map(|n| (n % 1000 - 500) as i16).collect();
+        let original = wav(FORMAT_PCM, 1, 16, &pcm16(&samples));
+
+        let (data, _) = run(&original, 0.0, true, &[]);
+        let differences: Vec<i32> = chunk(&data, b"data")
+            .chunks_exact(2)
+            .zip(&samples)
+            .map(|(sample, original)| {
+                i32::from(i16::from_le_bytes([sample[0], sample[1]])) - i32::from(*original)
+            })
+            .collect();
+        assert!(differences.iter().all(|difference| difference.abs() <= 1));
+        let changed = differences
+            .iter()
+            .filter(|difference| **difference != 0)
+            .count();
+        // 1/4 of the triangular dither is rounded to another step
+        assert!((2000..3000).contains(&changed));
+        assert!(differences.iter().sum::<i32>().abs() < 200);
+    }
+
+    #[test]
+    fn apply_gain_clips_integer_samples() {
+        let original = wav(FORMAT_PCM, 1, 16, &pcm16(&[30000, -30000, 100]));
+
+        let (data, summary) = run(&original, DOUBLE, false, &[]);
+        assert_eq!(summary.clipped, 2);
+        assert_eq!(chunk(&data, b"data"), pcm16(&[i16::MAX, i16::MIN, 200]));
+    }
+
+    #[test]
+    fn apply_gain_adds_coding_history() {
+        let original = wav(FORMAT_PCM, 1, 16, &pcm16(&[1, 2, 3]));
+        let tags = [(
+            "NORMALIZER".to_string(),
+            "ffmpeg-audio-normalizer".to_string(),
+        )];
+
+        let (data, _) = run(&original, 0.0, false, &tags);
+        assert_eq!(riff::u32_at(&data, 4) as usize, data.len() - 8);
+        let history = String::from_utf8_lossy(&chunk(&data, bext::CHUNK_ID)[602..]).to_string();
+        assert_eq!(history, "T=NORMALIZER=ffmpeg-audio-normalizer\r\n");
+        assert_eq!(chunk(&data, b"data"), pcm16(&[1, 2, 3]));
+    }
+}
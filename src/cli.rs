@@ -1,7 +1,13 @@
+use crate::algorithm::range::{parse_time, parse_time_range, MeasureRange};
+use crate::algorithm::{dialogue, ebu_r128};
+use crate::plot;
+use crate::progress;
+use crate::tool::codec::{parse_bit_rate, parse_codec_mapping};
 use clap::builder::RangedI64ValueParser;
 use clap::builder::TypedValueParser;
 use clap::{
-    crate_authors, crate_description, crate_name, crate_version, error::ErrorKind, Error, Parser,
+    crate_authors, crate_description, crate_name, crate_version, error::ErrorKind, Args, Error,
+    Parser,
 };
 use core::ops::RangeBounds;
 use std::path::PathBuf;
@@ -16,20 +22,181 @@ pub struct Cli {
     #[arg(long)]
     pub verbose: bool,
 
+    /// Print the ffmpeg commands that would be run for each pass without running them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Progress output format
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = progress::Format::Bar)]
+    pub progress: progress::Format,
+
+    /// Write JSON progress events to this file descriptor instead of stdout (Unix only).
+    /// Requires --progress json
+    #[arg(long, value_name = "FD", requires = "progress")]
+    pub progress_fd: Option<u32>,
+
+    /// Export the ffmpeg commands of a dry run as a shell script
+    #[arg(long, value_name = "SCRIPT_FILE", requires = "dry_run")]
+    pub script: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+/// Options of the normalize subcommands ebu, rms, peak and dialogue
+#[derive(Args, Debug)]
+pub struct NormalizeArgs {
     /// Input audio file
     #[arg(long, short, value_name = "INPUT_FILE")]
     pub input_file: PathBuf,
 
-    /// Output audio file after normalization
-    #[arg(long, short, value_name = "OUTPUT_FILE")]
-    pub output_file: PathBuf,
+    /// Output audio file after normalization, required unless --in-place
+    #[arg(
+        long,
+        short,
+        value_name = "OUTPUT_FILE",
+        required_unless_present = "in_place"
+    )]
+    pub output_file: Option<PathBuf>,
 
     /// Force overwrite existing output file
     #[arg(long)]
     pub overwrite: bool,
 
-    #[clap(subcommand)]
-    pub command: Command,
+    /// Normalize the input file in place instead of writing a separate output file
+    #[arg(long, conflicts_with_all = ["output_file", "overwrite"])]
+    pub in_place: bool,
+
+    /// Keep the original input file as <INPUT_FILE>.bak when normalizing in place
+    #[arg(long, requires = "in_place")]
+    pub backup: bool,
+
+    /// Encode audio of input codec CODEC with ENCODER (or codec) instead, e.g. "truehd=flac".
+    /// Codecs ffmpeg cannot encode fall back to a built-in table (truehd=flac, dts=eac3, ...)
+    #[arg(long, value_name = "CODEC=ENCODER", value_parser = parse_codec_mapping)]
+    pub codec_map: Vec<(String, String)>,
+
+    /// Bitrate of the output audio, e.g. "640k". Defaults to the input bitrate,
+    /// or a default of the output codec for the number of channels
+    #[arg(long, value_name = "BITRATE", value_parser = parse_bit_rate)]
+    pub bitrate: Option<String>,
+
+    /// Encode with VBR quality from 1 (smallest) to 5 (best) instead of a fixed bitrate.
+    /// Codecs without VBR mode use a bitrate scaled by quality
+    #[arg(
+        long,
+        value_name = "QUALITY",
+        conflicts_with = "bitrate",
+        value_parser = RangedI64ValueParser::<u8>::new().range(1..=5)
+    )]
+    pub quality: Option<u8>,
+
+    /// Apply the gain of rms, peak and ebu normalization without re-encoding where the format
+    /// allows it: MP3 global_gain in steps of 1.5 dB, Opus output gain of the header,
+    /// WAV and FLAC samples without ffmpeg
+    #[arg(long)]
+    pub native: bool,
+
+    /// Limit sample peaks of the native gain of WAV and FLAC files to this level in dBFS.
+    /// Range is [-9.0 .. 0.0].
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        requires = "native",
+        allow_negative_numbers = true,
+        value_parser=RangedF64ValueParser::<f64>::new().range(-9.0..=0.0)
+    )]
+    pub limiter: Option<f64>,
+
+    /// Add triangular dither to integer samples of the native gain of WAV and FLAC files
+    #[arg(long, requires = "native")]
+    pub dither: bool,
+
+    /// Measure this reference file like the input and use its level as target level,
+    /// e.g. to match an episode to a reference master. Cannot be used with --target-level
+    #[arg(
+        long = "match",
+        value_name = "REFERENCE_FILE",
+        conflicts_with = "target_level"
+    )]
+    pub reference: Option<PathBuf>,
+
+    /// Measure the normalized output and write its loudness to the bext chunk of a WAV output file
+    /// (EBU Tech 3285 v2). Other bext fields are kept from the input
+    #[arg(long)]
+    pub bext: bool,
+
+    /// Measure the normalized output and write its momentary and short-term loudness over time
+    /// to this CSV or JSON file
+    #[arg(long, value_name = "TIMELINE_FILE")]
+    pub timeline: Option<PathBuf>,
+
+    /// Measure the normalized output and plot its loudness over time with the target level,
+    /// true peaks above the limit and the loudness range next to it, e.g. "movie.loudness.svg".
+    /// Can be repeated or comma separated
+    #[arg(long, value_name = "FORMAT", value_enum, value_delimiter = ',')]
+    pub plot: Vec<plot::Format>,
+
+    /// Measure the normalized output and add it with its input and output loudness, gain,
+    /// pass/fail against the target and loudness plot to this self-contained HTML report.
+    /// Runs with the same report file add up to one report of the batch
+    #[arg(long, value_name = "REPORT_FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Do not write provenance tags (tool version, algorithm, target, measured input values
+    /// and applied gain) to the output file
+    #[arg(long)]
+    pub no_tags: bool,
+
+    /// Leave the input file unchanged if its provenance tags show that it is already normalized
+    #[arg(long)]
+    pub skip_normalized: bool,
+}
+
+/// Options of the subcommands that measure a region of their input: ebu, rms, dialogue and check
+#[derive(Args, Debug)]
+pub struct MeasureRangeArgs {
+    /// Start of the region that pass 1 measures, in seconds or [HH:]MM:SS[.ms].
+    /// Pass 2 applies the gain to the whole file
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    pub measure_start: Option<f64>,
+
+    /// End of the region that pass 1 measures, in seconds or [HH:]MM:SS[.ms]
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    pub measure_end: Option<f64>,
+
+    /// Exclude a time range from the measurement of pass 1, e.g. "0-15" for a bumper
+    /// or "1:02:30-1:05:00" for end credits. Can be repeated
+    #[arg(long, value_name = "START-END", value_parser = parse_time_range)]
+    pub measure_exclude: Vec<(f64, f64)>,
+}
+
+impl Command {
+    /// The normalize options of ebu, rms, peak and dialogue, `None` for check
+    pub fn normalize(&self) -> Option<&NormalizeArgs> {
+        match self {
+            Command::Ebu { normalize, .. }
+            | Command::Rms { normalize, .. }
+            | Command::Peak { normalize, .. }
+            | Command::Dialogue { normalize, .. } => Some(normalize),
+            Command::Check { .. } => None,
+        }
+    }
+
+    /// The measured region of pass 1, the whole input for peak
+    pub fn measure_range(&self) -> anyhow::Result<MeasureRange> {
+        match self {
+            Command::Ebu { measure, .. }
+            | Command::Rms { measure, .. }
+            | Command::Dialogue { measure, .. }
+            | Command::Check { measure, .. } => MeasureRange::new(
+                measure.measure_start,
+                measure.measure_end,
+                &measure.measure_exclude,
+            ),
+            Command::Peak { .. } => Ok(MeasureRange::default()),
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -78,6 +245,12 @@ pub enum Command {
         )]
         offset: f64,
 
+        /// What the integrated loudness is measured over: "dialogue" detects speech in a
+        /// separate pass and measures only the speech regions, e.g. for programs with loud music
+        /// or effects between dialogue. The program loudness is reported too.
+        #[arg(long, value_name = "GATING", value_enum, default_value_t = ebu_r128::Gating::Program)]
+        gating: ebu_r128::Gating,
+
         /// Custom arguments for ffmpeg to override default values, e.g. "-c:a ac3 -b:a 640k -ar 48000 -dialnorm -31"
         #[arg(
             last = true,
@@ -86,6 +259,12 @@ pub enum Command {
             allow_hyphen_values = true
         )]
         ffmpeg_args: Vec<String>,
+
+        #[command(flatten)]
+        normalize: NormalizeArgs,
+
+        #[command(flatten)]
+        measure: MeasureRangeArgs,
     },
     /// RMS-based normalization brings the input file to the specified RMS level.
     Rms {
@@ -99,6 +278,17 @@ pub enum Command {
         )]
         target_level: f64,
 
+        /// Gate in dBFS: measure the RMS level of 400 ms windows and ignore windows below it,
+        /// e.g. -60 for silence. The ungated level is reported too.
+        /// The range is [-120.0 .. 0.0].
+        #[arg(
+            long,
+            value_name = "LEVEL",
+            allow_negative_numbers = true,
+            value_parser=RangedF64ValueParser::<f64>::new().range(-120.0..=0.0)
+        )]
+        gate: Option<f64>,
+
         /// Custom arguments for ffmpeg to override default values, e.g. "-c:a ac3 -b:a 640k -ar 48000 -dialnorm -31"
         #[arg(
             last = true,
@@ -107,6 +297,12 @@ pub enum Command {
             allow_hyphen_values = true
         )]
         ffmpeg_args: Vec<String>,
+
+        #[command(flatten)]
+        normalize: NormalizeArgs,
+
+        #[command(flatten)]
+        measure: MeasureRangeArgs,
     },
     /// Peak normalization brings the signal to the specified peak level.
     Peak {
@@ -128,6 +324,9 @@ pub enum Command {
             allow_hyphen_values = true
         )]
         ffmpeg_args: Vec<String>,
+
+        #[command(flatten)]
+        normalize: NormalizeArgs,
     },
     /// Dialogue normalization indicates how far the average dialogue level of the program is below digital 100%
     /// full scale (0 dBFS).
@@ -145,6 +344,20 @@ pub enum Command {
         )]
         target_level: i8,
 
+        /// Measure the dialogue loudness of the input and set the target level to it,
+        /// rounded to whole numbers and clamped to -31 to -1.
+        #[arg(long, conflicts_with = "target_level")]
+        auto: bool,
+
+        /// What to do if the output codec does not support dialnorm (only AC-3 and E-AC-3 do)
+        #[arg(long, value_name = "FALLBACK", value_enum, default_value_t = dialogue::Fallback::Error)]
+        fallback: dialogue::Fallback,
+
+        /// Encode with ffmpeg even if the dialnorm of a raw AC-3 or E-AC-3 file
+        /// can be rewritten without re-encoding
+        #[arg(long)]
+        reencode: bool,
+
         /// Custom arguments for ffmpeg to override default values, e.g. "-c:a ac3 -b:a 640k -ar 48000"
         #[arg(
             last = true,
@@ -153,6 +366,77 @@ pub enum Command {
             allow_hyphen_values = true
         )]
         ffmpeg_args: Vec<String>,
+
+        #[command(flatten)]
+        normalize: NormalizeArgs,
+
+        #[command(flatten)]
+        measure: MeasureRangeArgs,
+    },
+    /// Check measures files without writing output and checks their loudness against a spec,
+    /// e.g. to gate a delivery pipeline. The exit code is 0 if all files comply, 1 if a file
+    /// cannot be measured, otherwise the sum of 4 (integrated loudness), 8 (true peak),
+    /// 16 (loudness range) and 32 (short-term loudness) of the failed checks.
+    Check {
+        /// Audio files to check
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<PathBuf>,
+
+        #[command(flatten)]
+        measure: MeasureRangeArgs,
+
+        /// Target integrated loudness in LUFS.
+        /// The range is [-70.0 .. -5.0].
+        #[arg(
+            long,
+            default_value = "-23.0",
+            allow_negative_numbers = true,
+            value_parser=RangedF64ValueParser::<f64>::new().range(-70.0..=-5.0)
+        )]
+        target_level: f64,
+
+        /// Allowed deviation of the integrated loudness from the target level in LU.
+        /// The range is [0.0 .. 10.0].
+        #[arg(
+            long,
+            default_value = "1.0",
+            value_parser=RangedF64ValueParser::<f64>::new().range(0.0..=10.0)
+        )]
+        tolerance: f64,
+
+        /// Maximum true peak in dBTP.
+        /// The range is [-9.0 .. 0.0].
+        #[arg(
+            long,
+            default_value = "-1.0",
+            allow_negative_numbers = true,
+            value_parser=RangedF64ValueParser::<f64>::new().range(-9.0..=0.0)
+        )]
+        max_true_peak: f64,
+
+        /// Maximum loudness range in LU, not checked if not set.
+        /// The range is [1.0 .. 50.0].
+        #[arg(
+            long,
+            alias = "max-lra",
+            value_name = "LU",
+            value_parser=RangedF64ValueParser::<f64>::new().range(1.0..=50.0)
+        )]
+        max_loudness_range: Option<f64>,
+
+        /// Maximum short-term loudness in LUFS, not checked if not set.
+        /// The range is [-70.0 .. 0.0].
+        #[arg(
+            long,
+            value_name = "LUFS",
+            allow_negative_numbers = true,
+            value_parser=RangedF64ValueParser::<f64>::new().range(-70.0..=0.0)
+        )]
+        max_short_term: Option<f64>,
+
+        /// Write the result of every file to this JUnit XML (.xml) or JSON (.json) file
+        #[arg(long, value_name = "RESULT_FILE")]
+        result: Option<PathBuf>,
     },
 }
 
@@ -276,3 +560,81 @@ impl<T: TryFrom<f64>> Default for RangedF64ValueParser<T> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+
+    fn parse(args: &[&str]) -> Result<Cli, Error> {
+        Cli::try_parse_from([crate_name!()].iter().chain(args))
+    }
+
+    #[test]
+    fn normalize_options_follow_the_subcommand() {
+        let cli = parse(&[
+            "--dry-run",
+            "ebu",
+            "-i",
+            "in.wav",
+            "-o",
+            "out.wav",
+            "--native",
+        ])
+        .unwrap();
+        let normalize = cli.command.normalize().unwrap();
+        assert!(cli.dry_run && normalize.native);
+        assert_eq!(normalize.input_file, PathBuf::from("in.wav"));
+
+        let err = parse(&["ebu", "-i", "in.wav"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+        let cli = parse(&["rms", "-i", "in.wav", "--in-place", "--backup"]).unwrap();
+        assert!(cli.command.normalize().unwrap().output_file.is_none());
+    }
+
+    #[test]
+    fn check_rejects_normalize_options() {
+        let cli = parse(&["check", "a.wav", "--measure-start", "10"]).unwrap();
+        assert!(cli.command.normalize().is_none());
+        assert!(cli.command.measure_range().unwrap().filter().is_some());
+
+        for args in [
+            &["check", "a.wav", "-i", "b.wav"][..],
+            &["check", "a.wav", "--native"],
+        ] {
+            let err = parse(args).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnknownArgument, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn match_conflicts_with_target_level() {
+        let cli = parse(&[
+            "peak", "-i", "in.wav", "-o", "out.wav", "--match", "ref.wav",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command.normalize().unwrap().reference,
+            Some(PathBuf::from("ref.wav"))
+        );
+
+        let args = ["rms", "-i", "in.wav", "-o", "out.wav", "--match", "ref.wav"];
+        let err = parse(&[&args[..], &["--target-level", "-20"]].concat()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn peak_measures_the_whole_input() {
+        let args = [
+            "peak",
+            "-i",
+            "in.wav",
+            "-o",
+            "out.wav",
+            "--measure-start",
+            "10",
+        ];
+        let err = parse(&args).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownArgument);
+    }
+}
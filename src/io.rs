@@ -1,25 +1,18 @@
-use std::io::{stderr, stdout, BufRead, Write};
-
-pub fn to_stdout<R: BufRead>(reader: R) {
-    let stdout: std::io::Stdout = stdout();
-    let mut lock = stdout.lock();
+use crate::progress::Progress;
+use std::io::{stderr, BufRead, Write};
 
+pub fn to_progress<R: BufRead>(reader: R, progress: &dyn Progress) {
     reader
         .lines()
-        .filter_map(|line| line.ok())
-        .for_each(|line| {
-            let _ = writeln!(lock, "{line}");
-        });
+        .map_while(Result::ok)
+        .for_each(|line| progress.message(&line));
 }
 
 pub fn to_stderr<R: BufRead>(reader: R) {
     let stderr = stderr();
     let mut lock = stderr.lock();
 
-    reader
-        .lines()
-        .filter_map(|line| line.ok())
-        .for_each(|line| {
-            let _ = writeln!(lock, "{line}");
-        });
+    reader.lines().map_while(Result::ok).for_each(|line| {
+        let _ = writeln!(lock, "{line}");
+    });
 }
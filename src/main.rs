@@ -1,72 +1,405 @@
 mod algorithm;
+mod bitstream;
 mod cli;
 mod io;
+mod output;
+mod plan;
+mod plot;
+mod progress;
+mod provenance;
+mod report;
 mod tool;
+mod xml;
 
+use algorithm::bext;
+use algorithm::check;
 use algorithm::dialogue;
 use algorithm::ebu_r128;
 use algorithm::peak;
 use algorithm::rms;
+use algorithm::timeline;
 use anyhow::Result;
 use clap::Parser;
 use cli::{Cli, Command};
+use output::OutputFile;
+use plan::Plan;
+use progress::Progress;
+use std::process::ExitCode;
+use tool::codec::CodecOptions;
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
 
+    let progress = progress::new(cli.progress, cli.progress_fd, cli.verbose)?;
+
     match cli.command {
+        Command::Check { .. } => run_check(cli, progress.as_ref()).map(ExitCode::from),
+        _ => run(cli, progress.as_ref()).map(|()| ExitCode::SUCCESS),
+    }
+    .inspect_err(|err| progress.error(&format!("{err:#}")))
+}
+
+/// Measure the files of `check` against its spec, the exit code tells which checks failed
+fn run_check(cli: Cli, progress: &dyn Progress) -> Result<u8> {
+    let measure_range = cli.command.measure_range()?;
+    let Command::Check {
+        files,
+        target_level,
+        tolerance,
+        max_true_peak,
+        max_loudness_range,
+        max_short_term,
+        result,
+        ..
+    } = cli.command
+    else {
+        unreachable!("only check is run by run_check");
+    };
+
+    if let Some(result_file) = &result {
+        check::check(result_file)?;
+    }
+
+    let spec = check::Spec {
+        target_level,
+        tolerance,
+        max_true_peak,
+        max_loudness_range,
+        max_short_term,
+    };
+    let args = check::CheckArgs {
+        verbose: cli.verbose,
+        files: &files,
+        spec: &spec,
+        measure_range: &measure_range,
+        result_file: result.as_deref(),
+        progress,
+    };
+
+    if cli.dry_run {
+        let mut plan = Plan::default();
+        check::plan(args, &mut plan);
+        plan.print(progress);
+        if let Some(script) = &cli.script {
+            plan.write_script(script)?;
+        }
+        return Ok(0);
+    }
+
+    check::run(args)
+}
+
+fn run(cli: Cli, progress: &dyn Progress) -> Result<()> {
+    let Some(normalize) = cli.command.normalize() else {
+        unreachable!("check is run by run_check");
+    };
+    let input_file = &normalize.input_file;
+
+    let output = match &normalize.output_file {
+        Some(output_file) => OutputFile::new(input_file, output_file, normalize.overwrite)?,
+        None => OutputFile::in_place(input_file, normalize.backup)?,
+    };
+
+    if normalize.bext {
+        bext::check(output.path())?;
+    }
+    if let Some(timeline_file) = &normalize.timeline {
+        timeline::check(timeline_file)?;
+    }
+    if let Some(report_file) = &normalize.report {
+        report::check(report_file)?;
+    }
+
+    if normalize.skip_normalized {
+        if let Some(tags) = provenance::detect(input_file)? {
+            progress.message(&format!(
+                "Input file is already normalized ({tags}), it is skipped"
+            ));
+            return Ok(());
+        }
+    }
+
+    let measure_range = cli.command.measure_range()?;
+
+    let mut plan = cli.dry_run.then(Plan::default);
+
+    // the plot and the report mark the target of ebu, or the level of the reference file
+    // recorded by --match
+    let (algorithm, plot_target, plot_max_true_peak) = match &cli.command {
+        Command::Ebu {
+            target_level,
+            true_peak,
+            offset,
+            ..
+        } => ("ebu", Some((*target_level, *offset)), *true_peak),
+        Command::Rms { .. } => ("rms", None, plot::MAX_TRUE_PEAK),
+        Command::Peak { .. } => ("peak", None, plot::MAX_TRUE_PEAK),
+        Command::Dialogue { .. } => ("dialogue", None, plot::MAX_TRUE_PEAK),
+        Command::Check { .. } => unreachable!("check is run by run_check"),
+    };
+    // the report checks the true peak of ebu against its target (EBU R 128), and the sample peak
+    // of rms against full scale and of peak against its target, at most the level of --limiter
+    let (gating, ceiling) = match &cli.command {
+        Command::Ebu {
+            gating, true_peak, ..
+        } => (Some(*gating), Some(report::Ceiling::TruePeak(*true_peak))),
+        Command::Rms { .. } => (None, Some(report::Ceiling::SamplePeak(0.0))),
+        Command::Peak { target_level, .. } => {
+            (None, Some(report::Ceiling::SamplePeak(*target_level)))
+        }
+        _ => (None, normalize.limiter.map(report::Ceiling::SamplePeak)),
+    };
+    // the report checks the output RMS level of rms against its target, measured with its gate
+    let rms_target = match &cli.command {
+        Command::Rms {
+            target_level, gate, ..
+        } => Some((*target_level, *gate)),
+        _ => None,
+    };
+    let recorder = progress::Recorder::new(progress);
+    let progress: &dyn Progress = &recorder;
+
+    let codec_options = CodecOptions {
+        codec_map: normalize.codec_map.clone(),
+        bit_rate: normalize.bitrate.clone(),
+        quality: normalize.quality,
+        native: normalize.native,
+        limiter: normalize.limiter,
+        dither: normalize.dither,
+        tags: !normalize.no_tags,
+    };
+
+    match &cli.command {
         Command::Ebu {
             target_level,
             loudness_range_target,
             true_peak,
             offset,
+            gating,
             ffmpeg_args,
+            ..
         } => {
             let args = ebu_r128::NormalizationArgs {
                 verbose: cli.verbose,
-                input_file: &cli.input_file,
-                output_file: &cli.output_file,
-                overwrite: cli.overwrite,
-                target_level,
-                loudness_range_target,
-                true_peak,
-                offset,
-                ffmpeg_args: &ffmpeg_args,
+                input_file,
+                output_file: output.path(),
+                target_level: *target_level,
+                loudness_range_target: *loudness_range_target,
+                true_peak: *true_peak,
+                offset: *offset,
+                gating: *gating,
+                reference: normalize.reference.as_deref(),
+                measure_range: &measure_range,
+                ffmpeg_args,
+                codec_options: &codec_options,
+                progress,
             };
-            ebu_r128::normalize(args)
+            match &mut plan {
+                Some(plan) => ebu_r128::plan(args, plan),
+                None => ebu_r128::normalize(args),
+            }
         }
         Command::Rms {
             target_level,
+            gate,
             ffmpeg_args,
-        } => rms::normalize(rms::NormalizationArgs {
-            verbose: cli.verbose,
-            input_file: &cli.input_file,
-            output_file: &cli.output_file,
-            overwrite: cli.overwrite,
-            target_level,
-            ffmpeg_args: &ffmpeg_args,
-        }),
+            ..
+        } => {
+            let args = rms::NormalizationArgs {
+                verbose: cli.verbose,
+                input_file,
+                output_file: output.path(),
+                target_level: *target_level,
+                gate: *gate,
+                reference: normalize.reference.as_deref(),
+                measure_range: &measure_range,
+                ffmpeg_args,
+                codec_options: &codec_options,
+                progress,
+            };
+            match &mut plan {
+                Some(plan) => rms::plan(args, plan),
+                None => rms::normalize(args),
+            }
+        }
         Command::Peak {
             target_level,
             ffmpeg_args,
-        } => peak::normalize(peak::NormalizationArgs {
-            verbose: cli.verbose,
-            input_file: &cli.input_file,
-            output_file: &cli.output_file,
-            overwrite: cli.overwrite,
-            target_level,
-            ffmpeg_args: &ffmpeg_args,
-        }),
+            ..
+        } => {
+            let args = peak::NormalizationArgs {
+                verbose: cli.verbose,
+                input_file,
+                output_file: output.path(),
+                target_level: *target_level,
+                reference: normalize.reference.as_deref(),
+                ffmpeg_args,
+                codec_options: &codec_options,
+                progress,
+            };
+            match &mut plan {
+                Some(plan) => peak::plan(args, plan),
+                None => peak::normalize(args),
+            }
+        }
         Command::Dialogue {
             target_level,
+            auto,
+            fallback,
+            reencode,
             ffmpeg_args,
-        } => dialogue::normalize(dialogue::NormalizationArgs {
+            ..
+        } => {
+            let args = dialogue::NormalizationArgs {
+                verbose: cli.verbose,
+                input_file,
+                output_file: output.path(),
+                target_level: *target_level,
+                auto: *auto,
+                fallback: *fallback,
+                reencode: *reencode,
+                reference: normalize.reference.as_deref(),
+                measure_range: &measure_range,
+                ffmpeg_args,
+                codec_options: &codec_options,
+                progress,
+            };
+            match &mut plan {
+                Some(plan) => dialogue::plan(args, plan),
+                None => dialogue::normalize(args),
+            }
+        }
+        Command::Check { .. } => unreachable!("check is run by run_check"),
+    }?;
+
+    // the bext chunk, the timeline, the plot and the report share one pass measuring the output
+    let mut timeline = None;
+    if normalize.bext
+        || normalize.timeline.is_some()
+        || !normalize.plot.is_empty()
+        || normalize.report.is_some()
+    {
+        let args = timeline::MeasureArgs {
             verbose: cli.verbose,
-            input_file: &cli.input_file,
-            output_file: &cli.output_file,
-            overwrite: cli.overwrite,
-            target_level,
-            ffmpeg_args: &ffmpeg_args,
+            output_file: output.path(),
+            progress,
+        };
+        match &mut plan {
+            Some(plan) => {
+                timeline::plan(args, plan);
+                if normalize.bext {
+                    bext::plan(plan);
+                }
+                if let Some(timeline_file) = &normalize.timeline {
+                    timeline::plan_export(timeline_file, plan);
+                }
+                plot::plan(output.target(), &normalize.plot, plan);
+                if normalize.report.is_some() {
+                    report::plan_input(algorithm, input_file, plan);
+                    if let Some((_, gate)) = rms_target {
+                        rms::plan_output(
+                            rms::MeasureArgs {
+                                verbose: cli.verbose,
+                                input_file,
+                                output_file: output.path(),
+                                gate,
+                                measure_range: &measure_range,
+                                progress,
+                            },
+                            plan,
+                        )?;
+                    }
+                }
+            }
+            None => {
+                let measured = timeline::measure(args)?;
+                if normalize.bext {
+                    bext::write(output.path(), input_file, &measured.loudness)?;
+                }
+                if let Some(timeline_file) = &normalize.timeline {
+                    timeline::export(&measured, timeline_file)?;
+                }
+                // before the commit replaces the input of --in-place
+                if normalize.report.is_some() {
+                    report::measure_input(input_file, cli.verbose, &recorder)?;
+                    if let Some((_, gate)) = rms_target {
+                        rms::measure_output(rms::MeasureArgs {
+                            verbose: cli.verbose,
+                            input_file,
+                            output_file: output.path(),
+                            gate,
+                            measure_range: &measure_range,
+                            progress,
+                        })?;
+                    }
+                }
+                timeline = Some(measured);
+            }
+        }
+    }
+
+    let target = output.target().to_path_buf();
+    let title = target.file_name().unwrap_or_default().to_string_lossy();
+    let plot_args = timeline.as_ref().map(|timeline| plot::PlotArgs {
+        timeline,
+        title: &title,
+        target: plot_target.map(|(target_level, offset)| {
+            recorder.get("reference_i").unwrap_or(target_level) + offset
         }),
+        max_true_peak: plot_max_true_peak,
+    });
+    if let Some(plot_args) = &plot_args {
+        if !normalize.plot.is_empty() {
+            plot::write(plot_args, &target, &normalize.plot)?;
+        }
+    }
+
+    match plan {
+        Some(mut plan) => {
+            output.plan_commit(&mut plan);
+            if let Some(report_file) = &normalize.report {
+                report::plan(report_file, &mut plan);
+            }
+            plan.print(progress);
+            match &cli.script {
+                Some(script) => plan.write_script(script),
+                None => Ok(()),
+            }
+        }
+        None => {
+            output.commit()?;
+            // the report lists the committed output
+            match (&normalize.report, &plot_args) {
+                (Some(report_file), Some(plot_args)) => report::add(
+                    report::ReportArgs {
+                        input_file,
+                        output_file: &target,
+                        algorithm,
+                        gating,
+                        ceilings: ceiling
+                            .map(|ceiling| match ceiling {
+                                // peak normalizes to the level of the reference file of --match
+                                report::Ceiling::SamplePeak(level) => report::Ceiling::SamplePeak(
+                                    recorder
+                                        .get("reference_peak_level")
+                                        .unwrap_or(level)
+                                        .min(normalize.limiter.unwrap_or(0.0)),
+                                ),
+                                ceiling => ceiling,
+                            })
+                            .into_iter()
+                            .collect(),
+                        // rms normalizes to the level of the reference file of --match
+                        rms_target: rms_target.map(|(target_level, _)| {
+                            recorder.get("reference_rms_level").unwrap_or(target_level)
+                        }),
+                        plot: plot_args,
+                        recorder: &recorder,
+                    },
+                    report_file,
+                ),
+                _ => Ok(()),
+            }
+        }
     }
 }
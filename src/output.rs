@@ -0,0 +1,323 @@
+use crate::plan::Plan;
+use anyhow::{bail, Context, Result};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Normalized output that is written to a temporary file next to the target
+/// and atomically renamed into place once normalization succeeds.
+/// The temporary file is removed if the output is dropped without commit.
+pub struct OutputFile {
+    target: PathBuf,
+    temp: PathBuf,
+    backup: Option<PathBuf>,
+    /// Permissions of the input file that is replaced in place
+    permissions: Option<fs::Permissions>,
+    committed: bool,
+}
+
+impl OutputFile {
+    pub fn new(input_file: &Path, output_file: &Path, overwrite: bool) -> Result<Self> {
+        if is_same_file(input_file, output_file) {
+            bail!(
+                "Output file \"{}\" is the input file, use --in-place to normalize it in place",
+                output_file.display()
+            );
+        }
+
+        if output_file.exists() && !overwrite {
+            bail!(
+                "Output file \"{}\" already exists, use --overwrite to replace it",
+                output_file.display()
+            );
+        }
+
+        Ok(OutputFile::with_target(output_file, None, None))
+    }
+
+    /// Output that replaces `target` if it exists, e.g. a file the tool writes itself
+    pub fn replace(target: &Path) -> Self {
+        OutputFile::with_target(target, None, None)
+    }
+
+    pub fn in_place(input_file: &Path, keep_backup: bool) -> Result<Self> {
+        if !input_file.is_file() {
+            bail!("Input file \"{}\" does not exist", input_file.display());
+        }
+        let permissions = fs::metadata(input_file)
+            .with_context(|| format!("Failed to read input file \"{}\"", input_file.display()))?
+            .permissions();
+
+        let backup = keep_backup.then(|| {
+            let mut name = OsString::from(input_file.as_os_str());
+            name.push(".bak");
+            PathBuf::from(name)
+        });
+        // the backup of an earlier run may be the only original
+        if let Some(backup) = backup.as_ref().filter(|backup| backup.exists()) {
+            bail!(
+                "Backup file \"{}\" already exists, move it away to normalize in place again",
+                backup.display()
+            );
+        }
+
+        Ok(OutputFile::with_target(
+            input_file,
+            backup,
+            Some(permissions),
+        ))
+    }
+
+    fn with_target(
+        target: &Path,
+        backup: Option<PathBuf>,
+        permissions: Option<fs::Permissions>,
+    ) -> Self {
+        // keep the extension last so ffmpeg still detects the output format
+        let mut name = OsString::from(".");
+        name.push(target.file_stem().unwrap_or_default());
+        name.push(format!(".{}.tmp", process::id()));
+        if let Some(ext) = target.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+
+        OutputFile {
+            target: target.to_path_buf(),
+            temp: target.with_file_name(name),
+            backup,
+            permissions,
+            committed: false,
+        }
+    }
+
+    /// Path ffmpeg should write the normalized audio to.
+    pub fn path(&self) -> &Path {
+        &self.temp
+    }
+
+    /// Path the normalized audio is moved to by `commit`.
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// Add the renames done by `commit` to a dry run plan.
+    pub fn plan_commit(&self, plan: &mut Plan) {
+        if let Some(backup) = &self.backup {
+            plan.add_link("Keep the original file:", &self.target, backup);
+        }
+        plan.add_rename("Move normalized audio file:", &self.temp, &self.target);
+        if self.permissions.is_some() {
+            plan.note("The normalized audio file gets the permissions of the input file");
+        }
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        // the file written by ffmpeg has the default permissions of a new file
+        if let Some(permissions) = &self.permissions {
+            fs::set_permissions(&self.temp, permissions.clone()).with_context(|| {
+                format!("Failed to set permissions of \"{}\"", self.temp.display())
+            })?;
+        }
+
+        // the original stays at the target until the rename replaces it
+        if let Some(backup) = &self.backup {
+            keep_original(&self.target, backup).with_context(|| {
+                format!("Failed to create backup file \"{}\"", backup.display())
+            })?;
+        }
+
+        if let Err(err) = fs::rename(&self.temp, &self.target) {
+            if let Some(backup) = &self.backup {
+                let _ = fs::remove_file(backup);
+            }
+            return Err(err).with_context(|| {
+                format!(
+                    "Failed to move normalized audio to \"{}\"",
+                    self.target.display()
+                )
+            });
+        }
+
+        self.committed = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// Link `backup` to the original file, or copy it if the file system has no hard links. An
+/// existing backup is not replaced.
+fn keep_original(original: &Path, backup: &Path) -> io::Result<()> {
+    match fs::hard_link(original, backup) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => {
+            let mut original = fs::File::open(original)?;
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(backup)?;
+            io::copy(&mut original, &mut file)
+                .and_then(|_| file.set_permissions(original.metadata()?.permissions()))
+                .inspect_err(|_| {
+                    let _ = fs::remove_file(backup);
+                })
+        }
+        res => res,
+    }
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Empty directory of a test
+    fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("output-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn new_checks_target() {
+        let dir = dir("new");
+        let input = dir.join("in.wav");
+        let output = dir.join("out.wav");
+        fs::write(&input, "input").unwrap();
+
+        assert!(OutputFile::new(&input, &dir.join(".").join("in.wav"), true).is_err());
+        assert!(OutputFile::new(&input, &output, false).is_ok());
+        fs::write(&output, "output").unwrap();
+        assert!(OutputFile::new(&input, &output, false).is_err());
+
+        // the temporary file is next to the target and keeps its extension
+        let file = OutputFile::new(&input, &output, true).unwrap();
+        assert_eq!(file.path().parent(), Some(dir.as_path()));
+        assert_eq!(file.path().extension().unwrap(), "wav");
+        fs::write(file.path(), "normalized").unwrap();
+        file.commit().unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "normalized");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drop_removes_temporary_file() {
+        let dir = dir("drop");
+        let input = dir.join("in.wav");
+        fs::write(&input, "input").unwrap();
+
+        let file = OutputFile::in_place(&input, false).unwrap();
+        let temp = file.path().to_path_buf();
+        fs::write(&temp, "normalized").unwrap();
+        drop(file);
+        assert!(!temp.exists());
+        assert_eq!(fs::read_to_string(&input).unwrap(), "input");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn in_place_with_backup() {
+        let dir = dir("backup");
+        let input = dir.join("in.wav");
+        let backup = dir.join("in.wav.bak");
+        fs::write(&input, "input").unwrap();
+        assert!(OutputFile::in_place(&dir.join("missing.wav"), true).is_err());
+
+        let file = OutputFile::in_place(&input, true).unwrap();
+        fs::write(file.path(), "normalized").unwrap();
+        file.commit().unwrap();
+        assert_eq!(fs::read_to_string(&input).unwrap(), "normalized");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "input");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // a second run must not replace the original
+        let err = OutputFile::in_place(&input, true).map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{err}");
+        assert!(OutputFile::in_place(&input, false).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_commit_keeps_input() {
+        let dir = dir("failed");
+        let input = dir.join("in.wav");
+        let backup = dir.join("in.wav.bak");
+        fs::write(&input, "input").unwrap();
+
+        // no normalized audio was written, the backup is removed again
+        let file = OutputFile::in_place(&input, true).unwrap();
+        assert!(file.commit().is_err());
+        assert_eq!(fs::read_to_string(&input).unwrap(), "input");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // a backup created while normalizing is kept, the normalized audio is removed
+        let file = OutputFile::in_place(&input, true).unwrap();
+        fs::write(file.path(), "normalized").unwrap();
+        fs::write(&backup, "original").unwrap();
+        let err = file.commit().unwrap_err();
+        assert!(
+            err.to_string().contains("Failed to create backup file"),
+            "{err}"
+        );
+        assert_eq!(fs::read_to_string(&input).unwrap(), "input");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "original");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_original_leaves_no_partial_backup() {
+        let dir = dir("copy");
+        let input = dir.join("in.wav");
+        fs::write(&input, "input").unwrap();
+
+        // a directory cannot be linked, its copy fails and is removed
+        assert!(keep_original(&dir, &dir.join("dir.bak")).is_err());
+        assert!(!dir.join("dir.bak").exists());
+        keep_original(&input, &dir.join("in.wav.bak")).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("in.wav.bak")).unwrap(), "input");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn in_place_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = dir("permissions");
+        let input = dir.join("in.wav");
+        fs::write(&input, "input").unwrap();
+        fs::set_permissions(&input, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let file = OutputFile::in_place(&input, false).unwrap();
+        fs::write(file.path(), "normalized").unwrap();
+        fs::set_permissions(file.path(), fs::Permissions::from_mode(0o600)).unwrap();
+        file.commit().unwrap();
+        let mode = fs::metadata(&input).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640, "{mode:o}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+use crate::progress::{Pass, Progress};
+use crate::tool::ffmpeg::FFmpeg;
+use anyhow::{Context, Result};
+use clap::crate_name;
+use clap::crate_version;
+use std::ffi::OsStr;
+use std::fmt::{self, Display, Write as _};
+use std::fs;
+use std::path::Path;
+
+/// Value of an ffmpeg argument that is either known upfront or only after pass 1.
+#[derive(Clone, Copy)]
+pub enum Value {
+    Known(f64),
+    Placeholder(&'static str),
+}
+
+/// Encloses the name of a placeholder in an argument or note of a plan. No argument of a process
+/// (or file name) can contain NUL, so a placeholder is told apart from the literal text around
+/// it, e.g. a file name with "${1}", without parsing that text.
+const MARK: char = '\0';
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Known(value) => write!(f, "{value}"),
+            Value::Placeholder(name) => write!(f, "{MARK}{name}{MARK}"),
+        }
+    }
+}
+
+/// Part of an argument or note of a plan
+#[derive(Debug, PartialEq)]
+enum Part<'a> {
+    Literal(&'a str),
+    /// Name of a shell variable set from the output of an earlier pass
+    Placeholder(&'a str),
+}
+
+/// Literal parts and placeholders of `text`, the placeholders are enclosed by `MARK`
+fn parts(text: &str) -> impl Iterator<Item = Part<'_>> {
+    text.split(MARK)
+        .enumerate()
+        .filter(|(i, part)| i % 2 == 1 || !part.is_empty())
+        .map(|(i, part)| match i % 2 {
+            0 => Part::Literal(part),
+            _ => Part::Placeholder(part),
+        })
+}
+
+/// `text` with placeholders as "${NAME}", e.g. a note or the title of a step
+fn text(text: &str) -> String {
+    parts(text)
+        .map(|part| match part {
+            Part::Literal(literal) => literal.to_string(),
+            Part::Placeholder(name) => format!("${{{name}}}"),
+        })
+        .collect()
+}
+
+struct Step {
+    title: String,
+    commands: Vec<String>,
+    notes: Vec<String>,
+    placeholders: Vec<(&'static str, String)>,
+}
+
+/// Commands a normalization would run, collected by `--dry-run`.
+#[derive(Default)]
+pub struct Plan {
+    steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn add(&mut self, pass: &Pass, ffmpeg: &FFmpeg) {
+        self.add_command(pass, ffmpeg.command_line());
+    }
+
+    /// Add a pass that is not run by ffmpeg, e.g. a native bitstream rewrite.
+    pub fn add_command<'a>(&mut self, pass: &Pass, args: impl Iterator<Item = &'a OsStr>) {
+        self.steps.push(Step {
+            title: pass.to_string(),
+            commands: vec![command_line(args)],
+            notes: Vec::new(),
+            placeholders: Vec::new(),
+        });
+    }
+
+    /// Describe a placeholder used by the last added command.
+    pub fn placeholder(&mut self, name: &'static str, description: &str) {
+        if let Some(step) = self.steps.last_mut() {
+            step.placeholders.push((name, description.to_string()));
+        }
+    }
+
+    /// Describe what the last added pass does beyond its commands.
+    pub fn note(&mut self, note: &str) {
+        if let Some(step) = self.steps.last_mut() {
+            step.notes.push(note.to_string());
+        }
+    }
+
+    pub fn add_rename(&mut self, title: &str, from: &Path, to: &Path) {
+        self.steps.push(Step {
+            title: title.to_string(),
+            commands: vec![command_line(
+                [
+                    OsStr::new("mv"),
+                    OsStr::new("-f"),
+                    from.as_os_str(),
+                    to.as_os_str(),
+                ]
+                .into_iter(),
+            )],
+            notes: Vec::new(),
+            placeholders: Vec::new(),
+        });
+    }
+
+    /// Add a hard link `to` of the file `from`, which fails if `to` exists
+    pub fn add_link(&mut self, title: &str, from: &Path, to: &Path) {
+        self.steps.push(Step {
+            title: title.to_string(),
+            commands: vec![command_line(
+                [OsStr::new("ln"), from.as_os_str(), to.as_os_str()].into_iter(),
+            )],
+            notes: Vec::new(),
+            placeholders: Vec::new(),
+        });
+    }
+
+    /// Add a command to the last added pass.
+    pub fn append_command<'a>(&mut self, args: impl Iterator<Item = &'a OsStr>) {
+        if let Some(step) = self.steps.last_mut() {
+            step.commands.push(command_line(args));
+        }
+    }
+
+    /// Report the commands as messages, which are events of `--progress json`
+    pub fn print(&self, progress: &dyn Progress) {
+        progress.message("Dry run, no files are written. The following commands would be run:");
+
+        self.steps.iter().for_each(|step| {
+            progress.message(&text(&step.title));
+            step.commands
+                .iter()
+                .for_each(|command| progress.message(&format!("  {command}")));
+            step.notes
+                .iter()
+                .for_each(|note| progress.message(&format!("  # {}", text(note))));
+            step.placeholders.iter().for_each(|(name, description)| {
+                progress.message(&format!("  ${{{name}}}: {}", text(description)));
+            });
+        });
+    }
+
+    pub fn write_script(&self, path: &Path) -> Result<()> {
+        let mut script = String::new();
+
+        let _ = writeln!(script, "#!/bin/sh");
+        let _ = writeln!(
+            script,
+            "# Generated by {} {}",
+            crate_name!(),
+            crate_version!()
+        );
+        let _ = writeln!(script, "set -e");
+
+        self.steps.iter().for_each(|step| {
+            let _ = writeln!(script);
+            let _ = writeln!(script, "# {}", text(&step.title));
+            step.placeholders.iter().for_each(|(name, description)| {
+                let _ = writeln!(script, "# {name}: {}", text(description));
+                let _ = writeln!(script, ": \"${{{name}:?must be set from pass 1 output}}\"");
+            });
+            step.commands.iter().for_each(|command| {
+                let _ = writeln!(script, "{command}");
+            });
+            step.notes.iter().for_each(|note| {
+                let _ = writeln!(script, "# {}", text(note));
+            });
+        });
+
+        fs::write(path, script)
+            .with_context(|| format!("Failed to write script file \"{}\"", path.display()))?;
+
+        set_executable(path)
+    }
+}
+
+fn command_line<'a>(args: impl Iterator<Item = &'a OsStr>) -> String {
+    args.map(|arg| quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Argument for the shell, only its placeholders are expanded
+fn quote(arg: &str) -> String {
+    if arg.is_empty() {
+        return "''".to_string();
+    }
+
+    parts(arg)
+        .map(|part| match part {
+            Part::Literal(literal) => quote_literal(literal),
+            Part::Placeholder(name) => format!("\"${{{name}}}\""),
+        })
+        .collect()
+}
+
+fn quote_literal(literal: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_+=/.,:@%".contains(c);
+
+    if literal.chars().all(is_safe) {
+        literal.to_string()
+    } else {
+        format!("'{}'", literal.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).with_context(|| {
+        format!(
+            "Failed to make script file \"{}\" executable",
+            path.display()
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn set_executable(_: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{self, Format, Phase, Recorder};
+
+    const PASS: Pass = Pass {
+        number: 1,
+        count: 2,
+        phase: Phase::Measure,
+        description: "Processing audio file to measure loudness",
+    };
+
+    fn plan() -> Plan {
+        let mut plan = Plan::default();
+        let filter = format!("volume={}dB", Value::Placeholder("gain"));
+        plan.add_command(
+            &PASS,
+            ["ffmpeg", "-i", "in put ${1}.wav", "-af", &filter]
+                .into_iter()
+                .map(OsStr::new),
+        );
+        plan.placeholder("gain", "gain of pass 1 in dB");
+        plan.note(&format!(
+            "The {} is measured by pass 1",
+            Value::Placeholder("gain")
+        ));
+        plan
+    }
+
+    #[test]
+    fn print_reports_messages() {
+        let progress = progress::new(Format::Plain, None, false).unwrap();
+        let recorder = Recorder::new(progress.as_ref());
+
+        plan().print(&recorder);
+        assert_eq!(
+            *recorder.messages(),
+            [
+                "Dry run, no files are written. The following commands would be run:",
+                "[1/2] Processing audio file to measure loudness:",
+                "  ffmpeg -i 'in put ${1}.wav' -af volume=\"${gain}\"dB",
+                "  # The ${gain} is measured by pass 1",
+                "  ${gain}: gain of pass 1 in dB",
+            ]
+        );
+    }
+
+    #[test]
+    fn quote_args() {
+        assert_eq!(quote("-filter:a"), "-filter:a");
+        assert_eq!(
+            quote("loudnorm=I=-23:TP=-1,aresample=48000"),
+            "loudnorm=I=-23:TP=-1,aresample=48000"
+        );
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("in put.wav"), "'in put.wav'");
+        assert_eq!(quote("it's.wav"), "'it'\\''s.wav'");
+        assert_eq!(quote("$HOME.wav"), "'$HOME.wav'");
+        assert_eq!(quote("a;b"), "'a;b'");
+        // placeholders are expanded, everything else stays literal
+        let gain = Value::Placeholder("gain");
+        assert_eq!(
+            quote(&format!("volume={gain}dB \"$x\" `y` \\z ${{b}}")),
+            "volume=\"${gain}\"'dB \"$x\" `y` \\z ${b}'"
+        );
+        assert_eq!(quote(&gain.to_string()), "\"${gain}\"");
+        assert_eq!(
+            parts(&format!("a{gain}{gain}")).collect::<Vec<_>>(),
+            [
+                Part::Literal("a"),
+                Part::Placeholder("gain"),
+                Part::Placeholder("gain")
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn quote_args_for_the_shell() {
+        use std::process::Command;
+
+        let args = [
+            "",
+            "in put.wav",
+            "it's",
+            "$HOME",
+            "a;b|c&d",
+            "*",
+            "~",
+            "x\ny",
+            "\\",
+            "track ${1}.wav",
+            "a\\${b}",
+            "'${HOME}'",
+        ];
+        let script = format!(
+            "printf '%s\\0' {}",
+            command_line(args.into_iter().map(OsStr::new))
+        );
+        let output = Command::new("sh").arg("-c").arg(&script).output().unwrap();
+        let printed: Vec<_> = output
+            .stdout
+            .split(|byte| *byte == 0)
+            .map(String::from_utf8_lossy)
+            .collect();
+        assert_eq!(printed[..args.len()], args);
+
+        // the placeholder is set from pass 1
+        let script = format!(
+            "gain=-3.5; printf '%s' {}",
+            quote(&format!(
+                "volume={}dB $x ${{gain}}",
+                Value::Placeholder("gain")
+            ))
+        );
+        let output = Command::new("sh").arg("-c").arg(&script).output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "volume=-3.5dB $x ${gain}"
+        );
+    }
+
+    #[test]
+    fn write_script_of_plan() {
+        let path = std::env::temp_dir().join(format!("plan-{}.sh", std::process::id()));
+        let mut plan = plan();
+        plan.add_rename(
+            "Replacing input file",
+            Path::new("in put.tmp"),
+            Path::new("in put.wav"),
+        );
+
+        plan.write_script(&path).unwrap();
+        let script = fs::read_to_string(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o755
+            );
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert!(script.starts_with("#!/bin/sh\n# Generated by "));
+        assert!(script.contains(
+            "\n# [1/2] Processing audio file to measure loudness:\n\
+             # gain: gain of pass 1 in dB\n\
+             : \"${gain:?must be set from pass 1 output}\"\n\
+             ffmpeg -i 'in put ${1}.wav' -af volume=\"${gain}\"dB\n\
+             # The ${gain} is measured by pass 1\n"
+        ));
+        assert!(script.ends_with("\n# Replacing input file\nmv -f 'in put.tmp' 'in put.wav'\n"));
+    }
+}
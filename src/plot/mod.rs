@@ -0,0 +1,403 @@
+//! Loudness plot of the normalized output, rendered from the timeline as SVG or PNG.
+
+mod png;
+mod svg;
+
+use crate::algorithm::timeline::{Frame, Timeline};
+use crate::plan::Plan;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Highest true peak recommended by EBU R 128, marked if the algorithm has no true peak target
+pub const MAX_TRUE_PEAK: f64 = -1.0;
+
+const WIDTH: u32 = 1200;
+const HEIGHT: u32 = 540;
+const LEFT: f64 = 80.0;
+const RIGHT: f64 = 40.0;
+const TOP: f64 = 80.0;
+const BOTTOM: f64 = 70.0;
+/// Advance of a character, the SVG font size and the PNG font are chosen to match it
+const CHAR_WIDTH: f64 = 12.0;
+/// Lowest level of the level axis in LUFS
+const FLOOR: f64 = -70.0;
+
+/// Plot image format
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Scalable vector graphics
+    Svg,
+    /// Portable network graphics
+    Png,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Svg => "svg",
+            Format::Png => "png",
+        }
+    }
+}
+
+pub struct PlotArgs<'a> {
+    pub timeline: &'a Timeline,
+    /// Title of the plot, e.g. the output file name
+    pub title: &'a str,
+    /// Target level in LUFS, `None` if the algorithm does not target integrated loudness
+    pub target: Option<f64>,
+    /// Frames with a true peak above it in dBTP are marked
+    pub max_true_peak: f64,
+}
+
+#[derive(Clone, Copy)]
+enum Color {
+    Background,
+    Grid,
+    Text,
+    Band,
+    Momentary,
+    ShortTerm,
+    Target,
+    TruePeak,
+}
+
+/// RGB values of the colors, also the palette of the PNG
+const PALETTE: [(u8, u8, u8); 8] = [
+    (0xff, 0xff, 0xff),
+    (0xdd, 0xdd, 0xdd),
+    (0x33, 0x33, 0x33),
+    (0xd6, 0xe6, 0xf5),
+    (0x9e, 0xc5, 0xe8),
+    (0x1f, 0x5a, 0x96),
+    (0x2c, 0xa0, 0x2c),
+    (0xd6, 0x27, 0x28),
+];
+
+impl Color {
+    fn rgb(self) -> (u8, u8, u8) {
+        PALETTE[self as usize]
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+/// Drawing operations the plot is made of, coordinates are pixels from the top left
+trait Canvas {
+    fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, color: Color);
+    fn polyline(&mut self, points: &[(f64, f64)], color: Color, width: f64, dashed: bool);
+    /// Triangle pointing down to `x`, `y`
+    fn marker(&mut self, x: f64, y: f64, color: Color);
+    /// Text with its baseline at `y`
+    fn text(&mut self, x: f64, y: f64, text: &str, color: Color, anchor: Anchor);
+}
+
+/// Plot file of a format next to the output, e.g. "movie.loudness.svg" for "movie.mkv"
+pub fn file(output_file: &Path, format: Format) -> PathBuf {
+    let mut name = OsString::from(output_file.file_stem().unwrap_or_default());
+    name.push(".loudness.");
+    name.push(format.extension());
+    output_file.with_file_name(name)
+}
+
+/// Add a note of the plot files to the plan
+pub fn plan(output_file: &Path, formats: &[Format], plan: &mut Plan) {
+    formats.iter().for_each(|format| {
+        plan.note(&format!(
+            "The loudness over time is plotted to \"{}\"",
+            file(output_file, *format).display()
+        ))
+    });
+}
+
+/// Render the plot in every format next to the output
+pub fn write(args: &PlotArgs, output_file: &Path, formats: &[Format]) -> Result<()> {
+    for format in formats {
+        let data = match format {
+            Format::Svg => svg(args).into_bytes(),
+            Format::Png => {
+                let mut raster = png::Raster::new(WIDTH, HEIGHT);
+                draw(args, &mut raster);
+                raster.encode()
+            }
+        };
+
+        let plot_file = file(output_file, *format);
+        fs::write(&plot_file, data).with_context(|| {
+            format!("Failed to write loudness plot \"{}\"", plot_file.display())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Render the plot as SVG document, e.g. to embed it into the report
+pub fn svg(args: &PlotArgs) -> String {
+    let mut svg = svg::Svg::new(WIDTH, HEIGHT);
+    draw(args, &mut svg);
+    svg.finish()
+}
+
+/// Highest values of the frames that fall into one pixel column
+#[derive(Clone, Copy)]
+struct Column {
+    momentary: f64,
+    short_term: f64,
+    true_peak: Option<f64>,
+}
+
+fn draw(args: &PlotArgs, canvas: &mut dyn Canvas) {
+    let frames = &args.timeline.frames;
+    let loudness = &args.timeline.loudness;
+
+    let width = f64::from(WIDTH) - LEFT - RIGHT;
+    let height = f64::from(HEIGHT) - TOP - BOTTOM;
+    let duration = frames.last().map_or(0.0, |frame| frame.time).max(1.0);
+
+    // level axis from 0 down to 10 dB below the quietest short-term loudness
+    let lowest = frames
+        .iter()
+        .filter_map(|frame| frame.short_term)
+        .chain(args.target)
+        .filter(|level| *level > FLOOR)
+        .fold(-20.0, f64::min);
+    let bottom = ((lowest - 5.0) / 10.0).floor().max(FLOOR / 10.0) * 10.0;
+
+    let x = |time: f64| LEFT + time / duration * width;
+    let y = |level: f64| TOP + level.clamp(bottom, 0.0) / bottom * height;
+
+    canvas.rect(
+        0.0,
+        0.0,
+        f64::from(WIDTH),
+        f64::from(HEIGHT),
+        Color::Background,
+    );
+
+    if let Some((low, high)) = loudness_range(frames) {
+        canvas.rect(LEFT, y(high), width, y(low) - y(high), Color::Band);
+    }
+
+    // grid
+    let mut level = 0.0;
+    while level >= bottom {
+        canvas.polyline(
+            &[(LEFT, y(level)), (LEFT + width, y(level))],
+            Color::Grid,
+            1.0,
+            false,
+        );
+        canvas.text(
+            LEFT - 8.0,
+            y(level) + 7.0,
+            &format!("{level}"),
+            Color::Text,
+            Anchor::End,
+        );
+        level -= 10.0;
+    }
+    let step = time_step(duration);
+    let mut time = 0.0;
+    while time <= duration {
+        canvas.polyline(
+            &[(x(time), TOP), (x(time), TOP + height)],
+            Color::Grid,
+            1.0,
+            false,
+        );
+        canvas.text(
+            x(time),
+            TOP + height + 30.0,
+            &format_time(time, duration),
+            Color::Text,
+            Anchor::Middle,
+        );
+        time += step;
+    }
+    canvas.polyline(
+        &[
+            (LEFT, TOP),
+            (LEFT + width, TOP),
+            (LEFT + width, TOP + height),
+            (LEFT, TOP + height),
+            (LEFT, TOP),
+        ],
+        Color::Text,
+        1.0,
+        false,
+    );
+    canvas.text(LEFT - 8.0, TOP - 12.0, "LUFS", Color::Text, Anchor::End);
+
+    // loudness over time, reduced to the highest values per pixel column
+    let count = width as usize;
+    let mut columns: Vec<Option<Column>> = vec![None; count];
+    frames.iter().for_each(|frame| {
+        let index = ((frame.time / duration * (count - 1) as f64).round() as usize).min(count - 1);
+        // silence is drawn at the bottom
+        let momentary = frame.momentary.unwrap_or(f64::NEG_INFINITY);
+        let short_term = frame.short_term.unwrap_or(f64::NEG_INFINITY);
+        columns[index] = Some(match columns[index] {
+            Some(column) => Column {
+                momentary: column.momentary.max(momentary),
+                short_term: column.short_term.max(short_term),
+                true_peak: match (column.true_peak, frame.true_peak) {
+                    (Some(a), Some(b)) => Some(a.max(b)),
+                    (a, b) => a.or(b),
+                },
+            },
+            None => Column {
+                momentary,
+                short_term,
+                true_peak: frame.true_peak,
+            },
+        });
+    });
+    let column_x = |index: usize| LEFT + index as f64 * width / (count - 1) as f64;
+    let line = |level: fn(&Column) -> f64| -> Vec<(f64, f64)> {
+        columns
+            .iter()
+            .enumerate()
+            .filter_map(|(index, column)| column.map(|column| (column_x(index), y(level(&column)))))
+            .collect()
+    };
+    canvas.polyline(
+        &line(|column| column.momentary),
+        Color::Momentary,
+        1.0,
+        false,
+    );
+    canvas.polyline(
+        &line(|column| column.short_term),
+        Color::ShortTerm,
+        2.0,
+        false,
+    );
+
+    if let Some(target) = args.target {
+        canvas.polyline(
+            &[(LEFT, y(target)), (LEFT + width, y(target))],
+            Color::Target,
+            2.0,
+            true,
+        );
+        canvas.text(
+            LEFT + width - 6.0,
+            y(target) - 6.0,
+            &format!("Target {target} LUFS"),
+            Color::Target,
+            Anchor::End,
+        );
+    }
+
+    columns.iter().enumerate().for_each(|(index, column)| {
+        if let Some(true_peak) = column
+            .and_then(|column| column.true_peak)
+            .filter(|true_peak| *true_peak > args.max_true_peak)
+        {
+            canvas.marker(column_x(index), y(true_peak), Color::TruePeak);
+        }
+    });
+
+    // title and summary
+    canvas.text(LEFT, 28.0, args.title, Color::Text, Anchor::Start);
+    let summary = [
+        ("I", loudness.value, "LUFS"),
+        ("LRA", loudness.range, "LU"),
+        ("TP", loudness.max_true_peak, "dBTP"),
+        ("Max M", loudness.max_momentary, "LUFS"),
+        ("Max S", loudness.max_short_term, "LUFS"),
+    ]
+    .into_iter()
+    .filter_map(|(name, value, unit)| value.map(|value| format!("{name} {value:.1} {unit}")))
+    .collect::<Vec<_>>()
+    .join("  ");
+    canvas.text(LEFT, 52.0, &summary, Color::Text, Anchor::Start);
+
+    // legend
+    let mut legend_x = LEFT;
+    let legend_y = f64::from(HEIGHT) - 16.0;
+    let true_peak_label = format!("True peak > {} dBTP", args.max_true_peak);
+    let mut items = vec![
+        (Color::Momentary, "Momentary"),
+        (Color::ShortTerm, "Short-term"),
+        (Color::Band, "LRA"),
+    ];
+    if args.target.is_some() {
+        items.push((Color::Target, "Target"));
+    }
+    items.push((Color::TruePeak, &true_peak_label));
+    items.into_iter().for_each(|(color, label)| {
+        match color {
+            Color::Band => canvas.rect(legend_x, legend_y - 12.0, 20.0, 12.0, color),
+            Color::TruePeak => canvas.marker(legend_x + 10.0, legend_y - 2.0, color),
+            _ => canvas.polyline(
+                &[
+                    (legend_x, legend_y - 6.0),
+                    (legend_x + 20.0, legend_y - 6.0),
+                ],
+                color,
+                2.0,
+                matches!(color, Color::Target),
+            ),
+        }
+        canvas.text(legend_x + 28.0, legend_y, label, Color::Text, Anchor::Start);
+        legend_x += 28.0 + label.len() as f64 * CHAR_WIDTH + 24.0;
+    });
+}
+
+/// Loudness range as the 10th to 95th percentile of the gated short-term loudness (EBU Tech 3342)
+fn loudness_range(frames: &[Frame]) -> Option<(f64, f64)> {
+    let mut levels: Vec<f64> = frames
+        .iter()
+        .filter_map(|frame| frame.short_term)
+        .filter(|level| *level > FLOOR)
+        .collect();
+    if levels.is_empty() {
+        return None;
+    }
+
+    let power = levels
+        .iter()
+        .map(|level| 10f64.powf(level / 10.0))
+        .sum::<f64>()
+        / levels.len() as f64;
+    let gate = 10.0 * power.log10() - 20.0;
+    levels.retain(|level| *level > gate);
+    levels.sort_by(f64::total_cmp);
+
+    let percentile = |p: f64| levels[((levels.len() - 1) as f64 * p).round() as usize];
+    Some((percentile(0.10), percentile(0.95)))
+}
+
+/// Step of the time axis for at most 12 labels
+fn time_step(duration: f64) -> f64 {
+    [
+        1.0, 2.0, 5.0, 10.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 900.0, 1800.0, 3600.0,
+    ]
+    .into_iter()
+    .find(|step| duration / step <= 12.0)
+    .unwrap_or(7200.0)
+}
+
+/// Time as "M:SS", or "H:MM:SS" if the duration is an hour or longer
+fn format_time(time: f64, duration: f64) -> String {
+    let seconds = time.round() as u64;
+    if duration >= 3600.0 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
@@ -0,0 +1,470 @@
+use super::{Anchor, Canvas, Color, CHAR_WIDTH, PALETTE};
+
+/// Font scale of the 5x7 glyphs, a character is `CHAR_WIDTH` wide including spacing
+const SCALE: i64 = 2;
+
+/// CRC-32 lookup table of polynomial 0xEDB88320 (reflected 0x04C11DB7)
+const CRC32_TABLE: [u32; 256] = crc32_table(0xedb8_8320);
+
+/// Length base and extra bits of the deflate length codes 257 to 285
+const LENGTHS: [(u16, u8); 29] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 1),
+    (13, 1),
+    (15, 1),
+    (17, 1),
+    (19, 2),
+    (23, 2),
+    (27, 2),
+    (31, 2),
+    (35, 3),
+    (43, 3),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 4),
+    (115, 4),
+    (131, 5),
+    (163, 5),
+    (195, 5),
+    (227, 5),
+    (258, 0),
+];
+
+/// Plot as image of palette indices, encoded as PNG
+pub struct Raster {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Raster {
+    pub fn new(width: u32, height: u32) -> Self {
+        Raster {
+            width,
+            height,
+            pixels: vec![Color::Background as u8; width as usize * height as usize],
+        }
+    }
+
+    fn set(&mut self, x: i64, y: i64, color: Color) {
+        if (0..i64::from(self.width)).contains(&x) && (0..i64::from(self.height)).contains(&y) {
+            self.pixels[y as usize * self.width as usize + x as usize] = color as u8;
+        }
+    }
+
+    fn fill(&mut self, x0: i64, y0: i64, x1: i64, y1: i64, color: Color) {
+        (y0..y1).for_each(|y| (x0..x1).for_each(|x| self.set(x, y, color)));
+    }
+
+    /// Encode as 8 bit indexed color PNG
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ihdr = Vec::new();
+        ihdr.extend(self.width.to_be_bytes());
+        ihdr.extend(self.height.to_be_bytes());
+        // bit depth, color type indexed, compression, filter, interlace
+        ihdr.extend([8, 3, 0, 0, 0]);
+
+        let plte: Vec<u8> = PALETTE.iter().flat_map(|(r, g, b)| [*r, *g, *b]).collect();
+
+        // every row starts with filter type none
+        let rows: Vec<u8> = self
+            .pixels
+            .chunks(self.width as usize)
+            .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+            .collect();
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        chunk(&mut png, b"IHDR", &ihdr);
+        chunk(&mut png, b"PLTE", &plte);
+        chunk(&mut png, b"IDAT", &zlib(&rows));
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+impl Canvas for Raster {
+    fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, color: Color) {
+        self.fill(
+            x.round() as i64,
+            y.round() as i64,
+            (x + width).round() as i64,
+            (y + height).round() as i64,
+            color,
+        );
+    }
+
+    fn polyline(&mut self, points: &[(f64, f64)], color: Color, width: f64, dashed: bool) {
+        let size = (width.round() as i64).max(1);
+        let offset = (size - 1) / 2;
+        let mut travelled = 0.0;
+
+        points.windows(2).for_each(|segment| {
+            let ((x0, y0), (x1, y1)) = (segment[0], segment[1]);
+            let length = (x1 - x0).hypot(y1 - y0);
+            let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0) as usize;
+
+            (0..=steps).for_each(|step| {
+                let t = step as f64 / steps as f64;
+                // 8 pixels on, 8 pixels off
+                if dashed && ((travelled + t * length) / 8.0) as i64 % 2 == 1 {
+                    return;
+                }
+                let x = (x0 + t * (x1 - x0)).round() as i64 - offset;
+                let y = (y0 + t * (y1 - y0)).round() as i64 - offset;
+                self.fill(x, y, x + size, y + size, color);
+            });
+            travelled += length;
+        });
+    }
+
+    fn marker(&mut self, x: f64, y: f64, color: Color) {
+        let (x, y) = (x.round() as i64, y.round() as i64);
+        (0..10).for_each(|row| {
+            let half = row / 2;
+            self.fill(x - half, y - row, x + half + 1, y - row + 1, color);
+        });
+    }
+
+    fn text(&mut self, x: f64, y: f64, text: &str, color: Color, anchor: Anchor) {
+        let width = text.chars().count() as f64 * CHAR_WIDTH;
+        let left = match anchor {
+            Anchor::Start => x,
+            Anchor::Middle => x - width / 2.0,
+            Anchor::End => x - width,
+        }
+        .round() as i64;
+        let top = y.round() as i64 - 7 * SCALE;
+
+        text.chars().enumerate().for_each(|(i, c)| {
+            let char_left = left + i as i64 * CHAR_WIDTH as i64;
+            glyph(c).iter().enumerate().for_each(|(column, bits)| {
+                (0..7).filter(|row| bits >> row & 1 == 1).for_each(|row| {
+                    let x = char_left + column as i64 * SCALE;
+                    let y = top + row * SCALE;
+                    self.fill(x, y, x + SCALE, y + SCALE, color);
+                });
+            });
+        });
+    }
+}
+
+/// Columns of a 5x7 glyph, the lowest bit is the top row. Lower case is drawn as upper case.
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '-' => [0x08, 0x08, 0x08, 0x08, 0x08],
+        '+' => [0x08, 0x08, 0x3e, 0x08, 0x08],
+        '.' => [0x00, 0x60, 0x60, 0x00, 0x00],
+        ':' => [0x00, 0x36, 0x36, 0x00, 0x00],
+        '/' => [0x20, 0x10, 0x08, 0x04, 0x02],
+        '>' => [0x00, 0x41, 0x22, 0x14, 0x08],
+        '<' => [0x08, 0x14, 0x22, 0x41, 0x00],
+        '=' => [0x14, 0x14, 0x14, 0x14, 0x14],
+        '%' => [0x23, 0x13, 0x08, 0x64, 0x62],
+        '(' => [0x00, 0x1c, 0x22, 0x41, 0x00],
+        ')' => [0x00, 0x41, 0x22, 0x1c, 0x00],
+        '_' => [0x40, 0x40, 0x40, 0x40, 0x40],
+        '0' => [0x3e, 0x51, 0x49, 0x45, 0x3e],
+        '1' => [0x00, 0x42, 0x7f, 0x40, 0x00],
+        '2' => [0x42, 0x61, 0x51, 0x49, 0x46],
+        '3' => [0x21, 0x41, 0x45, 0x4b, 0x31],
+        '4' => [0x18, 0x14, 0x12, 0x7f, 0x10],
+        '5' => [0x27, 0x45, 0x45, 0x45, 0x39],
+        '6' => [0x3c, 0x4a, 0x49, 0x49, 0x30],
+        '7' => [0x01, 0x71, 0x09, 0x05, 0x03],
+        '8' => [0x36, 0x49, 0x49, 0x49, 0x36],
+        '9' => [0x06, 0x49, 0x49, 0x29, 0x1e],
+        'A' => [0x7e, 0x11, 0x11, 0x11, 0x7e],
+        'B' => [0x7f, 0x49, 0x49, 0x49, 0x36],
+        'C' => [0x3e, 0x41, 0x41, 0x41, 0x22],
+        'D' => [0x7f, 0x41, 0x41, 0x22, 0x1c],
+        'E' => [0x7f, 0x49, 0x49, 0x49, 0x41],
+        'F' => [0x7f, 0x09, 0x09, 0x01, 0x01],
+        'G' => [0x3e, 0x41, 0x41, 0x51, 0x32],
+        'H' => [0x7f, 0x08, 0x08, 0x08, 0x7f],
+        'I' => [0x00, 0x41, 0x7f, 0x41, 0x00],
+        'J' => [0x20, 0x40, 0x41, 0x3f, 0x01],
+        'K' => [0x7f, 0x08, 0x14, 0x22, 0x41],
+        'L' => [0x7f, 0x40, 0x40, 0x40, 0x40],
+        'M' => [0x7f, 0x02, 0x04, 0x02, 0x7f],
+        'N' => [0x7f, 0x04, 0x08, 0x10, 0x7f],
+        'O' => [0x3e, 0x41, 0x41, 0x41, 0x3e],
+        'P' => [0x7f, 0x09, 0x09, 0x09, 0x06],
+        'Q' => [0x3e, 0x41, 0x51, 0x21, 0x5e],
+        'R' => [0x7f, 0x09, 0x19, 0x29, 0x46],
+        'S' => [0x46, 0x49, 0x49, 0x49, 0x31],
+        'T' => [0x01, 0x01, 0x7f, 0x01, 0x01],
+        'U' => [0x3f, 0x40, 0x40, 0x40, 0x3f],
+        'V' => [0x1f, 0x20, 0x40, 0x20, 0x1f],
+        'W' => [0x7f, 0x20, 0x18, 0x20, 0x7f],
+        'X' => [0x63, 0x14, 0x08, 0x14, 0x63],
+        'Y' => [0x03, 0x04, 0x78, 0x04, 0x03],
+        'Z' => [0x61, 0x51, 0x49, 0x45, 0x43],
+        _ => [0x00; 5],
+    }
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    let crc = !crc32(crc32(0xffff_ffff, kind), data);
+    png.extend(crc.to_be_bytes());
+}
+
+const fn crc32_table(poly: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 with reflected polynomial 0xEDB88320, LSB first, without final XOR
+fn crc32(init: u32, data: &[u8]) -> u32 {
+    data.iter().fold(init, |crc, byte| {
+        (crc >> 8) ^ CRC32_TABLE[usize::from(crc as u8 ^ byte)]
+    })
+}
+
+/// Writes the bits of a deflate stream, LSB first
+#[derive(Default)]
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are written MSB first
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write(code.reverse_bits() >> (32 - length), length);
+    }
+
+    /// Code of a literal or length symbol of the fixed Huffman codes
+    fn write_symbol(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.data.push(self.bits as u8);
+        }
+        self.data
+    }
+}
+
+/// zlib stream of one deflate block with fixed Huffman codes. Runs of the same byte,
+/// which make up most of a plot, are encoded as matches of distance 1.
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    // last block, fixed Huffman codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    let mut i = 0;
+    while i < data.len() {
+        let run = if i > 0 {
+            data[i..]
+                .iter()
+                .take(258)
+                .take_while(|byte| **byte == data[i - 1])
+                .count()
+        } else {
+            0
+        };
+
+        if run >= 3 {
+            let index = LENGTHS
+                .iter()
+                .rposition(|(base, _)| usize::from(*base) <= run)
+                .unwrap_or_default();
+            let (base, extra) = LENGTHS[index];
+            writer.write_symbol(257 + index as u32);
+            writer.write(run as u32 - u32::from(base), u32::from(extra));
+            // distance code 0 is distance 1
+            writer.write_code(0, 5);
+            i += run;
+        } else {
+            writer.write_symbol(u32::from(data[i]));
+            i += 1;
+        }
+    }
+    writer.write_symbol(256);
+
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + u32::from(*byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+
+    let mut zlib = vec![0x78, 0x01];
+    zlib.extend(writer.finish());
+    zlib.extend(((b << 16) | a).to_be_bytes());
+    zlib
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the bits of a deflate stream, LSB first
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |value, i| {
+                let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+                self.pos += 1;
+                value | u32::from(bit) << i
+            })
+        }
+
+        /// Huffman codes are read MSB first
+        fn read_code(&mut self, length: u32) -> u32 {
+            (0..length).fold(0, |code, _| code << 1 | self.read(1))
+        }
+
+        /// Literal or length symbol of the fixed Huffman codes
+        fn read_symbol(&mut self) -> u32 {
+            let code = self.read_code(7);
+            if code <= 0x17 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.read(1);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => 280 + code - 0xc0,
+                _ => 144 + (code << 1 | self.read(1)) - 0x190,
+            }
+        }
+    }
+
+    /// Data of a zlib stream of one deflate block with fixed Huffman codes and
+    /// distances of up to 4 bytes, as written by `zlib`
+    fn inflate(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(zlib[..2], [0x78, 0x01]);
+        let mut reader = BitReader {
+            data: &zlib[2..zlib.len() - 4],
+            pos: 0,
+        };
+        // last block, fixed Huffman codes
+        assert_eq!(reader.read(1), 1);
+        assert_eq!(reader.read(2), 1);
+
+        let mut data: Vec<u8> = Vec::new();
+        loop {
+            match reader.read_symbol() {
+                literal @ 0..=255 => data.push(literal as u8),
+                256 => break,
+                symbol => {
+                    let (base, extra) = LENGTHS[symbol as usize - 257];
+                    let length = usize::from(base) + reader.read(u32::from(extra)) as usize;
+                    let distance = reader.read_code(5) as usize + 1;
+                    assert!(distance <= 4);
+                    (0..length).for_each(|_| data.push(data[data.len() - distance]));
+                }
+            }
+        }
+
+        let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+            let a = (a + u32::from(*byte)) % 65521;
+            (a, (b + a) % 65521)
+        });
+        assert_eq!(zlib[zlib.len() - 4..], ((b << 16) | a).to_be_bytes());
+        data
+    }
+
+    /// Chunks of a PNG file, their CRC is checked
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < png.len() {
+            let size = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = png[pos + 4..pos + 8].try_into().unwrap();
+            let data = &png[pos + 8..pos + 8 + size];
+            let crc = u32::from_be_bytes(png[pos + 8 + size..pos + 12 + size].try_into().unwrap());
+            assert_eq!(crc, !crc32(crc32(0xffff_ffff, &kind), data));
+            chunks.push((kind, data.to_vec()));
+            pos += 12 + size;
+        }
+        chunks
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(!crc32(0xffff_ffff, b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn encode_decodes_to_pixels() {
+        // rows longer than the longest match of deflate
+        let mut raster = Raster::new(600, 40);
+        raster.rect(10.0, 5.0, 500.0, 20.0, Color::Band);
+        raster.polyline(&[(0.0, 30.0), (599.0, 0.0)], Color::Momentary, 2.0, true);
+        raster.text(20.0, 38.0, "-23 LUFS", Color::Text, Anchor::Start);
+
+        let chunks = chunks(&raster.encode());
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, [b"IHDR", b"PLTE", b"IDAT", b"IEND"]);
+
+        let ihdr = &chunks[0].1;
+        assert_eq!(ihdr[..4], 600u32.to_be_bytes());
+        assert_eq!(ihdr[4..8], 40u32.to_be_bytes());
+        assert_eq!(ihdr[8..], [8, 3, 0, 0, 0]);
+        assert_eq!(chunks[1].1.len(), PALETTE.len() * 3);
+
+        let rows = inflate(&chunks[2].1);
+        assert_eq!(rows.len(), 40 * 601);
+        let pixels: Vec<u8> = rows
+            .chunks(601)
+            .flat_map(|row| {
+                assert_eq!(row[0], 0);
+                row[1..].to_vec()
+            })
+            .collect();
+        assert_eq!(pixels, raster.pixels);
+        assert_eq!(pixels[0], Color::Background as u8);
+        assert_eq!(pixels[6 * 600 + 11], Color::Band as u8);
+        assert!(pixels.contains(&(Color::Momentary as u8)));
+        assert!(pixels.contains(&(Color::Text as u8)));
+    }
+}
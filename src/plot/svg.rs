@@ -0,0 +1,88 @@
+use super::{Anchor, Canvas, Color};
+use crate::xml::escape;
+use std::fmt::Write as _;
+
+/// Plot as SVG document
+pub struct Svg {
+    data: String,
+}
+
+impl Svg {
+    pub fn new(width: u32, height: u32) -> Self {
+        Svg {
+            data: format!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+                 viewBox=\"0 0 {width} {height}\" font-family=\"monospace\" font-size=\"20\">\n"
+            ),
+        }
+    }
+
+    pub fn finish(mut self) -> String {
+        self.data += "</svg>\n";
+        self.data
+    }
+}
+
+fn hex(color: Color) -> String {
+    let (r, g, b) = color.rgb();
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+impl Canvas for Svg {
+    fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, color: Color) {
+        let _ = writeln!(
+            self.data,
+            "<rect x=\"{x:.1}\" y=\"{y:.1}\" width=\"{width:.1}\" height=\"{height:.1}\" fill=\"{}\"/>",
+            hex(color)
+        );
+    }
+
+    fn polyline(&mut self, points: &[(f64, f64)], color: Color, width: f64, dashed: bool) {
+        if points.is_empty() {
+            return;
+        }
+
+        let points = points
+            .iter()
+            .map(|(x, y)| format!("{x:.1},{y:.1}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let dash = if dashed {
+            " stroke-dasharray=\"8 8\""
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            self.data,
+            "<polyline points=\"{points}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{width}\" \
+             stroke-linejoin=\"round\"{dash}/>",
+            hex(color)
+        );
+    }
+
+    fn marker(&mut self, x: f64, y: f64, color: Color) {
+        let _ = writeln!(
+            self.data,
+            "<path d=\"M{:.1},{:.1}L{:.1},{:.1}L{x:.1},{y:.1}Z\" fill=\"{}\"/>",
+            x - 5.0,
+            y - 10.0,
+            x + 5.0,
+            y - 10.0,
+            hex(color)
+        );
+    }
+
+    fn text(&mut self, x: f64, y: f64, text: &str, color: Color, anchor: Anchor) {
+        let anchor = match anchor {
+            Anchor::Start => "start",
+            Anchor::Middle => "middle",
+            Anchor::End => "end",
+        };
+        let _ = writeln!(
+            self.data,
+            "<text x=\"{x:.1}\" y=\"{y:.1}\" fill=\"{}\" text-anchor=\"{anchor}\">{}</text>",
+            hex(color),
+            escape(text)
+        );
+    }
+}
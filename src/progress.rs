@@ -0,0 +1,581 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::cell::{Cell, Ref, RefCell};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{stdout, Write};
+use std::time::{Duration, Instant};
+
+/// Progress output format
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable output with progress bar
+    Bar,
+    /// Human readable output without progress bar
+    Plain,
+    /// Newline-delimited JSON events
+    Json,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Measure,
+    Normalize,
+}
+
+/// Single ffmpeg run of a normalization
+pub struct Pass {
+    pub number: u8,
+    pub count: u8,
+    pub phase: Phase,
+    pub description: &'static str,
+}
+
+impl Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}/{}] {}:", self.number, self.count, self.description)
+    }
+}
+
+/// Progress block of a running pass reported by ffmpeg `-progress`
+#[derive(Default, Clone, Debug)]
+pub struct Status {
+    /// Position in the output
+    pub out_time: Duration,
+    /// Size of the output written so far in bytes
+    pub total_size: Option<u64>,
+    /// Output bitrate in kbit/s
+    pub bitrate: Option<f64>,
+    /// Encoding speed relative to real time
+    pub speed: Option<f64>,
+    /// `progress=end` is reported
+    pub end: bool,
+}
+
+/// Value measured or computed during normalization
+pub struct Measurement<'a> {
+    pub name: &'a str,
+    pub label: &'a str,
+    pub value: f64,
+    pub unit: &'a str,
+}
+
+/// Receives progress of a normalization.
+pub trait Progress {
+    /// A pass is started, `duration` is the input duration if known.
+    fn start(&self, pass: &Pass, duration: Option<Duration>);
+    /// ffmpeg reported progress of the running pass.
+    fn update(&self, status: &Status);
+    /// The running pass is completed, `status` is the last reported progress.
+    fn finish(&self, status: &Status);
+    /// A value is measured or computed.
+    fn measurement(&self, measurement: &Measurement);
+    /// Informational output, e.g. ffmpeg log.
+    fn message(&self, text: &str);
+    /// Normalization failed.
+    fn error(&self, message: &str);
+}
+
+pub fn new(format: Format, fd: Option<u32>, verbose: bool) -> Result<Box<dyn Progress>> {
+    if fd.is_some() && format != Format::Json {
+        bail!("--progress-fd requires --progress json");
+    }
+
+    Ok(match format {
+        Format::Bar => Box::new(BarProgress::new(verbose, true)),
+        Format::Plain => Box::new(BarProgress::new(verbose, false)),
+        Format::Json => {
+            let out: Box<dyn Write> = match fd {
+                Some(fd) => Box::new(open_fd(fd)?),
+                None => Box::new(stdout()),
+            };
+            Box::new(JsonProgress::new(out))
+        }
+    })
+}
+
+/// File of an inherited file descriptor, e.g. a pipe of the calling process
+#[cfg(unix)]
+fn open_fd(fd: u32) -> Result<File> {
+    use std::mem::ManuallyDrop;
+    use std::os::fd::{FromRawFd, RawFd};
+
+    let raw = RawFd::try_from(fd).with_context(|| format!("Invalid file descriptor {fd}"))?;
+    // SAFETY: the descriptor is passed to us for exclusive use. It is not closed on drop
+    // until fstat shows that it is open.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(raw) });
+    file.metadata()
+        .with_context(|| format!("Failed to open file descriptor {fd}"))?;
+
+    Ok(ManuallyDrop::into_inner(file))
+}
+
+#[cfg(not(unix))]
+fn open_fd(_: u32) -> Result<File> {
+    bail!("--progress-fd is only supported on Unix, use stdout instead")
+}
+
+struct BarProgress {
+    verbose: bool,
+    show_bar: bool,
+    phase: Cell<Phase>,
+    bar: RefCell<Option<(ProgressBar, bool)>>,
+}
+
+impl BarProgress {
+    fn new(verbose: bool, show_bar: bool) -> Self {
+        BarProgress {
+            verbose,
+            show_bar,
+            phase: Cell::new(Phase::Measure),
+            bar: RefCell::new(None),
+        }
+    }
+}
+
+impl Progress for BarProgress {
+    fn start(&self, pass: &Pass, duration: Option<Duration>) {
+        println!("{pass}");
+
+        self.phase.set(pass.phase);
+
+        if !self.show_bar {
+            return;
+        }
+
+        let bar = ProgressBar::new(
+            duration
+                .unwrap_or_else(|| Duration::from_secs(10))
+                .as_micros() as u64,
+        );
+
+        bar.set_style(
+            if duration.is_some() {
+                ProgressStyle::default_bar().template(
+                    "[{elapsed_precise}] {bar:50.cyan/cyan} {percent}% (remaining: {eta}) {msg}",
+                )
+            } else {
+                ProgressStyle::default_bar().template("[{elapsed_precise}] {spinner:.cyan} {msg}")
+            }
+            .unwrap_or_else(|_| ProgressStyle::default_bar()),
+        );
+
+        bar.set_position(0);
+
+        *self.bar.borrow_mut() = Some((bar, duration.is_some()));
+    }
+
+    fn update(&self, status: &Status) {
+        if let Some((bar, has_duration)) = self.bar.borrow().as_ref() {
+            let us = status.out_time.as_micros() as u64;
+            if *has_duration {
+                bar.set_position(us);
+            } else {
+                bar.set_position(us % 10);
+            }
+
+            let mut msg = Vec::new();
+            if let Some(speed) = status.speed {
+                msg.push(format!("{speed}x"));
+            }
+            if let Some(bitrate) = status.bitrate {
+                msg.push(format!("{bitrate}kbit/s"));
+            }
+            bar.set_message(msg.join(" "));
+        }
+    }
+
+    fn finish(&self, status: &Status) {
+        if let Some((bar, _)) = self.bar.borrow_mut().take() {
+            bar.finish();
+        }
+
+        if let Some(size) = status.total_size.filter(|size| *size > 0) {
+            if self.verbose && self.phase.get() == Phase::Normalize {
+                println!("  Output size = {}", HumanBytes(size));
+            }
+        }
+    }
+
+    fn measurement(&self, measurement: &Measurement) {
+        if self.verbose {
+            println!(
+                "  {} = {}{}",
+                measurement.label, measurement.value, measurement.unit
+            );
+        }
+    }
+
+    fn message(&self, text: &str) {
+        println!("{text}");
+    }
+
+    fn error(&self, _: &str) {
+        // anyhow prints the error on exit
+        if let Some((bar, _)) = self.bar.borrow_mut().take() {
+            bar.abandon();
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Start {
+        pass: u8,
+        passes: u8,
+        phase: Phase,
+        description: &'a str,
+        duration: Option<f64>,
+    },
+    Progress {
+        pass: u8,
+        phase: Phase,
+        out_time: f64,
+        percent: Option<f64>,
+        total_size: Option<u64>,
+        bitrate: Option<f64>,
+        speed: Option<f64>,
+        eta: Option<f64>,
+    },
+    Finish {
+        pass: u8,
+        phase: Phase,
+        out_time: f64,
+        total_size: Option<u64>,
+    },
+    Measurement {
+        name: &'a str,
+        value: f64,
+        unit: &'a str,
+    },
+    Message {
+        text: &'a str,
+    },
+    Error {
+        message: &'a str,
+    },
+}
+
+struct RunningPass {
+    number: u8,
+    phase: Phase,
+    duration: Option<Duration>,
+    started: Instant,
+}
+
+struct JsonProgress {
+    out: RefCell<Box<dyn Write>>,
+    pass: RefCell<Option<RunningPass>>,
+}
+
+impl JsonProgress {
+    fn new(out: Box<dyn Write>) -> Self {
+        JsonProgress {
+            out: RefCell::new(out),
+            pass: RefCell::new(None),
+        }
+    }
+
+    fn emit(&self, event: &Event) {
+        let mut out = self.out.borrow_mut();
+        if let Ok(line) = serde_json::to_string(event) {
+            let _ = writeln!(out, "{line}");
+            let _ = out.flush();
+        }
+    }
+}
+
+impl Progress for JsonProgress {
+    fn start(&self, pass: &Pass, duration: Option<Duration>) {
+        *self.pass.borrow_mut() = Some(RunningPass {
+            number: pass.number,
+            phase: pass.phase,
+            duration,
+            started: Instant::now(),
+        });
+
+        self.emit(&Event::Start {
+            pass: pass.number,
+            passes: pass.count,
+            phase: pass.phase,
+            description: pass.description,
+            duration: duration.map(|d| d.as_secs_f64()),
+        });
+    }
+
+    fn update(&self, status: &Status) {
+        if let Some(pass) = self.pass.borrow().as_ref() {
+            let out_time = status.out_time.as_secs_f64();
+            let duration = pass.duration.map(|d| d.as_secs_f64());
+
+            let percent = duration
+                .filter(|d| *d > 0.0)
+                .map(|d| (out_time / d * 100.0).min(100.0));
+
+            // remaining media time divided by the media time processed per second
+            let elapsed = pass.started.elapsed().as_secs_f64();
+            let rate = status
+                .speed
+                .or_else(|| (elapsed > 0.0).then(|| out_time / elapsed))
+                .filter(|rate| *rate > 0.0);
+            let eta = duration
+                .zip(rate)
+                .map(|(d, rate)| (d - out_time).max(0.0) / rate);
+
+            self.emit(&Event::Progress {
+                pass: pass.number,
+                phase: pass.phase,
+                out_time,
+                percent,
+                total_size: status.total_size,
+                bitrate: status.bitrate,
+                speed: status.speed,
+                eta,
+            });
+        }
+    }
+
+    fn finish(&self, status: &Status) {
+        if let Some(pass) = self.pass.borrow_mut().take() {
+            self.emit(&Event::Finish {
+                pass: pass.number,
+                phase: pass.phase,
+                out_time: status.out_time.as_secs_f64(),
+                total_size: status.total_size,
+            });
+        }
+    }
+
+    fn measurement(&self, measurement: &Measurement) {
+        self.emit(&Event::Measurement {
+            name: measurement.name,
+            value: measurement.value,
+            unit: measurement.unit,
+        });
+    }
+
+    fn message(&self, text: &str) {
+        self.emit(&Event::Message { text });
+    }
+
+    fn error(&self, message: &str) {
+        self.emit(&Event::Error { message });
+    }
+}
+
+/// Passes progress on and keeps the reported measurements and messages, e.g. the level of the
+/// reference file that is the target line of the plot
+pub struct Recorder<'a> {
+    inner: &'a dyn Progress,
+    measurements: RefCell<Vec<(String, f64)>>,
+    messages: RefCell<Vec<String>>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(inner: &'a dyn Progress) -> Self {
+        Recorder {
+            inner,
+            measurements: RefCell::new(Vec::new()),
+            messages: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Last reported value of the measurement `name`
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.measurements
+            .borrow()
+            .iter()
+            .rev()
+            .find(|(measured, _)| measured == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Reported messages including the ffmpeg log of normalizing passes
+    pub fn messages(&self) -> Ref<'_, Vec<String>> {
+        self.messages.borrow()
+    }
+}
+
+impl Progress for Recorder<'_> {
+    fn start(&self, pass: &Pass, duration: Option<Duration>) {
+        self.inner.start(pass, duration);
+    }
+
+    fn update(&self, status: &Status) {
+        self.inner.update(status);
+    }
+
+    fn finish(&self, status: &Status) {
+        self.inner.finish(status);
+    }
+
+    fn measurement(&self, measurement: &Measurement) {
+        self.measurements
+            .borrow_mut()
+            .push((measurement.name.to_string(), measurement.value));
+        self.inner.measurement(measurement);
+    }
+
+    fn message(&self, text: &str) {
+        self.messages.borrow_mut().push(text.to_string());
+        self.inner.message(text);
+    }
+
+    fn error(&self, message: &str) {
+        self.inner.error(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    const PASS: Pass = Pass {
+        number: 2,
+        count: 2,
+        phase: Phase::Normalize,
+        description: "Processing audio file to normalize",
+    };
+
+    /// Output of a JSON progress that stays readable after it is moved into the progress
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn events(&self) -> Vec<serde_json::Value> {
+            String::from_utf8(self.0.borrow().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn json_events() {
+        let buffer = Buffer::default();
+        let progress = JsonProgress::new(Box::new(buffer.clone()));
+
+        progress.start(&PASS, Some(Duration::from_secs(100)));
+        progress.update(&Status {
+            out_time: Duration::from_secs(25),
+            total_size: Some(1000),
+            bitrate: Some(320.0),
+            speed: Some(5.0),
+            end: false,
+        });
+        let end = Status {
+            out_time: Duration::from_secs(100),
+            total_size: Some(4000),
+            end: true,
+            ..Status::default()
+        };
+        progress.finish(&end);
+        // a finished pass reports no progress
+        progress.update(&end);
+        progress.measurement(&Measurement {
+            name: "output_i",
+            label: "Output integrated loudness",
+            value: -23.0,
+            unit: "LUFS",
+        });
+        progress.message("done");
+        progress.error("failed");
+
+        assert_eq!(
+            buffer.events(),
+            [
+                serde_json::json!({"event": "start", "pass": 2, "passes": 2,
+                    "phase": "normalize", "description": PASS.description, "duration": 100.0}),
+                // 75 s of media left at 5 times real time
+                serde_json::json!({"event": "progress", "pass": 2, "phase": "normalize",
+                    "out_time": 25.0, "percent": 25.0, "total_size": 1000, "bitrate": 320.0,
+                    "speed": 5.0, "eta": 15.0}),
+                serde_json::json!({"event": "finish", "pass": 2, "phase": "normalize",
+                    "out_time": 100.0, "total_size": 4000}),
+                serde_json::json!({"event": "measurement", "name": "output_i", "value": -23.0,
+                    "unit": "LUFS"}),
+                serde_json::json!({"event": "message", "text": "done"}),
+                serde_json::json!({"event": "error", "message": "failed"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_progress_without_duration() {
+        let buffer = Buffer::default();
+        let progress = JsonProgress::new(Box::new(buffer.clone()));
+
+        progress.start(&PASS, None);
+        progress.update(&Status {
+            out_time: Duration::from_secs(25),
+            ..Status::default()
+        });
+
+        let events = buffer.events();
+        assert_eq!(events[0]["duration"], serde_json::Value::Null);
+        assert_eq!(events[1]["percent"], serde_json::Value::Null);
+        assert_eq!(events[1]["eta"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn json_progress_percent_is_capped() {
+        let buffer = Buffer::default();
+        let progress = JsonProgress::new(Box::new(buffer.clone()));
+
+        // the input duration of ffprobe may be shorter than the decoded audio
+        progress.start(&PASS, Some(Duration::from_secs(10)));
+        progress.update(&Status {
+            out_time: Duration::from_secs(12),
+            speed: Some(2.0),
+            ..Status::default()
+        });
+
+        let events = buffer.events();
+        assert_eq!(events[1]["percent"], 100.0);
+        assert_eq!(events[1]["eta"], 0.0);
+    }
+
+    #[test]
+    fn recorder_keeps_last_measurement() {
+        let buffer = Buffer::default();
+        let inner = JsonProgress::new(Box::new(buffer.clone()));
+        let recorder = Recorder::new(&inner);
+
+        let measurement = |value| Measurement {
+            name: "input_i",
+            label: "Input integrated loudness",
+            value,
+            unit: "LUFS",
+        };
+        recorder.measurement(&measurement(-30.0));
+        recorder.measurement(&measurement(-27.5));
+        recorder.message("ffmpeg log");
+
+        assert_eq!(recorder.get("input_i"), Some(-27.5));
+        assert_eq!(recorder.get("input_tp"), None);
+        assert_eq!(*recorder.messages(), ["ffmpeg log"]);
+        // everything is passed on
+        assert_eq!(buffer.events().len(), 3);
+    }
+
+    #[test]
+    fn new_rejects_fd_without_json() {
+        assert!(new(Format::Plain, Some(3), false).is_err());
+    }
+}
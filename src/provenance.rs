@@ -0,0 +1,162 @@
+use crate::bitstream::bext;
+use crate::plan::Value;
+use crate::tool::ffprobe::{FFprobe, Probe};
+use anyhow::{Context, Result};
+use clap::{crate_name, crate_version};
+use std::path::Path;
+
+/// Name and version of the tool that normalized the file
+const TOOL_KEY: &str = "NORMALIZER";
+const ALGORITHM_KEY: &str = "NORMALIZER_ALGORITHM";
+const TARGET_KEY: &str = "NORMALIZER_TARGET";
+/// Measured values of the input, e.g. "input_i=-18.20 LUFS, input_tp=-0.50 dBTP"
+const INPUT_KEY: &str = "NORMALIZER_INPUT";
+const GAIN_KEY: &str = "NORMALIZER_GAIN";
+/// File name of the reference of `--match`
+const REFERENCE_KEY: &str = "NORMALIZER_REFERENCE";
+
+const KEYS: &[&str] = &[
+    TOOL_KEY,
+    ALGORITHM_KEY,
+    TARGET_KEY,
+    INPUT_KEY,
+    GAIN_KEY,
+    REFERENCE_KEY,
+];
+
+/// Output file extensions whose containers cannot store custom tags
+const UNTAGGED: &[&str] = &[
+    "w64", "aif", "aiff", "ac3", "eac3", "ec3", "aac", "adts", "thd",
+];
+
+/// Tag of the coding history of the bext chunk of WAV files, which holds the tags
+const CODING_HISTORY_KEY: &str = "coding_history";
+
+/// What a normalization did, written as tags to the output file.
+#[derive(Clone)]
+pub struct Provenance {
+    algorithm: &'static str,
+    target: String,
+    input: Vec<(&'static str, Value, &'static str)>,
+    gain: Option<Value>,
+    reference: Option<String>,
+}
+
+impl Provenance {
+    pub fn new(algorithm: &'static str, target: String) -> Self {
+        Provenance {
+            algorithm,
+            target,
+            input: Vec::new(),
+            gain: None,
+            reference: None,
+        }
+    }
+
+    /// Add a measured value of the input
+    pub fn input(mut self, name: &'static str, value: Value, unit: &'static str) -> Self {
+        self.input.push((name, value, unit));
+        self
+    }
+
+    /// Set the applied gain in dB
+    pub fn gain(mut self, gain: Value) -> Self {
+        self.gain = Some(gain);
+        self
+    }
+
+    /// Set the reference file whose level is the target
+    pub fn reference(mut self, reference: Option<&Path>) -> Self {
+        self.reference = reference.map(|reference| {
+            reference
+                .file_name()
+                .unwrap_or(reference.as_os_str())
+                .to_string_lossy()
+                .to_string()
+        });
+        self
+    }
+
+    pub fn tags(&self) -> Vec<(String, String)> {
+        let mut tags = vec![
+            (
+                TOOL_KEY.to_string(),
+                format!("{} {}", crate_name!(), crate_version!()),
+            ),
+            (ALGORITHM_KEY.to_string(), self.algorithm.to_string()),
+            (TARGET_KEY.to_string(), self.target.clone()),
+        ];
+        if !self.input.is_empty() {
+            let input: Vec<String> = self
+                .input
+                .iter()
+                .map(|(name, value, unit)| format!("{name}={} {unit}", format_value(value)))
+                .collect();
+            tags.push((INPUT_KEY.to_string(), input.join(", ")));
+        }
+        if let Some(gain) = &self.gain {
+            tags.push((GAIN_KEY.to_string(), format!("{} dB", format_value(gain))));
+        }
+        if let Some(reference) = &self.reference {
+            tags.push((REFERENCE_KEY.to_string(), reference.clone()));
+        }
+        tags
+    }
+}
+
+/// Whether tags are written to the output file, `--no-tags` disables them
+pub fn is_enabled(output_file: &Path, enabled: bool) -> bool {
+    let extension = output_file
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    enabled && !UNTAGGED.contains(&extension.as_str())
+}
+
+/// Provenance tags of a file as "KEY=value" list, `None` if it was not normalized
+pub fn detect(file: &Path) -> Result<Option<String>> {
+    let probe = FFprobe::probe(file).with_context(|| "Failed to get input file information")?;
+
+    Ok(read(&probe))
+}
+
+/// Provenance tags of a probed file as "KEY=value" list, `None` if it was not normalized
+pub fn read(probe: &Probe) -> Option<String> {
+    let tags = probe.format.tags.iter().chain(&probe.stream.tags);
+    let history = coding_history(probe)
+        .map(|history| bext::history_tags(history, TOOL_KEY))
+        .unwrap_or_default();
+
+    let tags: Vec<String> = KEYS
+        .iter()
+        .filter_map(|key| {
+            tags.clone()
+                .chain(history.iter().map(|(name, value)| (name, value)))
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, value)| format!("{key}={value}"))
+        })
+        .collect();
+
+    tags.first()
+        .is_some_and(|tag| tag.starts_with(&format!("{TOOL_KEY}=")))
+        .then(|| tags.join(", "))
+}
+
+/// Coding history of the bext chunk of a probed WAV file
+pub fn coding_history(probe: &Probe) -> Option<&str> {
+    probe
+        .format
+        .tags
+        .iter()
+        .chain(&probe.stream.tags)
+        .find(|(name, _)| name.eq_ignore_ascii_case(CODING_HISTORY_KEY))
+        .map(|(_, history)| history.as_str())
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Known(value) => format!("{value:.2}"),
+        Value::Placeholder(_) => value.to_string(),
+    }
+}
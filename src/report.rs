@@ -0,0 +1,646 @@
+//! Self-contained HTML report of normalized files, e.g. of a batch.
+//! Every run adds its file to the report, the entries are kept as JSON inside the page.
+
+use crate::algorithm::ebu_r128::Gating;
+use crate::algorithm::range::WHOLE;
+use crate::algorithm::timeline;
+use crate::output::OutputFile;
+use crate::plan::Plan;
+use crate::plot::{self, PlotArgs};
+use crate::progress::{Measurement, Pass, Phase, Progress, Recorder};
+use crate::xml::escape;
+use anyhow::{bail, Context, Result};
+use clap::{crate_name, crate_version};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    // normalization type in the loudnorm JSON of pass 2, e.g. "\t"normalization_type" : "dynamic","
+    static ref RE_NORMALIZATION_TYPE: Regex =
+        Regex::new(r#""normalization_type"\s*:\s*"(\w+)""#).unwrap();
+}
+
+/// Highest deviation of the output loudness from the target level in LU
+const TOLERANCE: f64 = 1.0;
+
+const INPUT_PASS: Pass = Pass {
+    number: 1,
+    count: 1,
+    phase: Phase::Measure,
+    description: "Processing audio file to measure input loudness for the report",
+};
+
+/// Input loudness of the report, only `ebu` measures all of it
+const INPUT_MEASUREMENTS: [(&str, &str, &str); 3] = [
+    ("input_i", "Integrated loudness", "LUFS"),
+    ("input_tp", "True peak", "dBTP"),
+    ("input_lra", "Loudness range", "LU"),
+];
+
+const DATA_START: &str = "<script type=\"application/json\" id=\"report-data\">";
+const DATA_END: &str = "</script>";
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #333; }
+table { border-collapse: collapse; font-size: 0.9em; }
+th, td { border: 1px solid #ddd; padding: 0.3em 0.6em; text-align: right; white-space: nowrap; }
+th { background: #f4f4f4; }
+td:first-child, td:nth-child(2), td:nth-last-child(2) { text-align: left; }
+.pass { color: #2ca02c; font-weight: bold; }
+.fail { color: #d62728; font-weight: bold; }
+section { margin-top: 2em; }
+svg { max-width: 100%; height: auto; border: 1px solid #ddd; }";
+
+/// Normalized file of the report
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    input_file: String,
+    output_file: String,
+    algorithm: String,
+    /// Target level in LUFS, `None` if the algorithm does not target integrated loudness
+    target: Option<f64>,
+    /// What the target level is measured over, `None` if there is no target level
+    gating: Option<Gating>,
+    /// Peak levels the output must not exceed
+    ceilings: Vec<Ceiling>,
+    /// RMS target level of `rms` in dBFS
+    #[serde(default)]
+    rms_target: Option<f64>,
+    /// Integrated loudness of the input in LUFS, of its dialogue with dialogue gating
+    input_i: Option<f64>,
+    input_tp: Option<f64>,
+    input_lra: Option<f64>,
+    output_i: Option<f64>,
+    output_tp: Option<f64>,
+    output_lra: Option<f64>,
+    output_sample_peak: Option<f64>,
+    /// RMS level of the output in dBFS, measured like pass 1 of `rms` measured the input
+    #[serde(default)]
+    output_rms: Option<f64>,
+    /// Dialogue loudness of the output in LUFS with dialogue gating, the input dialogue
+    /// loudness plus the gain
+    output_dialogue_i: Option<f64>,
+    gain: Option<f64>,
+    /// Normalization type of loudnorm, "linear" or "dynamic"
+    normalization_type: Option<String>,
+    /// Loudness plot as SVG document
+    plot: String,
+}
+
+/// Highest level the output peaks may reach
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Ceiling {
+    /// Sample peak in dBFS, e.g. the target of `peak` or `--limiter`
+    SamplePeak(f64),
+    /// True peak in dBTP, e.g. `--true-peak` of `ebu`
+    TruePeak(f64),
+}
+
+pub struct ReportArgs<'a> {
+    pub input_file: &'a Path,
+    pub output_file: &'a Path,
+    /// Name of the algorithm, e.g. "ebu"
+    pub algorithm: &'a str,
+    /// Gating of the target level of `ebu`
+    pub gating: Option<Gating>,
+    pub ceilings: Vec<Ceiling>,
+    /// RMS target level of `rms`
+    pub rms_target: Option<f64>,
+    pub plot: &'a PlotArgs<'a>,
+    /// Measurements and pass 2 log of the normalization
+    pub recorder: &'a Recorder<'a>,
+}
+
+/// Fail early if the report file exists but is no report, it would not be overwritten
+pub fn check(report_file: &Path) -> Result<()> {
+    if report_file.exists() {
+        read(report_file)?;
+    }
+    Ok(())
+}
+
+/// Add a note of the report to the plan
+pub fn plan(report_file: &Path, plan: &mut Plan) {
+    plan.note(&format!(
+        "The input and output loudness is added to the report \"{}\"",
+        report_file.display()
+    ));
+}
+
+/// Add the pass measuring the input loudness to the plan unless the algorithm measures it
+pub fn plan_input(algorithm: &str, input_file: &Path, plan: &mut Plan) {
+    if algorithm != "ebu" {
+        timeline::plan_file(input_file, &WHOLE, &INPUT_PASS, plan);
+    }
+}
+
+/// Measure the input loudness the algorithm has not measured, e.g. of `rms` and `peak`
+pub fn measure_input(input_file: &Path, verbose: bool, recorder: &Recorder) -> Result<()> {
+    let missing: Vec<_> = INPUT_MEASUREMENTS
+        .iter()
+        .filter(|(name, _, _)| recorder.get(name).is_none())
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let loudness = timeline::measure_file(input_file, &WHOLE, &INPUT_PASS, verbose, recorder)
+        .with_context(|| "Failed to processing audio file to measure input loudness")?
+        .loudness;
+
+    missing.into_iter().for_each(|(name, label, unit)| {
+        let value = match *name {
+            "input_i" => loudness.value,
+            "input_tp" => loudness.max_true_peak,
+            _ => loudness.range,
+        };
+        if let Some(value) = value {
+            recorder.measurement(&Measurement {
+                name,
+                label,
+                value,
+                unit,
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Add the normalized file to the report, replacing an earlier entry of the same output file.
+/// Runs of a batch share the report, so it is read and written under the lock of a sidecar file
+/// `<REPORT_FILE>.lock` and replaced by a rename, never partly written.
+pub fn add(args: ReportArgs, report_file: &Path) -> Result<()> {
+    update(entry(&args), report_file)
+}
+
+fn update(entry: Entry, report_file: &Path) -> Result<()> {
+    let mut lock_file = OsString::from(report_file.as_os_str());
+    lock_file.push(".lock");
+    let lock_file = PathBuf::from(lock_file);
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_file)
+        .with_context(|| format!("Failed to open lock file \"{}\"", lock_file.display()))?;
+    lock.lock()
+        .with_context(|| format!("Failed to lock \"{}\"", lock_file.display()))?;
+
+    let mut entries = if report_file.exists() {
+        read(report_file)?
+    } else {
+        Vec::new()
+    };
+
+    match entries
+        .iter_mut()
+        .find(|existing| existing.output_file == entry.output_file)
+    {
+        Some(existing) => *existing = entry,
+        None => entries.push(entry),
+    }
+
+    let output = OutputFile::replace(report_file);
+    fs::write(output.path(), html(&entries)?)
+        .with_context(|| format!("Failed to write report \"{}\"", report_file.display()))?;
+    output.commit()
+}
+
+fn entry(args: &ReportArgs) -> Entry {
+    let recorder = args.recorder;
+    let loudness = &args.plot.timeline.loudness;
+    let input_i = recorder.get("input_i");
+
+    // loudnorm of ebu logs the normalization type, a native gain is always linear
+    let normalization_type = match recorder.get("applied_gain") {
+        _ if args.algorithm != "ebu" => None,
+        Some(_) => Some("linear".to_string()),
+        None => recorder.messages().iter().rev().find_map(|message| {
+            RE_NORMALIZATION_TYPE
+                .captures(message)
+                .map(|caps| caps[1].to_string())
+        }),
+    };
+
+    // loudnorm reports no gain, it is the change of the integrated loudness of the program
+    let gain = recorder
+        .get("applied_gain")
+        .or_else(|| recorder.get("volume_adjustment"))
+        .or_else(|| Some(loudness.value? - recorder.get("program_i").or(input_i)?));
+    // the output is not measured again for speech
+    let output_dialogue_i = match args.gating {
+        Some(Gating::Dialogue) => input_i.zip(gain).map(|(input_i, gain)| input_i + gain),
+        _ => None,
+    };
+
+    Entry {
+        input_file: args.input_file.display().to_string(),
+        output_file: args.output_file.display().to_string(),
+        algorithm: args.algorithm.to_string(),
+        target: args.plot.target,
+        gating: args.plot.target.and(args.gating),
+        ceilings: args.ceilings.clone(),
+        rms_target: args.rms_target,
+        input_i,
+        input_tp: recorder.get("input_tp"),
+        input_lra: recorder.get("input_lra"),
+        output_i: loudness.value,
+        output_tp: loudness.max_true_peak,
+        output_lra: loudness.range,
+        output_sample_peak: args.plot.timeline.sample_peak,
+        output_rms: recorder.get("output_rms_level"),
+        output_dialogue_i,
+        gain,
+        normalization_type,
+        plot: plot::svg(args.plot),
+    }
+}
+
+/// Entries of an existing report
+fn read(report_file: &Path) -> Result<Vec<Entry>> {
+    let html = fs::read_to_string(report_file)
+        .with_context(|| format!("Failed to read report \"{}\"", report_file.display()))?;
+
+    let Some(data) = html
+        .split_once(DATA_START)
+        .and_then(|(_, data)| data.split_once(DATA_END).map(|(data, _)| data))
+    else {
+        bail!(
+            "\"{}\" is not a report of {}, it is not overwritten",
+            report_file.display(),
+            crate_name!()
+        );
+    };
+
+    serde_json::from_str(data).with_context(|| {
+        format!(
+            "Failed to parse entries of report \"{}\"",
+            report_file.display()
+        )
+    })
+}
+
+/// Reasons the output fails the target, empty if it passes
+fn failures(entry: &Entry) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    // a dialogue-gated target is compared with the dialogue loudness
+    let (label, output_i) = match entry.gating {
+        Some(Gating::Dialogue) => ("Dialogue loudness", entry.output_dialogue_i),
+        _ => ("Integrated loudness", entry.output_i),
+    };
+    match (entry.target, output_i) {
+        (Some(target), Some(output_i)) if (output_i - target).abs() > TOLERANCE => {
+            failures.push(format!(
+                "{label} {output_i:.1} LUFS deviates {:+.1} LU from target {target} LUFS",
+                output_i - target
+            ))
+        }
+        (Some(_), None) => failures.push(format!("{label} is not measured")),
+        _ => {}
+    }
+    match (entry.rms_target, entry.output_rms) {
+        (Some(target), Some(output_rms)) if (output_rms - target).abs() > TOLERANCE => failures
+            .push(format!(
+                "RMS level {output_rms:.1} dB deviates {:+.1} dB from target {target} dB",
+                output_rms - target
+            )),
+        (Some(_), None) => failures.push("RMS level is not measured".to_string()),
+        _ => {}
+    }
+
+    entry.ceilings.iter().for_each(|ceiling| {
+        let (label, peak, ceiling, unit) = match *ceiling {
+            Ceiling::SamplePeak(ceiling) => {
+                ("Sample peak", entry.output_sample_peak, ceiling, "dBFS")
+            }
+            Ceiling::TruePeak(ceiling) => ("True peak", entry.output_tp, ceiling, "dBTP"),
+        };
+        match peak {
+            Some(peak) if peak > ceiling => failures.push(format!(
+                "{label} {peak:.1} {unit} is above {ceiling} {unit}"
+            )),
+            None => failures.push(format!("{label} is not measured")),
+            _ => {}
+        }
+    });
+
+    failures
+}
+
+fn html(entries: &[Entry]) -> Result<String> {
+    let results: Vec<Vec<String>> = entries.iter().map(failures).collect();
+    let failed = results
+        .iter()
+        .filter(|failures| !failures.is_empty())
+        .count();
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Loudness report</title>\n<style>\n{STYLE}\n</style>\n</head>\n<body>\n\
+         <h1>Loudness report</h1>\n\
+         <p>{} files, {} passed, {failed} failed. Target tolerance is &plusmn;{TOLERANCE} LU. \
+         Created by {} {}.</p>\n",
+        entries.len(),
+        entries.len() - failed,
+        crate_name!(),
+        crate_version!()
+    );
+
+    html += "<table>\n<thead>\n<tr><th>File</th><th>Algorithm</th><th>Target</th>\
+             <th>Input I</th><th>Input TP</th><th>Input LRA</th>\
+             <th>Output I</th><th>Output TP</th><th>Output SP</th><th>Output LRA</th>\
+             <th>Gain</th><th>Normalization</th><th>Result</th></tr>\n</thead>\n<tbody>\n";
+    entries
+        .iter()
+        .zip(&results)
+        .enumerate()
+        .for_each(|(i, (entry, failures))| {
+            let _ = writeln!(
+                html,
+                "<tr><td><a href=\"#file-{i}\">{}</a></td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&entry.output_file),
+                escape(&entry.algorithm),
+                target(entry),
+                value(entry.input_i, "LUFS"),
+                value(entry.input_tp, "dBTP"),
+                value(entry.input_lra, "LU"),
+                value(entry.output_i, "LUFS"),
+                value(entry.output_tp, "dBTP"),
+                value(entry.output_sample_peak, "dBFS"),
+                value(entry.output_lra, "LU"),
+                entry
+                    .gain
+                    .map_or("&ndash;".to_string(), |gain| format!("{gain:+.1} dB")),
+                entry
+                    .normalization_type
+                    .as_deref()
+                    .map_or("&ndash;".to_string(), escape),
+                result(failures)
+            );
+        });
+    html += "</tbody>\n</table>\n";
+
+    entries
+        .iter()
+        .zip(&results)
+        .enumerate()
+        .for_each(|(i, (entry, failures))| {
+            let _ = writeln!(
+                html,
+                "<section id=\"file-{i}\">\n<h2>{}</h2>\n<p>Input file: {}. Result: {}</p>",
+                escape(&entry.output_file),
+                escape(&entry.input_file),
+                result(failures)
+            );
+            if let Some(output_rms) = entry.output_rms {
+                let _ = writeln!(html, "<p>RMS level: output {output_rms:.1} dB</p>");
+            }
+            if let (Some(input_i), Some(output_i)) = (entry.input_i, entry.output_dialogue_i) {
+                let _ = writeln!(
+                    html,
+                    "<p>Dialogue loudness: input {input_i:.1} LUFS, output {output_i:.1} LUFS \
+                     (input plus gain)</p>"
+                );
+            }
+            if !failures.is_empty() {
+                html += "<ul>\n";
+                failures.iter().for_each(|failure| {
+                    let _ = writeln!(html, "<li>{}</li>", escape(failure));
+                });
+                html += "</ul>\n";
+            }
+            html += &entry.plot;
+            html += "</section>\n";
+        });
+
+    // "</" in file names or the plots must not end the script element
+    let data = serde_json::to_string(entries)?.replace("</", "<\\/");
+    let _ = write!(html, "{DATA_START}{data}{DATA_END}\n</body>\n</html>\n");
+
+    Ok(html)
+}
+
+fn target(entry: &Entry) -> String {
+    let ceiling = entry
+        .ceilings
+        .iter()
+        .map(|ceiling| match ceiling {
+            Ceiling::SamplePeak(ceiling) => format!("SP &le; {ceiling} dBFS"),
+            Ceiling::TruePeak(ceiling) => format!("TP &le; {ceiling} dBTP"),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    match (entry.target, entry.gating, entry.rms_target) {
+        (Some(target), Some(Gating::Dialogue), _) => {
+            format!("{target} LUFS dialogue, {ceiling}")
+        }
+        (Some(target), _, _) => format!("{target} LUFS, {ceiling}"),
+        (None, _, Some(rms_target)) => format!("{rms_target} dB RMS, {ceiling}"),
+        (None, _, None) if ceiling.is_empty() => "&ndash;".to_string(),
+        (None, _, None) => ceiling,
+    }
+}
+
+fn value(value: Option<f64>, unit: &str) -> String {
+    value.map_or("&ndash;".to_string(), |value| format!("{value:.1} {unit}"))
+}
+
+fn result(failures: &[String]) -> &'static str {
+    if failures.is_empty() {
+        "<span class=\"pass\">Pass</span>"
+    } else {
+        "<span class=\"fail\">Fail</span>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entry of `peak` normalized to -1 dBFS
+    fn entry() -> Entry {
+        Entry {
+            input_file: "in.wav".to_string(),
+            output_file: "out.wav".to_string(),
+            algorithm: "peak".to_string(),
+            target: None,
+            gating: None,
+            ceilings: vec![Ceiling::SamplePeak(-1.0)],
+            rms_target: None,
+            input_i: Some(-20.0),
+            input_tp: Some(-3.5),
+            input_lra: Some(6.0),
+            output_i: Some(-17.8),
+            output_tp: Some(-1.2),
+            output_lra: Some(6.0),
+            output_sample_peak: Some(-1.0),
+            output_rms: None,
+            output_dialogue_i: None,
+            gain: Some(2.2),
+            normalization_type: None,
+            plot: String::new(),
+        }
+    }
+
+    #[test]
+    fn failures_of_passing_entry() {
+        assert!(failures(&entry()).is_empty());
+        assert_eq!(target(&entry()), "SP &le; -1 dBFS");
+    }
+
+    #[test]
+    fn failures_of_peak_target() {
+        // inter-sample peaks above -1 dBTP do not fail a sample peak target
+        let entry = Entry {
+            ceilings: vec![Ceiling::SamplePeak(-0.5)],
+            output_tp: Some(-0.2),
+            output_sample_peak: Some(-0.5),
+            ..entry()
+        };
+        assert!(failures(&entry).is_empty());
+
+        let entry = Entry {
+            output_sample_peak: Some(-0.3),
+            ..entry
+        };
+        assert_eq!(
+            failures(&entry),
+            ["Sample peak -0.3 dBFS is above -0.5 dBFS"]
+        );
+
+        let entry = Entry {
+            output_sample_peak: None,
+            ..entry
+        };
+        assert_eq!(failures(&entry), ["Sample peak is not measured"]);
+
+        // dialogue without --limiter has no target and no ceiling
+        let entry = Entry {
+            algorithm: "dialogue".to_string(),
+            ceilings: Vec::new(),
+            ..entry
+        };
+        assert!(failures(&entry).is_empty());
+        assert_eq!(target(&entry), "&ndash;");
+    }
+
+    #[test]
+    fn failures_of_rms_target() {
+        let entry = Entry {
+            algorithm: "rms".to_string(),
+            ceilings: vec![Ceiling::SamplePeak(0.0)],
+            rms_target: Some(-20.0),
+            output_rms: Some(-20.6),
+            ..entry()
+        };
+        assert!(failures(&entry).is_empty());
+        assert_eq!(target(&entry), "-20 dB RMS, SP &le; 0 dBFS");
+
+        // e.g. a gain limited by clipping
+        let entry = Entry {
+            output_rms: Some(-21.5),
+            ..entry
+        };
+        assert_eq!(
+            failures(&entry),
+            ["RMS level -21.5 dB deviates -1.5 dB from target -20 dB"]
+        );
+
+        let entry = Entry {
+            output_rms: None,
+            ..entry
+        };
+        assert_eq!(failures(&entry), ["RMS level is not measured"]);
+    }
+
+    #[test]
+    fn failures_of_true_peak_above_ceiling() {
+        // inter-sample peaks above the sample peak
+        let entry = Entry {
+            algorithm: "ebu".to_string(),
+            target: Some(-16.0),
+            gating: Some(Gating::Program),
+            ceilings: vec![Ceiling::TruePeak(-1.0)],
+            output_i: Some(-16.2),
+            output_tp: Some(-0.4),
+            ..entry()
+        };
+        assert_eq!(failures(&entry), ["True peak -0.4 dBTP is above -1 dBTP"]);
+        assert_eq!(target(&entry), "-16 LUFS, TP &le; -1 dBTP");
+
+        let entry = Entry {
+            output_i: Some(-18.0),
+            output_tp: None,
+            ..entry
+        };
+        assert_eq!(
+            failures(&entry),
+            [
+                "Integrated loudness -18.0 LUFS deviates -2.0 LU from target -16 LUFS",
+                "True peak is not measured"
+            ]
+        );
+    }
+
+    #[test]
+    fn update_of_parallel_runs() {
+        let dir = std::env::temp_dir().join(format!("report-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let report_file = dir.join("report.html");
+
+        // every run of a batch keeps its entry
+        std::thread::scope(|scope| {
+            (0..8).for_each(|i| {
+                let report_file = &report_file;
+                scope.spawn(move || {
+                    let entry = Entry {
+                        output_file: format!("out{i}.wav"),
+                        ..entry()
+                    };
+                    update(entry, report_file).unwrap();
+                });
+            });
+        });
+        assert_eq!(read(&report_file).unwrap().len(), 8);
+
+        // an entry of the same output file is replaced
+        update(entry(), &report_file).unwrap();
+        update(entry(), &report_file).unwrap();
+        assert_eq!(read(&report_file).unwrap().len(), 9);
+        // the report and the lock file, no temporary file is left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failures_of_dialogue_loudness() {
+        let entry = Entry {
+            algorithm: "ebu".to_string(),
+            target: Some(-23.0),
+            gating: Some(Gating::Dialogue),
+            ceilings: vec![Ceiling::TruePeak(-2.0)],
+            output_i: Some(-20.0),
+            output_tp: Some(-2.5),
+            output_dialogue_i: Some(-24.5),
+            ..entry()
+        };
+        assert_eq!(
+            failures(&entry),
+            ["Dialogue loudness -24.5 LUFS deviates -1.5 LU from target -23 LUFS"]
+        );
+        assert_eq!(target(&entry), "-23 LUFS dialogue, TP &le; -2 dBTP");
+    }
+}
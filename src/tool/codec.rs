@@ -0,0 +1,1009 @@
+use crate::tool::ffmpeg::FFmpeg;
+use crate::tool::ffprobe::AudioStream;
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+
+lazy_static! {
+    static ref RE_CODEC_ARG: Regex = Regex::new(r#"^-(c|codec)(:a(:\d+)?)?$|^-acodec$"#).unwrap();
+    static ref RE_PCM_CODEC: Regex = Regex::new(r#"^pcm_[suf](\d+)(le|be)?(_planar)?$"#).unwrap();
+}
+
+/// Encoders to try for a codec, in order of preference.
+/// Codecs not listed here are encoded by the encoder of the same name.
+const ENCODERS: &[(&str, &[&str])] = &[
+    ("aac", &["aac", "libfdk_aac"]),
+    ("ac3", &["ac3", "ac3_fixed"]),
+    ("mp3", &["libmp3lame", "libshine"]),
+    ("opus", &["libopus", "opus"]),
+    ("vorbis", &["libvorbis", "vorbis"]),
+];
+
+/// Codecs to use instead of codecs ffmpeg can decode but cannot encode
+/// (or only with an experimental encoder), in order of preference.
+const FALLBACKS: &[(&str, &[&str])] = &[
+    ("truehd", &["flac"]),
+    ("mlp", &["flac"]),
+    ("dts", &["eac3", "flac"]),
+    ("wmapro", &["aac"]),
+    ("wmav1", &["aac"]),
+    ("wmav2", &["aac"]),
+    ("cook", &["aac"]),
+    ("atrac3", &["aac"]),
+    ("atrac3p", &["aac"]),
+];
+
+/// Codecs supported by output containers by file extension, in order of preference.
+/// Containers not listed here (e.g. Matroska) accept any codec.
+const CONTAINERS: &[(&[&str], &[&str])] = &[
+    (&["ac3"], &["ac3"]),
+    (&["eac3", "ec3"], &["eac3"]),
+    (&["flac"], &["flac"]),
+    (&["mp3"], &["mp3"]),
+    (&["aac", "adts"], &["aac"]),
+    (
+        &["m4a", "m4b", "mp4", "mov"],
+        &["aac", "alac", "ac3", "eac3", "mp3", "opus", "flac"],
+    ),
+    (&["opus"], &["opus"]),
+    (&["ogg", "oga"], &["vorbis", "opus", "flac"]),
+    (&["webm"], &["opus", "vorbis"]),
+    (
+        &["wav", "w64"],
+        &[
+            "pcm_s16le",
+            "pcm_s24le",
+            "pcm_s32le",
+            "pcm_f32le",
+            "pcm_f64le",
+            "pcm_u8",
+        ],
+    ),
+    (
+        &["aif", "aiff"],
+        &["pcm_s16be", "pcm_s24be", "pcm_s32be", "pcm_f32be", "pcm_s8"],
+    ),
+    (&["wv"], &["wavpack"]),
+    (&["thd"], &["truehd"]),
+];
+
+/// Maximum number of channels supported by a codec
+const MAX_CHANNELS: &[(&str, u32)] = &[("ac3", 6), ("eac3", 6), ("mp3", 2)];
+
+/// Bitrates in kbit/s by number of channels up to
+type ChannelBitRates = &'static [(u32, u32)];
+
+/// Default bitrates of lossy codecs and the maximum bitrate of the codec in kbit/s
+const BIT_RATES: &[(&str, ChannelBitRates, u32)] = &[
+    ("ac3", &[(1, 96), (2, 192), (6, 448)], 640),
+    ("eac3", &[(1, 96), (2, 224), (6, 640)], 1536),
+    ("aac", &[(1, 96), (2, 192), (6, 384), (8, 512)], 512),
+    ("mp3", &[(1, 160), (2, 320)], 320),
+    ("opus", &[(1, 64), (2, 128), (6, 256), (8, 384)], 510),
+    ("vorbis", &[(1, 96), (2, 192), (6, 448), (8, 500)], 500),
+];
+
+/// Bitrates allowed by AC-3 in kbit/s
+const AC3_BIT_RATES: &[u32] = &[
+    32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640,
+];
+
+/// VBR quality arguments of encoders for `--quality` 1 to 5.
+/// Other encoders scale the default bitrate instead.
+const QUALITIES: &[(&str, &str, [&str; 5])] = &[
+    ("libmp3lame", "-q:a", ["7", "5", "4", "2", "0"]),
+    ("libvorbis", "-q:a", ["2", "4", "6", "8", "10"]),
+    ("libfdk_aac", "-vbr", ["1", "2", "3", "4", "5"]),
+];
+
+/// Factor of the default bitrate for `--quality` 1 to 5
+const QUALITY_SCALES: [f64; 5] = [0.5, 0.75, 1.0, 1.25, 1.5];
+
+/// Sample formats of lossless encoders supporting several bit depths,
+/// by bit depth up to, in order of preference
+const SAMPLE_FORMATS: &[(&str, &[(u32, &str)])] = &[
+    ("flac", &[(16, "s16"), (24, "s32")]),
+    ("alac", &[(16, "s16p"), (24, "s32p")]),
+    ("truehd", &[(16, "s16"), (24, "s32")]),
+    ("wavpack", &[(8, "u8p"), (16, "s16p"), (32, "s32p")]),
+];
+
+/// Dither method used when the bit depth is reduced
+const DITHER_METHOD: &str = "triangular_hp";
+
+/// Options controlling how the output audio stream is encoded.
+#[derive(Default)]
+pub struct CodecOptions {
+    /// Input codec to output codec or encoder, e.g. ("truehd", "flac")
+    pub codec_map: Vec<(String, String)>,
+    /// Bitrate of the output audio stream, e.g. "640k"
+    pub bit_rate: Option<String>,
+    /// VBR quality from 1 (smallest) to 5 (best)
+    pub quality: Option<u8>,
+    /// Apply gain without encoding with ffmpeg where the format allows it
+    pub native: bool,
+    /// Maximum sample peak in dBFS of the native gain of decoded formats
+    pub limiter: Option<f64>,
+    /// Dither integer samples of the native gain of decoded formats
+    pub dither: bool,
+    /// Write provenance tags to the output file
+    pub tags: bool,
+}
+
+/// Encoder and bitrate of the output audio stream.
+#[derive(Default)]
+pub struct OutputCodec {
+    /// `None` if the encoder is set by custom ffmpeg arguments
+    pub encoder: Option<String>,
+    /// Codec of the output, also if set by custom ffmpeg arguments
+    pub codec: Option<String>,
+    pub bit_rate: Option<String>,
+    /// Encoder specific VBR arguments, e.g. ["-q:a", "2"]
+    pub quality_args: Vec<String>,
+    /// Number of channels to downmix to
+    pub channels: Option<u32>,
+    pub format: SampleFormat,
+}
+
+/// Sample format, bit depth, sample rate and channel layout of the output audio stream.
+/// Each is `None` if it is chosen by ffmpeg or set by custom ffmpeg arguments.
+#[derive(Default)]
+pub struct SampleFormat {
+    pub sample_fmt: Option<String>,
+    pub bits_per_raw_sample: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub channel_layout: Option<String>,
+    /// Dither method if the bit depth is reduced
+    pub dither_method: Option<&'static str>,
+}
+
+/// Encoding settings for the output audio stream
+struct RateControl {
+    bit_rate: Option<String>,
+    quality_args: Vec<String>,
+}
+
+/// Parse "<CODEC>=<ENCODER>" command line value.
+pub fn parse_codec_mapping(value: &str) -> Result<(String, String)> {
+    value
+        .split_once('=')
+        .map(|(codec, encoder)| (codec.trim().to_string(), encoder.trim().to_string()))
+        .filter(|(codec, encoder)| !codec.is_empty() && !encoder.is_empty())
+        .ok_or_else(|| anyhow!("expected <CODEC>=<ENCODER>, e.g. truehd=flac"))
+}
+
+/// Parse bitrate command line value, e.g. "640k", "1.5M" or "192000".
+pub fn parse_bit_rate(value: &str) -> Result<String> {
+    let value = value.trim();
+    let number = value.trim_end_matches(['k', 'K', 'm', 'M']);
+    if value.len() - number.len() > 1
+        || number
+            .parse::<f64>()
+            .map_or(true, |n| !n.is_finite() || n <= 0.0)
+    {
+        bail!("expected bitrate in bit/s with optional k or M suffix, e.g. 640k");
+    }
+    Ok(value.to_string())
+}
+
+/// Select encoder and bitrate to encode the input audio stream with.
+/// Returns messages about replaced codec and conflicting ffmpeg arguments.
+pub fn select(
+    stream: &AudioStream,
+    output_file: &Path,
+    options: &CodecOptions,
+    ffmpeg_args: &[String],
+) -> Result<(OutputCodec, Vec<String>)> {
+    select_with(stream, output_file, options, ffmpeg_args, FFmpeg::encoders)
+}
+
+/// `select` with the encoders available in ffmpeg and whether they are experimental
+fn select_with(
+    stream: &AudioStream,
+    output_file: &Path,
+    options: &CodecOptions,
+    ffmpeg_args: &[String],
+    encoders: impl FnOnce() -> Result<HashMap<String, bool>>,
+) -> Result<(OutputCodec, Vec<String>)> {
+    let extension = output_file
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let container = CONTAINERS
+        .iter()
+        .find(|(extensions, _)| extensions.contains(&extension.as_str()))
+        .map(|(_, codecs)| *codecs);
+    let is_supported = |codec: &str| container.is_none_or(|codecs| codecs.contains(&codec));
+    let channels = stream.channels.unwrap_or(2);
+
+    // custom ffmpeg arguments take precedence
+    if let Some(pos) = ffmpeg_args
+        .iter()
+        .position(|arg| RE_CODEC_ARG.is_match(arg))
+    {
+        let mut messages = Vec::new();
+        let encoder = ffmpeg_args.get(pos + 1);
+
+        if let Some(encoder) = encoder {
+            if encoder == "copy" {
+                messages.push(format!(
+                    "Warning: \"{} copy\" conflicts with normalization, audio must be re-encoded",
+                    ffmpeg_args[pos]
+                ));
+            } else if !is_supported(codec_of(encoder)) {
+                messages.push(format!(
+                    "Warning: encoder \"{encoder}\" set by ffmpeg arguments is not supported by \".{extension}\" output file"
+                ));
+            }
+        }
+
+        // the encoder is known, but not its codec defaults
+        let rate = match encoder {
+            Some(encoder) if options.bit_rate.is_none() => {
+                // the input bitrate only applies if the codec is kept
+                let input_bit_rate = stream
+                    .bit_rate
+                    .as_ref()
+                    .filter(|_| codec_of(encoder) == stream.codec_name);
+                rate_control(encoder, channels, input_bit_rate, options)
+            }
+            _ => RateControl {
+                bit_rate: options.bit_rate.clone(),
+                quality_args: Vec::new(),
+            },
+        };
+
+        let format = sample_format(
+            stream,
+            encoder.map_or("", |encoder| encoder.as_str()),
+            None,
+            ffmpeg_args,
+            &mut messages,
+        );
+
+        return Ok((
+            OutputCodec {
+                encoder: None,
+                codec: encoder.map(|encoder| codec_of(encoder).to_string()),
+                bit_rate: rate.bit_rate,
+                quality_args: rate.quality_args,
+                channels: None,
+                format,
+            },
+            messages,
+        ));
+    }
+
+    let encoders = encoders().with_context(|| "Failed to check available encoders")?;
+    let allow_experimental = ffmpeg_args.iter().any(|arg| arg == "-strict");
+    let is_usable = |encoder: &str| {
+        encoders
+            .get(encoder)
+            .is_some_and(|experimental| !experimental || allow_experimental)
+    };
+
+    let input_codec = stream.codec_name.as_str();
+
+    let mapped = options
+        .codec_map
+        .iter()
+        .find(|(codec, _)| codec == input_codec)
+        .map(|(_, target)| target.as_str());
+
+    let candidates: Vec<&str> = match mapped {
+        Some(target) => vec![target],
+        None => {
+            let candidates: Vec<&str> = std::iter::once(input_codec)
+                .chain(
+                    FALLBACKS
+                        .iter()
+                        .filter(|(codec, _)| *codec == input_codec)
+                        .flat_map(|(_, fallbacks)| fallbacks.iter().copied()),
+                )
+                .filter(|codec| is_supported(codec))
+                .collect();
+
+            // input codec does not fit the output container
+            if candidates.is_empty() {
+                pcm_codecs(stream, container.unwrap_or_default())
+            } else {
+                candidates
+            }
+        }
+    };
+
+    // prefer codecs supporting all channels, otherwise downmix
+    let encoder = candidates
+        .iter()
+        .filter(|codec| max_channels(codec).is_none_or(|max| channels <= max))
+        .find_map(|codec| find_encoder(codec, &is_usable))
+        .or_else(|| {
+            candidates
+                .iter()
+                .find_map(|codec| find_encoder(codec, &is_usable))
+        })
+        .ok_or_else(|| match mapped {
+            Some(target) => anyhow!(
+                "Encoder \"{target}\" for \"{input_codec}\" audio is not available in ffmpeg"
+            ),
+            None if container.is_some() && !is_supported(input_codec) => anyhow!(
+                "ffmpeg cannot encode \"{input_codec}\" audio for \".{extension}\" output file, use --codec-map {input_codec}=<ENCODER> to choose another codec"
+            ),
+            None => anyhow!(
+                "ffmpeg cannot encode \"{input_codec}\" audio, use --codec-map {input_codec}=<ENCODER> to choose another codec"
+            ),
+        })?;
+    let codec = codec_of(encoder);
+
+    let downmix = max_channels(codec).filter(|max| channels > *max);
+
+    // the input bitrate only applies if the codec is kept
+    let input_bit_rate = stream.bit_rate.as_ref().filter(|_| codec == input_codec);
+    let rate = rate_control(
+        encoder,
+        downmix.unwrap_or(channels),
+        input_bit_rate,
+        options,
+    );
+
+    let mut messages = Vec::new();
+    let settings = match (&rate.bit_rate, options.quality) {
+        (_, Some(quality)) if !rate.quality_args.is_empty() => {
+            format!(", VBR quality {quality}")
+        }
+        (Some(bit_rate), _) => format!(", bitrate {bit_rate}"),
+        (None, _) => String::new(),
+    };
+    if codec != input_codec {
+        messages.push(format!(
+            "Audio codec \"{input_codec}\" is encoded as \"{codec}\" (encoder \"{encoder}\"{settings})"
+        ));
+    } else if input_bit_rate.is_none() && options.bit_rate.is_none() && options.quality.is_none() {
+        if let Some(bit_rate) = &rate.bit_rate {
+            messages.push(format!(
+                "Bitrate of \"{input_codec}\" audio is unknown, encoding with {bit_rate}"
+            ));
+        }
+    }
+    if options.quality.is_some() && rate.bit_rate.is_none() && rate.quality_args.is_empty() {
+        messages.push(format!(
+            "Warning: --quality has no effect on \"{codec}\" audio"
+        ));
+    }
+    if let Some(max) = downmix {
+        messages.push(format!(
+            "Warning: \"{codec}\" supports up to {max} channels, {channels} channels are downmixed"
+        ));
+    }
+
+    let format = sample_format(stream, encoder, downmix, ffmpeg_args, &mut messages);
+
+    Ok((
+        OutputCodec {
+            encoder: Some(encoder.to_string()),
+            codec: Some(codec.to_string()),
+            bit_rate: rate.bit_rate,
+            quality_args: rate.quality_args,
+            channels: downmix,
+            format,
+        },
+        messages,
+    ))
+}
+
+/// Bitrate or VBR arguments to encode with, in order of precedence:
+/// `--bitrate`, `--quality`, the input bitrate and the codec default.
+fn rate_control(
+    encoder: &str,
+    channels: u32,
+    input_bit_rate: Option<&String>,
+    options: &CodecOptions,
+) -> RateControl {
+    let codec = codec_of(encoder);
+
+    if let Some(bit_rate) = &options.bit_rate {
+        return RateControl {
+            bit_rate: Some(bit_rate.clone()),
+            quality_args: Vec::new(),
+        };
+    }
+
+    if let Some(quality) = options.quality {
+        let level = usize::from(quality.clamp(1, 5)) - 1;
+
+        if let Some((_, arg, values)) = QUALITIES.iter().find(|(name, _, _)| *name == encoder) {
+            return RateControl {
+                bit_rate: None,
+                quality_args: vec![arg.to_string(), values[level].to_string()],
+            };
+        }
+
+        let bit_rate = default_kbps(codec, channels).map(|(kbps, max)| {
+            let kbps = ((f64::from(kbps) * QUALITY_SCALES[level]) as u32).min(max);
+            format!("{}k", snap_bit_rate(codec, kbps))
+        });
+        return RateControl {
+            // libopus is VBR by default, bitrate is the target
+            quality_args: match (encoder, &bit_rate) {
+                ("libopus", Some(_)) => vec!["-vbr".to_string(), "on".to_string()],
+                _ => Vec::new(),
+            },
+            bit_rate,
+        };
+    }
+
+    RateControl {
+        bit_rate: input_bit_rate
+            .cloned()
+            .or_else(|| default_bit_rate(codec, channels)),
+        quality_args: Vec::new(),
+    }
+}
+
+/// Reproduce the sample format, bit depth, sample rate and channel layout of the input.
+/// Filters such as loudnorm change the sample rate, and sample format negotiation
+/// after a filter may pick a lower bit depth than the input.
+fn sample_format(
+    stream: &AudioStream,
+    encoder: &str,
+    downmix: Option<u32>,
+    ffmpeg_args: &[String],
+    messages: &mut Vec<String>,
+) -> SampleFormat {
+    let has_arg = |names: &[&str]| ffmpeg_args.iter().any(|arg| names.contains(&arg.as_str()));
+    let mut format = SampleFormat::default();
+
+    if !has_arg(&["-ar"]) {
+        format.sample_rate = stream.sample_rate;
+    }
+
+    // downmix sets the layout by the number of channels
+    if downmix.is_none() && !has_arg(&["-ac", "-channel_layout", "-ch_layout"]) {
+        format.channel_layout = stream
+            .channel_layout
+            .clone()
+            .filter(|layout| !layout.is_empty() && layout != "unknown");
+    }
+
+    if has_arg(&["-sample_fmt", "-bits_per_raw_sample"]) {
+        return format;
+    }
+    let Some(input_bits) = bit_depth(stream) else {
+        return format;
+    };
+
+    // lossy encoders have no bit depth
+    let output_bits = if let Some(captures) = RE_PCM_CODEC.captures(encoder) {
+        captures[1].parse::<u32>().ok()
+    } else if let Some((_, formats)) = SAMPLE_FORMATS.iter().find(|(name, _)| *name == encoder) {
+        let (bits, sample_fmt) = formats
+            .iter()
+            .find(|(bits, _)| input_bits <= *bits)
+            .or(formats.last())
+            .copied()
+            .unwrap_or_default();
+        format.sample_fmt = Some(sample_fmt.to_string());
+        // e.g. 24 bit samples in s32
+        format.bits_per_raw_sample =
+            Some(bits).filter(|bits| sample_fmt.contains("32") && *bits < 32);
+        Some(bits)
+    } else {
+        None
+    };
+
+    if let Some(output_bits) = output_bits.filter(|bits| *bits < input_bits) {
+        format.dither_method = Some(DITHER_METHOD);
+        messages.push(format!(
+            "Warning: {input_bits} bit audio is reduced to {output_bits} bit by encoder \"{encoder}\", dithered with {DITHER_METHOD}"
+        ));
+    }
+
+    format
+}
+
+/// Bit depth of the decoded input. Floating point samples count as 32 or 64 bit
+/// for PCM only, lossy decoders output floating point samples too.
+fn bit_depth(stream: &AudioStream) -> Option<u32> {
+    let is_pcm = stream.codec_name.starts_with("pcm_");
+    stream.bits_per_raw_sample.or_else(|| {
+        match stream.sample_fmt.as_deref()?.trim_end_matches('p') {
+            "u8" => Some(8),
+            "s16" => Some(16),
+            "s32" => Some(32),
+            "flt" if is_pcm => Some(32),
+            "s64" => Some(64),
+            "dbl" if is_pcm => Some(64),
+            _ => None,
+        }
+    })
+}
+
+/// Codecs of a container with the PCM codecs closest to the bit depth of the input first:
+/// the same depth, higher depths, then lower depths, e.g. "pcm_s24le" for 24 bit FLAC.
+/// Keeps the order of the container if the depth is unknown.
+fn pcm_codecs<'a>(stream: &AudioStream, codecs: &[&'a str]) -> Vec<&'a str> {
+    let mut codecs = codecs.to_vec();
+    let Some(input_bits) = bit_depth(stream) else {
+        return codecs;
+    };
+    let is_float = stream.codec_name.starts_with("pcm_f");
+
+    codecs.sort_by_key(|codec| {
+        let bits = RE_PCM_CODEC
+            .captures(codec)
+            .and_then(|captures| captures[1].parse::<u32>().ok());
+        match bits {
+            Some(bits) if codec.starts_with("pcm_f") == is_float => {
+                (0, bits < input_bits, bits.abs_diff(input_bits))
+            }
+            Some(_) => (1, false, 0),
+            None => (2, false, 0),
+        }
+    });
+
+    codecs
+}
+
+fn find_encoder<'a>(codec: &'a str, is_usable: &impl Fn(&str) -> bool) -> Option<&'a str> {
+    encoders_for(codec)
+        .into_iter()
+        .find(|encoder| is_usable(encoder))
+}
+
+fn encoders_for(codec: &str) -> Vec<&str> {
+    ENCODERS
+        .iter()
+        .find(|(name, _)| *name == codec)
+        .map(|(_, encoders)| encoders.to_vec())
+        .unwrap_or_else(|| vec![codec])
+}
+
+/// Codec name of an encoder
+fn codec_of(encoder: &str) -> &str {
+    ENCODERS
+        .iter()
+        .find(|(_, encoders)| encoders.contains(&encoder))
+        .map(|(codec, _)| *codec)
+        .unwrap_or(encoder)
+}
+
+fn max_channels(codec: &str) -> Option<u32> {
+    MAX_CHANNELS
+        .iter()
+        .find(|(name, _)| *name == codec)
+        .map(|(_, max)| *max)
+}
+
+fn default_bit_rate(codec: &str, channels: u32) -> Option<String> {
+    default_kbps(codec, channels).map(|(kbps, _)| format!("{kbps}k"))
+}
+
+/// Default and maximum bitrate of a lossy codec in kbit/s
+fn default_kbps(codec: &str, channels: u32) -> Option<(u32, u32)> {
+    BIT_RATES
+        .iter()
+        .find(|(name, _, _)| *name == codec)
+        .and_then(|(_, defaults, max)| {
+            defaults
+                .iter()
+                .find(|(up_to, _)| channels <= *up_to)
+                .or(defaults.last())
+                .map(|(_, kbps)| (*kbps, *max))
+        })
+}
+
+/// Round down to the nearest bitrate the codec allows
+fn snap_bit_rate(codec: &str, kbps: u32) -> u32 {
+    match codec {
+        "ac3" => AC3_BIT_RATES
+            .iter()
+            .rev()
+            .find(|allowed| **allowed <= kbps)
+            .copied()
+            .unwrap_or(AC3_BIT_RATES[0]),
+        _ => kbps,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(
+        codec: &str,
+        channels: u32,
+        bits: Option<u32>,
+        bit_rate: Option<&str>,
+    ) -> AudioStream {
+        AudioStream {
+            codec_name: codec.to_string(),
+            channels: Some(channels),
+            channel_layout: None,
+            sample_fmt: None,
+            sample_rate: Some(48000),
+            bits_per_raw_sample: bits,
+            start_time: None,
+            duration: None,
+            bit_rate: bit_rate.map(str::to_string),
+            tags: HashMap::new(),
+        }
+    }
+
+    /// Encoders of an ffmpeg build without libfdk_aac and with the experimental TrueHD encoder
+    fn encoders() -> Result<HashMap<String, bool>> {
+        let available = [
+            "aac",
+            "ac3",
+            "eac3",
+            "libmp3lame",
+            "libopus",
+            "libvorbis",
+            "flac",
+            "alac",
+            "wavpack",
+            "pcm_s16le",
+            "pcm_s24le",
+            "pcm_s32le",
+            "pcm_f32le",
+            "pcm_s16be",
+            "pcm_s24be",
+            "pcm_s32be",
+        ];
+        Ok(available
+            .iter()
+            .map(|encoder| (encoder.to_string(), false))
+            .chain(std::iter::once(("truehd".to_string(), true)))
+            .collect())
+    }
+
+    fn unavailable() -> Result<HashMap<String, bool>> {
+        bail!("ffmpeg must not be run")
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Encoder or codec, bitrate and downmix selected for the output file, e.g. "ac3 448k"
+    fn selected(
+        stream: AudioStream,
+        output: &str,
+        ffmpeg_args: &[&str],
+        options: CodecOptions,
+    ) -> String {
+        let (codec, _) = select_with(
+            &stream,
+            Path::new(output),
+            &options,
+            &args(ffmpeg_args),
+            encoders,
+        )
+        .unwrap();
+        let mut selected = codec.encoder.or(codec.codec).unwrap_or_default();
+        if let Some(bit_rate) = codec.bit_rate {
+            selected += &format!(" {bit_rate}");
+        }
+        if let Some(channels) = codec.channels {
+            selected += &format!(" downmix {channels}");
+        }
+        selected
+    }
+
+    fn select_error(stream: AudioStream, output: &str, options: CodecOptions) -> String {
+        select_with(&stream, Path::new(output), &options, &[], encoders)
+            .map(|_| ())
+            .unwrap_err()
+            .to_string()
+    }
+
+    /// Bitrate or quality arguments of the rate control, e.g. "256k" or "-q:a 2"
+    fn rate(
+        encoder: &str,
+        channels: u32,
+        input: Option<&str>,
+        bit_rate: Option<&str>,
+        quality: Option<u8>,
+    ) -> String {
+        let options = CodecOptions {
+            bit_rate: bit_rate.map(str::to_string),
+            quality,
+            ..Default::default()
+        };
+        let rate = rate_control(
+            encoder,
+            channels,
+            input.map(str::to_string).as_ref(),
+            &options,
+        );
+        rate.bit_rate
+            .into_iter()
+            .chain(rate.quality_args)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn parse_values() {
+        for value in ["640k", " 1.5M ", "192000", "96K"] {
+            assert_eq!(parse_bit_rate(value).unwrap(), value.trim(), "{value}");
+        }
+        for value in [
+            "", "k", "0", "-5k", "640kk", "abc", "NaN", "inf", "infk", "-inf",
+        ] {
+            assert!(parse_bit_rate(value).is_err(), "{value}");
+        }
+
+        assert_eq!(
+            parse_codec_mapping(" truehd = flac ").unwrap(),
+            ("truehd".to_string(), "flac".to_string())
+        );
+        for value in ["truehd", "=flac", "truehd="] {
+            assert!(parse_codec_mapping(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn select_fallbacks() {
+        let default = CodecOptions::default;
+
+        let mp3 = || stream("mp3", 2, None, Some("128000"));
+        assert_eq!(
+            selected(mp3(), "out.mp3", &[], default()),
+            "libmp3lame 128000"
+        );
+        let mp3 = stream("mp3", 2, None, None);
+        assert_eq!(selected(mp3, "out.mp3", &[], default()), "libmp3lame 320k");
+
+        // the experimental encoder is only used with -strict
+        let truehd = || stream("truehd", 6, Some(24), Some("3000000"));
+        assert_eq!(selected(truehd(), "out.mkv", &[], default()), "flac");
+        assert_eq!(
+            selected(truehd(), "out.mkv", &["-strict", "-2"], default()),
+            "truehd 3000000"
+        );
+        let options = CodecOptions {
+            codec_map: vec![("truehd".to_string(), "aac".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(selected(truehd(), "out.mkv", &[], options), "aac 384k");
+
+        // the input bitrate of another codec does not apply
+        let dts = stream("dts", 6, None, Some("1509000"));
+        assert_eq!(selected(dts, "out.mkv", &[], default()), "eac3 640k");
+        // E-AC-3 supports up to 6 channels, FLAC all of them
+        let dts = stream("dts", 8, None, None);
+        assert_eq!(selected(dts, "out.mkv", &[], default()), "flac");
+
+        let wma = || stream("wmav2", 2, None, Some("128000"));
+        assert_eq!(selected(wma(), "out.mka", &[], default()), "aac 192k");
+        let wma = stream("wmapro", 2, None, None);
+        assert_eq!(selected(wma, "out.flac", &[], default()), "flac");
+    }
+
+    #[test]
+    fn select_by_container() {
+        let default = CodecOptions::default;
+
+        // neither the codec nor its fallback fit the container
+        let wma = stream("wmav2", 2, None, None);
+        assert_eq!(selected(wma, "out.mp3", &[], default()), "libmp3lame 320k");
+        let aac = stream("aac", 2, None, Some("128000"));
+        assert_eq!(selected(aac, "out.ogg", &[], default()), "libvorbis 192k");
+        let flac = stream("flac", 2, Some(24), None);
+        assert_eq!(selected(flac, "out.m4a", &[], default()), "flac");
+
+        // the channels of the codec
+        let ac3 = || stream("ac3", 6, None, Some("448000"));
+        assert_eq!(selected(ac3(), "out.ac3", &[], default()), "ac3 448000");
+        assert_eq!(
+            selected(ac3(), "out.MP3", &[], default()),
+            "libmp3lame 320k downmix 2"
+        );
+
+        // the PCM codec of the bit depth of the input
+        let flac = |bits| stream("flac", 2, Some(bits), None);
+        assert_eq!(selected(flac(24), "out.wav", &[], default()), "pcm_s24le");
+        assert_eq!(selected(flac(16), "out.WAV", &[], default()), "pcm_s16le");
+        assert_eq!(selected(flac(20), "out.aiff", &[], default()), "pcm_s24be");
+        assert_eq!(selected(flac(32), "out.aif", &[], default()), "pcm_s32be");
+        let mut float = stream("pcm_f32le", 2, None, None);
+        float.sample_fmt = Some("flt".to_string());
+        assert_eq!(selected(float, "out.wav", &[], default()), "pcm_f32le");
+    }
+
+    #[test]
+    fn select_messages_and_errors() {
+        let dts = stream("dts", 6, None, None);
+        let (_, messages) = select_with(
+            &dts,
+            Path::new("out.mkv"),
+            &CodecOptions::default(),
+            &[],
+            encoders,
+        )
+        .unwrap();
+        assert_eq!(
+            messages,
+            ["Audio codec \"dts\" is encoded as \"eac3\" (encoder \"eac3\", bitrate 640k)"]
+        );
+
+        let aac = stream("aac", 2, None, None);
+        let (_, messages) = select_with(
+            &aac,
+            Path::new("out.m4a"),
+            &CodecOptions::default(),
+            &[],
+            encoders,
+        )
+        .unwrap();
+        assert_eq!(
+            messages,
+            ["Bitrate of \"aac\" audio is unknown, encoding with 192k"]
+        );
+
+        let options = CodecOptions {
+            codec_map: vec![("truehd".to_string(), "libfdk_aac".to_string())],
+            ..Default::default()
+        };
+        let err = select_error(stream("truehd", 6, None, None), "out.mkv", options);
+        assert!(err.contains("\"libfdk_aac\""), "{err}");
+
+        let err = select_error(
+            stream("unknown", 2, None, None),
+            "out.mkv",
+            CodecOptions::default(),
+        );
+        assert!(err.contains("--codec-map unknown="), "{err}");
+        let err = select_error(
+            stream("unknown", 2, None, None),
+            "out.thd",
+            CodecOptions::default(),
+        );
+        assert!(err.contains("for \".thd\" output file"), "{err}");
+    }
+
+    #[test]
+    fn select_custom_encoder() {
+        let flac = || stream("flac", 2, Some(16), Some("1411200"));
+        let custom = |ffmpeg_args: &[&str], bit_rate: Option<&str>| {
+            let options = CodecOptions {
+                bit_rate: bit_rate.map(str::to_string),
+                ..Default::default()
+            };
+            let (codec, messages) = select_with(
+                &flac(),
+                Path::new("out.mkv"),
+                &options,
+                &args(ffmpeg_args),
+                unavailable,
+            )
+            .unwrap();
+            assert_eq!(codec.encoder, None);
+            assert!(messages.is_empty(), "{messages:?}");
+            (codec.codec, codec.bit_rate)
+        };
+        let some = |value: &str| Some(value.to_string());
+
+        // the bitrate of FLAC does not apply to Opus
+        assert_eq!(
+            custom(&["-c:a", "libopus"], None),
+            (some("opus"), some("128k"))
+        );
+        assert_eq!(
+            custom(&["-c:a", "libopus"], Some("96k")),
+            (some("opus"), some("96k"))
+        );
+        assert_eq!(
+            custom(&["-acodec", "flac"], None),
+            (some("flac"), some("1411200"))
+        );
+        // the encoder is missing
+        assert_eq!(custom(&["-c:a"], None), (None, None));
+        assert_eq!(custom(&["-c:a"], Some("96k")), (None, some("96k")));
+
+        let (_, messages) = select_with(
+            &flac(),
+            Path::new("out.mp3"),
+            &CodecOptions::default(),
+            &args(&["-c:a", "copy"]),
+            unavailable,
+        )
+        .unwrap();
+        assert!(
+            messages[0].contains("\"-c:a copy\" conflicts"),
+            "{messages:?}"
+        );
+    }
+
+    #[test]
+    fn rate_control_precedence() {
+        let input = Some("128000");
+        assert_eq!(rate("libmp3lame", 2, input, Some("256k"), Some(4)), "256k");
+        assert_eq!(rate("libmp3lame", 2, input, None, Some(4)), "-q:a 2");
+        assert_eq!(rate("libmp3lame", 2, input, None, None), "128000");
+        assert_eq!(rate("libmp3lame", 2, None, None, None), "320k");
+        assert_eq!(rate("libfdk_aac", 2, None, None, Some(1)), "-vbr 1");
+
+        // the default bitrate is scaled and snapped to the AC-3 bitrates
+        assert_eq!(rate("ac3", 6, input, None, Some(1)), "224k");
+        assert_eq!(rate("ac3", 2, None, None, Some(2)), "128k");
+        assert_eq!(rate("ac3", 6, None, None, Some(5)), "640k");
+        assert_eq!(rate("libopus", 2, None, None, Some(3)), "128k -vbr on");
+
+        assert_eq!(rate("flac", 2, None, None, Some(3)), "");
+        assert_eq!(rate("flac", 2, None, None, None), "");
+    }
+
+    #[test]
+    fn default_and_snapped_bit_rates() {
+        assert_eq!(default_kbps("aac", 1), Some((96, 512)));
+        assert_eq!(default_kbps("aac", 3), Some((384, 512)));
+        assert_eq!(default_kbps("aac", 12), Some((512, 512)));
+        assert_eq!(default_kbps("flac", 2), None);
+        assert_eq!(default_bit_rate("eac3", 6).as_deref(), Some("640k"));
+
+        assert_eq!(snap_bit_rate("ac3", 20), 32);
+        assert_eq!(snap_bit_rate("ac3", 300), 256);
+        assert_eq!(snap_bit_rate("ac3", 640), 640);
+        assert_eq!(snap_bit_rate("eac3", 333), 333);
+    }
+
+    #[test]
+    fn sample_formats() {
+        let mut messages = Vec::new();
+        let mut input = stream("pcm_s24le", 2, Some(24), None);
+        input.channel_layout = Some("stereo".to_string());
+
+        let format = sample_format(&input, "flac", None, &[], &mut messages);
+        assert_eq!(format.sample_fmt.as_deref(), Some("s32"));
+        assert_eq!(format.bits_per_raw_sample, Some(24));
+        assert_eq!(format.sample_rate, Some(48000));
+        assert_eq!(format.channel_layout.as_deref(), Some("stereo"));
+        assert_eq!(format.dither_method, None);
+
+        let format = sample_format(&input, "alac", None, &[], &mut messages);
+        assert_eq!(format.sample_fmt.as_deref(), Some("s32p"));
+        assert!(messages.is_empty(), "{messages:?}");
+
+        // the bit depth is reduced
+        let format = sample_format(&input, "pcm_s16le", None, &[], &mut messages);
+        assert_eq!(format.sample_fmt, None);
+        assert_eq!(format.dither_method, Some(DITHER_METHOD));
+        assert_eq!(messages.len(), 1);
+        assert!(
+            messages[0].contains("24 bit audio is reduced to 16 bit"),
+            "{messages:?}"
+        );
+
+        // lossy encoders have no bit depth, the downmix sets the layout
+        let format = sample_format(&input, "aac", Some(2), &[], &mut messages);
+        assert_eq!(format.sample_fmt, None);
+        assert_eq!(format.dither_method, None);
+        assert_eq!(format.channel_layout, None);
+
+        // ffmpeg arguments take precedence
+        let ffmpeg_args = args(&["-ar", "44100", "-sample_fmt", "s16", "-ac", "1"]);
+        let format = sample_format(&input, "flac", None, &ffmpeg_args, &mut messages);
+        assert_eq!(format.sample_rate, None);
+        assert_eq!(format.sample_fmt, None);
+        assert_eq!(format.channel_layout, None);
+
+        // the depth of the sample format
+        let mut input = stream("pcm_s32le", 2, None, None);
+        input.sample_fmt = Some("s32".to_string());
+        input.channel_layout = Some("unknown".to_string());
+        let format = sample_format(&input, "wavpack", None, &[], &mut messages);
+        assert_eq!(format.sample_fmt.as_deref(), Some("s32p"));
+        assert_eq!(format.bits_per_raw_sample, None);
+        assert_eq!(format.channel_layout, None);
+
+        // lossy decoders output floating point samples
+        input.codec_name = "aac".to_string();
+        input.sample_fmt = Some("fltp".to_string());
+        assert_eq!(bit_depth(&input), None);
+        input.codec_name = "pcm_f32le".to_string();
+        assert_eq!(bit_depth(&input), Some(32));
+    }
+}
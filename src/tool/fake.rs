@@ -0,0 +1,33 @@
+//! Fake ffmpeg of the tests that logs far more than a pipe buffer before its progress ends
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Once;
+
+/// Number of frames logged
+pub const FRAMES: usize = 20000;
+
+const SCRIPT: &str = r#"#!/bin/sh
+# progress of the whole run only after the log, like ffmpeg with a framelog
+awk -v n=FRAMES 'BEGIN { for (i = 1; i <= n; i++) printf "frame %d of a long log\n", i }' >&2
+echo "out_time_us=2000000000"
+echo "progress=end"
+"#;
+
+/// Path of the fake ffmpeg, which is written on first use
+pub fn path() -> PathBuf {
+    static INSTALL: Once = Once::new();
+    let ffmpeg = env::temp_dir()
+        .join(format!("fake-ffmpeg-{}", std::process::id()))
+        .join("ffmpeg");
+
+    INSTALL.call_once(|| {
+        fs::create_dir_all(ffmpeg.parent().unwrap()).unwrap();
+        fs::write(&ffmpeg, SCRIPT.replace("FRAMES", &FRAMES.to_string())).unwrap();
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755)).unwrap();
+    });
+
+    ffmpeg
+}
@@ -1,45 +1,67 @@
+use crate::bitstream::bext;
 use crate::io::to_stderr;
-use crate::tool::ffprobe::AudioStream;
+use crate::progress::{Pass, Progress, Status};
+use crate::provenance;
+use crate::tool::codec::OutputCodec;
+use crate::tool::ffprobe::Probe;
 use anyhow::{anyhow, bail, Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
-use lazy_static::lazy_static;
-use regex::Regex;
+use std::collections::HashMap;
 use std::env::consts::OS;
 use std::env::current_dir;
-use std::io::{BufRead, BufReader};
-use std::path::Path;
-use std::process::{ChildStderr, Command, Stdio};
+use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
 use std::time::Duration;
 
-lazy_static! {
-    static ref RE_DURATION: Regex = Regex::new(r#"^\s*out_time_ms\s*=\s*(\d+).*$"#).unwrap();
-}
+/// Log of an ffmpeg run on stderr
+pub type Log = Cursor<Vec<u8>>;
+
+/// Output file extensions of the MP4 muxer
+const MP4_EXTENSIONS: &[&str] = &["m4a", "m4b", "mp4", "mov"];
 
 pub struct FFmpeg {
-    cmd: Command,
+    program: PathBuf,
+    args: Args,
 }
 
-impl FFmpeg {
-    pub fn new(input_file: &Path) -> Self {
-        let mut path = current_dir().unwrap_or_default();
-        let ffmpeg = match OS {
-            "windows" => "ffmpeg.exe",
-            _ => "ffmpeg",
-        };
+/// Arguments of an ffmpeg command, the process is only created to run it. The arguments of a
+/// planned command can have placeholders (see `plan::Value`), which no process can be given.
+#[derive(Default)]
+pub struct Args(Vec<OsString>);
 
-        path.push(ffmpeg);
+impl Args {
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.0.push(arg.as_ref().to_os_string());
+        self
+    }
 
-        if !Path::new(&path).exists() {
-            path.clear();
-            path.push(ffmpeg);
-        }
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        args.into_iter().for_each(|arg| {
+            self.arg(arg);
+        });
+        self
+    }
+}
+
+impl FFmpeg {
+    pub fn new(input_file: &Path) -> Self {
+        FFmpeg::with_program(&FFmpeg::ffmpeg_path(), input_file)
+    }
 
+    fn with_program(program: &Path, input_file: &Path) -> Self {
         let mut ffmpeg = FFmpeg {
-            cmd: Command::new(path),
+            program: program.to_path_buf(),
+            args: Args::default(),
         };
 
         ffmpeg
-            .cmd
+            .args
             // send program-friendly progress information to stdout
             .arg("-progress")
             .arg("-")
@@ -56,88 +78,192 @@ impl FFmpeg {
         ffmpeg
     }
 
-    pub fn cmd(&mut self) -> &mut Command {
-        &mut self.cmd
+    /// Audio encoders supported by ffmpeg, the value is `true` for experimental encoders
+    pub fn encoders() -> Result<HashMap<String, bool>> {
+        let output = Command::new(FFmpeg::ffmpeg_path())
+            .arg("-hide_banner")
+            .arg("-encoders")
+            .output()
+            .with_context(|| "Failed to run FFmpeg tool")?;
+
+        if !output.status.success() {
+            bail!("Failed to get list of FFmpeg encoders");
+        }
+
+        // e.g. " A..X.. truehd               TrueHD"
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let flags = fields.next()?;
+                let name = fields.next()?;
+                (flags.len() == 6 && flags.starts_with('A'))
+                    .then(|| (name.to_string(), flags.chars().nth(3) == Some('X')))
+            })
+            .collect())
+    }
+
+    fn ffmpeg_path() -> PathBuf {
+        let mut path = current_dir().unwrap_or_default();
+        let ffmpeg = match OS {
+            "windows" => "ffmpeg.exe",
+            _ => "ffmpeg",
+        };
+
+        path.push(ffmpeg);
+
+        if !Path::new(&path).exists() {
+            path.clear();
+            path.push(ffmpeg);
+        }
+
+        path
+    }
+
+    pub fn cmd(&mut self) -> &mut Args {
+        &mut self.args
     }
 
-    fn dump_command_args(&self) {
-        println!("Running FFmpeg with the following arguments:");
-        print!("[ ");
-        self.cmd
-            .get_args()
-            .for_each(|arg| print!("{} ", arg.to_str().unwrap_or_default()));
-        println!("]");
+    /// Program followed by all arguments
+    pub fn command_line(&self) -> impl Iterator<Item = &OsStr> {
+        std::iter::once(self.program.as_os_str()).chain(self.args.0.iter().map(OsString::as_os_str))
     }
 
-    pub fn add_common_args(&mut self, file_info: &AudioStream, ffmpeg_args: &[String]) {
+    fn dump_command_args(&self, progress: &dyn Progress) {
+        let args: Vec<_> = self
+            .args
+            .0
+            .iter()
+            .map(|arg| arg.to_str().unwrap_or_default())
+            .collect();
+        progress.message("Running FFmpeg with the following arguments:");
+        progress.message(&format!("[ {} ]", args.join(" ")));
+    }
+
+    /// Set global metadata tags of the output file, `input` is the probed input file
+    pub fn add_metadata(&mut self, output_file: &Path, input: &Probe, tags: &[(String, String)]) {
+        if tags.is_empty() {
+            return;
+        }
+
+        // MP4 files only keep custom keys with this flag
+        let extension = output_file
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if MP4_EXTENSIONS.contains(&extension.as_str()) {
+            self.args.arg("-movflags").arg("+use_metadata_tags");
+        }
+        // WAV files only keep known keys, the tags are a line of the bext coding history. It
+        // replaces the history ffmpeg copies from the input, so the line is added to that.
+        if extension == "wav" {
+            let history = provenance::coding_history(input).unwrap_or_default();
+            self.args
+                .arg("-write_bext")
+                .arg("1")
+                .arg("-metadata")
+                .arg(format!(
+                    "coding_history={}",
+                    bext::add_coding_history(history, tags).trim_end()
+                ));
+            return;
+        }
+
+        tags.iter().for_each(|(key, value)| {
+            self.args.arg("-metadata").arg(format!("{key}={value}"));
+        });
+    }
+
+    pub fn add_common_args(&mut self, codec: &OutputCodec, ffmpeg_args: &[String]) {
         // set bit rate
-        if let Some(bitrate) = &file_info.bit_rate {
-            self.cmd.arg("-b:a").arg(bitrate);
+        if let Some(bitrate) = &codec.bit_rate {
+            self.args.arg("-b:a").arg(bitrate);
         }
 
-        // set codec name
-        self.cmd.arg("-c:a").arg(file_info.codec_name.as_str());
+        // set encoder
+        if let Some(encoder) = &codec.encoder {
+            self.args.arg("-c:a").arg(encoder);
+        }
+
+        // VBR quality
+        self.args.args(&codec.quality_args);
+
+        // downmix
+        if let Some(channels) = codec.channels {
+            self.args.arg("-ac").arg(channels.to_string());
+        }
+
+        // keep sample format and layout of the input
+        let format = &codec.format;
+        if let Some(sample_fmt) = &format.sample_fmt {
+            self.args.arg("-sample_fmt").arg(sample_fmt);
+        }
+        if let Some(bits) = format.bits_per_raw_sample {
+            self.args.arg("-bits_per_raw_sample").arg(bits.to_string());
+        }
+        if let Some(dither_method) = format.dither_method {
+            self.args.arg("-dither_method").arg(dither_method);
+        }
+        if let Some(sample_rate) = format.sample_rate {
+            self.args.arg("-ar").arg(sample_rate.to_string());
+        }
+        if let Some(channel_layout) = &format.channel_layout {
+            self.args.arg("-channel_layout").arg(channel_layout);
+        }
 
         // custom args
         ffmpeg_args.iter().for_each(|arg| {
-            self.cmd.arg(arg);
+            self.args.arg(arg);
         });
     }
 
+    /// Run the command, report its progress and return its log. The log is read on its own
+    /// thread while the command runs, so a long log cannot fill the pipe and block ffmpeg.
     pub fn exec(
         &mut self,
-        info_msg: &str,
+        pass: &Pass,
         verbose: bool,
         duration: Option<Duration>,
-    ) -> Result<BufReader<ChildStderr>> {
-        self.cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
-
-        println!("{info_msg}");
+        progress: &dyn Progress,
+    ) -> Result<Log> {
+        progress.start(pass, duration);
 
         if verbose {
-            self.dump_command_args();
+            self.dump_command_args(progress);
         }
 
-        let mut child = self
-            .cmd
+        let mut child = Command::new(&self.program)
+            .args(&self.args.0)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
             .with_context(|| "Failed to run FFmpeg tool")?;
 
-        let bar = ProgressBar::new(
-            duration
-                .unwrap_or_else(|| Duration::from_secs(10))
-                .as_micros() as u64,
-        );
-
-        bar.set_style(
-            if duration.is_some() {
-                ProgressStyle::default_bar().template(
-                    "[{elapsed_precise}] {bar:50.cyan/cyan} {percent}% (remaining: {eta})",
-                )
-            } else {
-                ProgressStyle::default_bar().template("[{elapsed_precise}] {spinner:.cyan}")
-            }
-            .unwrap_or_else(|_| ProgressStyle::default_bar()),
-        );
-
-        bar.set_position(0);
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| anyhow!("Failed to open FFmpeg stderr"))?;
+        let log = thread::spawn(move || {
+            let mut log = Vec::new();
+            BufReader::new(stderr).read_to_end(&mut log).map(|_| log)
+        });
 
         if let Some(stdout) = child.stdout.take() {
+            let mut status = Status::default();
+
             BufReader::new(stdout)
                 .lines()
-                .filter_map(|line| line.ok())
+                .map_while(Result::ok)
                 .for_each(|line| {
-                    if line == "progress=end" {
-                        bar.finish();
-                    } else if let Some(ms) = RE_DURATION
-                        .captures(line.as_str())
-                        .and_then(|m| m.get(1).map(|m| m.as_str()))
-                        .and_then(|ms| ms.parse::<u64>().ok())
-                    {
-                        if duration.is_some() {
-                            bar.set_position(ms);
-                        } else {
-                            bar.set_position(ms % 10);
+                    if let Some((key, value)) = line.split_once('=') {
+                        parse_progress(&mut status, key.trim(), value.trim());
+
+                        // "progress" key closes the block
+                        if key.trim() == "progress" {
+                            progress.update(&status);
+                            if status.end {
+                                progress.finish(&status);
+                            }
                         }
                     }
                 });
@@ -147,14 +273,16 @@ impl FFmpeg {
 
         let res = child.wait();
 
-        let stderr = child.stderr.take().map(BufReader::new);
+        let log = log
+            .join()
+            .map_err(|_| anyhow!("Failed to read FFmpeg stderr"))?
+            .with_context(|| "Failed to read FFmpeg stderr")?;
+        let log = Cursor::new(log);
 
         match res {
             Ok(status) => {
                 if !status.success() {
-                    if let Some(stderr) = stderr {
-                        to_stderr(stderr);
-                    }
+                    to_stderr(log);
                     if let Some(code) = status.code() {
                         bail!("Failed to run FFmpeg with exit code={}", code);
                     } else {
@@ -163,13 +291,132 @@ impl FFmpeg {
                 }
             }
             Err(err) => {
-                if let Some(stderr) = stderr {
-                    to_stderr(stderr);
-                }
+                to_stderr(log);
                 return Err(err).with_context(|| "Failed to run FFmpeg tool");
             }
         }
 
-        stderr.ok_or_else(|| anyhow!("Failed to open FFmpeg stderr"))
+        Ok(log)
+    }
+}
+
+/// Update progress with a key/value pair of the ffmpeg `-progress` stream.
+fn parse_progress(status: &mut Status, key: &str, value: &str) {
+    match key {
+        // out_time_ms is in microseconds too
+        "out_time_us" | "out_time_ms" => {
+            if let Ok(us) = value.parse::<u64>() {
+                status.out_time = Duration::from_micros(us);
+            }
+        }
+        "total_size" => status.total_size = value.parse::<u64>().ok(),
+        "bitrate" => status.bitrate = value.trim_end_matches("kbits/s").trim().parse::<f64>().ok(),
+        "speed" => status.speed = value.trim_end_matches('x').trim().parse::<f64>().ok(),
+        "progress" => status.end = value == "end",
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tool::ffprobe;
+
+    #[test]
+    fn metadata_of_wav_keeps_coding_history() {
+        let tags = [("NORMALIZER".to_string(), "test 1.0".to_string())];
+        let metadata = |output_file: &str, json: &str| {
+            let mut ffmpeg = FFmpeg::new(Path::new("in.wav"));
+            let input = ffprobe::parse(json.as_bytes()).unwrap();
+            ffmpeg.add_metadata(Path::new(output_file), &input, &tags);
+            ffmpeg
+                .command_line()
+                .skip_while(|arg| *arg != "-i")
+                .skip(2)
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        let input = r#"{"format": {"tags": {"coding_history": "A=PCM,F=48000,W=24,M=stereo\r\nT=NORMALIZER=test 0.9\r\n"}},
+            "streams": [{"codec_name": "pcm_s24le"}]}"#;
+
+        // the earlier line of the tool is replaced
+        assert_eq!(
+            metadata("out.wav", input),
+            [
+                "-write_bext",
+                "1",
+                "-metadata",
+                "coding_history=A=PCM,F=48000,W=24,M=stereo\r\nT=NORMALIZER=test 1.0"
+            ]
+        );
+        assert_eq!(
+            metadata("out.wav", r#"{"streams": [{"codec_name": "pcm_s16le"}]}"#)[3],
+            "coding_history=T=NORMALIZER=test 1.0"
+        );
+        assert_eq!(
+            metadata("out.m4a", input),
+            [
+                "-movflags",
+                "+use_metadata_tags",
+                "-metadata",
+                "NORMALIZER=test 1.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_progress_block() {
+        let mut status = Status::default();
+        [
+            ("frame", "0"),
+            ("total_size", "524288"),
+            ("out_time_us", "12500000"),
+            ("out_time_ms", "12500000"),
+            ("out_time", "00:00:12.500000"),
+            ("bitrate", " 335.5kbits/s"),
+            ("speed", "41.7x"),
+            ("progress", "continue"),
+        ]
+        .into_iter()
+        .for_each(|(key, value)| parse_progress(&mut status, key, value.trim()));
+
+        assert_eq!(status.out_time, Duration::from_millis(12500));
+        assert_eq!(status.total_size, Some(524288));
+        assert_eq!(status.bitrate, Some(335.5));
+        assert_eq!(status.speed, Some(41.7));
+        assert!(!status.end);
+
+        // values of ffmpeg before the first output are unknown, the last block ends the run
+        parse_progress(&mut status, "bitrate", "N/A");
+        parse_progress(&mut status, "speed", "N/A");
+        parse_progress(&mut status, "out_time_us", "N/A");
+        parse_progress(&mut status, "progress", "end");
+        assert_eq!(status.bitrate, None);
+        assert_eq!(status.speed, None);
+        assert_eq!(status.out_time, Duration::from_millis(12500));
+        assert!(status.end);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exec_reads_log_longer_than_pipe_buffer() {
+        use crate::progress::{self, Format, Phase};
+        use crate::tool::fake;
+
+        let pass = Pass {
+            number: 1,
+            count: 1,
+            phase: Phase::Measure,
+            description: "Processing audio file with a long log",
+        };
+        let progress = progress::new(Format::Plain, None, false).unwrap();
+
+        let log = FFmpeg::with_program(&fake::path(), Path::new("in.wav"))
+            .exec(&pass, false, None, progress.as_ref())
+            .unwrap();
+
+        // far more than the 64 KiB pipe buffer of Linux
+        assert!(log.get_ref().len() > 1 << 16);
+        assert_eq!(log.lines().count(), fake::FRAMES);
     }
 }
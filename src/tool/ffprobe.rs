@@ -1,7 +1,9 @@
 use anyhow::{anyhow, bail, Context, Result};
 use serde::{de::Error, Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::env::consts::OS;
 use std::env::current_dir;
+use std::fmt::{self, Display};
 use std::io;
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -11,7 +13,8 @@ use std::time::Duration;
 pub struct FFprobe {}
 
 impl FFprobe {
-    pub fn info(file: &Path) -> Result<AudioStream> {
+    /// Container format and first audio stream information of the file
+    pub fn probe(file: &Path) -> Result<Probe> {
         let output = Command::new(FFprobe::ffprobe_path())
             .arg("-i")
             .arg(file)
@@ -19,6 +22,7 @@ impl FFprobe {
             .arg("error")
             .arg("-print_format")
             .arg("json")
+            .arg("-show_format")
             .arg("-show_streams")
             .arg("-select_streams")
             .arg("a:0")
@@ -37,12 +41,7 @@ impl FFprobe {
             }
         }
 
-        let mut res = serde_json::from_slice::<FileInfo>(&output.stdout)
-            .with_context(|| "Failed to parse FFprobe output")?;
-
-        res.streams
-            .pop()
-            .ok_or_else(|| anyhow!("FFprobe does not return stream information"))
+        parse(&output.stdout)
     }
 
     fn ffprobe_path() -> PathBuf {
@@ -65,16 +64,155 @@ impl FFprobe {
 
 #[derive(Deserialize)]
 struct FileInfo {
+    #[serde(default)]
+    format: Format,
     streams: Vec<AudioStream>,
 }
 
+pub struct Probe {
+    pub format: Format,
+    pub stream: AudioStream,
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "format={} ({})",
+            self.format.format_name,
+            self.format.format_long_name.as_deref().unwrap_or("unknown")
+        )?;
+        if let Some(bit_rate) = &self.format.bit_rate {
+            write!(f, " bit_rate={bit_rate}")?;
+        }
+        write!(f, ", audio codec={}", self.stream.codec_name)?;
+        if let Some(bit_rate) = &self.stream.bit_rate {
+            write!(f, " bit_rate={bit_rate}")?;
+        }
+        if let Some(sample_fmt) = &self.stream.sample_fmt {
+            write!(f, " sample_fmt={sample_fmt}")?;
+        }
+        if let Some(bits) = self.stream.bits_per_raw_sample {
+            write!(f, " bits_per_raw_sample={bits}")?;
+        }
+        if let Some(sample_rate) = self.stream.sample_rate {
+            write!(f, " sample_rate={sample_rate}")?;
+        }
+        if let Some(channel_layout) = &self.stream.channel_layout {
+            write!(f, " channel_layout={channel_layout}")?;
+        }
+        if let Some(start_time) = self.stream.start_time.or(self.format.start_time) {
+            write!(f, " start_time={start_time}s")?;
+        }
+        if let Some(duration) = self.stream.duration {
+            write!(f, " duration={}s", duration.as_secs_f64())?;
+        }
+        let mut tags: Vec<_> = self.format.tags.iter().chain(&self.stream.tags).collect();
+        tags.sort();
+        tags.iter()
+            .try_for_each(|(key, value)| write!(f, "\n  {key}={value}"))
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct Format {
+    #[serde(default)]
+    pub format_name: String,
+    #[serde(default)]
+    pub format_long_name: Option<String>,
+    #[serde(default, deserialize_with = "from_seconds")]
+    pub start_time: Option<f64>,
+    #[serde(default, deserialize_with = "from_duration")]
+    pub duration: Option<Duration>,
+    #[serde(default)]
+    pub bit_rate: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
 #[derive(Deserialize)]
 pub struct AudioStream {
     pub codec_name: String,
+    #[serde(default)]
+    pub channels: Option<u32>,
+    #[serde(default)]
+    pub channel_layout: Option<String>,
+    #[serde(default)]
+    pub sample_fmt: Option<String>,
+    #[serde(default, deserialize_with = "from_number")]
+    pub sample_rate: Option<u32>,
+    /// Bit depth of lossless codecs, `None` if unknown
+    #[serde(default, deserialize_with = "from_number")]
+    pub bits_per_raw_sample: Option<u32>,
+    #[serde(default, deserialize_with = "from_seconds")]
+    pub start_time: Option<f64>,
     #[serde(default, deserialize_with = "from_duration")]
     pub duration: Option<Duration>,
     #[serde(default)]
     pub bit_rate: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+/// Format and stream of the JSON output of ffprobe, the duration of the stream falls back to
+/// the duration of the format
+pub fn parse(json: &[u8]) -> Result<Probe> {
+    let mut res = serde_json::from_slice::<FileInfo>(json)
+        .with_context(|| "Failed to parse FFprobe output")?;
+
+    let mut stream = res
+        .streams
+        .pop()
+        .ok_or_else(|| anyhow!("FFprobe does not return stream information"))?;
+
+    // many containers (e.g. Matroska) have no stream level duration
+    stream.duration = stream
+        .duration
+        .or_else(|| tag_duration(&stream.tags))
+        .or(res.format.duration)
+        .or_else(|| tag_duration(&res.format.tags));
+
+    Ok(Probe {
+        format: res.format,
+        stream,
+    })
+}
+
+/// Duration from a Matroska style "DURATION" tag, e.g. "01:23:45.678000000"
+fn tag_duration(tags: &HashMap<String, String>) -> Option<Duration> {
+    tags.iter()
+        .find(|(key, _)| key.to_uppercase().starts_with("DURATION"))
+        .and_then(|(_, value)| {
+            let mut parts = value.trim().rsplitn(3, ':');
+            let seconds = parts.next()?.parse::<f64>().ok()?;
+            let minutes = parts.next().unwrap_or("0").parse::<u64>().ok()?;
+            let hours = parts.next().unwrap_or("0").parse::<u64>().ok()?;
+            Some(
+                Duration::from_secs(hours * 3600 + minutes * 60)
+                    + Duration::try_from_secs_f64(seconds).ok()?,
+            )
+        })
+}
+
+fn from_seconds<'a, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    let s: &str = Deserialize::deserialize(deserializer)?;
+    s.parse::<f64>()
+        .map(Some)
+        .map_err(|err| D::Error::custom(err.to_string()))
+}
+
+/// Positive number from a string, `None` for "0" (unknown)
+fn from_number<'a, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    let s: &str = Deserialize::deserialize(deserializer)?;
+    s.parse::<u32>()
+        .map(|n| (n > 0).then_some(n))
+        .map_err(|err| D::Error::custom(err.to_string()))
 }
 
 fn from_duration<'a, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
@@ -86,3 +224,88 @@ where
         .map(|d| Some(Duration::from_micros((d * 1_000_000.0).trunc() as u64)))
         .map_err(|err| D::Error::custom(err.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duration(json: &str) -> Option<Duration> {
+        parse(json.as_bytes()).unwrap().stream.duration
+    }
+
+    fn tags(key: &str, value: &str) -> HashMap<String, String> {
+        HashMap::from([(key.to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn tag_duration_of_matroska() {
+        assert_eq!(
+            tag_duration(&tags("DURATION", "01:23:45.678000000")),
+            Some(Duration::from_millis(5_025_678))
+        );
+        // language suffix of the tag, minutes and seconds only
+        assert_eq!(
+            tag_duration(&tags("duration-eng", " 02:03.5 ")),
+            Some(Duration::from_millis(123_500))
+        );
+        assert_eq!(
+            tag_duration(&tags("DURATION", "12.25")),
+            Some(Duration::from_millis(12250))
+        );
+        assert_eq!(tag_duration(&tags("DURATION", "unknown")), None);
+        assert_eq!(tag_duration(&tags("DURATION", "1:-2:00")), None);
+        assert_eq!(tag_duration(&tags("TITLE", "00:01:00")), None);
+    }
+
+    #[test]
+    fn duration_falls_back_to_format() {
+        let stream =
+            r#"{"codec_name": "aac", "duration": "10.5", "tags": {"DURATION": "00:00:20.0"}}"#;
+        let format = r#"{"duration": "30.25", "tags": {"DURATION": "00:00:40.0"}}"#;
+        let json = |stream: &str, format: &str| {
+            format!(r#"{{"format": {format}, "streams": [{stream}]}}"#)
+        };
+
+        assert_eq!(
+            duration(&json(stream, format)),
+            Some(Duration::from_millis(10500))
+        );
+        // a Matroska stream has the duration in its tags
+        let stream = r#"{"codec_name": "aac", "tags": {"DURATION": "00:00:20.0"}}"#;
+        assert_eq!(
+            duration(&json(stream, format)),
+            Some(Duration::from_secs(20))
+        );
+        let stream = r#"{"codec_name": "aac"}"#;
+        assert_eq!(
+            duration(&json(stream, format)),
+            Some(Duration::from_millis(30250))
+        );
+        let format = r#"{"tags": {"DURATION": "00:00:40.0"}}"#;
+        assert_eq!(
+            duration(&json(stream, format)),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(duration(&json(stream, "{}")), None);
+        assert_eq!(duration(&format!(r#"{{"streams": [{stream}]}}"#)), None);
+    }
+
+    #[test]
+    fn parse_without_stream() {
+        assert!(parse(br#"{"format": {}, "streams": []}"#).is_err());
+        assert!(parse(b"not json").is_err());
+    }
+
+    #[test]
+    fn parse_stream_numbers() {
+        let probe = parse(
+            br#"{"streams": [{"codec_name": "flac", "sample_rate": "48000",
+                "bits_per_raw_sample": "0", "start_time": "0.025"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(probe.stream.sample_rate, Some(48000));
+        // 0 is unknown
+        assert_eq!(probe.stream.bits_per_raw_sample, None);
+        assert_eq!(probe.stream.start_time, Some(0.025));
+    }
+}
@@ -1,2 +1,7 @@
+pub mod codec;
 pub mod ffmpeg;
 pub mod ffprobe;
+
+// the fake ffmpeg of the tests is a shell script
+#[cfg(all(test, unix))]
+pub mod fake;
@@ -0,0 +1,9 @@
+//! Text of XML documents, e.g. of the SVG plot, the HTML report and the JUnit result.
+
+/// Escape the markup characters of `text` in element content and attribute values
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}